@@ -76,7 +76,6 @@ struct Inputs {
 }
 
 fuzz_target!(|input: Inputs| {
-    let progress_reporter = ripunzip::NullProgressReporter;
     let tempdir = tempfile::tempdir().unwrap();
     let output_directory = tempdir.path().join("out_ripunzip");
     let output_directory_unzip = tempdir.path().join("out_unzip");
@@ -84,8 +83,7 @@ fuzz_target!(|input: Inputs| {
     let options = ripunzip::UnzipOptions {
         single_threaded: input.single_threaded,
         output_directory: Some(output_directory.clone()),
-        filename_filter: None,
-        progress_reporter: Box::new(progress_reporter),
+        ..Default::default()
     };
     let zipfile = tempdir.path().join("file.zip");
     let mut zip_data = Vec::new();
@@ -109,6 +107,8 @@ fuzz_target!(|input: Inputs| {
             let ripunzip = ripunzip::UnzipEngine::for_uri(
                 uri,
                 readahead_limit,
+                Default::default(),
+                Default::default(),
                 || {},
             )?;
             ripunzip.unzip(options)
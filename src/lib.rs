@@ -6,12 +6,61 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#![forbid(unsafe_code)]
+#![cfg_attr(
+    not(any(feature = "io_priority", feature = "mmap", feature = "sandbox")),
+    forbid(unsafe_code)
+)]
+// Memory mapping the input file requires one unsafe call, and sandboxing
+// and setting I/O priority need a few system calls, which are explicitly
+// allowed.
+#![cfg_attr(
+    any(feature = "io_priority", feature = "mmap", feature = "sandbox"),
+    deny(unsafe_code)
+)]
 
 mod unzip;
 
+pub use unzip::process_umask;
+pub use unzip::AccessPattern;
+pub use unzip::ArchiveSummary;
+pub use unzip::CaseCollisionPolicy;
+pub use unzip::ConcatSink;
+pub use unzip::CrxHeader;
+pub use unzip::EntryMetadata;
+pub use unzip::EntryReader;
+pub use unzip::EntryType;
+pub use unzip::ExistingFileAction;
+pub use unzip::ExistingFileHandler;
+pub use unzip::ExtractionFailure;
+pub use unzip::ExtractionPolicy;
 pub use unzip::FilenameFilter;
+pub use unzip::FilenameSanitization;
+pub use unzip::FilterSet;
+pub use unzip::HttpOptions;
+pub use unzip::IoPriority;
+pub use unzip::ListedEntry;
+pub use unzip::Manifest;
+pub use unzip::MemoryBudget;
+pub use unzip::MetadataFilter;
+pub use unzip::ModificationTimes;
+pub use unzip::NameTransform;
 pub use unzip::NullProgressReporter;
+pub use unzip::OutputSink;
+pub use unzip::OverwritePolicy;
+pub use unzip::PermissionsPolicy;
+pub use unzip::ReadaheadWatermarks;
+#[cfg(feature = "s3")]
+pub use unzip::S3Credentials;
+#[cfg(feature = "s3")]
+pub use unzip::S3Sink;
+pub use unzip::SignatureVerifier;
+pub use unzip::SymlinkPolicy;
+pub use unzip::TarSink;
+pub use unzip::Timings;
 pub use unzip::UnzipEngine;
 pub use unzip::UnzipOptions;
 pub use unzip::UnzipProgressReporter;
+pub use unzip::WarningKind;
+pub use unzip::WorkerPriority;
+pub use unzip::ZipCompression;
+pub use unzip::ZipSink;
@@ -8,13 +8,34 @@
 
 #![forbid(unsafe_code)]
 
-use std::{fmt::Write, fs::File, path::PathBuf, sync::RwLock};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+    fs::File,
+    io::{IsTerminal, Seek},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::Result;
-use clap::{Args, Parser, Subcommand};
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use console::{style, StyledObject};
+use indicatif::{
+    HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
+};
+use rayon::prelude::*;
 use ripunzip::{
-    FilenameFilter, NullProgressReporter, UnzipEngine, UnzipOptions, UnzipProgressReporter,
+    CaseCollisionPolicy, ConcatSink, EntryType, ExistingFileAction, ExistingFileHandler,
+    ExtractionFailure, ExtractionPolicy, FilenameFilter, FilenameSanitization, FilterSet,
+    HttpOptions, IoPriority, ListedEntry, Manifest, MemoryBudget, MetadataFilter,
+    ModificationTimes, NameTransform, OutputSink, OverwritePolicy, PermissionsPolicy,
+    ReadaheadWatermarks, SignatureVerifier, SymlinkPolicy, Timings, UnzipEngine, UnzipOptions,
+    UnzipProgressReporter, WarningKind, WorkerPriority, ZipCompression, ZipSink,
 };
 use wildmatch::WildMatch;
 
@@ -31,6 +52,43 @@ struct RipunzipArgs {
 
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
+
+    /// When to color the output. By default it's colored when writing to
+    /// a terminal, unless the NO_COLOR environment variable is set.
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_enum,
+        default_value = "auto",
+        global = true
+    )]
+    color: ColorChoice,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Turn colors on or off for everything written to the terminal.
+    fn apply(self) {
+        let enabled = match self {
+            Self::Auto => {
+                // Colors are already only used for terminals.
+                if std::env::var_os("NO_COLOR").map_or(true, |value| value.is_empty()) {
+                    return;
+                }
+                false
+            }
+            Self::Always => true,
+            Self::Never => false,
+        };
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -39,6 +97,12 @@ enum Commands {
     ListFile {
         #[command(flatten)]
         file_args: FileArgs,
+
+        #[command(flatten)]
+        filter_args: FilterArgs,
+
+        #[command(flatten)]
+        list_args: ListArgs,
     },
 
     /// Unzip a zip file
@@ -46,6 +110,19 @@ enum Commands {
         #[command(flatten)]
         file_args: FileArgs,
 
+        /// Treat FILE as a directory, and unzip every zip file anywhere
+        /// beneath it into a directory next to it named after it. If an
+        /// output directory is given, they're unzipped into the same
+        /// places relative to it instead.
+        #[arg(long)]
+        scan: bool,
+
+        /// If FILE holds several zip files one after another, unzip each
+        /// into a subdirectory of the output directory named after its
+        /// number, counting from 1.
+        #[arg(long, conflicts_with_all = ["scan", "archive"])]
+        all_archives: bool,
+
         #[command(flatten)]
         unzip_args: UnzipArgs,
     },
@@ -54,6 +131,12 @@ enum Commands {
     ListUri {
         #[command(flatten)]
         uri_args: UriArgs,
+
+        #[command(flatten)]
+        filter_args: FilterArgs,
+
+        #[command(flatten)]
+        list_args: ListArgs,
     },
 
     /// Unzips a zip file from a URI
@@ -61,9 +144,119 @@ enum Commands {
         #[command(flatten)]
         uri_args: UriArgs,
 
+        /// Another zip file to download and unzip at the same time. May be
+        /// given more than once. Each zip file is then unzipped into a
+        /// subdirectory of the output directory named after it, and they
+        /// share the same threads.
+        #[arg(long, value_name = "URI")]
+        also_uri: Vec<String>,
+
+        /// Keep the output directory in sync with the zip file: only unzip
+        /// files which have changed since it was last unzipped there, and
+        /// remove those which are no longer in it. What was unzipped is
+        /// remembered in a .ripunzip-mirror.json file in the directory.
+        #[arg(long, conflicts_with = "also_uri")]
+        mirror: bool,
+
         #[command(flatten)]
         unzip_args: UnzipArgs,
     },
+
+    /// Writes a new zip file holding some of the files in a zip file,
+    /// possibly compressed differently
+    RepackFile {
+        #[command(flatten)]
+        file_args: FileArgs,
+
+        #[command(flatten)]
+        repack_args: RepackArgs,
+    },
+
+    /// Writes a new zip file holding some of the files in a zip file from a
+    /// URI, possibly compressed differently
+    RepackUri {
+        #[command(flatten)]
+        uri_args: UriArgs,
+
+        #[command(flatten)]
+        repack_args: RepackArgs,
+    },
+
+    /// Prints the start of one file in a zip file, without unzipping
+    /// anything else. No more of it than is printed is decompressed, and
+    /// for zip files from URIs, little more than it is downloaded.
+    Head {
+        /// Zip file, or URI of a zip file to download parts of
+        #[arg(value_name = "FILE|URI")]
+        zipfile: String,
+
+        /// Name of the file in the zip file to print the start of
+        #[arg(value_name = "ENTRY")]
+        entry: String,
+
+        /// How many bytes to print
+        #[arg(short = 'c', long, value_name = "N", default_value_t = 1024)]
+        bytes: u64,
+    },
+
+    /// Mounts a zip file as a read-only filesystem, until it's unmounted
+    #[cfg(all(feature = "fuse", unix))]
+    MountFile {
+        #[command(flatten)]
+        file_args: FileArgs,
+
+        /// Directory on which to mount the zip file's contents
+        mountpoint: PathBuf,
+    },
+
+    /// Mounts a zip file from a URI as a read-only filesystem, until it's
+    /// unmounted
+    #[cfg(all(feature = "fuse", unix))]
+    MountUri {
+        #[command(flatten)]
+        uri_args: UriArgs,
+
+        /// Directory on which to mount the zip file's contents
+        mountpoint: PathBuf,
+    },
+
+    /// Serves the files in a zip file over HTTP, until killed
+    #[cfg(feature = "serve")]
+    ServeFile {
+        #[command(flatten)]
+        file_args: FileArgs,
+
+        #[command(flatten)]
+        serve_args: ServeArgs,
+    },
+
+    /// Serves the files in a zip file from a URI over HTTP, until killed
+    #[cfg(feature = "serve")]
+    ServeUri {
+        #[command(flatten)]
+        uri_args: UriArgs,
+
+        #[command(flatten)]
+        serve_args: ServeArgs,
+    },
+
+    /// Prints a script which completes ripunzip's arguments in a shell
+    Completions {
+        /// The shell for which to print the script
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
+
+    /// Prints ripunzip's man page, in roff format
+    Manpage,
+}
+
+#[cfg(feature = "serve")]
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// The address and port on which to serve the files.
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:8080")]
+    address: String,
 }
 
 #[derive(Args, Debug)]
@@ -83,17 +276,512 @@ struct UnzipArgs {
     #[arg(long)]
     single_threaded: bool,
 
-    /// Optionally, a list of files to unzip from the zip file. Omit
-    /// to unzip all of them. This can include wildcards.
+    /// Extract into a temporary directory next to the output directory, and
+    /// only move the files into place once every file has been extracted
+    /// successfully. The new tree is swapped into place at once, alongside
+    /// any files already in the output directory, so a partially extracted
+    /// tree is never visible.
+    #[arg(long)]
+    staging: bool,
+
+    /// Flush each extracted file, and the directories containing them, to
+    /// disk before reporting success. This is slower, but ensures the
+    /// extracted files survive a power loss.
+    #[arg(long)]
+    fsync: bool,
+
+    /// Create sparse output files, skipping over long runs of zeros rather
+    /// than writing them. Useful for disk images.
+    #[arg(long)]
+    sparse: bool,
+
+    /// How to handle filenames which aren't valid on Windows, such as those
+    /// containing ':' or named 'CON'. By default, names are only sanitized
+    /// when running on Windows.
+    #[arg(long, value_name = "MODE")]
+    sanitize_names: Option<SanitizeMode>,
+
+    /// The character used to replace invalid characters in filenames, when
+    /// sanitizing them by replacement.
+    #[arg(long, value_name = "CHAR", default_value_t = '_')]
+    sanitize_replacement: char,
+
+    /// What to do about files whose names differ only by case, which would
+    /// overwrite each other on a case-insensitive filesystem. By default,
+    /// such files are renamed on Windows and macOS, and left alone elsewhere.
+    #[arg(long, value_name = "POLICY")]
+    case_collisions: Option<CaseCollisionMode>,
+
+    /// Fail if the extracted files would add up to more than this many
+    /// bytes. Useful when extracting untrusted zip files, which may
+    /// decompress to far more than their size suggests.
+    #[arg(long, value_name = "BYTES")]
+    max_output_size: Option<u64>,
+
+    /// Keep the memory used for data on its way to the output files within
+    /// this many bytes, by fetching less ahead and extracting fewer files
+    /// at once. Accepts suffixes such as K, M and G.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<u64>,
+
+    /// Run the threads which extract files at this niceness, from 0 to 19,
+    /// so that they don't starve other programs of CPU. Only available on
+    /// Linux.
+    #[arg(long, value_name = "NICENESS", value_parser = clap::value_parser!(i32).range(0..=19))]
+    nice: Option<i32>,
+
+    /// Give the threads which extract files this I/O priority: 'idle', to
+    /// only use the disk when nothing else wants it, or a best-effort level
+    /// from 0 to 7, where 7 is the lowest. Only available on Linux, in builds
+    /// with the io_priority feature.
+    #[arg(long, value_name = "PRIORITY", value_parser = parse_io_priority)]
+    io_priority: Option<IoPriority>,
+
+    /// Before decompressing anything, sandbox the threads which do it so
+    /// that they can only reach files in the output directory, and can't
+    /// run programs. Useful when extracting untrusted zip files. Only
+    /// available on Linux with Landlock, in builds with the sandbox
+    /// feature.
+    #[arg(long)]
+    sandbox: bool,
+
+    /// What to do with symbolic links. By default, each is extracted as a
+    /// file containing the path it points to. Creating symbolic links on
+    /// Windows needs Developer Mode or administrator privileges, which
+    /// junctions and copies don't.
+    #[arg(long, value_name = "POLICY")]
+    symlinks: Option<SymlinkMode>,
+
+    /// Rewrite the names of files with a sed-style expression such as
+    /// 's/^old-prefix/new-prefix/' before extracting them, as GNU tar's
+    /// --transform does. The regular expression uses Rust regex syntax,
+    /// and the replacement may use & and \1 to \9. May be given more than
+    /// once, to apply each in turn.
+    #[arg(long, value_name = "EXPRESSION")]
+    transform: Vec<NameTransform>,
+
+    /// Extract every file directly into the output directory, leaving out
+    /// the directories. Files with the same name are numbered, like
+    /// name-1.ext, and listed in the error report, if there is one.
+    #[arg(long)]
+    flatten: bool,
+
+    /// Once everything's extracted, read back each file and check that it
+    /// has the CRC-32 recorded in the zip file, to catch anything that went
+    /// wrong writing it to disk.
+    #[arg(long)]
+    verify_after: bool,
+
+    /// Set permissions exactly as recorded in the zip file, rather than
+    /// masking them with the umask as usual.
+    #[arg(long)]
+    no_umask: bool,
+
+    /// On Windows, set the hidden attribute on files which had it when they
+    /// were zipped. Read-only attributes are always restored.
+    #[arg(long)]
+    restore_hidden: bool,
+
+    /// Leave out the __MACOSX directory, .DS_Store files and AppleDouble
+    /// ._ files which macOS adds to zip files. This is the default.
+    #[arg(long, overrides_with = "keep_macos_junk")]
+    skip_macos_junk: bool,
+
+    /// Extract the __MACOSX directory, .DS_Store files and AppleDouble ._
+    /// files like any others.
+    #[arg(long, overrides_with = "skip_macos_junk")]
+    keep_macos_junk: bool,
+
+    /// Give the Finder info, resource forks and other extended attributes
+    /// in AppleDouble ._ files back to the files they belong to, rather
+    /// than leaving them out. Only supported on macOS.
+    #[arg(long)]
+    restore_apple_double: bool,
+
+    /// Leave files alone if they already exist with the same size and CRC
+    /// as in the zip file, so re-extracting a mostly unchanged zip file is
+    /// quick.
+    #[arg(long)]
+    freshen: bool,
+
+    /// Write the contents of the files to standard output, one after
+    /// another in the order they're in the zip file, instead of extracting
+    /// them. Implies --single-threaded.
+    #[arg(short = 'p', long, conflicts_with = "priority")]
+    pipe: bool,
+
+    /// Overwrite files which already exist without asking. Otherwise, when
+    /// run in a terminal, ripunzip asks what to do about each one.
+    #[arg(short = 'o', long)]
+    overwrite: bool,
+
+    /// Once everything has been extracted, remove any files from the output
+    /// directory which aren't in the zip file, so that it matches the zip
+    /// file exactly. If a list of files to unzip is given, only files
+    /// matching it are removed.
+    #[arg(long)]
+    delete_extraneous: bool,
+
+    /// Hardlink files with identical contents to each other, rather than
+    /// writing out each copy. Saves space when a zip file contains many
+    /// copies of the same file.
+    #[arg(long)]
+    hardlink_duplicates: bool,
+
+    /// Always extract a file per CPU at once. By default, fewer files are
+    /// extracted at once while the network or the disk is the bottleneck.
+    #[arg(long)]
+    fixed_threads: bool,
+
+    /// Extract files matching this pattern before any others, so that they
+    /// can be used while the rest are extracted. May be given more than
+    /// once, and can include wildcards.
+    #[arg(long, value_name = "PATTERN")]
+    priority: Vec<String>,
+
+    /// Skip files which are corrupt, and extract the rest, rather than
+    /// failing.
+    #[arg(long)]
+    skip_corrupt: bool,
+
+    /// Give every extracted file and directory the same modification time,
+    /// given as @ followed by seconds since 1970, such as @0, so that the
+    /// output is identical each time. Or 'archive' to use the times
+    /// recorded in the zip file. By default, files are left with the time
+    /// they were extracted.
+    #[arg(long, value_name = "WHEN", value_parser = parse_modification_times)]
+    mtime: Option<ModificationTimes>,
+
+    /// Delete the zip file once every file in it has been extracted
+    /// successfully. Zip files from URIs or standard input aren't kept
+    /// anyway.
+    #[arg(long)]
+    remove_archive: bool,
+
+    /// Print a line for each file once extraction is finished, in the order
+    /// they're in the zip file, rather than as they're extracted. The
+    /// output is then the same from one run to the next.
+    #[arg(long)]
+    ordered_output: bool,
+
+    /// Once extracted, check the files against this list of SHA-256
+    /// digests, in the format written by sha256sum. Files which are
+    /// missing or different are reported as failures.
+    #[arg(long, value_name = "PATH")]
+    check_manifest: Option<PathBuf>,
+
+    /// Once finished, write metrics about the extraction to this file, in
+    /// the Prometheus textfile format.
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Once finished, write a JSON report of every file which failed to
+    /// extract to this file, along with any extracted under a different
+    /// name from the one in the zip file.
+    #[arg(long, value_name = "PATH")]
+    error_report: Option<PathBuf>,
+
+    /// Once finished, print how long was spent opening the zip file,
+    /// waiting for the network, decompressing and writing. Apart from
+    /// opening it, these are added up across all the threads extracting
+    /// files, so may add up to more than the time taken.
+    #[arg(long)]
+    timings: bool,
+
+    /// Once extraction has finished, whether successfully or not, run this
+    /// shell command. It's told the outcome by the environment variables
+    /// RIPUNZIP_STATUS ("success" or "failure"), RIPUNZIP_EXTRACTED,
+    /// RIPUNZIP_SKIPPED and RIPUNZIP_FAILED (numbers of files),
+    /// RIPUNZIP_BYTES_WRITTEN and RIPUNZIP_OUTPUT_DIR.
+    #[arg(long, value_name = "CMD")]
+    on_complete: Option<String>,
+
+    #[command(flatten)]
+    filter_args: FilterArgs,
+
+    /// Read while parsing arguments, before any threads are started.
+    #[arg(skip = ripunzip::process_umask())]
+    umask: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct RepackArgs {
+    /// The zip file to write.
+    #[arg(short = 'o', long, value_name = "PATH")]
+    output: PathBuf,
+
+    /// Compress every file this way. By default, each is compressed the
+    /// way it was before, or with deflate if that's not possible.
+    #[arg(long, value_name = "METHOD")]
+    compression: Option<CompressionMode>,
+
+    /// Password to decrypt encrypted zipfile entries (if any). The new zip
+    /// file isn't encrypted.
+    #[arg(short = 'P', long, value_name = "PASSWORD")]
+    password: Option<String>,
+
+    #[command(flatten)]
+    filter_args: FilterArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CompressionMode {
+    /// Don't compress files.
+    Store,
+    /// Compress files with deflate, which any unzip tool can read.
+    Deflate,
+    /// Compress files with bzip2.
+    Bzip2,
+    /// Compress files with Zstandard, which not all unzip tools can read.
+    Zstd,
+}
+
+#[derive(Args, Debug)]
+struct FilterArgs {
+    /// Leave out files matching this pattern, even if they're in the list of
+    /// files. May be given more than once, and can include wildcards.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Leave out version control directories and files, such as .git/,
+    /// .svn/ and .gitignore.
+    #[arg(long)]
+    exclude_vcs: bool,
+
+    /// Leave out files which operating systems leave lying around, such as
+    /// Thumbs.db, desktop.ini and .DS_Store.
+    #[arg(long)]
+    exclude_junk: bool,
+
+    /// Only include files last modified after this time, given in RFC 3339
+    /// form, such as 2024-01-31T12:00:00Z or just 2024-01-31, or as how
+    /// long ago, such as 7d or 12h. Times in zip files are taken to be UTC.
+    #[arg(long, value_name = "WHEN", value_parser = parse_time)]
+    newer_than: Option<SystemTime>,
+
+    /// Only include files last modified before this time, given in the
+    /// same way as for --newer-than.
+    #[arg(long, value_name = "WHEN", value_parser = parse_time)]
+    older_than: Option<SystemTime>,
+
+    /// Only include files of at least this many bytes, uncompressed.
+    #[arg(long, value_name = "BYTES")]
+    min_entry_size: Option<u64>,
+
+    /// Only include files of at most this many bytes, uncompressed. When
+    /// unzipping from a URI, bigger files aren't downloaded.
+    #[arg(long, value_name = "BYTES")]
+    max_entry_size: Option<u64>,
+
+    /// Only include entries of this type. May be given more than once. For
+    /// example, 'dirs' recreates just the directory structure.
+    #[arg(long = "type", value_name = "TYPE")]
+    entry_types: Vec<TypeChoice>,
+
+    /// Only include files within this directory of the zip file. When
+    /// unzipping from a URI, nothing else is downloaded.
+    #[arg(long, value_name = "DIRECTORY")]
+    only_dir: Option<String>,
+
+    /// Optionally, a list of files to unzip or list from the zip file. Omit
+    /// to include all of them. This can include wildcards.
     #[arg(value_name = "FILES")]
     filenames_to_unzip: Vec<String>,
 }
 
+impl FilterArgs {
+    fn filter_set(&self) -> FilterSet<'static> {
+        let mut filters = FilterSet::new().metadata(self.metadata_filter());
+        for pattern in &self.filenames_to_unzip {
+            filters = filters.include(pattern);
+        }
+        for pattern in &self.exclude {
+            filters = filters.exclude(pattern);
+        }
+        let mut excluded_names = Vec::new();
+        if self.exclude_vcs {
+            excluded_names.extend_from_slice(VCS_NAMES);
+        }
+        if self.exclude_junk {
+            excluded_names.extend_from_slice(JUNK_NAMES);
+        }
+        if excluded_names.is_empty() && self.only_dir.is_none() {
+            return filters;
+        }
+        filters.filter(LocationFilter {
+            excluded_names,
+            only_dir: self
+                .only_dir
+                .as_ref()
+                .map(|dir| format!("{}/", dir.trim_end_matches('/'))),
+        })
+    }
+
+    fn metadata_filter(&self) -> MetadataFilter {
+        MetadataFilter {
+            modified_after: self.newer_than,
+            modified_before: self.older_than,
+            min_size: self.min_entry_size,
+            max_size: self.max_entry_size,
+            types: self
+                .entry_types
+                .iter()
+                .map(|choice| match choice {
+                    TypeChoice::Files => EntryType::File,
+                    TypeChoice::Dirs => EntryType::Directory,
+                    TypeChoice::Symlinks => EntryType::Symlink,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parse a time given as an RFC 3339 timestamp or date, or as a duration
+/// before now.
+fn parse_time(value: &str) -> Result<SystemTime, String> {
+    if let Ok(ago) = humantime::parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("{value} ago is too long ago"));
+    }
+    let timestamp = if value.len() == "YYYY-MM-DD".len() {
+        format!("{value}T00:00:00Z")
+    } else {
+        value.to_string()
+    };
+    humantime::parse_rfc3339_weak(&timestamp).map_err(|_| {
+        "expected a time such as 2024-01-31T12:00:00Z, or a duration such as 7d".to_string()
+    })
+}
+
+/// Parse a number of bytes, optionally followed by K, M or G for
+/// kibibytes, mebibytes or gibibytes.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim_end_matches(['B', 'b']);
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| "expected a size such as 256M".to_string())
+}
+
+/// Parse an I/O priority given as 'idle' or a best-effort level.
+fn parse_io_priority(value: &str) -> Result<IoPriority, String> {
+    if value == "idle" {
+        return Ok(IoPriority::Idle);
+    }
+    match value.parse() {
+        Ok(level @ 0..=7) => Ok(IoPriority::BestEffort(level)),
+        _ => Err("expected 'idle' or a level from 0 to 7".to_string()),
+    }
+}
+
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// Order the files by this, instead of listing them in the order
+    /// they're in the zip file.
+    #[arg(long, value_name = "KEY")]
+    sort: Option<SortKey>,
+
+    /// List the files in descending order.
+    #[arg(long)]
+    descending: bool,
+
+    /// How to list the files.
+    #[arg(long, value_name = "FORMAT", default_value = "names")]
+    format: ListFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ListFormat {
+    /// One filename per line.
+    Names,
+    /// CSV, with a header row, giving each file's name, sizes, compression
+    /// ratio, compression method, CRC-32 and modification time.
+    Csv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SortKey {
+    /// The filename.
+    Name,
+    /// The uncompressed size.
+    Size,
+    /// The compressed size.
+    Compressed,
+    /// Where the file is in the zip file.
+    Offset,
+    /// The modification time.
+    Mtime,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SanitizeMode {
+    /// Leave filenames as they are.
+    None,
+    /// Replace invalid characters.
+    Replace,
+    /// Percent-encode invalid characters.
+    PercentEncode,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CaseCollisionMode {
+    /// Extract all files, letting later ones overwrite earlier ones.
+    Allow,
+    /// Extract later files under a new name.
+    Rename,
+    /// Don't extract later files.
+    Skip,
+    /// Fail without extracting anything.
+    Error,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TypeChoice {
+    /// Regular files.
+    Files,
+    /// Directories.
+    Dirs,
+    /// Symbolic links.
+    Symlinks,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SymlinkMode {
+    /// Extract each as a file containing the path it points to.
+    File,
+    /// Create symbolic links.
+    Create,
+    /// On Windows, create directory junctions for links to directories.
+    Junction,
+    /// Copy whatever each link points to.
+    Copy,
+    /// Don't extract links.
+    Skip,
+}
+
 #[derive(Args, Debug)]
 struct FileArgs {
-    /// Zip file to unzip
+    /// Zip file to unzip, or - to read it from standard input as it arrives.
+    /// When unzipping, the filename may have wildcards, in which case each
+    /// matching zip file is unzipped into a subdirectory named after it.
     #[arg(value_name = "FILE")]
     zipfile: PathBuf,
+
+    /// If FILE holds several zip files one after another, read the Nth,
+    /// counting from 1, rather than the last.
+    #[arg(long, value_name = "N")]
+    archive: Option<usize>,
+
+    #[command(flatten)]
+    signature_args: SignatureArgs,
 }
 
 #[derive(Args, Debug)]
@@ -108,6 +796,60 @@ struct UriArgs {
     /// problem, but may make transfers much less efficient by requiring multiple HTTP streams.
     #[arg(long, value_name = "BYTES")]
     readahead_limit: Option<usize>,
+
+    /// Pause fetching data ahead of extracting it once this much is waiting in memory.
+    /// Files bigger than this are fetched as they're extracted. [default: 64MiB]
+    #[arg(long, value_name = "BYTES")]
+    readahead_high_watermark: Option<u64>,
+
+    /// Once fetching has paused, resume it when the data waiting in memory falls to this
+    /// much. [default: 32MiB, or the high watermark if that's lower]
+    #[arg(long, value_name = "BYTES")]
+    readahead_low_watermark: Option<u64>,
+
+    /// How much of the end of the zip file to fetch in a single request when opening it,
+    /// since the zip file's directory is found there. Zero disables this.
+    #[arg(long, value_name = "BYTES", default_value_t = HttpOptions::default().tail_fetch_size)]
+    tail_fetch_size: u64,
+
+    /// How much to read from the server at a time. By default, this is chosen according
+    /// to how quickly the server responds, and how much of the zip file is being extracted.
+    #[arg(long, value_name = "BYTES")]
+    http_chunk_size: Option<usize>,
+
+    /// If the server can't send parts of the zip file, so it has to be
+    /// downloaded whole, keep it at this path afterwards, so that it
+    /// needn't be downloaded again. If the download is interrupted, running
+    /// again with the same path resumes it.
+    #[arg(long, value_name = "PATH")]
+    keep_download: Option<PathBuf>,
+
+    #[command(flatten)]
+    signature_args: SignatureArgs,
+}
+
+#[derive(Args, Debug, Default)]
+struct SignatureArgs {
+    /// Before reading the zip file, check it against this detached
+    /// signature, made by minisign. Zip files from URIs are then downloaded
+    /// whole first.
+    #[arg(long, value_name = "PATH", requires = "pubkey")]
+    signature: Option<PathBuf>,
+
+    /// The minisign public key with which to check --signature.
+    #[arg(long, value_name = "PATH", requires = "signature")]
+    pubkey: Option<PathBuf>,
+}
+
+impl SignatureArgs {
+    fn verifier(&self) -> Result<Option<SignatureVerifier>> {
+        match (&self.signature, &self.pubkey) {
+            (Some(signature), Some(pubkey)) => {
+                Ok(Some(SignatureVerifier::load(signature, pubkey)?))
+            }
+            _ => Ok(None),
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -115,124 +857,1681 @@ fn main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(args.verbose.log_level_filter())
         .init();
+    args.color.apply();
     match args.command {
-        Commands::ListFile { file_args } => list(construct_file_engine(file_args)?),
-        Commands::ListUri { uri_args } => list(construct_uri_engine(uri_args)?),
+        Commands::ListFile {
+            file_args,
+            filter_args,
+            list_args,
+        } => list(construct_file_engine(file_args)?, filter_args, list_args),
+        Commands::ListUri {
+            uri_args,
+            filter_args,
+            list_args,
+        } => list(
+            construct_uri_engine(&uri_args.uri, &uri_args)?,
+            filter_args,
+            list_args,
+        ),
         Commands::UnzipFile {
             file_args,
+            scan: true,
             unzip_args,
-        } => unzip(
-            construct_file_engine(file_args)?,
+            ..
+        } => {
+            if file_args.signature_args.signature.is_some() {
+                bail!("--signature can only be used when unzipping one zip file");
+            }
+            unzip_scan(&file_args.zipfile, unzip_args, args.verbose.is_silent())
+        }
+        Commands::UnzipFile {
+            file_args,
+            all_archives: true,
+            unzip_args,
+            ..
+        } => unzip_all_archives(file_args, unzip_args, args.verbose.is_silent()),
+        Commands::UnzipFile {
+            file_args,
+            unzip_args,
+            ..
+        } => match expand_wildcards(&file_args.zipfile)? {
+            None => {
+                let zipfile =
+                    (file_args.zipfile.as_os_str() != "-").then(|| file_args.zipfile.clone());
+                unzip(
+                    construct_file_engine(file_args)?,
+                    unzip_args,
+                    args.verbose.is_silent(),
+                    false,
+                    zipfile.as_deref(),
+                )
+            }
+            Some(_) if file_args.signature_args.signature.is_some() => {
+                bail!("--signature can only be used when unzipping one zip file")
+            }
+            Some(_) if file_args.archive.is_some() => {
+                bail!("--archive can only be used when unzipping one zip file")
+            }
+            Some(zipfiles) => unzip_files(zipfiles, unzip_args, args.verbose.is_silent()),
+        },
+        Commands::UnzipUri {
+            uri_args,
+            mirror: true,
+            unzip_args,
+            ..
+        } => unzip_mirror(
+            construct_uri_engine(&uri_args.uri, &uri_args)?,
             unzip_args,
             args.verbose.is_silent(),
         ),
         Commands::UnzipUri {
             uri_args,
+            also_uri,
             unzip_args,
-        } => unzip(
-            construct_uri_engine(uri_args)?,
+            ..
+        } if also_uri.is_empty() => unzip(
+            construct_uri_engine(&uri_args.uri, &uri_args)?,
             unzip_args,
             args.verbose.is_silent(),
+            true,
+            None,
+        ),
+        Commands::UnzipUri {
+            uri_args,
+            also_uri,
+            unzip_args,
+            ..
+        } => unzip_uris(&uri_args, also_uri, unzip_args, args.verbose.is_silent()),
+        Commands::RepackFile {
+            file_args,
+            repack_args,
+        } => repack(
+            construct_file_engine(file_args)?,
+            repack_args,
+            args.verbose.is_silent(),
+            false,
+        ),
+        Commands::RepackUri {
+            uri_args,
+            repack_args,
+        } => repack(
+            construct_uri_engine(&uri_args.uri, &uri_args)?,
+            repack_args,
+            args.verbose.is_silent(),
+            true,
         ),
+        Commands::Head {
+            zipfile,
+            entry,
+            bytes,
+        } => head(&zipfile, &entry, bytes),
+        #[cfg(all(feature = "fuse", unix))]
+        Commands::MountFile {
+            file_args,
+            mountpoint,
+        } => construct_file_engine(file_args)?.mount(&mountpoint),
+        #[cfg(all(feature = "fuse", unix))]
+        Commands::MountUri {
+            uri_args,
+            mountpoint,
+        } => construct_uri_engine(&uri_args.uri, &uri_args)?.mount(&mountpoint),
+        #[cfg(feature = "serve")]
+        Commands::ServeFile {
+            file_args,
+            serve_args,
+        } => serve(construct_file_engine(file_args)?, serve_args),
+        #[cfg(feature = "serve")]
+        Commands::ServeUri {
+            uri_args,
+            serve_args,
+        } => serve(construct_uri_engine(&uri_args.uri, &uri_args)?, serve_args),
+        Commands::Completions { shell } => {
+            print_completions(shell, &mut std::io::stdout());
+            Ok(())
+        }
+        Commands::Manpage => print_manpage(&mut std::io::stdout()),
     }
 }
 
-fn unzip(engine: UnzipEngine, unzip_args: UnzipArgs, is_silent: bool) -> Result<()> {
-    let filename_filter: Option<Box<dyn FilenameFilter + Sync>> =
-        if unzip_args.filenames_to_unzip.is_empty() {
-            None
-        } else {
-            Some(Box::new(FileListFilter(RwLock::new(
-                unzip_args
-                    .filenames_to_unzip
-                    .iter()
-                    .map(|s| WildMatch::new(s))
-                    .collect(),
-            ))))
-        };
-    let progress_reporter: Box<dyn UnzipProgressReporter + Sync> = if is_silent {
-        Box::new(NullProgressReporter)
-    } else {
-        Box::new(ProgressDisplayer::new())
-    };
-    let options = UnzipOptions {
-        output_directory: unzip_args.output_directory,
-        password: unzip_args.password,
-        single_threaded: unzip_args.single_threaded,
-        filename_filter,
-        progress_reporter,
-    };
-    engine.unzip(options)
+fn print_completions(shell: clap_complete::Shell, out: &mut dyn std::io::Write) {
+    clap_complete::generate(shell, &mut RipunzipArgs::command(), "ripunzip", out);
 }
 
-fn construct_file_engine(file_args: FileArgs) -> Result<UnzipEngine> {
-    let zipfile = File::open(file_args.zipfile)?;
-    UnzipEngine::for_file(zipfile)
+fn print_manpage(out: &mut dyn std::io::Write) -> Result<()> {
+    clap_mangen::Man::new(RipunzipArgs::command()).render(out)?;
+    Ok(())
 }
 
-fn construct_uri_engine(uri_args: UriArgs) -> Result<UnzipEngine> {
-    UnzipEngine::for_uri(
-        &uri_args.uri,
-        uri_args.readahead_limit,
-        report_on_insufficient_readahead_size,
-    )
+#[cfg(feature = "serve")]
+fn serve(engine: UnzipEngine, serve_args: ServeArgs) -> Result<()> {
+    eprintln!("Serving on http://{}/", serve_args.address);
+    engine.serve(&serve_args.address)
 }
 
-fn list(engine: UnzipEngine) -> Result<()> {
-    let files = engine.list()?;
-    for f in files {
-        println!("{}", f);
+/// Unzip a zip file. If it's a local file, `zipfile` is its path, so it
+/// can be removed afterwards if asked.
+fn unzip(
+    engine: UnzipEngine,
+    unzip_args: UnzipArgs,
+    is_silent: bool,
+    is_remote: bool,
+    zipfile: Option<&Path>,
+) -> Result<()> {
+    let manifest = unzip_args
+        .check_manifest
+        .as_ref()
+        .map(|path| -> Result<Manifest> {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+            Manifest::parse(&text)
+        })
+        .transpose()?;
+    let mut progress_displayer = ProgressDisplayer::new(is_remote, is_silent);
+    if unzip_args.ordered_output {
+        progress_displayer.buffer_output(engine.names_in_archive_order()?);
+    }
+    // Only one zip file is unzipped at once, so it's clear which one is
+    // being asked about.
+    let overwrite_prompt = (!unzip_args.overwrite
+        && !unzip_args.freshen
+        && std::io::stdin().is_terminal()
+        && std::io::stdout().is_terminal())
+    .then(|| OverwritePrompt::new(progress_displayer.bars.clone()));
+    let mut options = UnzipOptions {
+        manifest,
+        ..unzip_options(
+            &unzip_args,
+            unzip_args.output_directory.clone(),
+            Box::new(&progress_displayer),
+        )
+    };
+    if let Some(prompt) = overwrite_prompt {
+        options.policy.overwrite = OverwritePolicy::Ask(Box::new(prompt));
+    }
+    let result = engine.unzip(options);
+    finish_unzipping(&progress_displayer, &unzip_args, result.is_ok())?;
+    result?;
+    // Corrupt files may have been skipped.
+    if let Some(zipfile) = zipfile.filter(|_| unzip_args.remove_archive) {
+        if progress_displayer.failed.load(Ordering::Relaxed) == 0 {
+            remove_archive(zipfile)?;
+        }
     }
     Ok(())
 }
 
-struct FileListFilter(RwLock<Vec<WildMatch>>);
-
-impl FilenameFilter for FileListFilter {
-    fn should_unzip(&self, filename: &str) -> bool {
-        let lock = self.0.read().unwrap();
-        lock.iter().any(|m| m.matches(filename))
-    }
+fn remove_archive(zipfile: &Path) -> Result<()> {
+    std::fs::remove_file(zipfile).with_context(|| format!("Failed to remove {}", zipfile.display()))
 }
 
-fn report_on_insufficient_readahead_size() {
-    eprintln!("Warning: this operation required several HTTP(S) streams.\nThis can slow down decompression.\nYou may wish to iuse --readahead-limit to increase the amount of data which can be held in memory.");
+/// Where `--mirror` keeps what it unzipped into the output directory.
+const MIRROR_SNAPSHOT: &str = ".ripunzip-mirror.json";
+
+/// What was in a zip file when it was last mirrored: its ETag, and the size
+/// and CRC-32 of each file.
+#[derive(Debug, Default, PartialEq)]
+struct MirrorSnapshot {
+    etag: Option<String>,
+    entries: BTreeMap<String, (u64, Option<u32>)>,
 }
 
-struct ProgressDisplayer(ProgressBar);
+impl MirrorSnapshot {
+    /// Read the snapshot at `path`, or an empty one if there isn't one.
+    fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let snapshot: serde_json::Value = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let entries = snapshot["entries"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(name, entry)| {
+                let size = entry["size"].as_u64().unwrap_or_default();
+                let crc32 = entry["crc32"].as_u64().map(|crc32| crc32 as u32);
+                (name.clone(), (size, crc32))
+            })
+            .collect();
+        Ok(Self {
+            etag: snapshot["etag"].as_str().map(str::to_string),
+            entries,
+        })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let entries: serde_json::Map<String, serde_json::Value> = self
+            .entries
+            .iter()
+            .map(|(name, (size, crc32))| {
+                let entry = serde_json::json!({ "size": size, "crc32": crc32 });
+                (name.clone(), entry)
+            })
+            .collect();
+        let snapshot = serde_json::json!({ "etag": self.etag, "entries": entries });
+        std::fs::write(path, serde_json::to_string_pretty(&snapshot).unwrap())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Unzip only the files which have changed since the zip file was last
+/// unzipped into the output directory, and remove those which have gone.
+fn unzip_mirror(engine: UnzipEngine, unzip_args: UnzipArgs, is_silent: bool) -> Result<()> {
+    if unzip_args.ordered_output || unzip_args.check_manifest.is_some() || unzip_args.pipe {
+        bail!("--ordered-output, --check-manifest and --pipe can't be used with --mirror");
+    }
+    let output_directory = unzip_args
+        .output_directory
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let snapshot_path = output_directory.join(MIRROR_SNAPSHOT);
+    let previous = MirrorSnapshot::load(&snapshot_path)?;
+    if previous.etag.is_some() && previous.etag.as_deref() == engine.etag() {
+        if !is_silent {
+            eprintln!("Nothing to do, as the zip file hasn't changed");
+        }
+        return Ok(());
+    }
+    let filters = unzip_args.filter_args.filter_set();
+    let current = MirrorSnapshot {
+        etag: engine.etag().map(str::to_string),
+        entries: engine
+            .list_details()?
+            .into_iter()
+            .filter(|entry| filters.selects_listed(entry))
+            .map(|entry| (entry.name, (entry.size, entry.crc32)))
+            .collect(),
+    };
+    // Files inside directories come after them, so are removed first.
+    for name in previous.entries.keys().rev() {
+        if !current.entries.contains_key(name) && filters.should_unzip(name) {
+            remove_mirrored(&output_directory, name)?;
+        }
+    }
+    let changed = current
+        .entries
+        .iter()
+        .filter(|(name, details)| {
+            previous.entries.get(*name) != Some(details) || !output_directory.join(name).exists()
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    let progress_displayer = ProgressDisplayer::new(true, is_silent);
+    let options = UnzipOptions {
+        filters: FilterSet::new().filter(NameSetFilter(changed)),
+        ..unzip_options(
+            &unzip_args,
+            Some(output_directory),
+            Box::new(&progress_displayer),
+        )
+    };
+    let result = engine.unzip(options);
+    finish_unzipping(&progress_displayer, &unzip_args, result.is_ok())?;
+    result?;
+    current.save(&snapshot_path)
+}
+
+/// Remove the file or directory which was unzipped from the entry called
+/// `name`, if it's still there. Directories are left if they're not empty.
+fn remove_mirrored(output_directory: &Path, name: &str) -> Result<()> {
+    let is_enclosed = Path::new(name)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !is_enclosed {
+        return Ok(());
+    }
+    let path = output_directory.join(name);
+    let result = if name.ends_with('/') {
+        std::fs::remove_dir(&path)
+    } else {
+        std::fs::remove_file(&path)
+    };
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound || name.ends_with('/') => Ok(()),
+        result => result.with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+/// Selects the files with these names.
+struct NameSetFilter(HashSet<String>);
+
+impl FilenameFilter for NameSetFilter {
+    fn should_unzip(&self, filename: &str) -> bool {
+        self.0.contains(filename)
+    }
+}
+
+/// Unzip several zip files from URIs at once, each into a subdirectory of
+/// the output directory.
+fn unzip_uris(
+    uri_args: &UriArgs,
+    also_uri: Vec<String>,
+    unzip_args: UnzipArgs,
+    is_silent: bool,
+) -> Result<()> {
+    if uri_args.keep_download.is_some() {
+        bail!("--keep-download can only be used when unzipping one zip file");
+    }
+    if uri_args.signature_args.signature.is_some() {
+        bail!("--signature can only be used when unzipping one zip file");
+    }
+    let archives = std::iter::once(uri_args.uri.clone())
+        .chain(also_uri)
+        .map(|uri| {
+            let url = reqwest::Url::parse(&uri).with_context(|| format!("Invalid URI {uri}"))?;
+            let filename = url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .unwrap_or_default();
+            let subdirectory = archive_subdirectory(filename)
+                .ok_or_else(|| anyhow!("Can't tell what to call the directory for {uri}"))?;
+            Ok((uri, subdirectory))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    unzip_several(
+        archives,
+        |uri| construct_uri_engine(uri, uri_args),
+        unzip_args,
+        is_silent,
+        true,
+    )
+}
+
+/// Unzip several local zip files at once, each into a subdirectory of the
+/// output directory.
+fn unzip_files(zipfiles: Vec<PathBuf>, unzip_args: UnzipArgs, is_silent: bool) -> Result<()> {
+    let archives = zipfiles
+        .into_iter()
+        .map(|zipfile| {
+            let subdirectory = zipfile
+                .file_name()
+                .and_then(|filename| archive_subdirectory(&filename.to_string_lossy()))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Can't tell what to call the directory for {}",
+                        zipfile.display()
+                    )
+                })?;
+            Ok((zipfile.display().to_string(), subdirectory))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    unzip_several(
+        archives,
+        |zipfile| {
+            construct_file_engine(FileArgs {
+                zipfile: PathBuf::from(zipfile),
+                archive: None,
+                signature_args: SignatureArgs::default(),
+            })
+        },
+        unzip_args,
+        is_silent,
+        false,
+    )
+}
+
+/// Unzip every zip file beneath `directory`, each into a subdirectory at
+/// the same place relative to the output directory, which is `directory`
+/// unless another is given.
+fn unzip_scan(directory: &Path, unzip_args: UnzipArgs, is_silent: bool) -> Result<()> {
+    let mut zipfiles = Vec::new();
+    find_zip_files(directory, &mut zipfiles)?;
+    if zipfiles.is_empty() {
+        bail!("No zip files found in {}", directory.display());
+    }
+    zipfiles.sort();
+    let archives = zipfiles
+        .iter()
+        .map(|zipfile| {
+            let subdirectory = zipfile.strip_prefix(directory).unwrap().with_extension("");
+            (
+                zipfile.display().to_string(),
+                subdirectory.display().to_string(),
+            )
+        })
+        .collect();
+    let unzip_args = UnzipArgs {
+        output_directory: Some(
+            unzip_args
+                .output_directory
+                .unwrap_or_else(|| directory.to_path_buf()),
+        ),
+        ..unzip_args
+    };
+    unzip_several(
+        archives,
+        |zipfile| {
+            construct_file_engine(FileArgs {
+                zipfile: PathBuf::from(zipfile),
+                archive: None,
+                signature_args: SignatureArgs::default(),
+            })
+        },
+        unzip_args,
+        is_silent,
+        false,
+    )
+}
+
+/// Unzip each of the zip files stored one after another in a file into a
+/// subdirectory of the output directory named after its number.
+fn unzip_all_archives(file_args: FileArgs, unzip_args: UnzipArgs, is_silent: bool) -> Result<()> {
+    if file_args.zipfile.as_os_str() == "-" {
+        bail!("--all-archives can't be used when reading from standard input");
+    }
+    if unzip_args.remove_archive {
+        bail!("--remove-archive can't be used with --all-archives");
+    }
+    let mut zipfile = File::open(&file_args.zipfile)?;
+    if let Some(verifier) = file_args.signature_args.verifier()? {
+        verifier.verify(&mut zipfile)?;
+    }
+    let ranges = UnzipEngine::archives_in_file(&zipfile)?;
+    let archives: Vec<(String, String)> = (1..=ranges.len())
+        .map(|number| {
+            let name = format!("zip file {number} in {}", file_args.zipfile.display());
+            (name, number.to_string())
+        })
+        .collect();
+    let ranges: HashMap<&str, Range<u64>> = archives
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .zip(ranges)
+        .collect();
+    unzip_several(
+        archives.clone(),
+        |name| UnzipEngine::for_file_archive(zipfile.try_clone()?, ranges[name].clone()),
+        unzip_args,
+        is_silent,
+        false,
+    )
+}
+
+/// Add the zip files beneath `directory` to `zipfiles`. Symbolic links
+/// aren't followed, so there's no danger of going round in circles.
+fn find_zip_files(directory: &Path, zipfiles: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory {}", directory.display()))?
+    {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_zip_files(&entry.path(), zipfiles)?;
+        } else if file_type.is_file()
+            && Path::new(&entry.file_name())
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+        {
+            zipfiles.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Unzip the zip files `archives`, each given with the name of the
+/// subdirectory of the output directory to unzip it into, at once.
+fn unzip_several(
+    archives: Vec<(String, String)>,
+    construct_engine: impl Fn(&str) -> Result<UnzipEngine> + Sync,
+    unzip_args: UnzipArgs,
+    is_silent: bool,
+    is_remote: bool,
+) -> Result<()> {
+    if unzip_args.ordered_output || unzip_args.check_manifest.is_some() || unzip_args.pipe {
+        bail!("--ordered-output, --check-manifest and --pipe can only be used when unzipping one zip file");
+    }
+    let mut subdirectories = HashSet::new();
+    for (_, subdirectory) in &archives {
+        if !subdirectories.insert(subdirectory) {
+            bail!("More than one zip file would be unzipped into {subdirectory}");
+        }
+    }
+    let output_directory = unzip_args
+        .output_directory
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let progress_displayer = ProgressDisplayer::new(is_remote, is_silent);
+    // The zip files are unzipped using the same thread pool as each one's
+    // files, so they share its threads, however many zip files there are.
+    let results: Vec<Result<()>> = archives
+        .par_iter()
+        .map(|(archive, subdirectory)| {
+            let progress_reporter = ArchiveProgressReporter {
+                subdirectory,
+                progress_displayer: &progress_displayer,
+                failed: AtomicBool::new(false),
+            };
+            construct_engine(archive)
+                .and_then(|engine| {
+                    engine.unzip(unzip_options(
+                        &unzip_args,
+                        Some(output_directory.join(subdirectory)),
+                        Box::new(&progress_reporter),
+                    ))
+                })
+                .and_then(|()| {
+                    if unzip_args.remove_archive
+                        && !is_remote
+                        && !progress_reporter.failed.load(Ordering::Relaxed)
+                    {
+                        remove_archive(Path::new(archive))?;
+                    }
+                    Ok(())
+                })
+                .with_context(|| format!("Failed to unzip {archive}"))
+        })
+        .collect();
+    let succeeded = results.iter().all(Result::is_ok);
+    finish_unzipping(&progress_displayer, &unzip_args, succeeded)?;
+    results.into_iter().collect()
+}
+
+/// The name of the subdirectory into which to unzip a zip file called
+/// `filename`: the filename without its extension.
+fn archive_subdirectory(filename: &str) -> Option<String> {
+    let name = [".zip", ".tar.gz", ".tgz", ".tar.zst", ".tzst"]
+        .iter()
+        .find_map(|extension| filename.strip_suffix(extension))
+        .unwrap_or(filename);
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+/// If there's no file at `path`, but its filename has wildcards, the files
+/// in its directory which match it, in order. Shells on Windows don't
+/// expand wildcards themselves.
+fn expand_wildcards(path: &Path) -> Result<Option<Vec<PathBuf>>> {
+    let Some(pattern) = path.file_name().and_then(|pattern| pattern.to_str()) else {
+        return Ok(None);
+    };
+    if !pattern.contains(['*', '?']) || path.exists() {
+        return Ok(None);
+    }
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let pattern = WildMatch::new(pattern);
+    let mut matches = Vec::new();
+    for entry in std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read directory {}", directory.display()))?
+    {
+        let filename = entry?.file_name();
+        let path = path.with_file_name(&filename);
+        if filename.to_str().is_some_and(|name| pattern.matches(name)) && path.is_file() {
+            matches.push(path);
+        }
+    }
+    if matches.is_empty() {
+        bail!("No files match {}", path.display());
+    }
+    matches.sort();
+    Ok(Some(matches))
+}
+
+/// The options with which to unzip, apart from the manifest.
+fn unzip_options<'a, 'b>(
+    unzip_args: &'a UnzipArgs,
+    output_directory: Option<PathBuf>,
+    progress_reporter: Box<dyn UnzipProgressReporter + Sync + 'b>,
+) -> UnzipOptions<'a, 'b> {
+    let priority_filter: Option<Box<dyn FilenameFilter + Sync>> = if unzip_args.priority.is_empty()
+    {
+        None
+    } else {
+        Some(Box::new(FileListFilter(RwLock::new(
+            unzip_args
+                .priority
+                .iter()
+                .map(|s| WildMatch::new(s))
+                .collect(),
+        ))))
+    };
+    let filename_sanitization = match unzip_args.sanitize_names {
+        None => FilenameSanitization::default(),
+        Some(SanitizeMode::None) => FilenameSanitization::None,
+        Some(SanitizeMode::Replace) => {
+            FilenameSanitization::Replace(unzip_args.sanitize_replacement)
+        }
+        Some(SanitizeMode::PercentEncode) => FilenameSanitization::PercentEncode,
+    };
+    let case_collisions = match unzip_args.case_collisions {
+        None => CaseCollisionPolicy::default(),
+        Some(CaseCollisionMode::Allow) => CaseCollisionPolicy::Allow,
+        Some(CaseCollisionMode::Rename) => CaseCollisionPolicy::Rename,
+        Some(CaseCollisionMode::Skip) => CaseCollisionPolicy::Skip,
+        Some(CaseCollisionMode::Error) => CaseCollisionPolicy::Error,
+    };
+    let symlinks = match unzip_args.symlinks {
+        None => SymlinkPolicy::default(),
+        Some(SymlinkMode::File) => SymlinkPolicy::File,
+        Some(SymlinkMode::Create) => SymlinkPolicy::Create,
+        Some(SymlinkMode::Junction) => SymlinkPolicy::Junction,
+        Some(SymlinkMode::Copy) => SymlinkPolicy::Copy,
+        Some(SymlinkMode::Skip) => SymlinkPolicy::Skip,
+    };
+    UnzipOptions {
+        output_directory,
+        password: unzip_args.password.clone(),
+        single_threaded: unzip_args.single_threaded || unzip_args.pipe,
+        filters: unzip_args.filter_args.filter_set(),
+        progress_reporter,
+        staging: unzip_args.staging,
+        fsync: unzip_args.fsync,
+        sparse: unzip_args.sparse,
+        output_sink: unzip_args
+            .pipe
+            .then(|| Box::new(ConcatSink::new(std::io::stdout())) as Box<dyn OutputSink>),
+        policy: ExtractionPolicy {
+            permissions: PermissionsPolicy {
+                apply_umask: !unzip_args.no_umask,
+                restore_hidden_attribute: unzip_args.restore_hidden,
+            },
+            mtimes: unzip_args.mtime.unwrap_or_default(),
+            symlinks,
+            overwrite: if unzip_args.freshen {
+                OverwritePolicy::Freshen
+            } else {
+                OverwritePolicy::Always
+            },
+            path_sanitization: filename_sanitization,
+            skip_macos_junk: !unzip_args.keep_macos_junk,
+            restore_apple_double: unzip_args.restore_apple_double,
+        },
+        case_collisions,
+        max_output_size: unzip_args.max_output_size,
+        delete_extraneous: unzip_args.delete_extraneous,
+        hardlink_duplicates: unzip_args.hardlink_duplicates,
+        tune_thread_count: !unzip_args.fixed_threads,
+        priority_filter,
+        skip_corrupt: unzip_args.skip_corrupt,
+        manifest: None,
+        verify_after: unzip_args.verify_after,
+        name_transforms: unzip_args.transform.clone(),
+        flatten: unzip_args.flatten,
+        entry_indices: None,
+        sandbox: unzip_args.sandbox,
+        memory_budget: unzip_args.max_memory.map(MemoryBudget::new),
+        worker_priority: (unzip_args.nice.is_some() || unzip_args.io_priority.is_some()).then_some(
+            WorkerPriority {
+                nice: unzip_args.nice,
+                io: unzip_args.io_priority,
+            },
+        ),
+        umask: unzip_args.umask,
+    }
+}
+
+fn parse_modification_times(value: &str) -> Result<ModificationTimes, String> {
+    if value == "archive" {
+        return Ok(ModificationTimes::Archive);
+    }
+    let seconds = value
+        .strip_prefix('@')
+        .and_then(|seconds| seconds.parse().ok())
+        .ok_or_else(|| "expected @SECONDS or 'archive'".to_string())?;
+    Ok(ModificationTimes::Fixed(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+    ))
+}
+
+/// Sum up the extraction, write out any reports asked for, and run the
+/// command to be run on completion, if there is one.
+fn finish_unzipping(
+    progress_displayer: &ProgressDisplayer,
+    unzip_args: &UnzipArgs,
+    succeeded: bool,
+) -> Result<()> {
+    progress_displayer.print_summary();
+    if unzip_args.timings {
+        eprintln!("{}", progress_displayer.timings_summary());
+    }
+    if let Some(metrics_file) = &unzip_args.metrics_file {
+        write_report(metrics_file, &progress_displayer.metrics())
+            .with_context(|| format!("Failed to write metrics to {}", metrics_file.display()))?;
+    }
+    if let Some(error_report) = &unzip_args.error_report {
+        write_report(error_report, &progress_displayer.error_report()).with_context(|| {
+            format!("Failed to write error report to {}", error_report.display())
+        })?;
+    }
+    if let Some(command) = &unzip_args.on_complete {
+        run_completion_command(command, progress_displayer, unzip_args, succeeded)?;
+    }
+    Ok(())
+}
+
+fn run_completion_command(
+    command: &str,
+    progress_displayer: &ProgressDisplayer,
+    unzip_args: &UnzipArgs,
+    succeeded: bool,
+) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+    let output_directory = unzip_args
+        .output_directory
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let status = shell
+        .arg(command)
+        .env(
+            "RIPUNZIP_STATUS",
+            if succeeded { "success" } else { "failure" },
+        )
+        .env("RIPUNZIP_EXTRACTED", load(&progress_displayer.extracted))
+        .env("RIPUNZIP_SKIPPED", load(&progress_displayer.skipped))
+        .env("RIPUNZIP_FAILED", load(&progress_displayer.failed))
+        .env(
+            "RIPUNZIP_BYTES_WRITTEN",
+            progress_displayer.written.position().to_string(),
+        )
+        .env("RIPUNZIP_OUTPUT_DIR", output_directory)
+        .status()
+        .with_context(|| format!("Failed to run {command}"))?;
+    if !status.success() {
+        bail!("{command} failed: {status}");
+    }
+    Ok(())
+}
+
+/// Write a report to a file, replacing it all at once so that nothing ever
+/// reads it half-written.
+fn write_report(path: &Path, report: &str) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, report)?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn construct_file_engine(file_args: FileArgs) -> Result<UnzipEngine> {
+    let verifier = file_args.signature_args.verifier()?;
+    if file_args.zipfile.as_os_str() == "-" {
+        if verifier.is_some() {
+            bail!("--signature can't be used when reading from standard input");
+        }
+        return UnzipEngine::for_reader(std::io::stdin());
+    }
+    let mut zipfile = File::open(&file_args.zipfile)?;
+    if let Some(verifier) = verifier {
+        verifier.verify(&mut zipfile)?;
+        zipfile.rewind()?;
+    }
+    match file_args.archive {
+        Some(number) => {
+            let archives = UnzipEngine::archives_in_file(&zipfile)?;
+            let Some(archive) = number.checked_sub(1).and_then(|i| archives.get(i)) else {
+                bail!(
+                    "{} holds {} zip files, so there's no zip file {number}",
+                    file_args.zipfile.display(),
+                    archives.len()
+                );
+            };
+            UnzipEngine::for_file_archive(zipfile, archive.clone())
+        }
+        None => UnzipEngine::for_file(zipfile),
+    }
+}
+
+fn construct_uri_engine(uri: &str, uri_args: &UriArgs) -> Result<UnzipEngine> {
+    let defaults = ReadaheadWatermarks::default();
+    let high = uri_args.readahead_high_watermark.unwrap_or(defaults.high);
+    let low = uri_args
+        .readahead_low_watermark
+        .unwrap_or_else(|| defaults.low.min(high));
+    UnzipEngine::for_uri(
+        uri,
+        uri_args.readahead_limit,
+        ReadaheadWatermarks { high, low },
+        HttpOptions {
+            tail_fetch_size: uri_args.tail_fetch_size,
+            chunk_size: uri_args.http_chunk_size,
+            keep_download: uri_args.keep_download.clone(),
+            signature: uri_args.signature_args.verifier()?,
+            content_length: None,
+            access_pattern: None,
+        },
+        // This is reported as a warning instead.
+        || {},
+    )
+}
+
+/// Write the files in `engine` which the filters select into a new zip file,
+/// decompressing and compressing them again in parallel.
+fn repack(
+    engine: UnzipEngine,
+    repack_args: RepackArgs,
+    is_silent: bool,
+    is_remote: bool,
+) -> Result<()> {
+    let output = File::create(&repack_args.output)
+        .with_context(|| format!("Failed to create {}", repack_args.output.display()))?;
+    let mut zip_sink = ZipSink::new(std::io::BufWriter::new(output));
+    if let Some(compression) = repack_args.compression {
+        zip_sink = zip_sink.compression(match compression {
+            CompressionMode::Store => ZipCompression::Stored,
+            CompressionMode::Deflate => ZipCompression::Deflate,
+            CompressionMode::Bzip2 => ZipCompression::Bzip2,
+            CompressionMode::Zstd => ZipCompression::Zstd,
+        });
+    }
+    let progress_displayer = ProgressDisplayer::new(is_remote, is_silent);
+    let options = UnzipOptions {
+        password: repack_args.password,
+        filters: repack_args.filter_args.filter_set(),
+        progress_reporter: Box::new(&progress_displayer),
+        output_sink: Some(Box::new(&zip_sink)),
+        policy: ExtractionPolicy {
+            // Names are kept as they are, other than leaving out any which
+            // would be unsafe to extract.
+            path_sanitization: FilenameSanitization::None,
+            ..Default::default()
+        },
+        case_collisions: CaseCollisionPolicy::Allow,
+        tune_thread_count: true,
+        ..Default::default()
+    };
+    let result = engine.unzip(options);
+    progress_displayer.print_summary();
+    result?;
+    zip_sink
+        .into_inner()?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Print the first `bytes` bytes of `entry` in `zipfile`, which is read
+/// from a server if it's a URI.
+fn head(zipfile: &str, entry: &str, bytes: u64) -> Result<()> {
+    let engine = if zipfile.contains("://") {
+        UnzipEngine::for_uri(
+            zipfile,
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
+        )?
+    } else {
+        UnzipEngine::for_file(File::open(zipfile)?)?
+    };
+    let mut reader = engine.open_entry_prefix(entry, bytes)?;
+    std::io::copy(&mut reader, &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+fn list(engine: UnzipEngine, filter_args: FilterArgs, list_args: ListArgs) -> Result<()> {
+    let print = |files: &mut dyn Iterator<Item = Result<String>>| -> Result<()> {
+        for f in files {
+            println!("{}", f?);
+        }
+        Ok(())
+    };
+    let filters = filter_args.filter_set();
+    if list_args.sort.is_some()
+        || list_args.descending
+        || list_args.format == ListFormat::Csv
+        || !filter_args.metadata_filter().is_empty()
+    {
+        let mut entries = engine.list_details()?;
+        entries.retain(|entry| filters.selects_listed(entry));
+        sort_entries(&mut entries, list_args.sort, list_args.descending);
+        if list_args.format == ListFormat::Csv {
+            println!("{CSV_HEADER}");
+            return print(&mut entries.iter().map(|entry| Ok(csv_row(entry))));
+        }
+        return print(&mut entries.into_iter().map(|entry| Ok(entry.name)));
+    }
+    if filters.selects_all_names() {
+        print(&mut engine.list()?)
+    } else {
+        print(&mut engine.list_matching(&filters)?)
+    }
+}
+
+const CSV_HEADER: &str = "name,size,compressed_size,ratio,method,crc32,mtime";
+
+/// Describe `entry` as a CSV row with the columns in [`CSV_HEADER`]. What
+/// the archive doesn't record is left empty.
+fn csv_row(entry: &ListedEntry) -> String {
+    let name = if entry.name.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", entry.name.replace('"', "\"\""))
+    } else {
+        entry.name.clone()
+    };
+    let compressed_size = entry.compressed_size.map(|size| size.to_string());
+    let ratio = entry
+        .compressed_size
+        .filter(|_| entry.size > 0)
+        .map(|compressed_size| format!("{:.3}", compressed_size as f64 / entry.size as f64));
+    let method = entry.compression_method.map(|method| match method {
+        0 => "stored".to_string(),
+        8 => "deflate".to_string(),
+        9 => "deflate64".to_string(),
+        12 => "bzip2".to_string(),
+        14 => "lzma".to_string(),
+        93 => "zstd".to_string(),
+        95 => "xz".to_string(),
+        99 => "aes".to_string(),
+        other => other.to_string(),
+    });
+    let crc32 = entry.crc32.map(|crc32| format!("{crc32:08x}"));
+    let mtime = entry
+        .last_modified
+        .map(|mtime| humantime::format_rfc3339_seconds(mtime).to_string());
+    format!(
+        "{name},{},{},{},{},{},{}",
+        entry.size,
+        compressed_size.unwrap_or_default(),
+        ratio.unwrap_or_default(),
+        method.unwrap_or_default(),
+        crc32.unwrap_or_default(),
+        mtime.unwrap_or_default()
+    )
+}
+
+/// Order `entries` by `key`, then by name, or leave them in archive order
+/// if there's no key.
+fn sort_entries(entries: &mut [ListedEntry], key: Option<SortKey>, descending: bool) {
+    if let Some(key) = key {
+        entries.sort_by(|a, b| {
+            let by_key = match key {
+                SortKey::Name => std::cmp::Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Compressed => a.compressed_size.cmp(&b.compressed_size),
+                SortKey::Offset => a.offset.cmp(&b.offset),
+                SortKey::Mtime => a.last_modified.cmp(&b.last_modified),
+            };
+            by_key.then_with(|| a.name.cmp(&b.name))
+        });
+    }
+    if descending {
+        entries.reverse();
+    }
+}
+
+/// The names of version control directories and files, left out by
+/// `--exclude-vcs`.
+const VCS_NAMES: &[&str] = &[
+    ".git",
+    ".gitignore",
+    ".gitattributes",
+    ".gitmodules",
+    ".svn",
+    ".hg",
+    ".hgignore",
+    ".hgtags",
+    ".bzr",
+    ".bzrignore",
+    "CVS",
+    ".cvsignore",
+    "_darcs",
+];
+
+/// The names of files left lying around by operating systems, left out by
+/// `--exclude-junk`.
+const JUNK_NAMES: &[&str] = &["Thumbs.db", "ehthumbs.db", "desktop.ini", ".DS_Store"];
+
+struct FileListFilter(RwLock<Vec<WildMatch>>);
+
+impl FilenameFilter for FileListFilter {
+    fn should_unzip(&self, filename: &str) -> bool {
+        let lock = self.0.read().unwrap();
+        lock.iter().any(|m| m.matches(filename))
+    }
+}
+
+/// Files within the directory they must be in, if there is one, and not
+/// within directories with names to leave out.
+struct LocationFilter {
+    /// Names of files and directories to leave out wherever they are,
+    /// along with anything within them.
+    excluded_names: Vec<&'static str>,
+    /// The directory files must be within, ending with a slash.
+    only_dir: Option<String>,
+}
+
+impl FilenameFilter for LocationFilter {
+    fn should_unzip(&self, filename: &str) -> bool {
+        self.only_dir
+            .as_ref()
+            .map_or(true, |dir| filename.starts_with(dir.as_str()))
+            && !filename.split('/').any(|component| {
+                self.excluded_names
+                    .iter()
+                    .any(|name| component.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+/// A short name for what sort of error this is, from the innermost error
+/// which is an I/O or zip error.
+fn error_kind(error: &anyhow::Error) -> String {
+    let kind = error.chain().rev().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            Some(format!("{:?}", e.kind()))
+        } else {
+            cause
+                .downcast_ref::<zip::result::ZipError>()
+                .map(|e| format!("{e:?}"))
+        }
+    });
+    match kind {
+        // Variants' contents are in the message.
+        Some(kind) => kind.split('(').next().unwrap_or_default().to_string(),
+        None => "Other".to_string(),
+    }
+}
+
+/// Shows an overall progress bar, and beneath it a line for each file being
+/// extracted, unless silenced; then sums up at the end.
+struct ProgressDisplayer {
+    bars: MultiProgress,
+    overall: ProgressBar,
+    /// Counts uncompressed bytes written, just to measure how fast that is.
+    written: ProgressBar,
+    /// Counts bytes downloaded, to measure how fast that is separately from
+    /// how fast they're extracted.
+    downloaded: ProgressBar,
+    /// Whether the zip file is being downloaded, rather than read locally.
+    is_remote: bool,
+    is_silent: bool,
+    files: Mutex<HashMap<String, ProgressBar>>,
+    started: Instant,
+    extracted: AtomicU64,
+    skipped: AtomicU64,
+    failed: AtomicU64,
+    readahead_peak: AtomicU64,
+    http_streams: AtomicU64,
+    timings: Mutex<Timings>,
+    /// The files which failed to extract, for the error report.
+    failures: Mutex<Vec<serde_json::Value>>,
+    /// The files extracted under a different name, and those names, for
+    /// the error report.
+    renamed: Mutex<BTreeMap<String, String>>,
+    /// Lines to print once finished, rather than as they happen, if the
+    /// output should be in the zip file's order.
+    buffered_output: Option<BufferedOutput>,
+}
+
+/// Lines of output about each file, kept to be printed in the order the
+/// files are in the zip file.
+struct BufferedOutput {
+    /// Where each file is in the zip file.
+    positions: HashMap<String, usize>,
+    /// Lines about particular files, with their names.
+    lines: Mutex<Vec<(String, String)>>,
+    /// Warnings, which aren't always about one file.
+    warnings: Mutex<Vec<String>>,
+}
+
+impl BufferedOutput {
+    /// The warnings, then the lines about each file in order.
+    fn sorted_lines(&self) -> Vec<String> {
+        let mut warnings = self.warnings.lock().unwrap().clone();
+        warnings.sort();
+        let mut lines = self.lines.lock().unwrap().clone();
+        // Anything whose name doesn't match an entry, perhaps because it
+        // was renamed, goes at the end.
+        lines.sort_by_cached_key(|(name, line)| {
+            let position = self.positions.get(name).copied().unwrap_or(usize::MAX);
+            (position, name.clone(), line.clone())
+        });
+        warnings
+            .into_iter()
+            .chain(lines.into_iter().map(|(_, line)| line))
+            .collect()
+    }
+}
 
 impl ProgressDisplayer {
-    fn new() -> Self {
-        Self(ProgressBar::new(0))
+    fn new(is_remote: bool, is_silent: bool) -> Self {
+        let bars = if is_silent {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        };
+        let overall = bars.add(ProgressBar::new(0));
+        Self {
+            bars,
+            overall,
+            written: ProgressBar::hidden(),
+            downloaded: ProgressBar::hidden(),
+            is_remote,
+            is_silent,
+            files: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+            extracted: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            readahead_peak: AtomicU64::new(0),
+            http_streams: AtomicU64::new(0),
+            timings: Mutex::new(Timings::default()),
+            failures: Mutex::new(Vec::new()),
+            renamed: Mutex::new(BTreeMap::new()),
+            buffered_output: None,
+        }
+    }
+
+    /// Rather than showing progress bars, keep a line about each file to be
+    /// printed by [`Self::print_summary`], in the order of `names`.
+    fn buffer_output(&mut self, names: Vec<String>) {
+        self.bars.set_draw_target(ProgressDrawTarget::hidden());
+        let positions = names
+            .into_iter()
+            .enumerate()
+            .map(|(position, name)| (name, position))
+            .collect();
+        self.buffered_output = Some(BufferedOutput {
+            positions,
+            lines: Mutex::new(Vec::new()),
+            warnings: Mutex::new(Vec::new()),
+        });
+    }
+
+    /// Note what happened to a file, if lines are being buffered.
+    fn buffer_line(&self, display_name: &str, line: String) {
+        if self.is_silent {
+            return;
+        }
+        if let Some(buffered_output) = &self.buffered_output {
+            buffered_output
+                .lines
+                .lock()
+                .unwrap()
+                .push((display_name.to_string(), line));
+        }
+    }
+
+    fn remove_file(&self, display_name: &str) {
+        if let Some(bar) = self.files.lock().unwrap().remove(display_name) {
+            bar.finish_and_clear();
+            self.bars.remove(&bar);
+        }
+    }
+
+    /// Print a line summing up the extraction. This is printed even when
+    /// silenced.
+    fn print_summary(&self) {
+        self.overall.finish();
+        if let Some(buffered_output) = &self.buffered_output {
+            for line in buffered_output.sorted_lines() {
+                eprintln!("{}", colorize_line(&line));
+            }
+        }
+        let elapsed = self.started.elapsed();
+        let written = self.written.position();
+        let throughput = (written as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        let count = |counter: &AtomicU64, color: fn(StyledObject<u64>) -> StyledObject<u64>| {
+            match counter.load(Ordering::Relaxed) {
+                0 => style(0),
+                count => color(style(count)),
+            }
+            .for_stderr()
+        };
+        let mut summary = format!(
+            "Extracted {} entries ({} skipped, {} failed); wrote {} in {:.1}s ({}/s)",
+            count(&self.extracted, StyledObject::green),
+            count(&self.skipped, StyledObject::yellow),
+            count(&self.failed, StyledObject::red),
+            HumanBytes(written),
+            elapsed.as_secs_f64(),
+            HumanBytes(throughput),
+        );
+        if self.is_remote {
+            write!(
+                summary,
+                "; readahead peaked at {}",
+                HumanBytes(self.readahead_peak.load(Ordering::Relaxed))
+            )
+            .unwrap();
+        }
+        eprintln!("{summary}");
+    }
+
+    /// A line saying where the time went.
+    fn timings_summary(&self) -> String {
+        let timings = self.timings.lock().unwrap();
+        format!(
+            "Opening took {:.3}s; extracting files spent {:.3}s waiting for the network, {:.3}s decompressing and {:.3}s writing",
+            timings.opening.as_secs_f64(),
+            timings.network_wait.as_secs_f64(),
+            timings.decompression.as_secs_f64(),
+            timings.writing.as_secs_f64(),
+        )
+    }
+
+    /// Details of the files which failed to extract, as JSON.
+    fn error_report(&self) -> String {
+        let mut failures = self.failures.lock().unwrap();
+        // Files fail in whatever order the threads get to them, but the
+        // report should be the same each time.
+        failures.sort_by_cached_key(|failure| {
+            (
+                failure["offset"].as_u64().unwrap_or(u64::MAX),
+                failure["name"].as_str().unwrap_or_default().to_string(),
+            )
+        });
+        let mut report = serde_json::json!({ "failures": *failures });
+        let renamed = self.renamed.lock().unwrap();
+        if !renamed.is_empty() {
+            report["renamed"] = serde_json::json!(*renamed);
+        }
+        serde_json::to_string_pretty(&report).unwrap()
+    }
+
+    /// Metrics about the extraction, in the Prometheus text format.
+    fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        let mut metric = |name: &str, help: &str, samples: &[(&str, String)]| {
+            writeln!(metrics, "# HELP ripunzip_{name} {help}").unwrap();
+            writeln!(metrics, "# TYPE ripunzip_{name} gauge").unwrap();
+            for (labels, value) in samples {
+                writeln!(metrics, "ripunzip_{name}{labels} {value}").unwrap();
+            }
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        metric(
+            "duration_seconds",
+            "Time taken to extract the zip file.",
+            &[("", self.started.elapsed().as_secs_f64().to_string())],
+        );
+        metric(
+            "entries",
+            "Entries in the zip file, by what happened to them.",
+            &[
+                ("{result=\"extracted\"}", load(&self.extracted)),
+                ("{result=\"skipped\"}", load(&self.skipped)),
+                ("{result=\"failed\"}", load(&self.failed)),
+            ],
+        );
+        metric(
+            "errors",
+            "Entries which failed to extract.",
+            &[("", load(&self.failed))],
+        );
+        metric(
+            "compressed_bytes",
+            "Compressed bytes of the zip file extracted.",
+            &[("", self.overall.position().to_string())],
+        );
+        metric(
+            "written_bytes",
+            "Uncompressed bytes written out.",
+            &[("", self.written.position().to_string())],
+        );
+        if self.is_remote {
+            metric(
+                "downloaded_bytes",
+                "Bytes of the zip file downloaded while extracting it.",
+                &[("", self.downloaded.position().to_string())],
+            );
+            metric(
+                "http_streams",
+                "HTTP(S) streams opened to read the zip file.",
+                &[("", load(&self.http_streams))],
+            );
+            metric(
+                "readahead_peak_bytes",
+                "Most data held in memory having been fetched ahead of extraction.",
+                &[("", load(&self.readahead_peak))],
+            );
+        }
+        metrics
     }
 }
 
-impl UnzipProgressReporter for ProgressDisplayer {
+impl UnzipProgressReporter for &ProgressDisplayer {
     fn extraction_starting(&self, display_name: &str) {
-        self.0.set_message(format!("Extracting {display_name}"))
+        if self.is_silent || self.buffered_output.is_some() {
+            return;
+        }
+        let bar = self.bars.add(ProgressBar::new(0));
+        bar.set_style(
+            ProgressStyle::with_template("  {percent:>3}% {binary_bytes_per_sec:>12} {wide_msg}")
+                .unwrap(),
+        );
+        bar.set_message(display_name.to_string());
+        self.files
+            .lock()
+            .unwrap()
+            .insert(display_name.to_string(), bar);
+    }
+
+    fn extraction_finished(&self, display_name: &str) {
+        self.extracted.fetch_add(1, Ordering::Relaxed);
+        self.remove_file(display_name);
+        self.buffer_line(display_name, format!("Extracted {display_name}"));
+    }
+
+    fn extraction_skipped(&self, display_name: &str) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        self.remove_file(display_name);
+        self.buffer_line(display_name, format!("Skipped {display_name}"));
+    }
+
+    fn extraction_failed(&self, failure: &ExtractionFailure) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.remove_file(failure.name);
+        self.buffer_line(
+            failure.name,
+            format!("Failed {}: {:#}", failure.name, failure.error),
+        );
+        self.failures.lock().unwrap().push(serde_json::json!({
+            "name": failure.name,
+            "offset": failure.header_offset,
+            "kind": error_kind(failure.error),
+            "message": format!("{:#}", failure.error),
+        }));
+    }
+
+    // These are added up, as several zip files may be unzipped at once.
+
+    fn readahead_peak(&self, bytes: u64) {
+        self.readahead_peak.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn http_streams(&self, count: usize) {
+        self.http_streams.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn timings(&self, timings: &Timings) {
+        *self.timings.lock().unwrap() += *timings;
+    }
+
+    fn total_bytes_expected(&self, expected: u64) {
+        self.overall.inc_length(expected);
+        // The compressed bytes extracted are those read from the zip file,
+        // so their rate is how fast it's being read, unless it's being
+        // downloaded, which is counted separately.
+        let read = if self.is_remote {
+            "download {download_per_sec}"
+        } else {
+            "read {bytes_per_sec}"
+        };
+        let written = self.written.clone();
+        let downloaded = self.downloaded.clone();
+        // Nothing's expected if the zip file's being read from a stream, so
+        // there's no telling how far through it we are.
+        let template = if expected == 0 {
+            format!("{{spinner:.green}} [{{elapsed_precise}}] {{bytes}} ({read}, write {{write_per_sec}})")
+        } else {
+            format!("{{spinner:.green}} [{{elapsed_precise}}] [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}} (ETA {{eta}}, {read}, write {{write_per_sec}})")
+        };
+        self.overall.set_style(
+            ProgressStyle::with_template(&template)
+                .unwrap()
+                .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+                    write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+                })
+                .with_key(
+                    "write_per_sec",
+                    move |_: &ProgressState, w: &mut dyn Write| {
+                        write!(w, "{}/s", HumanBytes(written.per_sec() as u64)).unwrap()
+                    },
+                )
+                .with_key(
+                    "download_per_sec",
+                    move |_: &ProgressState, w: &mut dyn Write| {
+                        write!(w, "{}/s", HumanBytes(downloaded.per_sec() as u64)).unwrap()
+                    },
+                )
+                .progress_chars("#-"),
+        );
+    }
+
+    fn bytes_extracted(&self, count: u64) {
+        self.overall.inc(count)
+    }
+
+    fn bytes_written(&self, count: u64) {
+        self.written.inc(count)
+    }
+
+    fn bytes_downloaded(&self, count: u64) {
+        self.downloaded.inc(count)
+    }
+
+    fn file_bytes_extracted(&self, display_name: &str, count: u64, total: u64) {
+        if let Some(bar) = self.files.lock().unwrap().get(display_name) {
+            bar.set_length(total);
+            bar.inc(count);
+        }
+    }
+
+    fn warning(&self, kind: WarningKind, message: &str) {
+        if self.is_silent {
+            return;
+        }
+        let hint = match kind {
+            WarningKind::ReadaheadTooSmall => "\nYou may wish to use --readahead-limit to increase the amount of data which can be held in memory.",
+            WarningKind::ConcatenatedArchives => "\nYou may wish to use --archive or --all-archives to read the others.",
+            _ => "",
+        };
+        let warning = format!("Warning: {message}{hint}");
+        match &self.buffered_output {
+            Some(buffered_output) => buffered_output.warnings.lock().unwrap().push(warning),
+            None => self.overall.println(colorize_line(&warning)),
+        }
+    }
+
+    fn file_renamed(&self, original_name: &str, new_name: &str) {
+        self.renamed
+            .lock()
+            .unwrap()
+            .insert(original_name.to_string(), new_name.to_string());
+    }
+
+    fn priority_files_extracted(&self) {
+        if self.buffered_output.is_none() {
+            self.overall.println("Priority files extracted")
+        }
+    }
+}
+
+/// Color a line of output by what it says happened: green for files
+/// extracted, yellow for those skipped and warnings, and red for failures.
+fn colorize_line(line: &str) -> String {
+    let styled = style(line).for_stderr();
+    let styled = if line.starts_with("Extracted ") {
+        styled.green()
+    } else if line.starts_with("Skipped ") || line.starts_with("Warning: ") {
+        styled.yellow()
+    } else if line.starts_with("Failed ") {
+        styled.red()
+    } else {
+        styled
+    };
+    styled.to_string()
+}
+
+/// Asks whether to overwrite each file which already exists, as unzip does.
+/// Only one file is asked about at once, however many are being extracted.
+struct OverwritePrompt {
+    bars: MultiProgress,
+    /// The answer for every file, once one is given. This is locked while
+    /// asking.
+    answer_for_all: Mutex<Option<ExistingFileAction>>,
+}
+
+impl OverwritePrompt {
+    fn new(bars: MultiProgress) -> Self {
+        Self {
+            bars,
+            answer_for_all: Mutex::new(None),
+        }
+    }
+}
+
+impl ExistingFileHandler for OverwritePrompt {
+    fn handle_existing_file(&self, path: &Path) -> ExistingFileAction {
+        let mut answer_for_all = self.answer_for_all.lock().unwrap();
+        if let Some(answer) = &*answer_for_all {
+            return answer.clone();
+        }
+        self.bars.suspend(|| loop {
+            let Some(answer) = prompt(&format!(
+                "replace {}? [y]es, [n]o, [A]ll, [N]one, [r]ename: ",
+                path.display()
+            )) else {
+                println!("(EOF or read error, treating as \"[N]one\")");
+                *answer_for_all = Some(ExistingFileAction::Skip);
+                return ExistingFileAction::Skip;
+            };
+            match answer.as_str() {
+                "y" | "Y" => return ExistingFileAction::Overwrite,
+                "n" => return ExistingFileAction::Skip,
+                "A" => {
+                    *answer_for_all = Some(ExistingFileAction::Overwrite);
+                    return ExistingFileAction::Overwrite;
+                }
+                "N" => {
+                    *answer_for_all = Some(ExistingFileAction::Skip);
+                    return ExistingFileAction::Skip;
+                }
+                "r" => {
+                    if let Some(new_name) = prompt("new name: ").filter(|name| !name.is_empty()) {
+                        return ExistingFileAction::Rename(PathBuf::from(new_name));
+                    }
+                }
+                _ => println!("error: invalid response [{answer}]"),
+            }
+        })
+    }
+}
+
+/// Print `question`, and read the answer from standard input, or `None`
+/// if there isn't one.
+fn prompt(question: &str) -> Option<String> {
+    print!("{question}");
+    std::io::Write::flush(&mut std::io::stdout()).ok()?;
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(answer.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
+
+/// Reports progress unzipping one of several zip files, naming files by
+/// the subdirectory they're unzipped into.
+struct ArchiveProgressReporter<'a> {
+    subdirectory: &'a str,
+    progress_displayer: &'a ProgressDisplayer,
+    /// Whether any file failed to extract.
+    failed: AtomicBool,
+}
+
+impl ArchiveProgressReporter<'_> {
+    fn display_name(&self, display_name: &str) -> String {
+        format!("{}/{display_name}", self.subdirectory)
+    }
+}
+
+impl UnzipProgressReporter for &ArchiveProgressReporter<'_> {
+    fn extraction_starting(&self, display_name: &str) {
+        self.progress_displayer
+            .extraction_starting(&self.display_name(display_name))
+    }
+
+    fn extraction_finished(&self, display_name: &str) {
+        self.progress_displayer
+            .extraction_finished(&self.display_name(display_name))
+    }
+
+    fn extraction_skipped(&self, display_name: &str) {
+        self.progress_displayer
+            .extraction_skipped(&self.display_name(display_name))
+    }
+
+    fn extraction_failed(&self, failure: &ExtractionFailure) {
+        self.failed.store(true, Ordering::Relaxed);
+        self.progress_displayer
+            .extraction_failed(&ExtractionFailure {
+                name: &self.display_name(failure.name),
+                ..*failure
+            })
+    }
+
+    fn readahead_peak(&self, bytes: u64) {
+        self.progress_displayer.readahead_peak(bytes)
+    }
+
+    fn http_streams(&self, count: usize) {
+        self.progress_displayer.http_streams(count)
+    }
+
+    fn timings(&self, timings: &Timings) {
+        self.progress_displayer.timings(timings)
     }
 
     fn total_bytes_expected(&self, expected: u64) {
-        self.0.set_length(expected);
-        self.0.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})\n{msg}")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-        .progress_chars("#-"));
+        self.progress_displayer.total_bytes_expected(expected)
     }
 
     fn bytes_extracted(&self, count: u64) {
-        self.0.inc(count)
+        self.progress_displayer.bytes_extracted(count)
+    }
+
+    fn bytes_written(&self, count: u64) {
+        self.progress_displayer.bytes_written(count)
+    }
+
+    fn bytes_downloaded(&self, count: u64) {
+        self.progress_displayer.bytes_downloaded(count)
+    }
+
+    fn file_bytes_extracted(&self, display_name: &str, count: u64, total: u64) {
+        self.progress_displayer
+            .file_bytes_extracted(&self.display_name(display_name), count, total)
+    }
+
+    fn warning(&self, kind: WarningKind, message: &str) {
+        self.progress_displayer
+            .warning(kind, &format!("{}: {message}", self.subdirectory))
+    }
+
+    fn file_renamed(&self, original_name: &str, new_name: &str) {
+        self.progress_displayer.file_renamed(
+            &self.display_name(original_name),
+            &self.display_name(new_name),
+        )
+    }
+
+    fn priority_files_extracted(&self) {
+        self.progress_displayer.overall.println(format!(
+            "Priority files extracted from {}",
+            self.subdirectory
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::RwLock;
+    use std::{
+        sync::RwLock,
+        time::{Duration, SystemTime},
+    };
 
-    use ripunzip::FilenameFilter;
+    use ripunzip::{
+        ExtractionFailure, FilenameFilter, IoPriority, Timings, UnzipProgressReporter, WarningKind,
+    };
     use wildmatch::WildMatch;
 
-    use crate::FileListFilter;
+    use crate::{
+        archive_subdirectory, colorize_line, csv_row, expand_wildcards, find_zip_files,
+        parse_io_priority, parse_size, parse_time, print_completions, print_manpage,
+        FileListFilter, FilterArgs, MirrorSnapshot, ProgressDisplayer, MIRROR_SNAPSHOT,
+    };
 
     #[test]
     fn test_filelist_filter() {
@@ -249,4 +2548,336 @@ mod tests {
         assert!(filter.should_unzip("moose"));
         assert!(!filter.should_unzip("mouuuuuse"));
     }
+
+    #[test]
+    fn test_pattern_filter() {
+        let filter_args = FilterArgs {
+            exclude: vec!["*.o".to_string()],
+            exclude_vcs: false,
+            exclude_junk: false,
+            newer_than: None,
+            older_than: None,
+            min_entry_size: None,
+            max_entry_size: None,
+            entry_types: Vec::new(),
+            only_dir: None,
+            filenames_to_unzip: vec!["src/*".to_string()],
+        };
+        let filter = filter_args.filter_set();
+        assert!(filter.should_unzip("src/a.c"));
+        assert!(!filter.should_unzip("src/a.o"));
+        assert!(!filter.should_unzip("README"));
+
+        let filter_args = FilterArgs {
+            exclude: vec!["*.o".to_string()],
+            exclude_vcs: false,
+            exclude_junk: false,
+            newer_than: None,
+            older_than: None,
+            min_entry_size: None,
+            max_entry_size: None,
+            entry_types: Vec::new(),
+            only_dir: None,
+            filenames_to_unzip: Vec::new(),
+        };
+        let filter = filter_args.filter_set();
+        assert!(filter.should_unzip("README"));
+        assert!(!filter.should_unzip("src/a.o"));
+
+        let filter_args = FilterArgs {
+            exclude: Vec::new(),
+            exclude_vcs: false,
+            exclude_junk: false,
+            newer_than: None,
+            older_than: None,
+            min_entry_size: None,
+            max_entry_size: None,
+            entry_types: Vec::new(),
+            only_dir: Some("src/".to_string()),
+            filenames_to_unzip: Vec::new(),
+        };
+        let filter = filter_args.filter_set();
+        assert!(filter.should_unzip("src/a.c"));
+        assert!(filter.should_unzip("src/"));
+        assert!(!filter.should_unzip("srcs/a.c"));
+        assert!(!filter.should_unzip("README"));
+
+        let filter_args = FilterArgs {
+            exclude: Vec::new(),
+            exclude_vcs: true,
+            exclude_junk: true,
+            newer_than: None,
+            older_than: None,
+            min_entry_size: None,
+            max_entry_size: None,
+            entry_types: Vec::new(),
+            only_dir: None,
+            filenames_to_unzip: Vec::new(),
+        };
+        let filter = filter_args.filter_set();
+        assert!(filter.should_unzip("src/a.c"));
+        assert!(filter.should_unzip("src/.github/a.yml"));
+        assert!(!filter.should_unzip(".git/"));
+        assert!(!filter.should_unzip("src/.svn/entries"));
+        assert!(!filter.should_unzip("src/.gitignore"));
+        assert!(!filter.should_unzip("photos/thumbs.db"));
+    }
+
+    #[test]
+    fn test_error_report() {
+        let displayer = ProgressDisplayer::new(false, true);
+        let error = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .context("Failed to create file");
+        (&displayer).extraction_failed(&ExtractionFailure {
+            name: "a.txt",
+            header_offset: Some(42),
+            error: &error,
+        });
+        let report: serde_json::Value = serde_json::from_str(&displayer.error_report()).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "failures": [{
+                    "name": "a.txt",
+                    "offset": 42,
+                    "kind": "PermissionDenied",
+                    "message": "Failed to create file: permission denied",
+                }]
+            })
+        );
+
+        (&displayer).file_renamed("a/b.txt", "b-1.txt");
+        let report: serde_json::Value = serde_json::from_str(&displayer.error_report()).unwrap();
+        assert_eq!(
+            report["renamed"],
+            serde_json::json!({ "a/b.txt": "b-1.txt" })
+        );
+    }
+
+    #[test]
+    fn test_ordered_output() {
+        let mut displayer = ProgressDisplayer::new(false, false);
+        displayer.buffer_output(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let reporter = &displayer;
+        reporter.extraction_finished("c");
+        reporter.extraction_finished("renamed");
+        reporter.extraction_skipped("a");
+        reporter.warning(WarningKind::FileRenamed, "Renamed b");
+        reporter.extraction_finished("b");
+        assert_eq!(
+            displayer.buffered_output.as_ref().unwrap().sorted_lines(),
+            [
+                "Warning: Renamed b",
+                "Skipped a",
+                "Extracted b",
+                "Extracted c",
+                "Extracted renamed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_colorize_line() {
+        console::set_colors_enabled_stderr(true);
+        assert_eq!(
+            colorize_line("Extracted a"),
+            "\u{1b}[32mExtracted a\u{1b}[0m"
+        );
+        assert_eq!(
+            colorize_line("Failed b: oops"),
+            "\u{1b}[31mFailed b: oops\u{1b}[0m"
+        );
+        assert_eq!(
+            colorize_line("Priority files extracted"),
+            "Priority files extracted"
+        );
+        console::set_colors_enabled_stderr(false);
+        assert_eq!(colorize_line("Skipped c"), "Skipped c");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let displayer = ProgressDisplayer::new(true, true);
+        let reporter = &displayer;
+        reporter.extraction_finished("a");
+        reporter.extraction_skipped("b");
+        reporter.bytes_written(42);
+        reporter.http_streams(3);
+        reporter.bytes_downloaded(7);
+        let metrics = displayer.metrics();
+        assert!(metrics.contains("# TYPE ripunzip_entries gauge\n"));
+        assert!(metrics.contains("\nripunzip_entries{result=\"extracted\"} 1\n"));
+        assert!(metrics.contains("\nripunzip_entries{result=\"skipped\"} 1\n"));
+        assert!(metrics.contains("\nripunzip_written_bytes 42\n"));
+        assert!(metrics.contains("\nripunzip_http_streams 3\n"));
+        assert!(metrics.contains("\nripunzip_downloaded_bytes 7\n"));
+    }
+
+    #[test]
+    fn test_timings_summary() {
+        let displayer = ProgressDisplayer::new(false, true);
+        let reporter = &displayer;
+        let timings = Timings {
+            opening: Duration::from_millis(250),
+            network_wait: Duration::ZERO,
+            decompression: Duration::from_secs(1),
+            writing: Duration::from_millis(500),
+        };
+        // Several zip files' timings are added up.
+        reporter.timings(&timings);
+        reporter.timings(&timings);
+        assert_eq!(
+            displayer.timings_summary(),
+            "Opening took 0.500s; extracting files spent 0.000s waiting for the network, 2.000s decompressing and 1.000s writing"
+        );
+    }
+
+    #[test]
+    fn test_mirror_snapshot() {
+        let td = tempfile::tempdir().unwrap();
+        let path = td.path().join(MIRROR_SNAPSHOT);
+        assert_eq!(
+            MirrorSnapshot::load(&path).unwrap(),
+            MirrorSnapshot::default()
+        );
+        let snapshot = MirrorSnapshot {
+            etag: Some("\"abc\"".to_string()),
+            entries: [
+                ("a/".to_string(), (0, Some(0))),
+                ("a/b.txt".to_string(), (3, Some(0xdeadbeef))),
+                ("c.txt".to_string(), (4, None)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        snapshot.save(&path).unwrap();
+        assert_eq!(MirrorSnapshot::load(&path).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2024-01-31").unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1706659200)
+        );
+        assert_eq!(
+            parse_time("2024-01-31T12:00:00Z").unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1706702400)
+        );
+        let week_ago = SystemTime::now() - Duration::from_secs(7 * 24 * 60 * 60);
+        let error = week_ago.duration_since(parse_time("7d").unwrap());
+        assert!(error.map_or(true, |error| error < Duration::from_secs(1)));
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("256MB").unwrap(), 256 * 1024 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_io_priority() {
+        assert_eq!(parse_io_priority("idle").unwrap(), IoPriority::Idle);
+        assert_eq!(parse_io_priority("7").unwrap(), IoPriority::BestEffort(7));
+        assert!(parse_io_priority("8").is_err());
+        assert!(parse_io_priority("high").is_err());
+    }
+
+    #[test]
+    fn test_archive_subdirectory() {
+        assert_eq!(archive_subdirectory("b.zip").as_deref(), Some("b"));
+        assert_eq!(archive_subdirectory("c.tar.gz").as_deref(), Some("c"));
+        assert_eq!(archive_subdirectory("d").as_deref(), Some("d"));
+        assert_eq!(archive_subdirectory(""), None);
+        assert_eq!(archive_subdirectory(".zip"), None);
+    }
+
+    #[test]
+    fn test_expand_wildcards() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["b.zip", "a.zip", "c.tgz"] {
+            std::fs::write(directory.path().join(name), "").unwrap();
+        }
+        std::fs::create_dir(directory.path().join("d.zip")).unwrap();
+        let expand = |pattern: &str| expand_wildcards(&directory.path().join(pattern));
+        assert_eq!(
+            expand("*.zip").unwrap(),
+            Some(vec![
+                directory.path().join("a.zip"),
+                directory.path().join("b.zip")
+            ])
+        );
+        assert_eq!(expand("c.tgz").unwrap(), None);
+        assert!(expand("*.tar").is_err());
+    }
+
+    #[test]
+    fn test_find_zip_files() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(directory.path().join("a/b")).unwrap();
+        for name in ["a/b/c.zip", "a/D.ZIP", "e.tgz", "f.zip"] {
+            std::fs::write(directory.path().join(name), "").unwrap();
+        }
+        let mut zipfiles = Vec::new();
+        find_zip_files(directory.path(), &mut zipfiles).unwrap();
+        zipfiles.sort();
+        assert_eq!(
+            zipfiles,
+            ["a/D.ZIP", "a/b/c.zip", "f.zip"].map(|name| directory.path().join(name))
+        );
+    }
+
+    #[test]
+    fn test_csv_row() {
+        use std::io::Write;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("z.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .last_modified_time(zip::DateTime::from_date_and_time(2024, 3, 4, 5, 6, 8).unwrap());
+        zip.start_file("plain.txt", options).unwrap();
+        zip.write_all(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")
+            .unwrap();
+        zip.start_file(
+            "a, \"quoted\" name",
+            options.compression_method(zip::CompressionMethod::Stored),
+        )
+        .unwrap();
+        zip.add_directory("empty/", options).unwrap();
+        zip.finish().unwrap();
+        let engine = ripunzip::UnzipEngine::for_file(std::fs::File::open(&path).unwrap()).unwrap();
+        let rows: Vec<_> = engine.list_details().unwrap().iter().map(csv_row).collect();
+        let plain: Vec<_> = rows[0].split(',').collect();
+        assert_eq!(plain[..3], ["plain.txt", "40", plain[2]]);
+        assert!(plain[2].parse::<u64>().unwrap() < 40);
+        assert_eq!(
+            plain[3],
+            format!("{:.3}", plain[2].parse::<f64>().unwrap() / 40.0)
+        );
+        assert_eq!(plain[4..], ["deflate", "c95b8a25", "2024-03-04T05:06:08Z"]);
+        assert_eq!(
+            rows[1],
+            "\"a, \"\"quoted\"\" name\",0,0,,stored,00000000,2024-03-04T05:06:08Z"
+        );
+        assert!(rows[2].starts_with("empty/,0,0,,"));
+    }
+
+    #[test]
+    fn test_completions_and_manpage() {
+        let mut completions = Vec::new();
+        print_completions(clap_complete::Shell::Bash, &mut completions);
+        let completions = String::from_utf8(completions).unwrap();
+        assert!(completions.contains("unzip-uri"));
+        assert!(completions.contains("--output-directory"));
+        let mut manpage = Vec::new();
+        print_manpage(&mut manpage).unwrap();
+        let manpage = String::from_utf8(manpage).unwrap();
+        assert!(manpage.contains(".TH ripunzip"));
+        assert!(manpage.contains("unzip\\-file"));
+    }
 }
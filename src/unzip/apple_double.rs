@@ -0,0 +1,261 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! When macOS zips a file, it puts the metadata which zip files have no
+//! room for in an AppleDouble file beside it, named `._` followed by the
+//! file's name, usually within a `__MACOSX` directory. That holds the
+//! file's Finder info, such as its colour label, its resource fork and any
+//! other extended attributes. On macOS, these can be given back to the
+//! extracted files once everything has been extracted.
+
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{bail, Result};
+use cap_std::fs::Dir;
+
+use super::{ExtractionFailure, UnzipProgressReporter, WarningKind};
+
+const APPLE_DOUBLE_MAGIC: u32 = 0x0005_1607;
+const APPLE_DOUBLE_HEADER_LEN: usize = 26;
+const ENTRY_DESCRIPTOR_LEN: usize = 12;
+const ENTRY_RESOURCE_FORK: u32 = 2;
+const ENTRY_FINDER_INFO: u32 = 9;
+const FINDER_INFO_LEN: usize = 32;
+/// Other extended attributes follow the Finder info, after two bytes of
+/// padding, in a block with this header.
+const ATTR_MAGIC: &[u8] = b"ATTR";
+const ATTR_HEADER_LEN: usize = 36;
+const ATTR_ENTRY_LEN: usize = 11;
+
+const FINDER_INFO_XATTR: &str = "com.apple.FinderInfo";
+const RESOURCE_FORK_XATTR: &str = "com.apple.ResourceFork";
+
+/// The name of the file whose metadata the AppleDouble file called `name`
+/// holds, if it's an AppleDouble file.
+pub(crate) fn apple_double_target(name: &str) -> Option<String> {
+    let name = name.strip_prefix("__MACOSX/").unwrap_or(name);
+    let (directory, file_name) = match name.rsplit_once('/') {
+        Some((directory, file_name)) => (Some(directory), file_name),
+        None => (None, name),
+    };
+    let target = file_name
+        .strip_prefix("._")
+        .filter(|target| !target.is_empty())?;
+    Some(match directory {
+        Some(directory) => format!("{directory}/{target}"),
+        None => target.to_string(),
+    })
+}
+
+/// Read the extended attributes which the AppleDouble file `data` holds,
+/// as their names and values. The Finder info and resource fork are
+/// returned as the attributes macOS exposes them as.
+pub(crate) fn parse_apple_double(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    if data.len() < APPLE_DOUBLE_HEADER_LEN || u32_be_at(data, 0) != APPLE_DOUBLE_MAGIC {
+        bail!("Not an AppleDouble file");
+    }
+    let entry_count = u16::from_be_bytes([data[24], data[25]]) as usize;
+    let mut attributes = Vec::new();
+    for i in 0..entry_count {
+        let descriptor = APPLE_DOUBLE_HEADER_LEN + i * ENTRY_DESCRIPTOR_LEN;
+        let Some(descriptor) = data.get(descriptor..descriptor + ENTRY_DESCRIPTOR_LEN) else {
+            bail!("The AppleDouble file is truncated");
+        };
+        let offset = u32_be_at(descriptor, 4) as usize;
+        let len = u32_be_at(descriptor, 8) as usize;
+        let Some(entry) = data.get(offset..offset + len) else {
+            bail!("The AppleDouble file is truncated");
+        };
+        match u32_be_at(descriptor, 0) {
+            ENTRY_RESOURCE_FORK if !entry.is_empty() => {
+                attributes.push((RESOURCE_FORK_XATTR.to_string(), entry.to_vec()));
+            }
+            ENTRY_FINDER_INFO if entry.len() >= FINDER_INFO_LEN => {
+                let finder_info = &entry[..FINDER_INFO_LEN];
+                if finder_info.iter().any(|byte| *byte != 0) {
+                    attributes.push((FINDER_INFO_XATTR.to_string(), finder_info.to_vec()));
+                }
+                if let Some(attr) = entry.get(FINDER_INFO_LEN + 2..) {
+                    if attr.starts_with(ATTR_MAGIC) {
+                        read_attr_block(data, attr, &mut attributes)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(attributes)
+}
+
+/// Read the extended attributes in the block `attr`, whose values are at
+/// offsets from the start of the whole file, `data`.
+fn read_attr_block(
+    data: &[u8],
+    attr: &[u8],
+    attributes: &mut Vec<(String, Vec<u8>)>,
+) -> Result<()> {
+    if attr.len() < ATTR_HEADER_LEN {
+        bail!("The AppleDouble file's attributes are truncated");
+    }
+    let count = u16::from_be_bytes([attr[34], attr[35]]) as usize;
+    let mut pos = ATTR_HEADER_LEN;
+    for _ in 0..count {
+        let Some(header) = attr.get(pos..pos + ATTR_ENTRY_LEN) else {
+            bail!("The AppleDouble file's attributes are truncated");
+        };
+        let offset = u32_be_at(header, 0) as usize;
+        let len = u32_be_at(header, 4) as usize;
+        let name_len = header[10] as usize;
+        let (Some(name), Some(value)) = (
+            attr.get(pos + ATTR_ENTRY_LEN..pos + ATTR_ENTRY_LEN + name_len),
+            data.get(offset..offset + len),
+        ) else {
+            bail!("The AppleDouble file's attributes are truncated");
+        };
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        attributes.push((String::from_utf8_lossy(name).into_owned(), value.to_vec()));
+        // Each entry is padded to a multiple of four bytes.
+        pos = (pos + ATTR_ENTRY_LEN + name_len).next_multiple_of(4);
+    }
+    Ok(())
+}
+
+fn u32_be_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// AppleDouble files to give back to the files they came from once
+/// everything has been extracted, with the names of those files.
+#[derive(Default)]
+pub(crate) struct DeferredAppleDouble(Mutex<Vec<(String, Vec<u8>)>>);
+
+impl DeferredAppleDouble {
+    pub(crate) fn defer(&self, target: String, data: Vec<u8>) {
+        self.0.lock().unwrap().push((target, data));
+    }
+
+    /// Give the extended attributes in each AppleDouble file to the file it
+    /// came from, if that was extracted into `output_dir` under its own
+    /// name. Files whose attributes can't be set are reported to
+    /// `progress_reporter` as failures.
+    pub(crate) fn restore(
+        self,
+        output_dir: &Dir,
+        progress_reporter: &dyn UnzipProgressReporter,
+    ) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+        for (target, data) in self.0.into_inner().unwrap() {
+            let path = super::sequential::enclosed_path(&target)
+                .filter(|path| output_dir.symlink_metadata(path).is_ok());
+            let Some(path) = path else {
+                let message =
+                    format!("Skipping AppleDouble file for {target}, which wasn't extracted");
+                log::info!("{message}");
+                progress_reporter.warning(WarningKind::FileSkipped, &message);
+                continue;
+            };
+            let result = parse_apple_double(&data)
+                .and_then(|attributes| set_attributes(output_dir, &path, &attributes));
+            if let Err(error) = result {
+                let error = error.context(format!("Failed to restore metadata of {target}"));
+                progress_reporter.extraction_failed(&ExtractionFailure {
+                    name: &target,
+                    header_offset: None,
+                    error: &error,
+                });
+                errors.push(error);
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set_attributes(output_dir: &Dir, path: &Path, attributes: &[(String, Vec<u8>)]) -> Result<()> {
+    use anyhow::Context;
+    use rustix::fs::{fsetxattr, XattrFlags};
+
+    let file = output_dir.open(path)?;
+    for (name, value) in attributes {
+        fsetxattr(&file, name.as_str(), value, XattrFlags::empty())
+            .with_context(|| format!("Failed to set extended attribute {name}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn set_attributes(
+    _output_dir: &Dir,
+    _path: &Path,
+    _attributes: &[(String, Vec<u8>)],
+) -> Result<()> {
+    bail!("AppleDouble files can only be restored on macOS")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apple_double_target, parse_apple_double};
+
+    #[test]
+    fn test_apple_double_target() {
+        assert_eq!(
+            apple_double_target("__MACOSX/a/._b.txt").as_deref(),
+            Some("a/b.txt")
+        );
+        assert_eq!(apple_double_target("._b.txt").as_deref(), Some("b.txt"));
+        assert_eq!(apple_double_target("a/b.txt"), None);
+        assert_eq!(apple_double_target("__MACOSX/a/"), None);
+        assert_eq!(apple_double_target("a/._"), None);
+    }
+
+    #[test]
+    fn test_parse_apple_double() {
+        // A header with two entries: Finder info followed by an attribute
+        // block holding one attribute, then a resource fork.
+        let mut data = vec![0, 5, 22, 7, 0, 2, 0, 0];
+        data.extend_from_slice(b"Mac OS X        ");
+        data.extend_from_slice(&2u16.to_be_bytes());
+        let finder_info_offset = 26 + 2 * 12;
+        let attr_len = 36 + 12 + 4;
+        let finder_info_len = 32 + 2 + attr_len;
+        let fork_offset = finder_info_offset + finder_info_len;
+        let value_offset = fork_offset + 4;
+        for (id, offset, len) in [
+            (9u32, finder_info_offset, finder_info_len),
+            (2, fork_offset, 4),
+        ] {
+            data.extend_from_slice(&id.to_be_bytes());
+            data.extend_from_slice(&(offset as u32).to_be_bytes());
+            data.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        data.extend_from_slice(b"TEXTttxt");
+        data.extend_from_slice(&[0; 24 + 2]);
+        data.extend_from_slice(b"ATTR");
+        data.extend_from_slice(&[0; 30]);
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&(value_offset as u32).to_be_bytes());
+        data.extend_from_slice(&3u32.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 4]);
+        data.extend_from_slice(b"a.b\0\0");
+        data.extend_from_slice(b"fork");
+        data.extend_from_slice(b"xyz");
+        let attributes = parse_apple_double(&data).unwrap();
+        let mut finder_info = b"TEXTttxt".to_vec();
+        finder_info.resize(32, 0);
+        assert_eq!(
+            attributes,
+            vec![
+                ("com.apple.FinderInfo".to_string(), finder_info),
+                ("a.b".to_string(), b"xyz".to_vec()),
+                ("com.apple.ResourceFork".to_string(), b"fork".to_vec()),
+            ]
+        );
+        assert!(parse_apple_double(b"PK\x03\x04").is_err());
+        assert!(parse_apple_double(&data[..60]).is_err());
+    }
+}
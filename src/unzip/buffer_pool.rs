@@ -0,0 +1,202 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Buffers which are reused from one file to the next, so that extracting
+//! zip files containing very many small files doesn't spend its time
+//! allocating and freeing them.
+
+use std::{
+    cell::RefCell,
+    io::{ErrorKind, Read, Write},
+    ops::{Deref, DerefMut},
+    sync::mpsc::sync_channel,
+    time::Instant,
+};
+
+use super::thread_tuner;
+
+/// The size of every pooled buffer.
+pub(crate) const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Files smaller than this aren't worth starting a thread to write.
+pub(crate) const DOUBLE_BUFFER_THRESHOLD: u64 = 4 * BUFFER_SIZE as u64;
+
+/// How many spare buffers each thread keeps.
+const MAX_SPARE_BUFFERS: usize = 4;
+
+thread_local! {
+    static SPARE_BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A buffer of [`BUFFER_SIZE`] bytes, which goes back to the pool of the
+/// thread which drops it. Its contents are whatever they were last time.
+pub(crate) struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    pub(crate) fn take() -> Self {
+        let spare = SPARE_BUFFERS.with(|buffers| buffers.borrow_mut().pop());
+        Self(spare.unwrap_or_else(|| vec![0u8; BUFFER_SIZE]))
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let buffer = std::mem::take(&mut self.0);
+        // The thread-local may already be gone if this is dropped while
+        // the thread is exiting, in which case the buffer is just freed.
+        let _ = SPARE_BUFFERS.try_with(|buffers| {
+            let mut buffers = buffers.borrow_mut();
+            if buffers.len() < MAX_SPARE_BUFFERS {
+                buffers.push(buffer);
+            }
+        });
+    }
+}
+
+/// Like [`std::io::copy`], but using a pooled buffer, which is also bigger
+/// than the one it uses.
+pub(crate) fn copy(reader: &mut impl Read, writer: &mut impl Write) -> std::io::Result<u64> {
+    let mut buf = PooledBuffer::take();
+    let mut total = 0u64;
+    loop {
+        let bytes_read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..bytes_read])?;
+        total += bytes_read as u64;
+    }
+}
+
+/// Read from `reader` until `buf` is full or there's nothing more to read,
+/// returning how many bytes were read.
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => filled += bytes_read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Like [`copy`], but each buffer is written on another thread while the
+/// next is read, so that a slow disk doesn't hold up decompression, nor
+/// the other way round. Time spent waiting for the writes to catch up is
+/// recorded as time spent writing.
+pub(crate) fn copy_double_buffered<W: Write + Send + ?Sized>(
+    reader: &mut impl Read,
+    writer: &mut W,
+) -> std::io::Result<u64> {
+    let (full_sender, full_receiver) = sync_channel::<(PooledBuffer, usize)>(1);
+    let (empty_sender, empty_receiver) = sync_channel(2);
+    for _ in 0..2 {
+        empty_sender.send(PooledBuffer::take()).unwrap();
+    }
+    std::thread::scope(|scope| {
+        let writing = scope.spawn(move || -> std::io::Result<()> {
+            for (buf, len) in full_receiver {
+                writer.write_all(&buf[..len])?;
+                // Nobody will want the buffer back if that was the last.
+                let _ = empty_sender.send(buf);
+            }
+            Ok(())
+        });
+        let mut total = 0u64;
+        let read_result = loop {
+            let started = Instant::now();
+            // If the writing thread has gone, it has an error to report.
+            let Ok(mut buf) = empty_receiver.recv() else {
+                break Ok(());
+            };
+            thread_tuner::record_write_time(started.elapsed());
+            let bytes_read = match fill(reader, &mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(bytes_read) => bytes_read,
+                Err(e) => break Err(e),
+            };
+            total += bytes_read as u64;
+            if full_sender.send((buf, bytes_read)).is_err() {
+                break Ok(());
+            }
+        };
+        drop(full_sender);
+        let write_result = writing.join().unwrap();
+        read_result?;
+        write_result?;
+        Ok(total)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind, Write};
+
+    use super::{copy, copy_double_buffered, PooledBuffer, BUFFER_SIZE};
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let mut buffer = PooledBuffer::take();
+        buffer[0] = 42;
+        let address = buffer.as_ptr();
+        drop(buffer);
+        let buffer = PooledBuffer::take();
+        assert_eq!(buffer.as_ptr(), address);
+        assert_eq!(buffer.len(), BUFFER_SIZE);
+        assert_eq!(buffer[0], 42);
+    }
+
+    #[test]
+    fn test_copy() {
+        let data: Vec<u8> = (0..BUFFER_SIZE * 2 + 5).map(|i| i as u8).collect();
+        let mut output = Vec::new();
+        let bytes_copied = copy(&mut Cursor::new(&data), &mut output).unwrap();
+        assert_eq!(bytes_copied, data.len() as u64);
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_copy_double_buffered() {
+        let data: Vec<u8> = (0..BUFFER_SIZE * 5 + 5).map(|i| i as u8).collect();
+        let mut output = Vec::new();
+        let bytes_copied = copy_double_buffered(&mut Cursor::new(&data), &mut output).unwrap();
+        assert_eq!(bytes_copied, data.len() as u64);
+        assert_eq!(output, data);
+
+        struct FullDisk;
+        impl Write for FullDisk {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let error = copy_double_buffered(&mut Cursor::new(&data), &mut FullDisk).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+    }
+}
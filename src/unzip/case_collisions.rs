@@ -0,0 +1,163 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+
+use super::sanitize::FilenameSanitization;
+
+/// What to do about files in the zip whose names differ only by case, and
+/// which would therefore overwrite each other on a case-insensitive
+/// filesystem. The first such file in the zip is always extracted as normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseCollisionPolicy {
+    /// Don't check; later files may overwrite earlier ones.
+    Allow,
+    /// Extract later files under a new name, such as `README (1)`.
+    Rename,
+    /// Don't extract later files.
+    Skip,
+    /// Fail before extracting anything.
+    Error,
+}
+
+impl Default for CaseCollisionPolicy {
+    /// The default filesystems on Windows and macOS are case-insensitive,
+    /// so on those platforms colliding files are renamed.
+    fn default() -> Self {
+        if cfg!(any(windows, target_os = "macos")) {
+            Self::Rename
+        } else {
+            Self::Allow
+        }
+    }
+}
+
+/// What to do with a file whose name collides with an earlier one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CollisionResolution {
+    Rename(PathBuf),
+    Skip,
+}
+
+fn case_insensitive_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Return a name like `name (1).ext`.
+fn numbered_name(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem} ({n}).{}", extension.to_string_lossy()),
+        None => format!("{stem} ({n})"),
+    };
+    path.with_file_name(file_name)
+}
+
+/// Find the files (by their names in the zip) which need to be renamed or
+/// skipped to avoid case collisions.
+pub(crate) fn resolve_case_collisions<'a>(
+    names: impl Iterator<Item = &'a str>,
+    policy: CaseCollisionPolicy,
+    sanitization: FilenameSanitization,
+) -> Result<HashMap<String, CollisionResolution>> {
+    let mut resolutions = HashMap::new();
+    if policy == CaseCollisionPolicy::Allow {
+        return Ok(resolutions);
+    }
+    let mut seen = HashSet::new();
+    let mut collisions = Vec::new();
+    // Directories can't overwrite each other, so only consider files.
+    for name in names.filter(|name| !name.ends_with('/')) {
+        let path = sanitization.sanitize(Path::new(name));
+        if seen.insert(case_insensitive_key(&path)) {
+            continue;
+        }
+        collisions.push(name);
+        let resolution = match policy {
+            CaseCollisionPolicy::Rename => {
+                let renamed = (1..)
+                    .map(|n| numbered_name(&path, n))
+                    .find(|renamed| !seen.contains(&case_insensitive_key(renamed)))
+                    .unwrap();
+                seen.insert(case_insensitive_key(&renamed));
+                CollisionResolution::Rename(renamed)
+            }
+            _ => CollisionResolution::Skip,
+        };
+        resolutions.insert(name.to_string(), resolution);
+    }
+    if policy == CaseCollisionPolicy::Error && !collisions.is_empty() {
+        bail!(
+            "Files have names which differ only by case: {}",
+            collisions.join(", ")
+        );
+    }
+    Ok(resolutions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{resolve_case_collisions, CaseCollisionPolicy, CollisionResolution};
+    use crate::FilenameSanitization;
+
+    const NAMES: &[&str] = &[
+        "README",
+        "dir/",
+        "DIR/",
+        "readme",
+        "Readme",
+        "dir/a.txt",
+        "DIR/A.TXT",
+        "other.txt",
+    ];
+
+    fn resolve(
+        policy: CaseCollisionPolicy,
+    ) -> anyhow::Result<std::collections::HashMap<String, CollisionResolution>> {
+        resolve_case_collisions(NAMES.iter().copied(), policy, FilenameSanitization::None)
+    }
+
+    #[test]
+    fn test_rename() {
+        let resolutions = resolve(CaseCollisionPolicy::Rename).unwrap();
+        assert_eq!(resolutions.len(), 3);
+        assert_eq!(
+            resolutions["readme"],
+            CollisionResolution::Rename(PathBuf::from("readme (1)"))
+        );
+        assert_eq!(
+            resolutions["Readme"],
+            CollisionResolution::Rename(PathBuf::from("Readme (2)"))
+        );
+        assert_eq!(
+            resolutions["DIR/A.TXT"],
+            CollisionResolution::Rename(PathBuf::from("DIR/A (1).TXT"))
+        );
+    }
+
+    #[test]
+    fn test_skip_and_error() {
+        let resolutions = resolve(CaseCollisionPolicy::Skip).unwrap();
+        assert_eq!(resolutions.len(), 3);
+        assert!(resolutions
+            .values()
+            .all(|resolution| *resolution == CollisionResolution::Skip));
+        assert!(resolve(CaseCollisionPolicy::Error).is_err());
+        assert!(resolve(CaseCollisionPolicy::Allow).unwrap().is_empty());
+    }
+}
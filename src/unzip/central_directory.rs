@@ -0,0 +1,552 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The zip crate doesn't expose some of what's recorded in the central
+//! directory, such as MS-DOS file attributes, and exposes other parts only
+//! once an entry has been opened, which means reading its local header. For
+//! a remote zip file, that's another HTTP request per entry, so read the
+//! central directory ourselves where we need to know about every entry.
+
+use std::{
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
+    ops::Range,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use zip::ZipArchive;
+
+use super::{
+    cloneable_seekable_reader::{CloneableSeekableReader, ReadAt, Truncated},
+    sink::zip_time_to_system_time,
+};
+
+#[cfg(any(windows, test))]
+pub(crate) const FILE_ATTRIBUTE_READONLY: u32 = 0x01;
+#[cfg(any(windows, test))]
+pub(crate) const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
+
+pub(crate) const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const CENTRAL_DIRECTORY_HEADER_LEN: usize = 46;
+pub(crate) const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const END_OF_CENTRAL_DIRECTORY_LEN: usize = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LEN: usize = 56;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_LOCATOR_LEN: usize = 20;
+const MAX_COMMENT_LEN: usize = 0xffff;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+const NTFS_EXTRA_FIELD_ID: u16 = 0x000a;
+const NTFS_TIMES_TAG: u16 = 0x0001;
+/// Seconds from 1601, when Windows file times start, to 1970.
+const FILETIME_UNIX_EPOCH: u64 = 11_644_473_600;
+pub(crate) const ZIP64_PLACEHOLDER: u32 = 0xffffffff;
+
+/// Set in an entry's flags if it's encrypted.
+pub(crate) const FLAG_ENCRYPTED: u16 = 0x0001;
+/// Set in an entry's flags if its sizes and CRC follow its data rather
+/// than being in its local header.
+pub(crate) const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+/// Set in an entry's flags if its name is UTF-8 rather than code page 437.
+const FLAG_UTF8: u16 = 0x0800;
+
+/// The characters for bytes 0x80 to 0xff in code page 437, the encoding
+/// of names without the UTF-8 flag.
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// Host systems, from the "version made by" field, whose external
+/// attributes are MS-DOS attributes: MS-DOS itself, NTFS and VFAT.
+#[cfg(any(windows, test))]
+const DOS_HOST_SYSTEMS: &[u8] = &[0, 10, 14];
+const UNIX_HOST_SYSTEM: u8 = 3;
+
+/// What the central directory records about one entry.
+pub(crate) struct CentralDirectoryEntry {
+    /// As decoded from the central directory, or as the zip crate names it
+    /// if it has read the central directory.
+    pub(crate) name: String,
+    /// The system on which the entry was zipped.
+    pub(crate) host_system: u8,
+    pub(crate) flags: u16,
+    pub(crate) compression_method: u16,
+    pub(crate) external_attributes: u32,
+    pub(crate) crc32: u32,
+    pub(crate) compressed_size: u64,
+    pub(crate) size: u64,
+    /// The offset of the entry's local header, which comes just before its
+    /// data.
+    pub(crate) header_offset: u64,
+    pub(crate) last_modified: Option<SystemTime>,
+}
+
+impl CentralDirectoryEntry {
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// Whether the zip crate, with the features it was built with, can
+    /// decompress the entry.
+    #[allow(deprecated)]
+    pub(crate) fn is_supported(&self) -> bool {
+        !matches!(
+            zip::CompressionMethod::from_u16(self.compression_method),
+            zip::CompressionMethod::Unsupported(_)
+        )
+    }
+
+    /// The Unix permissions recorded for the entry, interpreted the same
+    /// way as the zip crate does, since local headers don't record them.
+    pub(crate) fn unix_mode(&self) -> Option<u32> {
+        const S_IFDIR: u32 = 0o040000;
+        const S_IFREG: u32 = 0o100000;
+        if self.external_attributes == 0 {
+            None
+        } else if self.host_system == UNIX_HOST_SYSTEM {
+            Some(self.external_attributes >> 16)
+        } else if self.host_system == 0 {
+            let mut mode = if self.external_attributes & 0x10 != 0 {
+                S_IFDIR | 0o775
+            } else {
+                S_IFREG | 0o664
+            };
+            if self.external_attributes & 0x01 != 0 {
+                mode &= 0o555;
+            }
+            Some(mode)
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the central directory is, according to the end of central
+/// directory record.
+pub(crate) struct CentralDirectoryLocation {
+    pub(crate) start: u64,
+    pub(crate) len: u64,
+    pub(crate) entry_count: u64,
+}
+
+pub(crate) fn u16_at(header: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([header[offset], header[offset + 1]])
+}
+
+pub(crate) fn u32_at(header: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn u64_at(header: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        header.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Read the central directory entries, in the same order as the zip crate
+/// indexes them.
+pub(crate) fn read_central_directory<R: Read + Seek + Clone>(
+    zip_archive: &ZipArchive<R>,
+) -> Result<Vec<CentralDirectoryEntry>> {
+    let mut reader = BufReader::new(zip_archive.clone().into_inner());
+    reader.seek(SeekFrom::Start(zip_archive.central_directory_start()))?;
+    let entries = (0..zip_archive.len())
+        .map(|_| read_entry(&mut reader))
+        .collect::<Result<Vec<_>>>()?;
+    // Entries are stored in the same order in the central directory as the
+    // zip crate indexes them, so use its names rather than decoding them
+    // again. Their offsets are from the start of the zip file, which may
+    // come after other data, such as a Chrome extension's header.
+    let mut entries = entries;
+    for (entry, name) in entries.iter_mut().zip(zip_archive.file_names()) {
+        entry.name = name.to_string();
+        entry.header_offset += zip_archive.offset();
+    }
+    Ok(entries)
+}
+
+/// Read the central directory entries one at a time, without the zip crate
+/// having read the central directory first.
+pub(crate) fn central_directory_entries<R: Read + Seek>(
+    reader: R,
+    location: &CentralDirectoryLocation,
+) -> Result<impl Iterator<Item = Result<CentralDirectoryEntry>>> {
+    // Read in large blocks, since each read may mean an HTTP request.
+    let mut reader = BufReader::with_capacity(1024 * 1024, reader);
+    reader.seek(SeekFrom::Start(location.start))?;
+    Ok((0..location.entry_count).map(move |_| read_entry(&mut reader)))
+}
+
+fn read_entry<R: Read + Seek>(reader: &mut BufReader<R>) -> Result<CentralDirectoryEntry> {
+    let mut header = [0u8; CENTRAL_DIRECTORY_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if u32_at(&header, 0) != CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+        bail!("Invalid central directory header");
+    }
+    let compressed_size = u32_at(&header, 20);
+    let uncompressed_size = u32_at(&header, 24);
+    let header_offset = u32_at(&header, 42);
+    let flags = u16_at(&header, 8);
+    let mut name = vec![0u8; u16_at(&header, 28) as usize];
+    reader.read_exact(&mut name)?;
+    let mut extra_fields = vec![0u8; u16_at(&header, 30) as usize];
+    reader.read_exact(&mut extra_fields)?;
+    reader.seek_relative(u16_at(&header, 32) as i64)?;
+    let last_modified = zip::DateTime::try_from_msdos(u16_at(&header, 14), u16_at(&header, 12))
+        .ok()
+        .and_then(zip_time_to_system_time);
+    let mut entry = CentralDirectoryEntry {
+        name: decode_name(&name, flags),
+        host_system: (u16_at(&header, 4) >> 8) as u8,
+        flags,
+        compression_method: u16_at(&header, 10),
+        external_attributes: u32_at(&header, 38),
+        crc32: u32_at(&header, 16),
+        compressed_size: compressed_size as u64,
+        size: uncompressed_size as u64,
+        header_offset: header_offset as u64,
+        last_modified,
+    };
+    // Sizes and offsets too big for 32 bits are in the Zip64 extra field,
+    // in this order, but only if they're needed.
+    let mut zip64_field = zip64_extra_field(&extra_fields).unwrap_or_default();
+    let mut next_zip64_value = || {
+        let value = u64_at(zip64_field, 0);
+        zip64_field = zip64_field.get(8..).unwrap_or_default();
+        value
+    };
+    if uncompressed_size == ZIP64_PLACEHOLDER {
+        entry.size = next_zip64_value().unwrap_or_default();
+    }
+    if compressed_size == ZIP64_PLACEHOLDER {
+        entry.compressed_size = next_zip64_value().unwrap_or_default();
+    }
+    if header_offset == ZIP64_PLACEHOLDER {
+        entry.header_offset = next_zip64_value().unwrap_or_default();
+    }
+    Ok(entry)
+}
+
+/// Decode an entry's name as the zip crate does, except that names with
+/// the UTF-8 flag which aren't valid UTF-8 are decoded lossily.
+fn decode_name(name: &[u8], flags: u16) -> String {
+    if flags & FLAG_UTF8 != 0 {
+        return String::from_utf8_lossy(name).into_owned();
+    }
+    name.iter()
+        .map(|&byte| match byte {
+            0..=0x7f => byte as char,
+            _ => CP437_HIGH.chars().nth((byte - 0x80) as usize).unwrap(),
+        })
+        .collect()
+}
+
+/// Find the central directory from the records at the end of the zip file.
+/// Returns `None` for zip files with anything unusual about them, such as
+/// data before the start of the zip, which only the zip crate handles.
+pub(crate) fn locate_central_directory(
+    reader: impl Read + Seek,
+) -> Result<Option<CentralDirectoryLocation>> {
+    // If the central directory isn't where it says, there's data before
+    // the zip file which all the offsets would need adjusting for.
+    Ok(
+        read_end_records(reader)?.and_then(|(location, directory_end)| {
+            (location.start.checked_add(location.len) == Some(directory_end)).then_some(location)
+        }),
+    )
+}
+
+/// Where the zip files stored one after another in `reader` are, in order.
+/// There's only one unless several have been concatenated. Anything before
+/// the first, such as a Chrome extension's header, isn't included.
+pub(crate) fn locate_archives(reader: &impl ReadAt) -> Result<Vec<Range<u64>>> {
+    let mut archives = Vec::new();
+    let mut end = reader.len()?;
+    loop {
+        let view = CloneableSeekableReader::new(Truncated::new(reader, end));
+        let records = match read_end_records(view.clone()) {
+            Ok(records) => records,
+            Err(e) if archives.is_empty() => return Err(e),
+            // Whatever comes before the zip files isn't one.
+            Err(_) => None,
+        };
+        let Some((location, directory_end)) = records else {
+            break;
+        };
+        // Offsets are from the start of the zip file, so that's however
+        // far before where the central directory actually ends.
+        let Some(start) = location
+            .start
+            .checked_add(location.len)
+            .and_then(|directory_len| directory_end.checked_sub(directory_len))
+        else {
+            break;
+        };
+        if location.entry_count > 0 && !starts_central_directory(view, start + location.start)? {
+            break;
+        }
+        archives.push(start..end);
+        if start == 0 {
+            break;
+        }
+        end = start;
+    }
+    archives.reverse();
+    Ok(archives)
+}
+
+/// Whether there's a central directory header at `pos`.
+fn starts_central_directory(mut reader: impl Read + Seek, pos: u64) -> Result<bool> {
+    let mut signature = [0u8; 4];
+    reader.seek(SeekFrom::Start(pos))?;
+    match reader.read_exact(&mut signature) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        result => result.map(|()| u32_at(&signature, 0) == CENTRAL_DIRECTORY_HEADER_SIGNATURE),
+    }
+    .map_err(Into::into)
+}
+
+/// Read the records at the end of the zip file, returning where they say
+/// the central directory is, and where it actually ends. Returns `None` for
+/// zip files split across several disks, or whose Zip64 records can't be
+/// found.
+fn read_end_records(
+    mut reader: impl Read + Seek,
+) -> Result<Option<(CentralDirectoryLocation, u64)>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let tail_len = len.min((END_OF_CENTRAL_DIRECTORY_LEN + MAX_COMMENT_LEN) as u64);
+    let tail_start = len - tail_len;
+    reader.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+    let Some(record_offset) = (0..=tail.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_LEN))
+        .rev()
+        .find(|offset| u32_at(&tail, *offset) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+    else {
+        bail!("Could not find the end of the central directory");
+    };
+    let record = &tail[record_offset..];
+    if u16_at(record, 4) != 0 || u16_at(record, 6) != 0 {
+        // Split across several disks.
+        return Ok(None);
+    }
+    let record_start = tail_start + record_offset as u64;
+    let mut location = CentralDirectoryLocation {
+        start: u32_at(record, 16) as u64,
+        len: u32_at(record, 12) as u64,
+        entry_count: u16_at(record, 10) as u64,
+    };
+    let mut directory_end = record_start;
+    if u32_at(record, 16) == ZIP64_PLACEHOLDER
+        || u32_at(record, 12) == ZIP64_PLACEHOLDER
+        || u16_at(record, 10) == 0xffff
+    {
+        let Some(locator_start) = record_start.checked_sub(ZIP64_LOCATOR_LEN as u64) else {
+            return Ok(None);
+        };
+        let mut locator = [0u8; ZIP64_LOCATOR_LEN];
+        reader.seek(SeekFrom::Start(locator_start))?;
+        reader.read_exact(&mut locator)?;
+        if u32_at(&locator, 0) != ZIP64_LOCATOR_SIGNATURE {
+            return Ok(None);
+        }
+        let zip64_record_start = u64_at(&locator, 8).unwrap();
+        let mut zip64_record = [0u8; ZIP64_END_OF_CENTRAL_DIRECTORY_LEN];
+        reader.seek(SeekFrom::Start(zip64_record_start))?;
+        match reader.read_exact(&mut zip64_record) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        if u32_at(&zip64_record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+            return Ok(None);
+        }
+        location = CentralDirectoryLocation {
+            start: u64_at(&zip64_record, 48).unwrap(),
+            len: u64_at(&zip64_record, 40).unwrap(),
+            entry_count: u64_at(&zip64_record, 32).unwrap(),
+        };
+        directory_end = zip64_record_start;
+    }
+    Ok(Some((location, directory_end)))
+}
+
+/// Find the data of the extra field with the given ID.
+fn extra_field(mut extra_fields: &[u8], field_id: u16) -> Option<&[u8]> {
+    while extra_fields.len() >= 4 {
+        let id = u16_at(extra_fields, 0);
+        let len = u16_at(extra_fields, 2) as usize;
+        let data = extra_fields.get(4..4 + len)?;
+        if id == field_id {
+            return Some(data);
+        }
+        extra_fields = &extra_fields[4 + len..];
+    }
+    None
+}
+
+/// Find the data of the Zip64 extended information extra field.
+pub(crate) fn zip64_extra_field(extra_fields: &[u8]) -> Option<&[u8]> {
+    extra_field(extra_fields, ZIP64_EXTRA_FIELD_ID)
+}
+
+/// The times recorded in an NTFS extra field, which Windows tools add.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct NtfsTimes {
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) accessed: Option<SystemTime>,
+    pub(crate) created: Option<SystemTime>,
+}
+
+/// Read the times from the NTFS extra field, if there is one. Its data is
+/// four reserved bytes, then tagged attributes, of which only the first
+/// has been defined: the modification, access and creation times, as
+/// Windows `FILETIME`s.
+pub(crate) fn ntfs_times(extra_fields: &[u8]) -> Option<NtfsTimes> {
+    let mut attributes = extra_field(extra_fields, NTFS_EXTRA_FIELD_ID)?.get(4..)?;
+    while attributes.len() >= 4 {
+        let tag = u16_at(attributes, 0);
+        let len = u16_at(attributes, 2) as usize;
+        let data = attributes.get(4..4 + len)?;
+        if tag == NTFS_TIMES_TAG && len >= 24 {
+            let time_at = |offset| u64_at(data, offset).and_then(filetime_to_system_time);
+            return Some(NtfsTimes {
+                modified: time_at(0),
+                accessed: time_at(8),
+                created: time_at(16),
+            });
+        }
+        attributes = &attributes[4 + len..];
+    }
+    None
+}
+
+/// Convert a Windows `FILETIME`, a count of 100ns intervals since 1601.
+/// Zero means the time wasn't recorded.
+fn filetime_to_system_time(filetime: u64) -> Option<SystemTime> {
+    if filetime == 0 {
+        return None;
+    }
+    let since_1601 = Duration::new(filetime / 10_000_000, (filetime % 10_000_000) as u32 * 100);
+    SystemTime::UNIX_EPOCH
+        .checked_sub(Duration::from_secs(FILETIME_UNIX_EPOCH))?
+        .checked_add(since_1601)
+}
+
+/// Find the read-only and hidden attributes of each entry which was
+/// created on MS-DOS or Windows, by name. Entries with neither attribute
+/// set are omitted.
+#[cfg(any(windows, test))]
+pub(crate) fn dos_attributes(
+    entries: &[CentralDirectoryEntry],
+) -> std::collections::HashMap<String, u32> {
+    entries
+        .iter()
+        .filter(|entry| DOS_HOST_SYSTEMS.contains(&entry.host_system))
+        .map(|entry| {
+            let attributes =
+                entry.external_attributes & (FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN);
+            (entry.name.clone(), attributes)
+        })
+        .filter(|(_, attributes)| *attributes != 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+    use super::{
+        decode_name, dos_attributes, ntfs_times, read_central_directory, CP437_HIGH,
+        FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY, FLAG_UTF8,
+    };
+
+    /// The zip crate always records Unix as the host system, so patch the
+    /// central directory to look like it came from Windows.
+    fn make_dos_zip(entries: &[(&str, u32)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, _) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"data").unwrap();
+        }
+        let mut data = zip.finish().unwrap().into_inner();
+        let start = ZipArchive::new(Cursor::new(&data))
+            .unwrap()
+            .central_directory_start() as usize;
+        let mut offset = start;
+        for (_, attributes) in entries {
+            data[offset + 5] = 0;
+            data[offset + 38..offset + 42].copy_from_slice(&attributes.to_le_bytes());
+            let variable_len: usize = [28, 30, 32]
+                .iter()
+                .map(|field| u16::from_le_bytes([data[offset + field], data[offset + field + 1]]))
+                .map(usize::from)
+                .sum();
+            offset += 46 + variable_len;
+        }
+        data
+    }
+
+    #[test]
+    fn test_dos_attributes() {
+        let data = make_dos_zip(&[
+            ("readonly.txt", FILE_ATTRIBUTE_READONLY),
+            ("plain.txt", 0x20),
+            ("hidden.txt", FILE_ATTRIBUTE_HIDDEN | 0x20),
+        ]);
+        let entries = read_central_directory(&ZipArchive::new(Cursor::new(data)).unwrap()).unwrap();
+        let attributes = dos_attributes(&entries);
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(attributes["readonly.txt"], FILE_ATTRIBUTE_READONLY);
+        assert_eq!(attributes["hidden.txt"], FILE_ATTRIBUTE_HIDDEN);
+    }
+
+    #[test]
+    fn test_ntfs_times() {
+        // An unrelated extra field, then an NTFS one with an unknown
+        // attribute before the times, of which only the modification time
+        // was recorded.
+        let mut extra_fields = b"\x55\x54\x01\x00\x00\x0a\x00\x26\x00\0\0\0\0".to_vec();
+        extra_fields.extend_from_slice(b"\x02\x00\x02\x00xx\x01\x00\x18\x00");
+        extra_fields.extend_from_slice(&116_444_736_000_000_000u64.to_le_bytes());
+        extra_fields.extend_from_slice(&[0; 16]);
+        let times = ntfs_times(&extra_fields).unwrap();
+        assert_eq!(times.modified, Some(std::time::SystemTime::UNIX_EPOCH));
+        assert_eq!(times.accessed, None);
+        assert_eq!(times.created, None);
+        assert_eq!(ntfs_times(b"\x55\x54\x01\x00\x00"), None);
+    }
+
+    #[test]
+    fn test_decode_name() {
+        assert_eq!(CP437_HIGH.chars().count(), 128);
+        assert_eq!(decode_name(b"caf\x82/\xe1", 0), "café/ß");
+        assert_eq!(decode_name("café".as_bytes(), FLAG_UTF8), "café");
+        assert_eq!(decode_name(b"a\xffb", FLAG_UTF8), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn test_read_central_directory() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, len, large) in [("a", 10, false), ("b", 1000, true), ("c", 1, false)] {
+            let options = SimpleFileOptions::default().large_file(large);
+            zip.start_file(name, options).unwrap();
+            zip.write_all(&vec![b'x'; len]).unwrap();
+        }
+        let mut archive = ZipArchive::new(Cursor::new(zip.finish().unwrap().into_inner())).unwrap();
+        let entries = read_central_directory(&archive).unwrap();
+        assert_eq!(entries.len(), 3);
+        for (i, entry) in entries.iter().enumerate() {
+            let file = archive.by_index(i).unwrap();
+            assert_eq!(entry.name, file.name());
+            assert_eq!(entry.compressed_size, file.compressed_size());
+            assert_eq!(entry.header_offset, file.header_start());
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Files which are stored in the zip without compression can be copied in
+//! chunks by several threads at once, rather than streamed by one thread.
+//! This helps most with zip files of already-compressed media. Where the
+//! kernel can copy data between files itself, it needn't pass through our
+//! memory at all.
+
+#[cfg(not(target_os = "linux"))]
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::{fs::File, io::ErrorKind, time::Instant};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+#[cfg(not(target_os = "linux"))]
+use zip::ZipArchive;
+
+use super::{output_quota::OutputQuota, thread_tuner};
+
+/// How much of a file each thread copies at a time.
+pub(crate) const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Reads of the raw zip file data at any offset, from any thread.
+pub(crate) trait RawReader: Send + Sync {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+
+    /// Whether [`RawReader::copy_to_file`] is supported.
+    fn can_copy_to_file(&self) -> bool {
+        false
+    }
+
+    /// Copy `len` bytes from `offset` in the zip file to `out_offset` in
+    /// `out_file`, without the data passing through our memory.
+    fn copy_to_file(
+        &self,
+        _offset: u64,
+        _len: u64,
+        _out_file: &File,
+        _out_offset: u64,
+    ) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Reads the data underlying a zip archive, whose reader can be cheaply
+/// cloned for each read.
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct ArchiveRawReader<R: Read + Seek + Clone>(pub(crate) ZipArchive<R>);
+
+#[cfg(not(target_os = "linux"))]
+impl<R: Read + Seek + Clone + Send + Sync> RawReader for ArchiveRawReader<R> {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let mut reader = self.0.clone().into_inner();
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(buf)
+    }
+}
+
+/// Reads straight from a local zip file, which lets the kernel copy its
+/// data into the output files itself.
+#[cfg(target_os = "linux")]
+pub(crate) struct FileRawReader(pub(crate) Arc<File>);
+
+#[cfg(target_os = "linux")]
+impl RawReader for FileRawReader {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&*self.0, buf, offset)
+    }
+
+    fn can_copy_to_file(&self) -> bool {
+        true
+    }
+
+    fn copy_to_file(
+        &self,
+        offset: u64,
+        len: u64,
+        out_file: &File,
+        out_offset: u64,
+    ) -> std::io::Result<()> {
+        let (mut offset, mut out_offset) = (offset, out_offset);
+        let end = offset + len;
+        while offset < end {
+            let remaining = (end - offset).min(CHUNK_SIZE) as usize;
+            // Filesystems which support it may share the underlying
+            // blocks rather than copying them.
+            let result = rustix::fs::copy_file_range(
+                &*self.0,
+                Some(&mut offset),
+                out_file,
+                Some(&mut out_offset),
+                remaining,
+            );
+            match result {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(rustix::io::Errno::INTR) => {}
+                // Older kernels can't copy between filesystems, and some
+                // filesystems can't do it at all.
+                Err(
+                    rustix::io::Errno::XDEV
+                    | rustix::io::Errno::NOSYS
+                    | rustix::io::Errno::OPNOTSUPP
+                    | rustix::io::Errno::INVAL,
+                ) => {
+                    let mut buf = vec![0u8; (end - offset) as usize];
+                    self.read_exact_at(offset, &mut buf)?;
+                    return write_all_at(out_file, &buf, out_offset);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::write_all_at(file, buf, offset);
+    #[cfg(windows)]
+    {
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_write(file, buf, offset) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (file, buf, offset);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Copy `len` bytes of stored data, starting at `data_start` in the zip
+/// file, into `out_file` using several threads. Checks the data against
+/// the expected CRC, since nothing else will, unless the kernel copied it
+/// without us ever seeing it.
+pub(crate) fn copy_in_chunks(
+    raw_reader: &dyn RawReader,
+    data_start: u64,
+    len: u64,
+    expected_crc32: u32,
+    out_file: &File,
+    output_quota: Option<&OutputQuota>,
+    progress: impl Fn(u64) + Sync,
+) -> Result<()> {
+    let chunk_count = len.div_ceil(CHUNK_SIZE);
+    let zero_copy = raw_reader.can_copy_to_file();
+    let chunk_crcs: Vec<Option<crc32fast::Hasher>> = (0..chunk_count)
+        .into_par_iter()
+        .map(|chunk| {
+            let offset = chunk * CHUNK_SIZE;
+            let chunk_len = CHUNK_SIZE.min(len - offset);
+            if zero_copy {
+                if let Some(output_quota) = output_quota {
+                    output_quota.charge(chunk_len)?;
+                }
+                let started = Instant::now();
+                raw_reader
+                    .copy_to_file(data_start + offset, chunk_len, out_file, offset)
+                    .with_context(|| "Failed to copy file")?;
+                thread_tuner::record_write_time(started.elapsed());
+                progress(chunk_len);
+                return Ok(None);
+            }
+            let mut buf = vec![0u8; chunk_len as usize];
+            raw_reader
+                .read_exact_at(data_start + offset, &mut buf)
+                .with_context(|| "Failed to read from zip file")?;
+            if let Some(output_quota) = output_quota {
+                output_quota.charge(chunk_len)?;
+            }
+            let started = Instant::now();
+            write_all_at(out_file, &buf, offset).with_context(|| "Failed to write file")?;
+            thread_tuner::record_write_time(started.elapsed());
+            progress(chunk_len);
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&buf);
+            Ok(Some(hasher))
+        })
+        .collect::<Result<_>>()?;
+    if zero_copy {
+        return Ok(());
+    }
+    let mut crc = crc32fast::Hasher::new();
+    for chunk_crc in chunk_crcs.iter().flatten() {
+        crc.combine(chunk_crc);
+    }
+    if crc.finalize() != expected_crc32 {
+        // The same error as the zip crate gives.
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "Invalid checksum").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use tempfile::tempfile;
+
+    use super::{copy_in_chunks, RawReader, CHUNK_SIZE};
+
+    impl RawReader for Vec<u8> {
+        fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self[offset..offset + buf.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_in_chunks() {
+        let len = CHUNK_SIZE * 5 / 2;
+        let data: Vec<u8> = (0..len + 100).map(|i| (i % 251) as u8).collect();
+        let contents = &data[100..];
+        let crc = crc32fast::hash(contents);
+        let mut out_file = tempfile().unwrap();
+        let progress = AtomicU64::new(0);
+        copy_in_chunks(&data, 100, len, crc, &out_file, None, |bytes| {
+            progress.fetch_add(bytes, Ordering::Relaxed);
+        })
+        .unwrap();
+        assert_eq!(progress.into_inner(), len);
+        let mut written = Vec::new();
+        out_file.seek(SeekFrom::Start(0)).unwrap();
+        out_file.read_to_end(&mut written).unwrap();
+        assert_eq!(written, contents);
+
+        let out_file = tempfile().unwrap();
+        assert!(copy_in_chunks(&data, 100, len, crc ^ 1, &out_file, None, |_| {}).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_in_chunks_within_kernel() {
+        use std::{io::Write, sync::Arc};
+
+        use super::FileRawReader;
+
+        let len = CHUNK_SIZE + 1000;
+        let data: Vec<u8> = (0..len + 100).map(|i| (i % 251) as u8).collect();
+        let mut zip_file = tempfile().unwrap();
+        zip_file.write_all(&data).unwrap();
+        let raw_reader = FileRawReader(Arc::new(zip_file));
+        let mut out_file = tempfile().unwrap();
+        copy_in_chunks(&raw_reader, 100, len, 0, &out_file, None, |_| {}).unwrap();
+        let mut written = Vec::new();
+        out_file.seek(SeekFrom::Start(0)).unwrap();
+        out_file.read_to_end(&mut written).unwrap();
+        assert_eq!(written, &data[100..]);
+    }
+}
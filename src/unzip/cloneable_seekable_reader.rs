@@ -8,11 +8,75 @@
 
 use std::{
     io::{Read, Seek, SeekFrom},
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 
+#[cfg(any(test, not(any(unix, windows))))]
+use std::sync::Mutex;
+
+#[cfg(any(test, not(any(unix, windows))))]
 use super::determine_stream_len;
 
+/// A source of data which can be read at any offset, by several threads at
+/// once.
+pub(crate) trait ReadAt {
+    /// Read into the given buffer, starting at the given offset in the data stream.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+    /// Get the length of the data stream. This is assumed to be constant.
+    fn len(&self) -> std::io::Result<u64>;
+}
+
+/// Files can be read using positioned reads (`pread` on Unix), which don't
+/// affect any shared file position and so need no locking.
+#[cfg(any(unix, windows))]
+impl ReadAt for std::fs::File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        return std::os::unix::fs::FileExt::read_at(self, buf, offset);
+        // This moves the file position, but we never use that.
+        #[cfg(windows)]
+        return std::os::windows::fs::FileExt::seek_read(self, buf, offset);
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl<R: ReadAt> ReadAt for &R {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        (*self).read_at(offset, buf)
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        (*self).len()
+    }
+}
+
+/// The first `len` bytes of some data, as if that were all there is.
+pub(crate) struct Truncated<R: ReadAt> {
+    inner: R,
+    len: u64,
+}
+
+impl<R: ReadAt> Truncated<R> {
+    pub(crate) fn new(inner: R, len: u64) -> Self {
+        Self { inner, len }
+    }
+}
+
+impl<R: ReadAt> ReadAt for Truncated<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.len.saturating_sub(offset).min(buf.len() as u64) as usize;
+        self.inner.read_at(offset, &mut buf[..available])
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+#[cfg(any(test, not(any(unix, windows))))]
 struct Inner<R: Read + Seek> {
     /// The underlying Read implementation.
     r: R,
@@ -22,6 +86,7 @@ struct Inner<R: Read + Seek> {
     len: Option<u64>,
 }
 
+#[cfg(any(test, not(any(unix, windows))))]
 impl<R: Read + Seek> Inner<R> {
     fn new(r: R) -> Self {
         Self {
@@ -57,18 +122,42 @@ impl<R: Read + Seek> Inner<R> {
     }
 }
 
+/// Adapts any [`Read`] + [`Seek`] to be a [`ReadAt`], by taking a lock
+/// and seeking the underlying stream prior to each read. This is only
+/// needed on platforms without positioned reads.
+#[cfg(any(test, not(any(unix, windows))))]
+pub(crate) struct LockedReader<R: Read + Seek>(Mutex<Inner<R>>);
+
+#[cfg(any(test, not(any(unix, windows))))]
+impl<R: Read + Seek> LockedReader<R> {
+    pub(crate) fn new(r: R) -> Self {
+        Self(Mutex::new(Inner::new(r)))
+    }
+}
+
+#[cfg(any(test, not(any(unix, windows))))]
+impl<R: Read + Seek> ReadAt for LockedReader<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().read_at(offset, buf)
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        self.0.lock().unwrap().len()
+    }
+}
+
 /// A [`Read`] which refers to its underlying stream by reference count,
 /// and thus can be cloned cheaply. It supports seeking; each cloned instance
-/// maintains its own pointer into the file, and the underlying instance
-/// is seeked prior to each read.
-pub(crate) struct CloneableSeekableReader<R: Read + Seek> {
-    /// The wrapper around the Read implementation, shared between threads.
-    inner: Arc<Mutex<Inner<R>>>,
+/// maintains its own pointer into the file, and reads from the underlying
+/// instance at that position.
+pub(crate) struct CloneableSeekableReader<R: ReadAt> {
+    /// The underlying data, shared between threads.
+    inner: Arc<R>,
     /// The position of _this_ reader.
     pos: u64,
 }
 
-impl<R: Read + Seek> Clone for CloneableSeekableReader<R> {
+impl<R: ReadAt> Clone for CloneableSeekableReader<R> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -77,24 +166,23 @@ impl<R: Read + Seek> Clone for CloneableSeekableReader<R> {
     }
 }
 
-impl<R: Read + Seek> CloneableSeekableReader<R> {
-    /// Constructor. Takes ownership of the underlying `Read`.
+impl<R: ReadAt> CloneableSeekableReader<R> {
+    /// Constructor. Takes ownership of the underlying `ReadAt`.
     /// You should pass in only streams whose total length you expect
     /// to be fixed and unchanging. Odd behavior may occur if the length
     /// of the stream changes; any subsequent seeks will not take account
     /// of the changed stream length.
     pub(crate) fn new(r: R) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(Inner::new(r))),
+            inner: Arc::new(r),
             pos: 0u64,
         }
     }
 }
 
-impl<R: Read + Seek> Read for CloneableSeekableReader<R> {
+impl<R: ReadAt> Read for CloneableSeekableReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut inner = self.inner.lock().unwrap();
-        let read_result = inner.read_at(self.pos, buf);
+        let read_result = self.inner.read_at(self.pos, buf);
         if let Ok(bytes_read) = read_result {
             self.pos = self
                 .pos
@@ -108,12 +196,12 @@ impl<R: Read + Seek> Read for CloneableSeekableReader<R> {
     }
 }
 
-impl<R: Read + Seek> Seek for CloneableSeekableReader<R> {
+impl<R: ReadAt> Seek for CloneableSeekableReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         let new_pos = match pos {
             SeekFrom::Start(pos) => pos,
             SeekFrom::End(offset_from_end) => {
-                let file_len = self.inner.lock().unwrap().len()?;
+                let file_len = self.inner.len()?;
                 if -offset_from_end as u64 > file_len {
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidInput,
@@ -142,15 +230,15 @@ impl<R: Read + Seek> Seek for CloneableSeekableReader<R> {
 
 #[cfg(test)]
 mod test {
-    use super::CloneableSeekableReader;
-    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use super::{CloneableSeekableReader, LockedReader, Truncated};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
     use test_log::test;
 
     #[test]
     fn test_cloneable_seekable_reader() -> std::io::Result<()> {
         let buf: Vec<u8> = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
         let buf = Cursor::new(buf);
-        let mut reader = CloneableSeekableReader::new(buf);
+        let mut reader = CloneableSeekableReader::new(LockedReader::new(buf));
         let mut out = vec![0; 2];
         reader.read_exact(&mut out)?;
         assert_eq!(&out, &[0, 1]);
@@ -171,7 +259,7 @@ mod test {
     fn test_cloned_independent_positions() -> std::io::Result<()> {
         let buf: Vec<u8> = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
         let buf = Cursor::new(buf);
-        let mut r1 = CloneableSeekableReader::new(buf);
+        let mut r1 = CloneableSeekableReader::new(LockedReader::new(buf));
         let mut r2 = r1.clone();
         let mut out = vec![0; 2];
         r1.read_exact(&mut out)?;
@@ -187,4 +275,34 @@ mod test {
         assert_eq!(&out, &[4, 5]);
         Ok(())
     }
+
+    #[test]
+    fn test_truncated() -> std::io::Result<()> {
+        let buf = Cursor::new(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let mut reader = CloneableSeekableReader::new(Truncated::new(LockedReader::new(buf), 5));
+        let mut out = vec![0; 2];
+        reader.seek(SeekFrom::End(-2))?;
+        reader.read_exact(&mut out)?;
+        assert_eq!(&out, &[3, 4]);
+        assert_eq!(reader.read(&mut out)?, 0);
+        Ok(())
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn test_positioned_file_reads() -> std::io::Result<()> {
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9])?;
+        let mut r1 = CloneableSeekableReader::new(file);
+        let mut r2 = r1.clone();
+        let mut out = vec![0; 2];
+        r1.read_exact(&mut out)?;
+        assert_eq!(&out, &[0, 1]);
+        r2.seek(SeekFrom::End(-2))?;
+        r2.read_exact(&mut out)?;
+        assert_eq!(&out, &[8, 9]);
+        r1.read_exact(&mut out)?;
+        assert_eq!(&out, &[2, 3]);
+        Ok(())
+    }
 }
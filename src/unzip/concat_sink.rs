@@ -0,0 +1,65 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use super::sink::{EntryMetadata, OutputSink};
+
+/// An [`OutputSink`] which writes the contents of the extracted files one
+/// after another, leaving out directories. Files are written in the order
+/// they're extracted, which is only the order they're in the archive when
+/// unzipping on a single thread.
+pub struct ConcatSink<W: Write + Send> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> ConcatSink<W> {
+    /// Create a sink which writes the files' contents to the given `Write`.
+    pub fn new(w: W) -> Self {
+        Self {
+            writer: Mutex::new(w),
+        }
+    }
+
+    /// Return the underlying `Write`.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + Send> OutputSink for ConcatSink<W> {
+    fn create_directory(&self, _path: &Path, _metadata: &EntryMetadata) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_file(
+        &self,
+        _path: &Path,
+        _metadata: &EntryMetadata,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        // Holding the lock throughout keeps files from being interleaved.
+        let mut writer = self.writer.lock().unwrap();
+        std::io::copy(contents, &mut *writer).with_context(|| "Failed to write out file")?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .flush()
+            .with_context(|| "Failed to flush output")
+    }
+}
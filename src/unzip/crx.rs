@@ -0,0 +1,126 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Chrome extensions are zip files with a CRX header in front, which holds
+//! the keys and signatures used to check them. The zip crate finds the zip
+//! file after it, but it has to be skipped over when the zip file is read
+//! from a stream.
+
+use std::io::{BufRead, Read};
+
+use anyhow::{bail, Result};
+
+use super::central_directory::u32_at;
+
+const CRX_MAGIC: &[u8] = b"Cr24";
+
+/// The header of a Chrome extension, which comes before its zip file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrxHeader {
+    /// A CRX2 header, with the extension's public key, and its signature of
+    /// the zip file.
+    V2 {
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    /// A CRX3 header, with the serialized `CrxFileHeader` protocol buffer,
+    /// which holds the public keys and signatures.
+    V3 { header: Vec<u8> },
+}
+
+impl CrxHeader {
+    /// How far into the file the zip file starts.
+    pub fn zip_offset(&self) -> u64 {
+        let fields_len = match self {
+            Self::V2 {
+                public_key,
+                signature,
+            } => 16 + public_key.len() + signature.len(),
+            Self::V3 { header } => 12 + header.len(),
+        };
+        fields_len as u64
+    }
+}
+
+/// Read the CRX header at the start of `reader`, leaving it at the start of
+/// the zip file. If there isn't one, nothing is read.
+pub(crate) fn read_crx_header(reader: &mut impl BufRead) -> Result<Option<CrxHeader>> {
+    if !reader.fill_buf()?.starts_with(CRX_MAGIC) {
+        return Ok(None);
+    }
+    let mut fields = [0u8; 8];
+    reader.read_exact(&mut fields)?;
+    let version = u32_at(&fields, 4);
+    let mut read_field = |len: u32| -> Result<Vec<u8>> {
+        let mut field = Vec::new();
+        reader.by_ref().take(len as u64).read_to_end(&mut field)?;
+        if field.len() != len as usize {
+            bail!("The CRX header is truncated");
+        }
+        Ok(field)
+    };
+    let header = match version {
+        2 => {
+            let lens = read_field(8)?;
+            CrxHeader::V2 {
+                public_key: read_field(u32_at(&lens, 0))?,
+                signature: read_field(u32_at(&lens, 4))?,
+            }
+        }
+        3 => {
+            let len = u32_at(&read_field(4)?, 0);
+            CrxHeader::V3 {
+                header: read_field(len)?,
+            }
+        }
+        _ => bail!("Unsupported CRX version {version}"),
+    };
+    Ok(Some(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor};
+
+    use super::{read_crx_header, CrxHeader};
+
+    #[test]
+    fn test_read_crx_header() {
+        let crx2 = b"Cr24\x02\x00\x00\x00\x03\x00\x00\x00\x02\x00\x00\x00keysgPK";
+        let mut reader = Cursor::new(crx2);
+        let header = read_crx_header(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            header,
+            CrxHeader::V2 {
+                public_key: b"key".to_vec(),
+                signature: b"sg".to_vec(),
+            }
+        );
+        assert_eq!(header.zip_offset(), 21);
+        assert_eq!(reader.fill_buf().unwrap(), b"PK");
+
+        let crx3 = b"Cr24\x03\x00\x00\x00\x05\x00\x00\x00protoPK";
+        let mut reader = Cursor::new(crx3);
+        let header = read_crx_header(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            header,
+            CrxHeader::V3 {
+                header: b"proto".to_vec()
+            }
+        );
+        assert_eq!(header.zip_offset(), 17);
+        assert_eq!(reader.fill_buf().unwrap(), b"PK");
+
+        let mut reader = Cursor::new(b"PK\x03\x04");
+        assert!(read_crx_header(&mut reader).unwrap().is_none());
+        assert_eq!(reader.fill_buf().unwrap(), b"PK\x03\x04");
+
+        let mut reader = Cursor::new(b"Cr24\x03\x00\x00\x00\x05\x00\x00\x00pro");
+        assert!(read_crx_header(&mut reader).is_err());
+    }
+}
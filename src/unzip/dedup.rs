@@ -0,0 +1,144 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Support for hardlinking files in the zip which have identical contents,
+//! rather than writing out each copy.
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use cap_std::fs::Dir;
+
+use super::buffer_pool::PooledBuffer;
+
+/// Files with the same CRC, size and permissions are candidates to be
+/// hardlinked together, though their contents must still be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct DedupKey {
+    pub(crate) crc32: u32,
+    pub(crate) size: u64,
+    pub(crate) unix_mode: Option<u32>,
+}
+
+/// The files which have been extracted so far which later ones may be
+/// hardlinked to.
+#[derive(Default)]
+pub(crate) struct Deduplicator(Mutex<HashMap<DedupKey, PathBuf>>);
+
+impl Deduplicator {
+    /// A completely extracted file which might be identical to a file with
+    /// the given key.
+    pub(crate) fn original_for(&self, key: &DedupKey) -> Option<PathBuf> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    /// Note that a file has been completely extracted, so later files may
+    /// be hardlinked to it. The first file recorded for each key is used.
+    pub(crate) fn record(&self, key: DedupKey, path: &Path) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| path.to_path_buf());
+    }
+}
+
+/// Compare the contents of `candidate` with those of `original`. Returns
+/// `None` if they're identical, with `candidate` fully consumed. Otherwise,
+/// returns a reader which yields the whole of `candidate`'s contents,
+/// including the part which has already been read for comparison.
+pub(crate) fn compare_contents<'a, O: Read + Seek + 'a, C: Read + 'a>(
+    mut original: O,
+    mut candidate: C,
+) -> std::io::Result<Option<impl Read + 'a>> {
+    let mut candidate_buf = PooledBuffer::take();
+    let mut original_buf = PooledBuffer::take();
+    let mut matched: u64 = 0;
+    loop {
+        let bytes_read = read_fully(&mut candidate, &mut candidate_buf)?;
+        let original_bytes_read = read_fully(&mut original, &mut original_buf)?;
+        if bytes_read == 0 && original_bytes_read == 0 {
+            return Ok(None);
+        }
+        if bytes_read != original_bytes_read
+            || candidate_buf[..bytes_read] != original_buf[..bytes_read]
+        {
+            // Everything before this chunk matched, so it can be read back
+            // from the original.
+            let candidate_chunk = candidate_buf[..bytes_read].to_vec();
+            original.seek(SeekFrom::Start(0))?;
+            return Ok(Some(
+                original
+                    .take(matched)
+                    .chain(Cursor::new(candidate_chunk))
+                    .chain(candidate),
+            ));
+        }
+        matched += bytes_read as u64;
+    }
+}
+
+/// Replace any existing file at `name` with a hardlink to `original`.
+pub(crate) fn link_duplicate(dir: &Dir, original: &Path, name: &Path) -> Result<()> {
+    match dir.remove_file(name) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| "Failed to remove existing file");
+        }
+        _ => {}
+    }
+    dir.hard_link(original, dir, name)
+        .with_context(|| "Failed to create hardlink")
+}
+
+/// Read until `buf` is full or the reader is exhausted.
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::compare_contents;
+    use crate::unzip::buffer_pool::BUFFER_SIZE;
+
+    fn compare(original: &[u8], candidate: &[u8]) -> Option<Vec<u8>> {
+        compare_contents(Cursor::new(original), candidate)
+            .unwrap()
+            .map(|mut reader| {
+                let mut contents = Vec::new();
+                reader.read_to_end(&mut contents).unwrap();
+                contents
+            })
+    }
+
+    #[test]
+    fn test_compare_contents() {
+        let data: Vec<u8> = (0..BUFFER_SIZE * 3).map(|i| i as u8).collect();
+        assert_eq!(compare(&data, &data), None);
+        assert_eq!(compare(b"", b""), None);
+
+        let mut different = data.clone();
+        different[BUFFER_SIZE * 2 + 10] = 0xff;
+        assert_eq!(compare(&data, &different), Some(different.clone()));
+        assert_eq!(compare(&data, &data[..100]), Some(data[..100].to_vec()));
+        assert_eq!(compare(&data[..100], &data), Some(data.clone()));
+    }
+}
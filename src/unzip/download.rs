@@ -0,0 +1,234 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Downloading whole zip files from servers which can't send parts of
+//! them. If a download is interrupted, it's resumed from where it stopped,
+//! as servers which don't advertise support for ranges often honour them
+//! anyway.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use reqwest::{
+    blocking::{Client, Response},
+    header, StatusCode,
+};
+
+use super::http_range_reader::is_identity_encoded;
+
+/// How many times to try resuming an interrupted download before giving up.
+const RESUME_ATTEMPTS: usize = 3;
+
+/// Download `uri` to `path`. It's written to the same path with `.part`
+/// appended, and only renamed once it's all been downloaded, so if that's
+/// left over from an interrupted download, it's resumed. Which version of
+/// the resource it holds is kept alongside in a `.part.validator` file, and
+/// without that it's started again.
+pub(crate) fn download_to(uri: &str, path: &Path, etag: &mut Option<String>) -> Result<File> {
+    let part = with_suffix(path, ".part");
+    let validator_path = with_suffix(&part, ".validator");
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part)?;
+    let mut validator = std::fs::read_to_string(&validator_path).ok();
+    download_resuming(
+        uri,
+        &mut file,
+        etag,
+        &mut validator,
+        |validator| match validator {
+            Some(validator) => std::fs::write(&validator_path, validator),
+            None => remove_if_present(&validator_path),
+        },
+    )?;
+    std::fs::rename(&part, path)?;
+    remove_if_present(&validator_path)?;
+    log::info!("Keeping the zip file at {}", path.display());
+    Ok(file)
+}
+
+/// Download `uri` to `file`, which should be empty. `etag` is set to the
+/// resource's ETag, if the server gives one.
+pub(crate) fn download(uri: &str, file: &mut File, etag: &mut Option<String>) -> Result<()> {
+    download_resuming(uri, file, etag, &mut None, |_| Ok(()))
+}
+
+/// Download `uri` to the end of `file`. It's resumed from the file's current
+/// length if `validator` says which version of the resource the file holds
+/// and the server agrees it's still that one; otherwise it's started again,
+/// and the new validator is passed to `save_validator` before anything's
+/// written. `etag` is set to the resource's ETag, if the server gives one.
+fn download_resuming(
+    uri: &str,
+    file: &mut File,
+    etag: &mut Option<String>,
+    validator: &mut Option<String>,
+    mut save_validator: impl FnMut(Option<&str>) -> std::io::Result<()>,
+) -> Result<()> {
+    let client = Client::new();
+    let mut attempts = 0;
+    loop {
+        if validator.is_none() {
+            file.set_len(0)?;
+        }
+        let offset = file.seek(SeekFrom::End(0))?;
+        let response = request(&client, uri, offset, validator.as_deref())?;
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            if matches!(content_range(&response), Some((None, Some(total))) if total == offset) {
+                // There's nothing after what's already been downloaded.
+                return Ok(());
+            }
+            log::info!("The partial download doesn't match the resource, so starting again");
+            *validator = None;
+            continue;
+        }
+        let response = response.error_for_status()?;
+        if let Some(new_etag) = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+        {
+            *etag = Some(new_etag.to_string());
+        }
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            let resumes_here = match content_range(&response) {
+                Some((Some(range), total)) => {
+                    range.start == offset && total.map_or(true, |total| total == range.end)
+                }
+                _ => false,
+            };
+            if !resumes_here || !is_identity_encoded(&response) {
+                if offset == 0 {
+                    bail!("The server sent only part of the zip file");
+                }
+                log::info!("The server sent the wrong part of the download, so starting again");
+                *validator = None;
+                continue;
+            }
+            log::info!("Resuming download at {offset} bytes");
+        } else {
+            if offset > 0 {
+                log::info!("The server can't resume the download, so starting again");
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+            }
+            *validator = validator_of(&response);
+            save_validator(validator.as_deref())?;
+        }
+        match std::io::copy(&mut decoded_body(response)?, file) {
+            Ok(_) => return Ok(()),
+            Err(error) if attempts < RESUME_ATTEMPTS => {
+                attempts += 1;
+                log::warn!("Download interrupted ({error}), resuming");
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn remove_if_present(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// What identifies the version of the resource in `response`, for use in
+/// `If-Range` when resuming: its ETag if that's strong, or else when it was
+/// last modified.
+fn validator_of(response: &Response) -> Option<String> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    header(header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(header::LAST_MODIFIED))
+}
+
+/// The range sent in `response`, if any, and the total length of the
+/// resource, if known, from its `Content-Range` header.
+fn content_range(response: &Response) -> Option<(Option<Range<u64>>, Option<u64>)> {
+    let content_range = response.headers().get(header::CONTENT_RANGE)?;
+    let (range, total) = content_range
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('/')?;
+    let range = match range {
+        "*" => None,
+        range => {
+            let (first, last) = range.split_once('-')?;
+            Some(first.parse().ok()?..last.parse::<u64>().ok()? + 1)
+        }
+    };
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((range, total))
+}
+
+/// Ask for `uri` from `offset` onwards. Resumed downloads must be of the
+/// same version of the resource, and unencoded, so that offsets into it are
+/// offsets into the zip file.
+fn request(client: &Client, uri: &str, offset: u64, validator: Option<&str>) -> Result<Response> {
+    let mut builder = client.get(uri);
+    if offset > 0 {
+        builder = builder
+            .header(header::RANGE, format!("bytes={offset}-"))
+            .header(header::ACCEPT_ENCODING, "identity");
+        if let Some(validator) = validator {
+            builder = builder.header(header::IF_RANGE, validator);
+        }
+    }
+    Ok(builder.send()?)
+}
+
+/// The body of `response`, with any `Content-Encoding` the server applied
+/// for transport undone.
+fn decoded_body(response: Response) -> Result<Box<dyn Read>> {
+    let encodings: Vec<String> = response
+        .headers()
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|encoding| encoding.to_str().ok())
+        .flat_map(|encoding| encoding.split(','))
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect();
+    let mut body: Box<dyn Read> = Box::new(response);
+    // Encodings are listed in the order they were applied.
+    for encoding in encodings.iter().rev() {
+        log::info!("Decoding {encoding} Content-Encoding");
+        body = match encoding.as_str() {
+            "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(body)),
+            "deflate" => Box::new(flate2::read::ZlibDecoder::new(body)),
+            "zstd" => Box::new(zstd::stream::read::Decoder::new(body)?),
+            _ => bail!("The server sent the zip file with unsupported Content-Encoding {encoding}"),
+        };
+    }
+    Ok(body)
+}
@@ -0,0 +1,90 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::{Cursor, Read, Seek},
+    sync::mpsc::{sync_channel, Receiver},
+};
+
+use anyhow::{anyhow, Result};
+use zip::ZipArchive;
+
+/// How much of an entry to decompress at a time.
+const CHUNK_LEN: usize = 256 * 1024;
+/// How many chunks to decompress ahead of them being read.
+const CHUNKS_AHEAD: usize = 4;
+
+/// Reads one entry of a zip file, which is decompressed on another thread
+/// as it's read. Zip files from URIs are fetched as needed.
+pub struct EntryReader {
+    chunks: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl EntryReader {
+    /// Start reading the entry called `name` in `archive`. If `limit` is
+    /// given, no more than that many bytes of it are decompressed.
+    pub(crate) fn open<R: Read + Seek + Clone + Send + 'static>(
+        archive: &ZipArchive<R>,
+        name: &str,
+        limit: Option<u64>,
+    ) -> Result<Self> {
+        let index = archive
+            .index_for_name(name)
+            .ok_or_else(|| anyhow!("{name} isn't in the zip file"))?;
+        let mut archive = archive.clone();
+        let (sender, receiver) = sync_channel(CHUNKS_AHEAD);
+        std::thread::spawn(move || {
+            let mut file = match archive.by_index(index) {
+                Ok(file) => file,
+                Err(error) => {
+                    let _ = sender.send(Err(error.into()));
+                    return;
+                }
+            };
+            let mut remaining = limit.unwrap_or(u64::MAX);
+            while remaining > 0 {
+                let mut chunk = vec![0; remaining.min(CHUNK_LEN as u64) as usize];
+                let chunk = match file.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(len) => {
+                        remaining -= len as u64;
+                        chunk.truncate(len);
+                        Ok(chunk)
+                    }
+                    Err(error) => Err(error),
+                };
+                let failed = chunk.is_err();
+                // Stop if the reader's been dropped.
+                if sender.send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            chunks: receiver,
+            chunk: Cursor::default(),
+        })
+    }
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let len = self.chunk.read(buf)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                // The whole entry has been read.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
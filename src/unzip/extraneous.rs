@@ -0,0 +1,167 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use cap_std::fs::Dir;
+
+use super::FilenameFilter;
+
+/// Removes everything from an output directory which wasn't extracted from
+/// the zip file, so that the directory matches the zip file exactly.
+pub(crate) struct ExtraneousFileRemover<'a> {
+    /// The paths of the files and directories which were extracted.
+    expected: &'a HashSet<PathBuf>,
+    /// Only files which this filter selects are removed, so that extracting
+    /// some of the files from a zip file doesn't remove all the others.
+    filename_filter: Option<&'a (dyn FilenameFilter + Sync)>,
+    /// Directories from which something was removed.
+    changed_directories: Vec<PathBuf>,
+}
+
+impl<'a> ExtraneousFileRemover<'a> {
+    pub(crate) fn new(
+        expected: &'a HashSet<PathBuf>,
+        filename_filter: Option<&'a (dyn FilenameFilter + Sync)>,
+    ) -> Self {
+        Self {
+            expected,
+            filename_filter,
+            changed_directories: Vec::new(),
+        }
+    }
+
+    /// Remove extraneous files from `dir`. Returns the directories, relative
+    /// to `dir`, whose entries were changed.
+    pub(crate) fn remove_from(mut self, dir: &Dir) -> Result<Vec<PathBuf>> {
+        self.remove_from_subdirectory(dir, Path::new(""))?;
+        Ok(self.changed_directories)
+    }
+
+    /// Returns whether the directory is now empty, and whether anything was
+    /// removed from it.
+    fn remove_from_subdirectory(&mut self, dir: &Dir, path: &Path) -> Result<(bool, bool)> {
+        let mut is_empty = true;
+        let mut changed = false;
+        for entry in dir.entries()? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let entry_path = path.join(&file_name);
+            let is_expected = self.expected.contains(&entry_path);
+            // Symlinks are removed rather than followed.
+            if entry.file_type()?.is_dir() {
+                let subdir = dir.open_dir(&file_name)?;
+                let (subdir_is_empty, subdir_changed) =
+                    self.remove_from_subdirectory(&subdir, &entry_path)?;
+                // Directories emptied by removing extraneous files are
+                // extraneous too.
+                if subdir_is_empty && !is_expected && (subdir_changed || self.selected(&entry_path))
+                {
+                    log::info!("Removing extraneous directory {}", entry_path.display());
+                    dir.remove_dir(&file_name).with_context(|| {
+                        format!("Failed to remove directory {}", entry_path.display())
+                    })?;
+                    changed = true;
+                } else {
+                    is_empty = false;
+                }
+            } else if !is_expected && self.selected(&entry_path) {
+                log::info!("Removing extraneous file {}", entry_path.display());
+                dir.remove_file(&file_name)
+                    .with_context(|| format!("Failed to remove file {}", entry_path.display()))?;
+                changed = true;
+            } else {
+                is_empty = false;
+            }
+        }
+        if changed {
+            self.changed_directories.push(path.to_path_buf());
+        }
+        Ok((is_empty, changed))
+    }
+
+    fn selected(&self, path: &Path) -> bool {
+        let Some(filename_filter) = self.filename_filter else {
+            return true;
+        };
+        // Filters apply to names as they appear in the zip file.
+        let name = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        filename_filter.should_unzip(&name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, path::PathBuf};
+
+    use cap_std::{ambient_authority, fs::Dir};
+    use tempfile::tempdir;
+
+    use super::ExtraneousFileRemover;
+    use crate::FilenameFilter;
+
+    struct TxtFilter;
+    impl FilenameFilter for TxtFilter {
+        fn should_unzip(&self, filename: &str) -> bool {
+            filename.ends_with(".txt")
+        }
+    }
+
+    #[test]
+    fn test_remove_extraneous() {
+        let td = tempdir().unwrap();
+        for path in [
+            "a.txt",
+            "b.txt",
+            "b.dat",
+            "sub/c.txt",
+            "old/d.txt",
+            "keep/e.dat",
+        ] {
+            let path = td.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "x").unwrap();
+        }
+        std::fs::create_dir(td.path().join("empty")).unwrap();
+        let expected: HashSet<PathBuf> = ["a.txt", "sub", "sub/c.txt"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        let dir = Dir::open_ambient_dir(td.path(), ambient_authority()).unwrap();
+
+        let changed = ExtraneousFileRemover::new(&expected, Some(&TxtFilter))
+            .remove_from(&dir)
+            .unwrap();
+        assert!(!td.path().join("b.txt").exists());
+        assert!(!td.path().join("old").exists());
+        assert!(td.path().join("b.dat").exists());
+        assert!(td.path().join("keep/e.dat").exists());
+        // Directories never match this filter.
+        assert!(td.path().join("empty").exists());
+        assert!(changed.contains(&PathBuf::from("")));
+        assert!(changed.contains(&PathBuf::from("old")));
+
+        ExtraneousFileRemover::new(&expected, None)
+            .remove_from(&dir)
+            .unwrap();
+        for path in ["b.dat", "keep", "empty"] {
+            assert!(!td.path().join(path).exists());
+        }
+        for path in ["a.txt", "sub/c.txt"] {
+            assert!(td.path().join(path).exists());
+        }
+    }
+}
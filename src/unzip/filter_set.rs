@@ -0,0 +1,195 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use anyhow::Result;
+use regex::Regex;
+use wildmatch::WildMatch;
+
+use super::{FilenameFilter, ListedEntry, MetadataFilter};
+
+/// Which entries to extract or list, combining several kinds of filter. An
+/// entry is selected if:
+///
+/// 1. its name matches any include glob or regex, or there are none;
+/// 2. its name matches no exclude glob or regex, so excludes win over
+///    includes;
+/// 3. every custom filter accepts its name; and
+/// 4. the [`MetadataFilter`] selects it.
+///
+/// Globs may use `*` and `?` wildcards, and must match the whole name.
+/// Regexes need only match part of it, so may need anchoring with `^` and
+/// `$`. The default selects everything.
+#[derive(Default)]
+pub struct FilterSet<'a> {
+    include: Vec<WildMatch>,
+    include_regexes: Vec<Regex>,
+    exclude: Vec<WildMatch>,
+    exclude_regexes: Vec<Regex>,
+    custom: Vec<Box<dyn FilenameFilter + Sync + 'a>>,
+    metadata: MetadataFilter,
+}
+
+impl<'a> FilterSet<'a> {
+    /// A filter set which selects everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also select entries whose names match the glob `pattern`.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(WildMatch::new(pattern));
+        self
+    }
+
+    /// Also select entries whose names match `regex`.
+    pub fn include_regex(mut self, regex: &str) -> Result<Self> {
+        self.include_regexes.push(Regex::new(regex)?);
+        Ok(self)
+    }
+
+    /// Leave out entries whose names match the glob `pattern`.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(WildMatch::new(pattern));
+        self
+    }
+
+    /// Leave out entries whose names match `regex`.
+    pub fn exclude_regex(mut self, regex: &str) -> Result<Self> {
+        self.exclude_regexes.push(Regex::new(regex)?);
+        Ok(self)
+    }
+
+    /// Only select entries whose names `filter` accepts.
+    pub fn filter(mut self, filter: impl FilenameFilter + Sync + 'a) -> Self {
+        self.custom.push(Box::new(filter));
+        self
+    }
+
+    /// Only select entries for whose names `predicate` returns true.
+    pub fn filter_fn(self, predicate: impl Fn(&str) -> bool + Sync + 'a) -> Self {
+        self.filter(PredicateFilter(predicate))
+    }
+
+    /// Only select entries which `metadata` selects, replacing any
+    /// metadata filter given before.
+    pub fn metadata(mut self, metadata: MetadataFilter) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Whether this selects every entry whatever its name, so that entries'
+    /// names needn't be known before deciding to extract them.
+    pub fn selects_all_names(&self) -> bool {
+        self.include.is_empty()
+            && self.include_regexes.is_empty()
+            && self.exclude.is_empty()
+            && self.exclude_regexes.is_empty()
+            && self.custom.is_empty()
+    }
+
+    /// Whether this selects everything.
+    pub fn is_empty(&self) -> bool {
+        self.selects_all_names() && self.metadata.is_empty()
+    }
+
+    /// Whether the entry listed as `entry` is selected.
+    pub fn selects_listed(&self, entry: &ListedEntry) -> bool {
+        self.should_unzip(&entry.name) && self.metadata.selects_listed(entry)
+    }
+
+    /// The filter on entries' names, or `None` if every name is selected.
+    pub(crate) fn name_filter(&self) -> Option<&(dyn FilenameFilter + Sync + 'a)> {
+        (!self.selects_all_names()).then_some(self as _)
+    }
+
+    /// The filter on what the archive records about entries.
+    pub(crate) fn metadata_filter(&self) -> &MetadataFilter {
+        &self.metadata
+    }
+}
+
+impl FilenameFilter for FilterSet<'_> {
+    /// Whether the entry called `filename` is selected, ignoring the
+    /// metadata filter.
+    fn should_unzip(&self, filename: &str) -> bool {
+        let included = (self.include.is_empty() && self.include_regexes.is_empty())
+            || self.include.iter().any(|glob| glob.matches(filename))
+            || self
+                .include_regexes
+                .iter()
+                .any(|regex| regex.is_match(filename));
+        included
+            && !self.exclude.iter().any(|glob| glob.matches(filename))
+            && !self
+                .exclude_regexes
+                .iter()
+                .any(|regex| regex.is_match(filename))
+            && self
+                .custom
+                .iter()
+                .all(|filter| filter.should_unzip(filename))
+    }
+}
+
+struct PredicateFilter<F>(F);
+
+impl<F: Fn(&str) -> bool> FilenameFilter for PredicateFilter<F> {
+    fn should_unzip(&self, filename: &str) -> bool {
+        (self.0)(filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilterSet;
+    use crate::{EntryType, FilenameFilter, ListedEntry, MetadataFilter};
+
+    #[test]
+    fn test_precedence() {
+        let filters = FilterSet::new()
+            .include("src/*")
+            .include_regex(r"^docs/.*\.md$")
+            .unwrap()
+            .exclude("*.o")
+            .exclude_regex("(^|/)tmp/")
+            .unwrap()
+            .filter_fn(|name| !name.contains("secret"));
+        assert!(filters.should_unzip("src/a.c"));
+        assert!(filters.should_unzip("docs/a.md"));
+        assert!(!filters.should_unzip("docs/a.txt"));
+        assert!(!filters.should_unzip("README"));
+        assert!(!filters.should_unzip("src/a.o"));
+        assert!(!filters.should_unzip("src/tmp/a.c"));
+        assert!(!filters.should_unzip("src/secret.c"));
+        assert!(FilterSet::new().exclude("*.o").should_unzip("README"));
+        assert!(FilterSet::new().name_filter().is_none());
+        assert!(FilterSet::new().is_empty());
+    }
+
+    #[test]
+    fn test_selects_listed() {
+        let filters = FilterSet::new().include("*.txt").metadata(MetadataFilter {
+            min_size: Some(10),
+            ..Default::default()
+        });
+        assert!(filters.name_filter().is_some());
+        let entry = |name: &str, size| ListedEntry {
+            name: name.to_string(),
+            size,
+            compressed_size: None,
+            offset: None,
+            last_modified: None,
+            crc32: None,
+            compression_method: None,
+            entry_type: EntryType::File,
+        };
+        assert!(filters.selects_listed(&entry("a.txt", 10)));
+        assert!(!filters.selects_listed(&entry("a.txt", 9)));
+        assert!(!filters.selects_listed(&entry("a.md", 10)));
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The names given so far to files extracted directly into the output
+/// directory, rather than into subdirectories.
+#[derive(Default)]
+pub(crate) struct FlattenedNames(Mutex<HashSet<String>>);
+
+impl FlattenedNames {
+    /// The name to give the file at `path` in the output directory: its
+    /// filename, or if that's already been given to another file, one
+    /// numbered like `name-1.ext`. Names which differ only by case are
+    /// treated as the same if `case_insensitive`.
+    pub(crate) fn claim(&self, path: &Path, case_insensitive: bool) -> Option<PathBuf> {
+        let file_name = PathBuf::from(path.file_name()?);
+        let key = |name: &Path| {
+            let name = name.to_string_lossy();
+            if case_insensitive {
+                name.to_lowercase()
+            } else {
+                name.into_owned()
+            }
+        };
+        let mut claimed = self.0.lock().unwrap();
+        if claimed.insert(key(&file_name)) {
+            return Some(file_name);
+        }
+        let numbered = (1..)
+            .map(|n| numbered_name(&file_name, n))
+            .find(|numbered| !claimed.contains(&key(numbered)))
+            .unwrap();
+        claimed.insert(key(&numbered));
+        Some(numbered)
+    }
+}
+
+/// Return a name like `name-1.ext`.
+fn numbered_name(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => PathBuf::from(format!("{stem}-{n}.{}", extension.to_string_lossy())),
+        None => PathBuf::from(format!("{stem}-{n}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::FlattenedNames;
+
+    #[test]
+    fn test_claim() {
+        let names = FlattenedNames::default();
+        let claim =
+            |path: &str, case_insensitive| names.claim(Path::new(path), case_insensitive).unwrap();
+        assert_eq!(claim("a/readme.txt", false), PathBuf::from("readme.txt"));
+        assert_eq!(claim("b/readme.txt", false), PathBuf::from("readme-1.txt"));
+        assert_eq!(claim("c/readme.txt", false), PathBuf::from("readme-2.txt"));
+        assert_eq!(claim("c/README.txt", false), PathBuf::from("README.txt"));
+        assert_eq!(claim("d/Readme.txt", true), PathBuf::from("Readme-3.txt"));
+        assert_eq!(claim("a/Makefile", false), PathBuf::from("Makefile"));
+        assert_eq!(claim("b/Makefile", false), PathBuf::from("Makefile-1"));
+    }
+}
@@ -6,9 +6,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::{cmp::min, io::Read};
+use std::{
+    cmp::min,
+    io::Read,
+    time::{Duration, Instant},
+};
 
-use reqwest::blocking::{Client, Response};
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode,
+};
 use thiserror::Error;
 
 /// Errors that may be returned by a [`RangeFetcher`].
@@ -24,6 +31,8 @@ pub(crate) enum Error {
     HttpGet(#[source] reqwest::Error),
     #[error("Reading while fast-forwarding to desired location failed")]
     FastForward(#[source] std::io::Error),
+    #[error("HTTP server ignored the range requested, so doesn't support ranges after all")]
+    RangeIgnored,
 }
 
 /// An object which can fetch different ranges of a URI, using the HTTP
@@ -35,34 +44,114 @@ pub(crate) struct RangeFetcher {
     uri: String,
     accept_ranges: bool,
     content_length: u64,
+    /// The ETag the server gave for the resource, if any.
+    etag: Option<String>,
+    /// How long the server took to answer the initial HEAD request.
+    latency: Duration,
     client: Client,
 }
 
 impl RangeFetcher {
-    /// Create a new range fetcher for a given resource.
-    pub(crate) fn new(uri: String) -> Result<Self, Error> {
+    /// Create a new range fetcher for a given resource. If its length is
+    /// already known, the server isn't asked about it, and is assumed to
+    /// support ranges.
+    pub(crate) fn new(uri: String, content_length: Option<u64>) -> Result<Self, Error> {
         let client = reqwest::blocking::Client::new();
-        let response = client.head(&uri).send().map_err(Error::HttpHead)?;
+        if let Some(content_length) = content_length {
+            if content_length == 0 {
+                return Err(Error::EmptyContentLength);
+            }
+            return Ok(Self {
+                uri,
+                accept_ranges: true,
+                content_length,
+                etag: None,
+                latency: Duration::ZERO,
+                client,
+            });
+        }
+        let started = Instant::now();
+        let head = client
+            .head(&uri)
+            .header(reqwest::header::ACCEPT_ENCODING, "identity")
+            .send()
+            .and_then(Response::error_for_status);
+        let latency = started.elapsed();
+        if let Ok(response) = &head {
+            if let (Some(content_length), true) = (
+                content_length_via_headers(response),
+                supports_ranges(response),
+            ) {
+                return Self::with_length(uri, client, response, content_length, true, latency);
+            }
+        }
+        // Some servers reject HEAD requests, or only say that they support
+        // ranges, and how long the resource is, when asked for one.
+        let started = Instant::now();
+        let probe = client
+            .get(&uri)
+            .header(reqwest::header::ACCEPT_ENCODING, "identity")
+            .header(reqwest::header::RANGE, "bytes=0-0")
+            .send();
+        if let Ok(response) = probe {
+            if let (StatusCode::PARTIAL_CONTENT, Some(content_length), true) = (
+                response.status(),
+                content_length_via_content_range(&response),
+                is_identity_encoded(&response),
+            ) {
+                log::debug!("Found the length of the resource by requesting a range");
+                let latency = started.elapsed();
+                return Self::with_length(uri, client, &response, content_length, true, latency);
+            }
+        }
+        // Otherwise the resource can only be read from the start, which
+        // needs its length from the HEAD request.
+        let response = head.map_err(Error::HttpHead)?;
         let content_length = content_length_via_headers(&response).ok_or(Error::NoContentLength)?;
+        Self::with_length(uri, client, &response, content_length, false, latency)
+    }
+
+    fn with_length(
+        uri: String,
+        client: Client,
+        response: &Response,
+        content_length: u64,
+        accept_ranges: bool,
+        latency: Duration,
+    ) -> Result<Self, Error> {
         if content_length == 0 {
             return Err(Error::EmptyContentLength);
         }
-        let accept_ranges = response
+        let etag = response
             .headers()
-            .contains_key(reqwest::header::ACCEPT_RANGES);
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
         Ok(Self {
             uri,
             accept_ranges,
             content_length,
+            etag,
+            latency,
             client,
         })
     }
 
+    /// How long the server took to respond to its first request.
+    pub(crate) fn latency(&self) -> Duration {
+        self.latency
+    }
+
     /// Return the total length of the resource.
     pub(crate) fn len(&self) -> u64 {
         self.content_length
     }
 
+    /// The resource's ETag, if the server gave one.
+    pub(crate) fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
     /// Whether this resource supports fetch of specific ranges.
     pub(crate) fn accepts_ranges(&self) -> bool {
         self.accept_ranges
@@ -70,18 +159,25 @@ impl RangeFetcher {
 
     /// Return a [`Read`] for this resource starting from the given offset.
     /// If the resource supports HTTP ranges, this will start reading from
-    /// the server at that point; otherwise, it will read from the outset
-    /// of the resource but discard bytes before that point. (Clearly that
-    /// can be expensive if you only care about a few bytes later in a
-    /// resource.)
-    pub(crate) fn fetch_range(&self, offset: u64) -> Result<Response, Error> {
-        log::debug!("Fetch range 0x{:x}", offset);
-        let mut builder = self.client.get(&self.uri);
+    /// the server at that point, and ask for nothing beyond `end`;
+    /// otherwise, it will read from the outset of the resource but discard
+    /// bytes before that point. (Clearly that can be expensive if you only
+    /// care about a few bytes later in a resource.)
+    pub(crate) fn fetch_range_until(&self, offset: u64, end: u64) -> Result<Response, Error> {
+        log::debug!("Fetch range 0x{:x}-0x{:x}", offset, end);
+        let mut builder = self
+            .client
+            .get(&self.uri)
+            .header(reqwest::header::ACCEPT_ENCODING, "identity");
         if self.accept_ranges {
-            let range_header = format!("bytes={}-{}", offset, self.len());
+            let range_header = format!("bytes={}-{}", offset, end);
             builder = builder.header(reqwest::header::RANGE, range_header);
         }
         let mut response = builder.send().map_err(Error::HttpGet)?;
+        // Servers which don't support ranges send the whole resource.
+        if self.accept_ranges && offset > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::RangeIgnored);
+        }
         if !self.accept_ranges && offset > 0 {
             // Read and discard data prior to 'offset'
             let mut to_read = offset as usize;
@@ -107,6 +203,33 @@ fn content_length_via_headers(response: &Response) -> Option<u64> {
         .and_then(|s| s.parse::<u64>().ok())
 }
 
+/// The total length of the resource from the `Content-Range` header of a
+/// response to a range request.
+fn content_length_via_content_range(response: &Response) -> Option<u64> {
+    let content_range = response.headers().get(reqwest::header::CONTENT_RANGE)?;
+    let (_, length) = content_range.to_str().ok()?.rsplit_once('/')?;
+    length.parse().ok()
+}
+
+/// Whether the response says the server supports ranges. Offsets into an
+/// encoded resource aren't offsets into the zip file, so it has to be
+/// downloaded whole and decoded instead.
+fn supports_ranges(response: &Response) -> bool {
+    response
+        .headers()
+        .contains_key(reqwest::header::ACCEPT_RANGES)
+        && is_identity_encoded(response)
+}
+
+/// Whether the response has no `Content-Encoding` other than `identity`.
+pub(crate) fn is_identity_encoded(response: &Response) -> bool {
+    response
+        .headers()
+        .get_all(reqwest::header::CONTENT_ENCODING)
+        .iter()
+        .all(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"identity"))
+}
+
 #[cfg(test)]
 mod tests {
     use httptest::{matchers::*, responders::*, Expectation, Server};
@@ -137,8 +260,6 @@ mod tests {
                 )
         });
 
-        let range_fetcher = RangeFetcher::new(server.url("/foo").to_string()).unwrap();
-
         // Test reading the whole thing
         server.expect(if accept_ranges {
             Expectation::matching(any())
@@ -155,14 +276,21 @@ mod tests {
                 .times(..)
                 .respond_with(status_code(200).body(body))
         });
+        // Without a HEAD response saying ranges are supported, one is asked
+        // for to check.
+        let range_fetcher = RangeFetcher::new(server.url("/foo").to_string(), None).unwrap();
         assert_eq!(accept_ranges, range_fetcher.accepts_ranges());
-        let mut resp = range_fetcher.fetch_range(0u64).unwrap();
+        let mut resp = range_fetcher
+            .fetch_range_until(0, range_fetcher.len())
+            .unwrap();
         let mut throwaway = [0u8; 10];
         resp.read_exact(&mut throwaway).unwrap();
         assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "0123456789");
 
         // Test read only a range
-        let mut resp = range_fetcher.fetch_range(4u64).unwrap();
+        let mut resp = range_fetcher
+            .fetch_range_until(4, range_fetcher.len())
+            .unwrap();
         let mut throwaway = [0u8; 6];
         resp.read_exact(&mut throwaway).unwrap();
         assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "456789");
@@ -177,4 +305,35 @@ mod tests {
     fn test_without_accept_range() {
         do_test(false);
     }
+
+    #[test]
+    fn test_without_head() {
+        let server = Server::run();
+        let body = "0123456789";
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/foo"))
+                .times(1)
+                .respond_with(status_code(405)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/foo"))
+                .times(..)
+                .respond_with(RangeAwareResponse::new(
+                    206,
+                    RangeAwareResponseType::Body {
+                        body: hyper::body::Bytes::from(body),
+                        expected_range: None,
+                    },
+                )),
+        );
+        let range_fetcher = RangeFetcher::new(server.url("/foo").to_string(), None).unwrap();
+        assert!(range_fetcher.accepts_ranges());
+        assert_eq!(range_fetcher.len(), 10);
+        let mut resp = range_fetcher
+            .fetch_range_until(4, range_fetcher.len())
+            .unwrap();
+        let mut throwaway = [0u8; 6];
+        resp.read_exact(&mut throwaway).unwrap();
+        assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "456789");
+    }
 }
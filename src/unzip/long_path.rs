@@ -0,0 +1,92 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Windows limits paths to `MAX_PATH` characters unless they're in the
+//! `\\?\` "verbatim" form, or long path support has been enabled in the
+//! registry. Extracted files are created relative to a directory handle, so
+//! aren't subject to that limit, but staging directories are moved around
+//! using absolute paths. These functions convert paths to that form, and do
+//! nothing on other platforms.
+
+use std::path::{Path, PathBuf};
+
+/// Convert the path to verbatim form, so that paths created by joining
+/// further components to it can be of any length.
+pub(crate) fn verbatim_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    if let Ok(verbatim) = to_verbatim(path) {
+        return verbatim;
+    }
+    path.to_path_buf()
+}
+
+/// Verbatim paths are passed to the filesystem without any processing, so
+/// they must be absolute, and must not include `.` or `..`.
+#[cfg(windows)]
+fn to_verbatim(path: &Path) -> std::io::Result<PathBuf> {
+    use std::{
+        ffi::OsString,
+        path::{Component, Prefix},
+    };
+
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+    let mut components = absolute.components();
+    let mut verbatim = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut verbatim = OsString::from(r"\\?\UNC\");
+                verbatim.push(server);
+                verbatim.push(r"\");
+                verbatim.push(share);
+                PathBuf::from(verbatim)
+            }
+            // Already verbatim, or a device path which we shouldn't touch.
+            _ => return Ok(absolute),
+        },
+        _ => return Ok(absolute),
+    };
+    for component in components {
+        match component {
+            Component::RootDir => verbatim.push(r"\"),
+            Component::CurDir | Component::Prefix(_) => {}
+            Component::ParentDir => {
+                verbatim.pop();
+            }
+            Component::Normal(name) => verbatim.push(name),
+        }
+    }
+    Ok(verbatim)
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::verbatim_path;
+
+    #[test]
+    fn test_verbatim_path() {
+        assert_eq!(
+            verbatim_path(Path::new(r"C:\a\.\b\..\c/d")),
+            PathBuf::from(r"\\?\C:\a\c\d")
+        );
+        assert_eq!(
+            verbatim_path(Path::new(r"\\server\share\a")),
+            PathBuf::from(r"\\?\UNC\server\share\a")
+        );
+        assert_eq!(
+            verbatim_path(Path::new(r"\\?\C:\a")),
+            PathBuf::from(r"\\?\C:\a")
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checking extracted files against a list of their expected SHA-256
+//! digests, as written by `sha256sum`.
+
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, Context, Result};
+use cap_std::fs::Dir;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::{buffer_pool, ExtractionFailure, FilenameFilter, UnzipProgressReporter};
+
+/// The expected SHA-256 digest of each of some files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    digests: Vec<(String, [u8; 32])>,
+}
+
+impl Manifest {
+    /// Parse a manifest in the format written by `sha256sum`: a line for
+    /// each file, containing its digest in hex, then a space, then either
+    /// another space or a `*`, then its path.
+    pub fn parse(text: &str) -> Result<Self> {
+        let digests = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                parse_line(line).with_context(|| format!("Invalid manifest line {}", index + 1))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { digests })
+    }
+
+    /// How many files are listed.
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    /// Whether no files are listed.
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    /// Check each listed file in `dir` which `filename_filter` selects,
+    /// reporting those which are missing or different to `progress_reporter`.
+    pub(crate) fn check(
+        &self,
+        dir: &Dir,
+        filename_filter: Option<&(dyn FilenameFilter + Sync)>,
+        progress_reporter: &dyn UnzipProgressReporter,
+    ) -> Vec<anyhow::Error> {
+        self.digests
+            .par_iter()
+            .filter(|(path, _)| filename_filter.map_or(true, |filter| filter.should_unzip(path)))
+            .filter_map(|(path, expected)| {
+                let error = match sha256_of(dir, path) {
+                    Ok(actual) if actual == *expected => return None,
+                    Ok(actual) => anyhow::Error::from(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "SHA-256 is {} rather than {}",
+                            hex::encode(actual),
+                            hex::encode(expected)
+                        ),
+                    )),
+                    Err(e) => e.into(),
+                };
+                let error = error.context(format!("{path} doesn't match the manifest"));
+                progress_reporter.extraction_failed(&ExtractionFailure {
+                    name: path,
+                    header_offset: None,
+                    error: &error,
+                });
+                Some(error)
+            })
+            .collect()
+    }
+}
+
+fn parse_line(line: &str) -> Result<(String, [u8; 32])> {
+    let (digest, path) = line
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("expected a digest and a path"))?;
+    let path = path
+        .strip_prefix(' ')
+        .or_else(|| path.strip_prefix('*'))
+        .ok_or_else(|| anyhow!("expected two spaces, or a space and '*', after the digest"))?;
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(digest, &mut bytes).context("expected a SHA-256 digest")?;
+    let path = path.strip_prefix("./").unwrap_or(path);
+    Ok((path.to_string(), bytes))
+}
+
+fn sha256_of(dir: &Dir, path: &str) -> std::io::Result<[u8; 32]> {
+    let mut file = dir.open(path)?;
+    let mut hasher = Sha256::new();
+    buffer_pool::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std::{ambient_authority, fs::Dir};
+    use sha2::{Digest, Sha256};
+    use tempfile::tempdir;
+
+    use super::Manifest;
+    use crate::NullProgressReporter;
+
+    #[test]
+    fn test_manifest() {
+        let td = tempdir().unwrap();
+        std::fs::write(td.path().join("a.txt"), b"a").unwrap();
+        std::fs::write(td.path().join("b.txt"), b"changed").unwrap();
+        let digest = |data: &[u8]| hex::encode(Sha256::digest(data));
+        let manifest = Manifest::parse(&format!(
+            "{}  ./a.txt\n{} *b.txt\n\n{}  missing.txt\n",
+            digest(b"a"),
+            digest(b"b"),
+            digest(b"c")
+        ))
+        .unwrap();
+        assert_eq!(manifest.len(), 3);
+        let dir = Dir::open_ambient_dir(td.path(), ambient_authority()).unwrap();
+        let mut errors: Vec<String> = manifest
+            .check(&dir, None, &NullProgressReporter)
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "b.txt doesn't match the manifest",
+                "missing.txt doesn't match the manifest"
+            ]
+        );
+
+        assert!(Manifest::parse("abc  a.txt").is_err());
+        assert!(Manifest::parse(&digest(b"a")).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::ReadaheadWatermarks;
+
+/// Roughly how much memory each file being extracted needs, for its
+/// decompressor's state, which for bzip2 and zstd can be several
+/// megabytes, and for the buffers its data passes through on the way to
+/// disk.
+const MEMORY_PER_THREAD: u64 = 4 * 1024 * 1024;
+
+/// A limit on the memory used for data on its way from the archive to the
+/// output files, as given by [`crate::UnzipOptions::memory_budget`]. A
+/// quarter of it goes on data fetched over HTTP(S) ahead of being needed,
+/// a quarter on files fetched ahead of being extracted, and the rest on
+/// the files being extracted, which limits how many are extracted at
+/// once. Memory used for other things, such as the archive's list of
+/// files, isn't counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    total: u64,
+}
+
+impl MemoryBudget {
+    /// A budget of `total` bytes.
+    pub fn new(total: u64) -> Self {
+        Self { total }
+    }
+
+    /// The total budget, in bytes.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The most data fetched over HTTP(S) which may be kept until it's
+    /// read.
+    pub fn readahead_limit(&self) -> usize {
+        (self.total / 4).try_into().unwrap_or(usize::MAX)
+    }
+
+    /// How much data may be fetched ahead of files being extracted.
+    pub fn readahead_watermarks(&self) -> ReadaheadWatermarks {
+        let high = self.total / 4;
+        ReadaheadWatermarks {
+            high,
+            low: high / 2,
+        }
+    }
+
+    /// How many files may be extracted at once, which is never more than
+    /// `available_threads`, nor less than one.
+    pub fn extraction_threads(&self, available_threads: usize) -> usize {
+        let affordable = (self.total / 2 / MEMORY_PER_THREAD).max(1);
+        available_threads.min(affordable.try_into().unwrap_or(usize::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBudget;
+    use crate::ReadaheadWatermarks;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(256 * 1024 * 1024);
+        assert_eq!(budget.readahead_limit(), 64 * 1024 * 1024);
+        assert_eq!(
+            budget.readahead_watermarks(),
+            ReadaheadWatermarks {
+                high: 64 * 1024 * 1024,
+                low: 32 * 1024 * 1024,
+            }
+        );
+        assert_eq!(budget.extraction_threads(64), 32);
+        assert_eq!(budget.extraction_threads(8), 8);
+        assert_eq!(MemoryBudget::new(1024).extraction_threads(8), 1);
+    }
+}
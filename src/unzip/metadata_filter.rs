@@ -0,0 +1,153 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::SystemTime;
+
+use super::{symlinks::is_symlink, EntryMetadata, ListedEntry};
+
+/// What sort of thing an entry in an archive is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EntryType {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+    /// Hard links, devices, encrypted files in zip files read from a
+    /// stream, and so on, none of which are extracted.
+    Other,
+}
+
+impl EntryType {
+    /// The type of the zip file entry called `name` with these Unix
+    /// permissions.
+    pub(crate) fn of_zip_entry(name: &str, unix_mode: Option<u32>) -> Self {
+        if name.ends_with('/') {
+            Self::Directory
+        } else if is_symlink(unix_mode) {
+            Self::Symlink
+        } else {
+            Self::File
+        }
+    }
+}
+
+/// Selects entries according to what the archive records about them, in
+/// addition to any filename filter. The default selects everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataFilter {
+    /// Only entries last modified after this. Entries without a
+    /// modification time aren't selected.
+    pub modified_after: Option<SystemTime>,
+    /// Only entries last modified before this. Entries without a
+    /// modification time aren't selected.
+    pub modified_before: Option<SystemTime>,
+    /// Only entries at least this big, uncompressed.
+    pub min_size: Option<u64>,
+    /// Only entries at most this big, uncompressed.
+    pub max_size: Option<u64>,
+    /// Only entries of these types, or of any type if this is empty.
+    pub types: Vec<EntryType>,
+}
+
+impl MetadataFilter {
+    /// Whether this selects everything.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the entry listed as `entry` is selected.
+    pub fn selects_listed(&self, entry: &ListedEntry) -> bool {
+        self.selects_type(entry.entry_type)
+            && self.selects_size(entry.size)
+            && self.selects_times(entry.last_modified)
+    }
+
+    /// Whether the entry with `metadata` is selected. Entries read from a
+    /// stream whose size isn't known until they've been read are taken to
+    /// be empty.
+    pub(crate) fn selects(&self, entry_type: EntryType, metadata: &EntryMetadata) -> bool {
+        self.selects_type(entry_type)
+            && self.selects_size(metadata.size)
+            && self.selects_times(metadata.last_modified)
+    }
+
+    fn selects_type(&self, entry_type: EntryType) -> bool {
+        self.types.is_empty() || self.types.contains(&entry_type)
+    }
+
+    fn selects_size(&self, size: u64) -> bool {
+        self.min_size.map_or(true, |min| size >= min)
+            && self.max_size.map_or(true, |max| size <= max)
+    }
+
+    fn selects_times(&self, last_modified: Option<SystemTime>) -> bool {
+        if self.modified_after.is_none() && self.modified_before.is_none() {
+            return true;
+        }
+        let Some(last_modified) = last_modified else {
+            return false;
+        };
+        self.modified_after
+            .map_or(true, |after| last_modified > after)
+            && self
+                .modified_before
+                .map_or(true, |before| last_modified < before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{EntryType, MetadataFilter};
+
+    #[test]
+    fn test_selects_times() {
+        let time = |seconds| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds));
+        let filter = MetadataFilter {
+            modified_after: time(100),
+            modified_before: time(200),
+            ..Default::default()
+        };
+        assert!(filter.selects_times(time(150)));
+        assert!(!filter.selects_times(time(100)));
+        assert!(!filter.selects_times(time(250)));
+        assert!(!filter.selects_times(None));
+        assert!(MetadataFilter::default().selects_times(None));
+    }
+
+    #[test]
+    fn test_selects_size() {
+        let filter = MetadataFilter {
+            min_size: Some(10),
+            max_size: Some(20),
+            ..Default::default()
+        };
+        assert!(filter.selects_size(10));
+        assert!(filter.selects_size(20));
+        assert!(!filter.selects_size(9));
+        assert!(!filter.selects_size(21));
+    }
+
+    #[test]
+    fn test_selects_type() {
+        let filter = MetadataFilter {
+            types: vec![EntryType::Directory, EntryType::Symlink],
+            ..Default::default()
+        };
+        let of = EntryType::of_zip_entry;
+        assert!(filter.selects_type(of("a/", None)));
+        assert!(filter.selects_type(of("a/b", Some(0o120777))));
+        assert!(!filter.selects_type(of("a/b", Some(0o100644))));
+        assert!(!filter.selects_type(of("a/b", None)));
+        assert!(MetadataFilter::default().selects_type(EntryType::Other));
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{fs::File, io::Cursor, sync::Arc};
+
+use memmap2::Mmap;
+
+/// A memory mapping of a file which can be cheaply cloned and shared
+/// between threads. Unlike [`super::cloneable_seekable_reader::CloneableSeekableReader`]
+/// reads need no system calls, since each reader has its own view of the data.
+#[derive(Clone)]
+pub(crate) struct SharedMmap(Arc<Mmap>);
+
+impl SharedMmap {
+    pub(crate) fn new(file: &File) -> std::io::Result<Self> {
+        // SAFETY: the mapping is only sound if nobody modifies the file while
+        // we're reading it. We can't guarantee that, which is why this is an
+        // opt-in feature; it's the same contract as any other unzip tool
+        // which maps its input.
+        #[allow(unsafe_code)]
+        let map = unsafe { Mmap::map(file)? };
+        Ok(Self(Arc::new(map)))
+    }
+
+    /// Create a new reader of the mapped data, starting at the beginning.
+    pub(crate) fn reader(&self) -> Cursor<SharedMmap> {
+        Cursor::new(self.clone())
+    }
+}
+
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::SharedMmap;
+
+    #[test]
+    fn test_mmap_readers_are_independent() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"0123456789").unwrap();
+        let map = SharedMmap::new(&file).unwrap();
+        let mut a = map.reader();
+        let mut b = a.clone();
+        a.seek(SeekFrom::Start(5)).unwrap();
+        let mut buf = [0u8; 3];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"567");
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"012");
+    }
+}
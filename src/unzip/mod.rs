@@ -6,28 +6,140 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+mod apple_double;
+mod buffer_pool;
+mod case_collisions;
+mod central_directory;
+mod chunked_writer;
 mod cloneable_seekable_reader;
+mod concat_sink;
+mod crx;
+mod dedup;
+mod download;
+mod entry_reader;
+mod extraneous;
+mod filter_set;
+mod flatten;
 mod http_range_reader;
+mod long_path;
+mod manifest;
+mod memory_budget;
+mod metadata_filter;
+#[cfg(feature = "mmap")]
+mod mmap_reader;
+#[cfg(all(feature = "fuse", unix))]
+mod mount;
+mod output_quota;
+mod pipeline;
+mod policy;
+mod priority;
 mod progress_updater;
+#[cfg(feature = "s3")]
+mod s3_sink;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+mod sandbox;
+mod sanitize;
 mod seekable_http_reader;
+mod sequential;
+#[cfg(feature = "serve")]
+mod serve;
+mod signature;
+mod sink;
+mod sparse_writer;
+mod staging;
+mod streaming;
+mod symlinks;
+mod tar_engine;
+mod tar_sink;
+mod thread_tuner;
+mod timestamps;
+mod transform;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring_writer;
+mod verification;
+mod zip_sink;
+mod zip_stream;
 
 use std::{
     borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{ErrorKind, Read, Seek, SeekFrom},
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use cap_std::{ambient_authority, fs::Dir};
 use rayon::prelude::*;
 use zip::{read::ZipFile, ZipArchive};
 
+#[cfg(not(target_os = "linux"))]
+use crate::unzip::chunked_writer::ArchiveRawReader;
+#[cfg(target_os = "linux")]
+use crate::unzip::chunked_writer::FileRawReader;
 use crate::unzip::{
-    cloneable_seekable_reader::CloneableSeekableReader, progress_updater::ProgressUpdater,
+    cloneable_seekable_reader::{CloneableSeekableReader, Truncated},
+    progress_updater::{ProgressBatcher, ProgressUpdater},
+    sparse_writer::SparseWriter,
 };
 
-use self::seekable_http_reader::{AccessPattern, SeekableHttpReader, SeekableHttpReaderEngine};
+use self::{
+    apple_double::{apple_double_target, DeferredAppleDouble},
+    buffer_pool::{PooledBuffer, DOUBLE_BUFFER_THRESHOLD},
+    case_collisions::{resolve_case_collisions, CollisionResolution},
+    central_directory::{
+        central_directory_entries, locate_archives, locate_central_directory,
+        read_central_directory, CentralDirectoryEntry, CentralDirectoryLocation,
+    },
+    chunked_writer::{copy_in_chunks, RawReader, CHUNK_SIZE},
+    crx::read_crx_header,
+    dedup::{compare_contents, link_duplicate, DedupKey, Deduplicator},
+    download::{download, download_to},
+    extraneous::ExtraneousFileRemover,
+    flatten::FlattenedNames,
+    output_quota::{OutputQuota, QuotaReader},
+    pipeline::{Pipeline, PipelinedReader, FETCH_THREADS},
+    seekable_http_reader::{NetworkEvent, SeekableHttpReader, SeekableHttpReaderEngine},
+    staging::StagingDirectory,
+    streaming::STREAMING_THRESHOLD,
+    symlinks::{is_symlink, DeferredSymlinks},
+    tar_engine::{Compression, UnzipTarEngine},
+    thread_tuner::{EntryTimer, ThreadTuner, TimedWriter},
+    timestamps::DirectoryTimes,
+    verification::WrittenFiles,
+    zip_stream::UnzipStreamEngine,
+};
+
+pub use self::{
+    case_collisions::CaseCollisionPolicy,
+    concat_sink::ConcatSink,
+    crx::CrxHeader,
+    entry_reader::EntryReader,
+    filter_set::FilterSet,
+    manifest::Manifest,
+    memory_budget::MemoryBudget,
+    metadata_filter::{EntryType, MetadataFilter},
+    pipeline::ReadaheadWatermarks,
+    policy::{ExtractionPolicy, OverwritePolicy, PermissionsPolicy},
+    priority::{IoPriority, WorkerPriority},
+    sanitize::FilenameSanitization,
+    seekable_http_reader::{AccessPattern, HttpOptions},
+    signature::SignatureVerifier,
+    sink::{EntryMetadata, OutputSink},
+    symlinks::SymlinkPolicy,
+    tar_sink::TarSink,
+    thread_tuner::Timings,
+    timestamps::ModificationTimes,
+    transform::NameTransform,
+    zip_sink::{ZipCompression, ZipSink},
+};
+
+#[cfg(feature = "s3")]
+pub use self::s3_sink::{S3Credentials, S3Sink};
 
 pub(crate) fn determine_stream_len<R: Seek>(stream: &mut R) -> std::io::Result<u64> {
     let old_pos = stream.stream_position()?;
@@ -46,10 +158,131 @@ pub struct UnzipOptions<'a, 'b> {
     pub password: Option<String>,
     /// Whether to run in single-threaded mode.
     pub single_threaded: bool,
-    /// A filename filter, optionally
-    pub filename_filter: Option<Box<dyn FilenameFilter + Sync + 'a>>,
+    /// Which entries to extract, by name and by what the archive records
+    /// about them.
+    pub filters: FilterSet<'a>,
     /// An object to receive notifications of unzip progress.
     pub progress_reporter: Box<dyn UnzipProgressReporter + Sync + 'b>,
+    /// Whether to extract into a temporary directory alongside the output
+    /// directory, and only move the files into place once all of them have
+    /// been extracted successfully.
+    pub staging: bool,
+    /// Whether to flush each extracted file, and the directories containing
+    /// them, to disk before reporting success.
+    pub fsync: bool,
+    /// Whether to leave holes in output files instead of writing out long
+    /// runs of zeros.
+    pub sparse: bool,
+    /// Somewhere to send the extracted files instead of the output
+    /// directory. If this is set, the options above which relate to
+    /// writing files to disk have no effect.
+    pub output_sink: Option<Box<dyn OutputSink + 'a>>,
+    /// How extracted files are named, and what they're given from the
+    /// archive besides their contents.
+    pub policy: ExtractionPolicy<'a>,
+    /// What to do about files whose names differ only by case.
+    pub case_collisions: CaseCollisionPolicy,
+    /// The most bytes to extract, in total, across all files. Extraction
+    /// fails as soon as this is exceeded.
+    pub max_output_size: Option<u64>,
+    /// Whether to remove files and directories from the output directory
+    /// which aren't in the zip file, once everything has been extracted.
+    /// If there's a filename filter, only files which it selects are
+    /// removed.
+    pub delete_extraneous: bool,
+    /// Whether to hardlink files with identical contents and permissions
+    /// to each other, rather than writing out each copy.
+    pub hardlink_duplicates: bool,
+    /// Adjust how many files are extracted at once according to whether
+    /// the network, the CPU or the disk is the bottleneck, rather than
+    /// always extracting a file per CPU. Ignored when single-threaded.
+    pub tune_thread_count: bool,
+    /// Files to extract before any others, so that they can be used while
+    /// the rest are still being extracted. The progress reporter hears
+    /// when they're done.
+    pub priority_filter: Option<Box<dyn FilenameFilter + Sync + 'a>>,
+    /// Carry on if a file's local header or data is corrupt, rather than
+    /// failing once everything else is extracted. The progress reporter
+    /// hears about each file which was skipped this way.
+    pub skip_corrupt: bool,
+    /// Once extracted, check the files against these digests. Those which
+    /// are missing or different are reported to the progress reporter as
+    /// failures, and the first is returned as the error.
+    pub manifest: Option<Manifest>,
+    /// Once extracted, read back each file and check that it's what was
+    /// in the archive: for zip files, that it has the CRC-32 they record.
+    /// Those which differ are reported to the progress reporter as
+    /// failures, and the first is returned as the error.
+    pub verify_after: bool,
+    /// Rewrite each entry's name with these, in order, before extracting
+    /// it. Filters see the original names. Entries whose names become
+    /// empty are skipped, as are those which would end up outside the
+    /// output directory.
+    pub name_transforms: Vec<NameTransform>,
+    /// Extract every file directly into the output directory, leaving out
+    /// the directories. Files whose names collide, or differ only by case
+    /// unless case collisions are allowed, are numbered, like `name-1.ext`,
+    /// and reported to the progress reporter as renamed.
+    pub flatten: bool,
+    /// Only extract the entries at these indices, counting from zero in the
+    /// order [`UnzipEngine::list_details`] lists them, if they're also
+    /// selected by the filters. For zip files from URIs, only those entries
+    /// are fetched.
+    pub entry_indices: Option<HashSet<usize>>,
+    /// Decompress on threads which are sandboxed, before they read any of
+    /// the archive, so that they can only reach files beneath the output
+    /// directory, or the temporary directory for output sinks, and can't
+    /// run programs. This needs Linux with Landlock, and the `sandbox`
+    /// feature; elsewhere, unzipping fails if it's set.
+    pub sandbox: bool,
+    /// Limit the memory used for data on its way to the output, by
+    /// limiting how much is fetched ahead of being needed and how many
+    /// files are extracted at once.
+    pub memory_budget: Option<MemoryBudget>,
+    /// Lower the CPU and I/O priority of the threads which extract files.
+    pub worker_priority: Option<WorkerPriority>,
+    /// The process umask, from [`process_umask`], with which permissions
+    /// are masked if the policy says to apply it. If this isn't given, it's
+    /// read when unzipping starts, which on some platforms isn't safe while
+    /// other threads are creating files.
+    pub umask: Option<u32>,
+}
+
+impl Default for UnzipOptions<'_, '_> {
+    /// Extract everything into the current directory, on a thread per CPU,
+    /// with the default [`ExtractionPolicy`] and nothing else besides.
+    /// Callers can set just the options they care about, and leave the
+    /// rest, including any added later, as they are here.
+    fn default() -> Self {
+        Self {
+            output_directory: None,
+            password: None,
+            single_threaded: false,
+            filters: FilterSet::default(),
+            progress_reporter: Box::new(NullProgressReporter),
+            staging: false,
+            fsync: false,
+            sparse: false,
+            output_sink: None,
+            policy: ExtractionPolicy::default(),
+            case_collisions: CaseCollisionPolicy::default(),
+            max_output_size: None,
+            delete_extraneous: false,
+            hardlink_duplicates: false,
+            tune_thread_count: false,
+            priority_filter: None,
+            skip_corrupt: false,
+            manifest: None,
+            verify_after: false,
+            name_transforms: Vec::new(),
+            flatten: false,
+            entry_indices: None,
+            sandbox: false,
+            memory_budget: None,
+            worker_priority: None,
+            umask: None,
+        }
+    }
 }
 
 /// A trait of types which wish to hear progress updates on the unzip.
@@ -60,6 +293,12 @@ pub trait UnzipProgressReporter: Sync {
     fn extraction_finished(&self, _display_name: &str) {}
     /// The total number of compressed bytes we expect to extract.
     fn total_bytes_expected(&self, _expected: u64) {}
+    /// The number of entries in the archive, including directories, if
+    /// it's known before extraction starts, which it isn't for archives
+    /// read from a stream or for tar files. Each entry is then reported as
+    /// finished, skipped or failed, so frontends can show how many have
+    /// been dealt with so far.
+    fn total_entries_expected(&self, _expected: u64) {}
     /// Some bytes of a file have been decompressed. This is probably
     /// the best way to display an overall progress bar. This should eventually
     /// add up to the number you're given using `total_bytes_expected`.
@@ -70,6 +309,159 @@ pub trait UnzipProgressReporter: Sync {
     /// bytes without downloading the whole zip file first, which rather
     /// defeats the point.
     fn bytes_extracted(&self, _count: u64) {}
+    /// Some more of a file's `total` compressed bytes have been extracted.
+    /// Unlike [`UnzipProgressReporter::bytes_extracted`], this isn't batched
+    /// up across files, so it's called between `extraction_starting` and
+    /// `extraction_finished` for the same file, though maybe on other
+    /// threads.
+    fn file_bytes_extracted(&self, _display_name: &str, _count: u64, _total: u64) {}
+    /// Some uncompressed bytes have been written out. Unlike the compressed
+    /// bytes given to [`UnzipProgressReporter::bytes_extracted`], these
+    /// can't be totalled up in advance.
+    fn bytes_written(&self, _count: u64) {}
+    /// A file isn't being extracted, because the filename filter didn't
+    /// select it, or its name collides with another file's, or it's
+    /// unchanged. This may follow `extraction_starting` for the same file.
+    fn extraction_skipped(&self, _display_name: &str) {}
+    /// Extracting a file failed.
+    fn extraction_failed(&self, _failure: &ExtractionFailure) {}
+    /// An HTTP(S) request has been made for the `range` of bytes of the zip
+    /// file. Only zip files fetched from URIs report requests and the data
+    /// they download, and only while extracting files. These may be called
+    /// on a thread of their own.
+    fn request_started(&self, _range: &Range<u64>) {}
+    /// Some bytes of the zip file have been downloaded. Like the count given
+    /// to [`UnzipProgressReporter::bytes_extracted`], this isn't a running
+    /// total, but unlike it, this counts data as soon as it arrives, along
+    /// with any which is downloaded but never extracted, so it gives the
+    /// download throughput.
+    fn bytes_downloaded(&self, _count: u64) {}
+    /// The request for `range` has finished, because it was all downloaded,
+    /// or it failed, or the rest of it wasn't needed.
+    fn request_completed(&self, _range: &Range<u64>) {}
+    /// Once extraction is over, the most memory which was used to hold
+    /// data fetched ahead of extracting it; or rather an upper bound on
+    /// that. Only zip files fetched from URIs report this.
+    fn readahead_peak(&self, _bytes: u64) {}
+    /// Once extraction is over, how many HTTP(S) streams were opened to
+    /// read the zip file. Only zip files fetched from URIs report this.
+    fn http_streams(&self, _count: usize) {}
+    /// Once extraction is over, where the time went. Only zip files, rather
+    /// than tar files, report how long was spent extracting files.
+    fn timings(&self, _timings: &Timings) {}
+    /// Something went wrong which didn't stop extraction, but which the
+    /// user may want to know about.
+    fn warning(&self, _kind: WarningKind, _message: &str) {}
+    /// A file is being extracted under a different name from the one in the
+    /// zip file, because the original name wasn't valid.
+    fn file_renamed(&self, _original_name: &str, _new_name: &str) {}
+    /// Every file selected by [`UnzipOptions::priority_filter`] has been
+    /// extracted successfully, and the rest are about to be.
+    fn priority_files_extracted(&self) {}
+}
+
+/// The kinds of non-fatal problem given to
+/// [`UnzipProgressReporter::warning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// A file is being extracted under a different name from the one in
+    /// the zip file.
+    FileRenamed,
+    /// A file isn't being extracted, because its name collides with
+    /// another's.
+    FileSkipped,
+    /// The HTTP(S) server doesn't support range requests, so the whole zip
+    /// file had to be downloaded before extracting anything.
+    RangeRequestsUnsupported,
+    /// The central directory couldn't be read up front, so files are
+    /// extracted in a less efficient order.
+    CentralDirectoryUnreadable,
+    /// Only some files are being extracted, which is done on one thread.
+    SingleThreaded,
+    /// The readahead limit was too small, so parts of the zip file had to
+    /// be fetched more than once.
+    ReadaheadTooSmall,
+    /// A file wasn't extracted because it's corrupt; see
+    /// [`UnzipOptions::skip_corrupt`].
+    CorruptFileSkipped,
+    /// The file holds several zip files one after another, and only the
+    /// last is being read; see [`UnzipEngine::archives_in_file`].
+    ConcatenatedArchives,
+}
+
+/// A file which couldn't be extracted, as given to
+/// [`UnzipProgressReporter::extraction_failed`].
+#[derive(Debug)]
+pub struct ExtractionFailure<'a> {
+    /// The file's name.
+    pub name: &'a str,
+    /// Where the file's local header is in the zip file, if known.
+    pub header_offset: Option<u64>,
+    /// What went wrong.
+    pub error: &'a anyhow::Error,
+}
+
+/// What an archive records about one of its entries, as given by
+/// [`UnzipEngine::list_details`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ListedEntry {
+    /// The entry's name in the archive.
+    pub name: String,
+    /// The uncompressed size.
+    pub size: u64,
+    /// The compressed size, if the entry is compressed on its own.
+    pub compressed_size: Option<u64>,
+    /// Where the entry's local header is in a zip file.
+    pub offset: Option<u64>,
+    /// When the entry was last modified.
+    pub last_modified: Option<SystemTime>,
+    /// The CRC-32 of the entry's contents, if the archive records one.
+    pub crc32: Option<u32>,
+    /// The zip compression method, such as 8 for deflate, if the entry is
+    /// compressed on its own and the archive's central directory was read.
+    pub compression_method: Option<u16>,
+    /// Whether it's a file, a directory and so on.
+    pub entry_type: EntryType,
+}
+
+/// What the archive records about its entries as a whole, as given by
+/// [`UnzipEngine::summary`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ArchiveSummary {
+    /// How many entries there are, including directories.
+    pub entry_count: u64,
+    /// The total size of the entries' data in the archive. Entries in tar
+    /// files aren't compressed on their own, so for them this is the same
+    /// as the uncompressed size.
+    pub compressed_size: u64,
+    /// The total size of the entries once extracted.
+    pub uncompressed_size: u64,
+    /// Whether any entries are encrypted, and so need a password. Only zip
+    /// files with central directories record this.
+    pub has_encrypted_entries: bool,
+    /// Whether any entries are compressed in ways which can't be
+    /// decompressed. Only zip files with central directories record this.
+    pub has_unsupported_entries: bool,
+    /// The header before the zip file, if it's a Chrome extension.
+    pub crx_header: Option<CrxHeader>,
+}
+
+impl ArchiveSummary {
+    fn of_listed(entries: &[ListedEntry]) -> Self {
+        Self {
+            crx_header: None,
+            entry_count: entries.len() as u64,
+            compressed_size: entries
+                .iter()
+                .map(|entry| entry.compressed_size.unwrap_or(entry.size))
+                .sum(),
+            uncompressed_size: entries.iter().map(|entry| entry.size).sum(),
+            ..Default::default()
+        }
+    }
 }
 
 /// A progress reporter which does nothing.
@@ -79,11 +471,18 @@ impl UnzipProgressReporter for NullProgressReporter {}
 
 /// An object which can unzip a zip file, in its entirety, from a local
 /// file or from a network stream. It tries to do this in parallel wherever
-/// possible.
+/// possible. It isn't generic, and is `Send`, so it can be kept in any
+/// struct or passed to another thread; the progress reporter is only given
+/// to it, boxed, in the [`UnzipOptions`] for each unzip.
 pub struct UnzipEngine {
     zipfile: Box<dyn UnzipEngineImpl>,
     compressed_length: u64,
-    directory_creator: DirectoryCreator,
+    /// The ETag the server gave for the zip file, if it was downloaded.
+    etag: Option<String>,
+    /// Warnings from before there was a progress reporter to give them to.
+    pending_warnings: Vec<(WarningKind, String)>,
+    /// How long it took to open the archive.
+    opening_time: Duration,
 }
 
 /// Code which can determine whether to unzip a given filename.
@@ -92,91 +491,596 @@ pub trait FilenameFilter {
     fn should_unzip(&self, filename: &str) -> bool;
 }
 
+/// What to do about a file which already exists in the output directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExistingFileAction {
+    /// Replace it with the file from the archive.
+    Overwrite,
+    /// Leave it alone, and don't extract the file from the archive.
+    Skip,
+    /// Extract the file from the archive to this path instead, relative to
+    /// the output directory.
+    Rename(PathBuf),
+}
+
+/// Code which can decide what to do about files which already exist. It
+/// may be asked about several files at once, from different threads.
+pub trait ExistingFileHandler {
+    /// Returns what to do about the existing file at `path`, relative to
+    /// the output directory.
+    fn handle_existing_file(&self, path: &Path) -> ExistingFileAction;
+}
+
 /// The underlying engine used by the unzipper. This is different
 /// for files and URIs.
-trait UnzipEngineImpl {
-    fn unzip(
-        &mut self,
-        options: UnzipOptions,
-        directory_creator: &DirectoryCreator,
-    ) -> Vec<anyhow::Error>;
+trait UnzipEngineImpl: Send {
+    fn unzip(&mut self, options: &UnzipOptions, state: &UnzipState) -> Vec<anyhow::Error>;
 
     // Due to lack of RPITIT we'll return a Vec<String> here
     fn list(&self) -> Result<Vec<String>, anyhow::Error>;
+
+    /// The filenames in the archive, in its order, read as they're needed
+    /// where that's possible.
+    fn list_lazily(self: Box<Self>) -> Result<Box<dyn Iterator<Item = Result<String>>>> {
+        Ok(Box::new(self.list()?.into_iter().map(Ok)))
+    }
+
+    /// What the archive records about each entry, in the archive's order.
+    fn list_details(&self) -> Result<Vec<ListedEntry>>;
+
+    /// A way to read the zip file at any offset from several threads at
+    /// once, if that's cheap.
+    fn raw_reader(&self) -> Option<Box<dyn RawReader>> {
+        None
+    }
+
+    /// The MS-DOS attributes of those files which have any, by name.
+    #[cfg(windows)]
+    fn dos_attributes(&self) -> Result<HashMap<String, u32>>;
+
+    /// Once extraction is over, report anything the progress reporter
+    /// might want to know about how the zip file was read.
+    fn report_statistics(&self, _progress_reporter: &dyn UnzipProgressReporter) {}
+
+    /// Keep any buffers used to read the zip file within `budget`.
+    fn limit_memory(&self, _budget: &MemoryBudget) {}
+
+    /// How many entries the archive has, if that's known without reading
+    /// all of it.
+    fn entry_count(&self) -> Option<u64> {
+        None
+    }
+
+    /// What the archive records about its entries as a whole.
+    fn summary(&self) -> Result<ArchiveSummary> {
+        Ok(ArchiveSummary::of_listed(&self.list_details()?))
+    }
+
+    /// Start reading the entry called `name` on its own, decompressing no
+    /// more than `limit` bytes of it if that's given.
+    fn open_entry(&self, _name: &str, _limit: Option<u64>) -> Result<EntryReader> {
+        bail!("Only entries in zip files which aren't read from a stream can be read on their own")
+    }
+
+    /// Mount the zip file's contents as a read-only filesystem until it's
+    /// unmounted.
+    #[cfg(all(feature = "fuse", unix))]
+    fn mount(&self, mountpoint: &Path) -> Result<()>;
+
+    /// Serve the zip file's contents over HTTP until the process is killed.
+    #[cfg(feature = "serve")]
+    fn serve(&self, address: &str) -> Result<()>;
 }
 
-/// Engine which knows how to unzip a file.
+/// Engine which knows how to unzip a file, given some reader of that file
+/// which can be cheaply cloned for each thread.
 #[derive(Clone)]
-struct UnzipFileEngine(ZipArchive<CloneableSeekableReader<File>>);
+struct UnzipFileEngine<R: Read + Seek + Clone> {
+    archive: ZipArchive<R>,
+    /// The zip file itself, from which the kernel can copy data.
+    #[cfg(target_os = "linux")]
+    file: Arc<File>,
+}
 
-impl UnzipEngineImpl for UnzipFileEngine {
-    fn unzip(
-        &mut self,
-        options: UnzipOptions,
-        directory_creator: &DirectoryCreator,
-    ) -> Vec<anyhow::Error> {
+impl<R: Read + Seek + Clone> UnzipFileEngine<R> {
+    fn new(archive: ZipArchive<R>, zipfile: File) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        drop(zipfile);
+        Self {
+            archive,
+            #[cfg(target_os = "linux")]
+            file: Arc::new(zipfile),
+        }
+    }
+}
+
+impl<R: Read + Seek + Clone + Send + Sync + 'static> UnzipEngineImpl for UnzipFileEngine<R> {
+    fn unzip(&mut self, options: &UnzipOptions, state: &UnzipState) -> Vec<anyhow::Error> {
+        // Start on the biggest files first, so that we don't end up with
+        // one big file being extracted on its own after everything else
+        // has finished.
+        let (mut order, _) =
+            extraction_order(&self.archive, options.progress_reporter.as_ref(), |entry| {
+                Reverse(entry.compressed_size)
+            });
+        retain_entry_indices(&self.archive, &mut order, options);
+        let priority_count = prioritize(&self.archive, &mut order, options);
         unzip_serial_or_parallel(
-            self.0.len(),
+            order,
+            priority_count,
             options,
-            directory_creator,
-            || self.0.clone(),
+            state,
+            || self.archive.clone(),
             || {},
         )
     }
 
     fn list(&self) -> Result<Vec<String>, anyhow::Error> {
-        list(&self.0)
+        list(&self.archive)
+    }
+
+    fn list_lazily(self: Box<Self>) -> Result<Box<dyn Iterator<Item = Result<String>>>> {
+        Ok(list_lazily(self.archive))
+    }
+
+    fn list_details(&self) -> Result<Vec<ListedEntry>> {
+        list_details(&self.archive)
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.archive.len() as u64)
+    }
+
+    fn summary(&self) -> Result<ArchiveSummary> {
+        summary(&self.archive)
+    }
+
+    fn open_entry(&self, name: &str, limit: Option<u64>) -> Result<EntryReader> {
+        EntryReader::open(&self.archive, name, limit)
+    }
+
+    #[cfg(all(feature = "fuse", unix))]
+    fn mount(&self, mountpoint: &Path) -> Result<()> {
+        mount::mount(self.archive.clone(), mountpoint)
+    }
+
+    #[cfg(feature = "serve")]
+    fn serve(&self, address: &str) -> Result<()> {
+        serve::serve(self.archive.clone(), address)
+    }
+
+    fn raw_reader(&self) -> Option<Box<dyn RawReader>> {
+        #[cfg(target_os = "linux")]
+        return Some(Box::new(FileRawReader(self.file.clone())));
+        #[cfg(not(target_os = "linux"))]
+        {
+            // Positioned writes are needed to write the chunks.
+            if cfg!(any(unix, windows)) {
+                Some(Box::new(ArchiveRawReader(self.archive.clone())))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    fn dos_attributes(&self) -> Result<HashMap<String, u32>> {
+        read_central_directory(&self.archive)
+            .map(|entries| central_directory::dos_attributes(&entries))
     }
 }
 
 /// Engine which knows how to unzip a URI; specifically a URI fetched from
 /// an HTTP server which supports `Range` requests.
-#[derive(Clone)]
-struct UnzipUriEngine<F: Fn()>(
-    Arc<SeekableHttpReaderEngine>,
-    ZipArchive<SeekableHttpReader>,
-    F,
-);
-
-impl<F: Fn()> UnzipEngineImpl for UnzipUriEngine<F> {
-    fn unzip(
-        &mut self,
-        options: UnzipOptions,
-        directory_creator: &DirectoryCreator,
-    ) -> Vec<anyhow::Error> {
-        self.0
-            .set_expected_access_pattern(AccessPattern::SequentialIsh);
-        let result = unzip_serial_or_parallel(
-            self.1.len(),
-            options,
-            directory_creator,
-            || self.1.clone(),
-            || self.0.read_skip_expected(),
-        );
-        let stats = self.0.get_stats();
+struct UnzipUriEngine<F: Fn()> {
+    uri: String,
+    engine: Arc<SeekableHttpReaderEngine>,
+    /// Read when first needed, since for zip files with big central
+    /// directories we can start extracting without it.
+    archive: OnceLock<ZipArchive<PipelinedReader<SeekableHttpReader>>>,
+    /// Where the central directory is, if it's big enough that it's worth
+    /// extracting files while it's still being read.
+    big_central_directory: Option<CentralDirectoryLocation>,
+    pipeline: Arc<Pipeline>,
+    /// How much to read from each HTTP(S) stream at a time, if specified.
+    chunk_size: Option<usize>,
+    /// How the zip file will be read when extracting, if the caller said.
+    access_pattern: Option<AccessPattern>,
+    callback_on_rewind: F,
+}
+
+impl<F: Fn() + Send + 'static> UnzipEngineImpl for UnzipUriEngine<F> {
+    fn unzip(&mut self, options: &UnzipOptions, state: &UnzipState) -> Vec<anyhow::Error> {
+        // Requests are reported from a thread of their own, since they're
+        // made by whichever thread happens to need more data.
+        let (sender, receiver) = mpsc::channel::<NetworkEvent>();
+        let progress_reporter = options.progress_reporter.as_ref();
+        let result = std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for event in receiver {
+                    event.report(progress_reporter);
+                }
+            });
+            let _network_events = self.engine.send_network_events(sender);
+            match self.streamable_central_directory(options) {
+                Some(location) => {
+                    self.unzip_while_reading_central_directory(location, options, state)
+                }
+                None => match self.archive() {
+                    Ok(archive) => self.unzip_archive(archive, options, state),
+                    Err(e) => vec![e],
+                },
+            }
+        });
+        let stats = self.engine.get_stats();
         if stats.cache_shrinks > 0 {
-            self.2()
+            options.progress_reporter.warning(
+                WarningKind::ReadaheadTooSmall,
+                "This operation required several HTTP(S) streams, which can slow down decompression. The readahead limit may be too small.",
+            );
+            (self.callback_on_rewind)()
         }
         result
     }
 
     fn list(&self) -> Result<Vec<String>, anyhow::Error> {
-        list(&self.1)
+        list(self.archive()?)
+    }
+
+    fn list_lazily(self: Box<Self>) -> Result<Box<dyn Iterator<Item = Result<String>>>> {
+        // Big central directories are read a block at a time as names are
+        // needed, rather than by the zip crate all at once.
+        if let (None, Some(location)) = (self.archive.get(), &self.big_central_directory) {
+            let entries = central_directory_entries(self.engine.clone().create_reader(), location)?;
+            return Ok(Box::new(entries.map(|entry| Ok(entry?.name))));
+        }
+        self.archive()?;
+        Ok(list_lazily(self.archive.into_inner().unwrap()))
+    }
+
+    fn list_details(&self) -> Result<Vec<ListedEntry>> {
+        list_details(self.archive()?)
+    }
+
+    fn summary(&self) -> Result<ArchiveSummary> {
+        summary(self.archive()?)
+    }
+
+    fn open_entry(&self, name: &str, limit: Option<u64>) -> Result<EntryReader> {
+        let archive = self.archive()?;
+        if let (Some(_), None) = (limit, &self.access_pattern) {
+            // Only the start of the entry will be read, so don't fetch
+            // anything beyond the entry itself.
+            let entries = read_central_directory(archive)?;
+            let entry = entries
+                .iter()
+                .find(|entry| entry.name == name)
+                .ok_or_else(|| anyhow!("{name} isn't in the zip file"))?;
+            let span = entry.header_offset..span_ends(archive, &entries)(entry.header_offset);
+            self.engine
+                .set_expected_access_pattern(AccessPattern::PlannedRanges(vec![span]));
+        }
+        EntryReader::open(archive, name, limit)
+    }
+
+    #[cfg(all(feature = "fuse", unix))]
+    fn mount(&self, mountpoint: &Path) -> Result<()> {
+        mount::mount(self.archive()?.clone(), mountpoint)
+    }
+
+    #[cfg(feature = "serve")]
+    fn serve(&self, address: &str) -> Result<()> {
+        serve::serve(self.archive()?.clone(), address)
+    }
+
+    #[cfg(windows)]
+    fn dos_attributes(&self) -> Result<HashMap<String, u32>> {
+        read_central_directory(self.archive()?)
+            .map(|entries| central_directory::dos_attributes(&entries))
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        match &self.big_central_directory {
+            Some(location) => Some(location.entry_count),
+            None => self.archive.get().map(|archive| archive.len() as u64),
+        }
+    }
+
+    fn limit_memory(&self, budget: &MemoryBudget) {
+        self.engine.limit_readahead(budget.readahead_limit());
+        self.pipeline
+            .limit_watermarks(budget.readahead_watermarks());
+    }
+
+    fn report_statistics(&self, progress_reporter: &dyn UnzipProgressReporter) {
+        let stats = self.engine.get_stats();
+        progress_reporter
+            .readahead_peak(self.pipeline.peak_bytes_buffered() + stats.peak_cache_size as u64);
+        progress_reporter.http_streams(stats.num_http_streams);
+    }
+}
+
+impl<F: Fn()> UnzipUriEngine<F> {
+    fn archive(&self) -> Result<&ZipArchive<PipelinedReader<SeekableHttpReader>>> {
+        if let Some(archive) = self.archive.get() {
+            return Ok(archive);
+        }
+        let reader =
+            PipelinedReader::new(self.engine.clone().create_reader(), self.pipeline.clone());
+        let archive = ZipArchive::new(reader)?;
+        Ok(self.archive.get_or_init(|| archive))
+    }
+
+    /// The central directory, if we can start extracting files before it
+    /// has all been read.
+    fn streamable_central_directory(
+        &self,
+        options: &UnzipOptions,
+    ) -> Option<&CentralDirectoryLocation> {
+        // There's nothing to gain if the zip crate has already read the
+        // whole central directory. And if we're only extracting some files,
+        // or some first, we'd have to read every local header to find their
+        // names.
+        if self.archive.get().is_some()
+            || !options.filters.selects_all_names()
+            || options.entry_indices.is_some()
+            || options.priority_filter.is_some()
+        {
+            return None;
+        }
+        self.big_central_directory.as_ref()
+    }
+
+    fn unzip_archive(
+        &self,
+        archive: &ZipArchive<PipelinedReader<SeekableHttpReader>>,
+        options: &UnzipOptions,
+        state: &UnzipState,
+    ) -> Vec<anyhow::Error> {
+        // Extract files in the order their data appears in the zip file, so
+        // that we read through it sequentially.
+        let (mut order, entries) =
+            extraction_order(archive, options.progress_reporter.as_ref(), |entry| {
+                entry.header_offset
+            });
+        retain_entry_indices(archive, &mut order, options);
+        // Priority files are fetched first too.
+        let priority_count = prioritize(archive, &mut order, options);
+        if is_selective(archive, &entries, options) {
+            self.engine.expect_selective_reads();
+        }
+        let spans = selected_spans(archive, &order, &entries, options);
+        let access_pattern = match &self.access_pattern {
+            Some(access_pattern) => access_pattern.clone(),
+            // Don't download anything in between the files we want.
+            None if !options.filters.is_empty() || options.entry_indices.is_some() => {
+                AccessPattern::PlannedRanges(spans.clone())
+            }
+            None => AccessPattern::SequentialIsh,
+        };
+        if !options.single_threaded {
+            // Have the fetch threads fetch the local header and data of
+            // each file which will be extracted, in the order they'll be
+            // extracted.
+            self.pipeline.plan(spans);
+        }
+        self.engine.set_expected_access_pattern(access_pattern);
+        let (engine, pipeline) = (&self.engine, &self.pipeline);
+        std::thread::scope(|scope| {
+            for _ in 0..FETCH_THREADS {
+                let reader = engine.clone().create_reader();
+                scope.spawn(move || pipeline.fetch(reader));
+            }
+            let result = unzip_serial_or_parallel(
+                order,
+                priority_count,
+                options,
+                state,
+                || archive.clone(),
+                || engine.read_skip_expected(),
+            );
+            pipeline.finish();
+            result
+        })
+    }
+
+    fn unzip_while_reading_central_directory(
+        &self,
+        location: &CentralDirectoryLocation,
+        options: &UnzipOptions,
+        state: &UnzipState,
+    ) -> Vec<anyhow::Error> {
+        // Read the central directory over its own HTTP(S) stream, so that
+        // it doesn't keep interrupting the stream of file data.
+        let directory_engine = match SeekableHttpReaderEngine::new(
+            self.uri.clone(),
+            None,
+            AccessPattern::SequentialIsh,
+            self.chunk_size,
+            // There's no need to ask the server about it again.
+            Some(self.engine.len()),
+        ) {
+            Ok(directory_engine) => directory_engine,
+            Err(e) => return vec![e.into()],
+        };
+        self.engine.set_expected_access_pattern(
+            self.access_pattern
+                .clone()
+                .unwrap_or(AccessPattern::SequentialIsh),
+        );
+        let (mut errors, deferred) = streaming::unzip_while_reading_central_directory(
+            directory_engine.create_reader(),
+            self.engine.clone().create_reader(),
+            location,
+            options,
+            state,
+        );
+        if !deferred.is_empty() {
+            log::info!(
+                "{} files can't be extracted until the whole central directory has been read",
+                deferred.len()
+            );
+            let engine = &self.engine;
+            match self.archive() {
+                Ok(archive) => errors.extend(unzip_serial_or_parallel(
+                    deferred,
+                    0,
+                    options,
+                    state,
+                    || archive.clone(),
+                    || engine.read_skip_expected(),
+                )),
+                Err(e) => errors.push(e),
+            }
+        }
+        errors
+    }
+}
+
+/// Find where the part of the zip file holding an entry ends, given the
+/// offset of its local header.
+fn span_ends<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+    entries: &[CentralDirectoryEntry],
+) -> impl Fn(u64) -> u64 {
+    // Each file's data runs up to the next file's local header, or the
+    // central directory.
+    let mut offsets: Vec<u64> = entries.iter().map(|entry| entry.header_offset).collect();
+    offsets.push(archive.central_directory_start());
+    offsets.sort_unstable();
+    move |start| {
+        offsets
+            .get(offsets.partition_point(|offset| *offset <= start))
+            .copied()
+            .unwrap_or(start)
     }
 }
 
+/// The parts of the zip file holding the local header and data of each file
+/// which will be extracted, in the order they'll be extracted.
+fn selected_spans<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+    order: &[usize],
+    entries: &[CentralDirectoryEntry],
+    options: &UnzipOptions,
+) -> Vec<Range<u64>> {
+    let span_end = span_ends(archive, entries);
+    let selected = |i: usize| {
+        let Some(name) = archive.name_for_index(i) else {
+            return false;
+        };
+        let entry = &entries[i];
+        let metadata = EntryMetadata {
+            size: entry.size,
+            unix_mode: entry.unix_mode(),
+            last_modified: entry.last_modified,
+            accessed: None,
+            created: None,
+            compression_method: Some(entry.compression_method),
+        };
+        options
+            .filters
+            .metadata_filter()
+            .selects(EntryType::of_zip_entry(name, metadata.unix_mode), &metadata)
+            && options.filters.should_unzip(name)
+    };
+    order
+        .iter()
+        .filter(|i| selected(**i))
+        .map(|i| entries[*i].header_offset)
+        .map(|start| start..span_end(start))
+        .collect()
+}
+
 impl UnzipEngine {
-    /// Create an unzip engine which knows how to unzip a file.
+    /// Create an unzip engine which knows how to unzip a file. A gzip- or
+    /// zstd-compressed tar file is extracted instead if that's what it
+    /// turns out to be.
     pub fn for_file(mut zipfile: File) -> Result<Self> {
+        let started = Instant::now();
         // The following line doesn't actually seem to make any significant
         // performance difference.
         // let zipfile = BufReader::new(zipfile);
         let compressed_length = determine_stream_len(&mut zipfile)?;
-        let zipfile = CloneableSeekableReader::new(zipfile);
+        let mut pending_warnings = Vec::new();
+        // This fails for tar files, which can't be concatenated like this.
+        let archive_count = Self::archives_in_file(&zipfile).map_or(0, |archives| archives.len());
+        if archive_count > 1 {
+            pending_warnings.push((
+                WarningKind::ConcatenatedArchives,
+                format!(
+                    "This file holds {archive_count} zip files one after another; only the last is being read"
+                ),
+            ));
+        }
         Ok(Self {
-            zipfile: Box::new(UnzipFileEngine(ZipArchive::new(zipfile)?)),
+            zipfile: local_engine(zipfile)?,
             compressed_length,
-            directory_creator: DirectoryCreator::default(),
+            etag: None,
+            pending_warnings,
+            opening_time: started.elapsed(),
+        })
+    }
+
+    /// Where the zip files stored one after another in `zipfile` are, in
+    /// order. There's only one unless several zip files have been
+    /// concatenated, in which case [`UnzipEngine::for_file`] only reads the
+    /// last, but [`UnzipEngine::for_file_archive`] can read any of them.
+    pub fn archives_in_file(zipfile: &File) -> Result<Vec<Range<u64>>> {
+        #[cfg(any(unix, windows))]
+        return locate_archives(zipfile);
+        #[cfg(not(any(unix, windows)))]
+        return locate_archives(&cloneable_seekable_reader::LockedReader::new(
+            zipfile.try_clone()?,
+        ));
+    }
+
+    /// Create an unzip engine which reads only the zip file in the `archive`
+    /// part of `zipfile`, as found by [`UnzipEngine::archives_in_file`].
+    pub fn for_file_archive(zipfile: File, archive: Range<u64>) -> Result<Self> {
+        let started = Instant::now();
+        let reader = zipfile.try_clone()?;
+        #[cfg(not(any(unix, windows)))]
+        let reader = cloneable_seekable_reader::LockedReader::new(reader);
+        // The zip crate finds where the zip file starts by itself.
+        let reader = CloneableSeekableReader::new(Truncated::new(reader, archive.end));
+        Ok(Self {
+            zipfile: Box::new(UnzipFileEngine::new(ZipArchive::new(reader)?, zipfile)),
+            compressed_length: archive.end - archive.start,
+            etag: None,
+            pending_warnings: Vec::new(),
+            opening_time: started.elapsed(),
+        })
+    }
+
+    /// Create an unzip engine which extracts a zip file as it's read from a
+    /// stream which can't seek, such as standard input. Files are extracted
+    /// in the order they're stored, without their Unix permissions, which
+    /// are only recorded in the central directory at the end. The stream
+    /// can only be read once, so can't be both listed and extracted. As
+    /// with files, compressed tar files are extracted too.
+    pub fn for_reader(reader: impl Read + Send + 'static) -> Result<Self> {
+        let started = Instant::now();
+        let mut reader = BufReader::new(reader);
+        let tar_compression = Compression::from_magic(reader.fill_buf()?);
+        // The zip crate skips over any CRX header in files, but here there's
+        // no going back to the zip file once it's been seen.
+        let crx_header = match tar_compression {
+            Some(_) => None,
+            None => read_crx_header(&mut reader)?,
+        };
+        let reader = Box::new(reader);
+        let zipfile: Box<dyn UnzipEngineImpl> = match tar_compression {
+            Some(compression) => Box::new(UnzipTarEngine::for_stream(compression, reader)),
+            None => Box::new(UnzipStreamEngine::new(reader, crx_header)),
+        };
+        Ok(Self {
+            zipfile,
+            // This isn't known up front.
+            compressed_length: 0,
+            etag: None,
+            pending_warnings: Vec::new(),
+            opening_time: started.elapsed(),
         })
     }
 
@@ -185,80 +1089,456 @@ impl UnzipEngine {
     /// - the URI
     /// - unzip options
     /// - how big a readahead buffer to create in memory.
+    /// - how much data to fetch ahead of extracting it.
+    /// - how to make HTTP(S) requests.
     /// - a progress reporter (set of callbacks)
     /// - an additional callback to warn if performance was impaired by
     ///   rewinding the HTTP stream. (This implies the readahead buffer was
     ///   too small.)
-    pub fn for_uri<F: Fn() + 'static>(
+    pub fn for_uri<F: Fn() + Send + 'static>(
         uri: &str,
         readahead_limit: Option<usize>,
+        readahead_watermarks: ReadaheadWatermarks,
+        http_options: HttpOptions,
         callback_on_rewind: F,
     ) -> Result<Self> {
-        let seekable_http_reader = SeekableHttpReaderEngine::new(
-            uri.to_string(),
-            readahead_limit,
-            AccessPattern::RandomAccess,
-        );
-        let (compressed_length, zipfile): (u64, Box<dyn UnzipEngineImpl>) =
-            match seekable_http_reader {
-                Ok(seekable_http_reader) => (
-                    seekable_http_reader.len(),
-                    Box::new(UnzipUriEngine(
-                        seekable_http_reader.clone(),
-                        ZipArchive::new(seekable_http_reader.create_reader())?,
-                        callback_on_rewind,
-                    )),
-                ),
-                Err(_) => {
+        let started = Instant::now();
+        if readahead_watermarks.low > readahead_watermarks.high {
+            bail!("The low readahead watermark must not be above the high one");
+        }
+        if http_options.chunk_size == Some(0) {
+            bail!("The HTTP chunk size must not be zero");
+        }
+        // A zip file whose signature is to be checked is downloaded whole,
+        // since every byte of it has to be read before any of it is used.
+        let seekable_http_reader = match http_options.signature {
+            Some(_) => None,
+            None => SeekableHttpReaderEngine::new(
+                uri.to_string(),
+                readahead_limit,
+                AccessPattern::RandomAccess,
+                http_options.chunk_size,
+                http_options.content_length,
+            )
+            .ok(),
+        };
+        let tar_compression = match &seekable_http_reader {
+            Some(seekable_http_reader) => {
+                Compression::sniff(seekable_http_reader.clone().create_reader())?
+            }
+            None => None,
+        };
+        let mut etag = seekable_http_reader
+            .as_ref()
+            .and_then(|seekable_http_reader| seekable_http_reader.etag().map(str::to_string));
+        let mut pending_warnings = Vec::new();
+        let (compressed_length, zipfile): (u64, Box<dyn UnzipEngineImpl>) = match (
+            seekable_http_reader,
+            tar_compression,
+        ) {
+            (Some(seekable_http_reader), Some(compression)) => {
+                // Tar files are read from start to end, with no need
+                // for ranges.
+                let engine = UnzipTarEngine::for_uri(compression, uri.to_string());
+                (seekable_http_reader.len(), Box::new(engine))
+            }
+            (Some(seekable_http_reader), None) => {
+                seekable_http_reader.prefetch_tail(http_options.tail_fetch_size);
+                let big_central_directory =
+                    locate_central_directory(seekable_http_reader.clone().create_reader())?
+                        .filter(|location| location.len >= STREAMING_THRESHOLD);
+                let engine = UnzipUriEngine {
+                    uri: uri.to_string(),
+                    engine: seekable_http_reader.clone(),
+                    archive: OnceLock::new(),
+                    big_central_directory,
+                    pipeline: Arc::new(Pipeline::new(readahead_watermarks)),
+                    chunk_size: http_options.chunk_size,
+                    access_pattern: http_options.access_pattern.clone(),
+                    callback_on_rewind,
+                };
+                if engine.big_central_directory.is_none() {
+                    engine.archive()?;
+                }
+                (seekable_http_reader.len(), Box::new(engine))
+            }
+            (None, _) => {
+                if http_options.signature.is_none() {
                     // This server probably doesn't support HTTP ranges.
                     // Let's fall back to fetching the request into a temporary
                     // file then unzipping.
-                    log::warn!("HTTP(S) server does not support range requests - falling back to fetching whole file.");
-                    let mut response = reqwest::blocking::get(uri)?;
-                    let mut tempfile = tempfile::tempfile()?;
-                    std::io::copy(&mut response, &mut tempfile)?;
-                    let compressed_length = determine_stream_len(&mut tempfile)?;
-                    let zipfile = CloneableSeekableReader::new(tempfile);
-                    (
-                        compressed_length,
-                        Box::new(UnzipFileEngine(ZipArchive::new(zipfile)?)),
-                    )
+                    let message = "HTTP(S) server does not support range requests - falling back to fetching whole file.";
+                    log::warn!("{message}");
+                    pending_warnings
+                        .push((WarningKind::RangeRequestsUnsupported, message.to_string()));
                 }
-            };
+                let mut file = match &http_options.keep_download {
+                    Some(path) => download_to(uri, path, &mut etag).with_context(|| {
+                        format!("Failed to download zip file to {}", path.display())
+                    })?,
+                    None => {
+                        let mut tempfile = tempfile::tempfile()?;
+                        download(uri, &mut tempfile, &mut etag)?;
+                        tempfile
+                    }
+                };
+                if let Some(signature) = &http_options.signature {
+                    file.seek(SeekFrom::Start(0))?;
+                    signature.verify(&mut file)?;
+                }
+                let compressed_length = determine_stream_len(&mut file)?;
+                (compressed_length, local_engine(file)?)
+            }
+        };
         Ok(Self {
             zipfile,
             compressed_length,
-            directory_creator: DirectoryCreator::default(),
+            etag,
+            pending_warnings,
+            opening_time: started.elapsed(),
         })
     }
 
+    /// Unzip on a thread pool of its own with `thread_count` threads,
+    /// whose priority is lowered and which are sandboxed before they start
+    /// decompressing, if asked.
+    fn unzip_on_own_pool(
+        &mut self,
+        options: &UnzipOptions,
+        state: &UnzipState,
+        thread_count: usize,
+    ) -> Result<Vec<anyhow::Error>> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .build()?;
+        if let Some(priority) = &options.worker_priority {
+            pool.broadcast(|_| priority.apply())
+                .into_iter()
+                .collect::<Result<()>>()?;
+        }
+        if options.sandbox {
+            sandbox_pool(&pool, options)?;
+        }
+        Ok(pool.install(|| self.zipfile.unzip(options, state)))
+    }
+
+    /// The ETag the server gave for the zip file, if it was downloaded and
+    /// the server gave one. This changes whenever the file does.
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
     /// The total compressed length that we expect to retrieve over
-    /// the network or from the compressed file.
+    /// the network or from the compressed file, or zero if it's read from a
+    /// stream.
     pub fn zip_length(&self) -> u64 {
         self.compressed_length
     }
 
     // Perform the unzip.
-    pub fn unzip(mut self, options: UnzipOptions) -> Result<()> {
+    pub fn unzip(mut self, mut options: UnzipOptions) -> Result<()> {
         log::debug!("Starting extract");
+        for (kind, message) in &self.pending_warnings {
+            options.progress_reporter.warning(*kind, message);
+        }
         options
             .progress_reporter
             .total_bytes_expected(self.compressed_length);
-        let errors = self.zipfile.unzip(options, &self.directory_creator);
-        // Return the first error code, if any.
-        errors.into_iter().next().map(Result::Err).unwrap_or(Ok(()))
-    }
-
-    /// List the filenames in the archive
-    pub fn list(self) -> Result<impl Iterator<Item = String>> {
-        // In future this might be a more dynamic iterator type.
-        self.zipfile.list().map(|mut v| {
-            // Names are returned in a HashMap iteration order so let's
-            // sort thme to be more reasonable
-            v.sort();
-            v.into_iter()
+        if let Some(entry_count) = self.zipfile.entry_count() {
+            options
+                .progress_reporter
+                .total_entries_expected(entry_count);
+        }
+        let output_directory = options
+            .output_directory
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."));
+        if options.manifest.is_some() && options.output_sink.is_some() {
+            bail!("Files can only be checked against a manifest when extracted to a directory");
+        }
+        if options.policy.restore_apple_double && !cfg!(target_os = "macos") {
+            bail!("AppleDouble files can only be restored on macOS");
+        }
+        let umask = options.umask.or_else(process_umask);
+        let staging_directory = if options.staging && options.output_sink.is_none() {
+            let staging_directory =
+                StagingDirectory::new(options.output_directory.as_deref(), umask)?;
+            options.output_directory = Some(staging_directory.path().to_path_buf());
+            Some(staging_directory)
+        } else {
+            None
+        };
+        let fsync = options.fsync;
+        let thread_count = options
+            .memory_budget
+            .map_or_else(rayon::current_num_threads, |budget| {
+                budget.extraction_threads(rayon::current_num_threads())
+            });
+        if let Some(budget) = &options.memory_budget {
+            self.zipfile.limit_memory(budget);
+        }
+        let mut state = UnzipState {
+            output_quota: options.max_output_size.map(OutputQuota::new),
+            #[cfg(unix)]
+            umask: if options.policy.permissions.apply_umask {
+                umask.unwrap_or_default()
+            } else {
+                0
+            },
+            thread_tuner: (options.tune_thread_count && !options.single_threaded)
+                .then(|| ThreadTuner::new(thread_count)),
+            ..Default::default()
+        };
+        if options.case_collisions != CaseCollisionPolicy::Allow {
+            let names = self.zipfile.list()?;
+            let names = names
+                .iter()
+                .enumerate()
+                .filter(|(i, name)| {
+                    options
+                        .entry_indices
+                        .as_ref()
+                        .map_or(true, |indices| indices.contains(i))
+                        && options.filters.should_unzip(name)
+                })
+                .map(|(_, name)| name.as_str());
+            state.case_collisions = resolve_case_collisions(
+                names,
+                options.case_collisions,
+                options.policy.path_sanitization,
+            )?;
+        }
+        if options.output_sink.is_none() && !options.sparse {
+            state.raw_reader = self.zipfile.raw_reader();
+        }
+        #[cfg(windows)]
+        if options.output_sink.is_none() {
+            state.dos_attributes = self.zipfile.dos_attributes()?;
+        }
+        if options.output_sink.is_none() {
+            let extraction_directory = options
+                .output_directory
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("."));
+            std::fs::create_dir_all(&extraction_directory)
+                .with_context(|| "Failed to create output directory")?;
+            // All files are created relative to this directory, so they
+            // can't escape it even by way of symlinks.
+            state.output_dir = Some(
+                Dir::open_ambient_dir(&extraction_directory, ambient_authority())
+                    .with_context(|| "Failed to open output directory")?,
+            );
+        }
+        let errors = if options.sandbox
+            || options.worker_priority.is_some()
+            || thread_count != rayon::current_num_threads()
+        {
+            self.unzip_on_own_pool(&options, &state, thread_count)?
+        } else {
+            self.zipfile.unzip(&options, &state)
+        };
+        state
+            .progress_batcher
+            .flush(options.progress_reporter.as_ref());
+        self.zipfile
+            .report_statistics(options.progress_reporter.as_ref());
+        options.progress_reporter.timings(&Timings {
+            opening: self.opening_time,
+            ..*state.timings.lock().unwrap()
+        });
+        // Return the first error code, if any. Any staged files are
+        // discarded.
+        if let Some(error) = errors.into_iter().next() {
+            return Err(error);
+        }
+        if let Some(output_sink) = &options.output_sink {
+            return output_sink.finish();
+        }
+        if let Some(output_dir) = &state.output_dir {
+            let errors = std::mem::take(&mut state.symlinks).create(
+                output_dir,
+                options
+                    .output_directory
+                    .as_deref()
+                    .unwrap_or_else(|| Path::new(".")),
+                options.policy.symlinks,
+                options.progress_reporter.as_ref(),
+            );
+            if let Some(error) = errors.into_iter().next() {
+                return Err(error);
+            }
+            let errors = std::mem::take(&mut state.apple_double)
+                .restore(output_dir, options.progress_reporter.as_ref());
+            if let Some(error) = errors.into_iter().next() {
+                return Err(error);
+            }
+        }
+        if let Some(output_dir) = state.output_dir.as_ref().filter(|_| options.verify_after) {
+            let errors = std::mem::take(&mut state.written_files)
+                .verify(output_dir, options.progress_reporter.as_ref());
+            if let Some(error) = errors.into_iter().next() {
+                return Err(error);
+            }
+        }
+        if let (Some(manifest), Some(output_dir)) = (&options.manifest, &state.output_dir) {
+            // Staged files are only moved into place if they're right.
+            let errors = manifest.check(
+                output_dir,
+                options.filters.name_filter(),
+                options.progress_reporter.as_ref(),
+            );
+            if let Some(error) = errors.into_iter().next() {
+                return Err(error);
+            }
+        }
+        if fsync {
+            state.sync_directories()?;
+        }
+        if let Some(staging_directory) = staging_directory {
+            let changed_directories = staging_directory.commit()?;
+            if fsync {
+                for directory in changed_directories {
+                    sync_directory(&directory)?;
+                }
+            }
+        }
+        if options.delete_extraneous {
+            let extracted_paths = state.extracted_paths.into_inner().unwrap();
+            let output_dir = Dir::open_ambient_dir(&output_directory, ambient_authority())
+                .with_context(|| "Failed to open output directory")?;
+            let changed_directories =
+                ExtraneousFileRemover::new(&extracted_paths, options.filters.name_filter())
+                    .remove_from(&output_dir)?;
+            if fsync {
+                for directory in changed_directories {
+                    sync_directory(&output_directory.join(directory))?;
+                }
+            }
+        }
+        // Extracting files into directories, or removing them, changes the
+        // directories' modification times, so those are set last.
+        if options.policy.mtimes != ModificationTimes::Extraction {
+            let output_dir = Dir::open_ambient_dir(&output_directory, ambient_authority())
+                .with_context(|| "Failed to open output directory")?;
+            state.directory_times.apply(&output_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Extract just the entries at `indices`, counting from zero in the
+    /// order [`UnzipEngine::list_details`] lists them, which are also
+    /// selected by `options`' filters. This saves matching names again
+    /// after listing the entries to choose them.
+    pub fn unzip_entries(
+        self,
+        indices: impl IntoIterator<Item = usize>,
+        options: UnzipOptions,
+    ) -> Result<()> {
+        self.unzip(UnzipOptions {
+            entry_indices: Some(indices.into_iter().collect()),
+            ..options
         })
     }
+
+    /// List the filenames in the archive, in the order they're in it. For
+    /// remote zip files with big central directories, names are yielded as
+    /// the central directory is fetched, rather than once it all has been.
+    pub fn list(self) -> Result<impl Iterator<Item = Result<String>>> {
+        self.zipfile.list_lazily()
+    }
+
+    /// List what the archive records about each entry, in the order
+    /// they're in the archive.
+    pub fn list_details(&self) -> Result<Vec<ListedEntry>> {
+        self.zipfile.list_details()
+    }
+
+    /// Summarize what the archive records about its entries, so that
+    /// callers can decide whether to go ahead and extract them. For zip
+    /// files, this only reads the central directory. Other archives are
+    /// read through, so archives read from a stream can't then be
+    /// extracted.
+    pub fn summary(&self) -> Result<ArchiveSummary> {
+        self.zipfile.summary()
+    }
+
+    /// Read the entry called `name` on its own, without extracting
+    /// anything. It's decompressed as it's read, and for zip files from
+    /// URIs, only the parts of the zip file holding it are fetched.
+    pub fn open_entry(&self, name: &str) -> Result<EntryReader> {
+        self.zipfile.open_entry(name, None)
+    }
+
+    /// Read no more than the first `len` bytes of the entry called `name`.
+    /// Only that much is decompressed, and for zip files from URIs, nothing
+    /// beyond the entry is fetched, so this is a cheap way to peek at the
+    /// start of a big file.
+    pub fn open_entry_prefix(&self, name: &str, len: u64) -> Result<EntryReader> {
+        self.zipfile.open_entry(name, Some(len))
+    }
+
+    /// List the filenames in the archive which `filter` would unzip.
+    pub fn list_matching(
+        self,
+        filter: &dyn FilenameFilter,
+    ) -> Result<impl Iterator<Item = Result<String>> + '_> {
+        Ok(self
+            .list()?
+            .filter(|name| name.as_ref().map_or(true, |name| filter.should_unzip(name))))
+    }
+
+    /// Mount the zip file's contents as a read-only filesystem at
+    /// `mountpoint`, decompressing each file when it's opened, and serve
+    /// them until the filesystem is unmounted.
+    #[cfg(all(feature = "fuse", unix))]
+    pub fn mount(self, mountpoint: &Path) -> Result<()> {
+        self.zipfile.mount(mountpoint)
+    }
+
+    /// Serve the files in the zip file over HTTP at `address`, such as
+    /// `127.0.0.1:8080`, decompressing each as it's requested, until the
+    /// process is killed. Range requests are supported.
+    #[cfg(feature = "serve")]
+    pub fn serve(self, address: &str) -> Result<()> {
+        self.zipfile.serve(address)
+    }
+
+    /// The filenames in the archive, in the order of its central directory.
+    /// For a remote zip file this fetches the whole central directory, so
+    /// files can no longer be extracted while it's being read.
+    pub fn names_in_archive_order(&self) -> Result<Vec<String>> {
+        self.zipfile.list()
+    }
+}
+
+/// Create the engine to extract a local file, which may be a compressed tar
+/// file rather than a zip file.
+fn local_engine(mut file: File) -> Result<Box<dyn UnzipEngineImpl>> {
+    file.seek(SeekFrom::Start(0))?;
+    let compression = Compression::sniff(&mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    match compression {
+        Some(compression) => Ok(Box::new(UnzipTarEngine::for_file(compression, file))),
+        None => file_engine(file),
+    }
+}
+
+/// Create the engine to unzip a local file. If the `mmap` feature is enabled,
+/// the file is memory mapped; otherwise, each thread uses positioned reads.
+fn file_engine(zipfile: File) -> Result<Box<dyn UnzipEngineImpl>> {
+    #[cfg(feature = "mmap")]
+    match mmap_reader::SharedMmap::new(&zipfile) {
+        Ok(map) => {
+            let archive = ZipArchive::new(map.reader())?;
+            return Ok(Box::new(UnzipFileEngine::new(archive, zipfile)));
+        }
+        Err(e) => log::debug!("Unable to map zip file, falling back to reads: {e}"),
+    }
+    let reader = zipfile.try_clone()?;
+    #[cfg(not(any(unix, windows)))]
+    let reader = cloneable_seekable_reader::LockedReader::new(reader);
+    let archive = ZipArchive::new(CloneableSeekableReader::new(reader))?;
+    Ok(Box::new(UnzipFileEngine::new(archive, zipfile)))
 }
 
 /// Return a list of filenames from the zip. For now this is infallible
@@ -268,49 +1548,224 @@ fn list<'a, T: Read + Seek + 'a>(zip_archive: &ZipArchive<T>) -> Result<Vec<Stri
     Ok(zip_archive.file_names().map(|s| s.to_string()).collect())
 }
 
-fn unzip_serial_or_parallel<'a, T: Read + Seek + 'a>(
-    len: usize,
-    options: UnzipOptions,
-    directory_creator: &DirectoryCreator,
-    get_ziparchive_clone: impl Fn() -> ZipArchive<T> + Sync,
-    // Call when a file is going to be skipped
-    file_skip_callback: impl Fn() + Sync + Send + Clone,
-) -> Vec<anyhow::Error> {
-    let progress_reporter: &dyn UnzipProgressReporter = options.progress_reporter.as_ref();
-    match (options.filename_filter, options.single_threaded) {
-        (None, true) => (0..len)
-            .map(|i| {
-                extract_file_by_index(
-                    &get_ziparchive_clone,
-                    i,
-                    &options.output_directory,
-                    &options.password,
-                    progress_reporter,
-                    directory_creator,
-                )
-            })
-            .filter_map(Result::err)
-            .collect(),
-        (None, false) => {
-            // We use par_bridge here rather than into_par_iter because it turns
+/// The filenames in `zip_archive`, in its order, one at a time.
+fn list_lazily<R: Read + Seek + 'static>(
+    zip_archive: ZipArchive<R>,
+) -> Box<dyn Iterator<Item = Result<String>>> {
+    Box::new((0..zip_archive.len()).map(move |i| {
+        Ok(zip_archive
+            .name_for_index(i)
+            .unwrap_or_default()
+            .to_string())
+    }))
+}
+
+fn list_details<R: Read + Seek + Clone>(zip_archive: &ZipArchive<R>) -> Result<Vec<ListedEntry>> {
+    Ok(read_central_directory(zip_archive)?
+        .into_iter()
+        .map(|entry| ListedEntry {
+            entry_type: EntryType::of_zip_entry(&entry.name, entry.unix_mode()),
+            name: entry.name,
+            size: entry.size,
+            compressed_size: Some(entry.compressed_size),
+            offset: Some(entry.header_offset),
+            last_modified: entry.last_modified,
+            crc32: Some(entry.crc32),
+            compression_method: Some(entry.compression_method),
+        })
+        .collect())
+}
+
+fn summary<R: Read + Seek + Clone>(zip_archive: &ZipArchive<R>) -> Result<ArchiveSummary> {
+    let entries = read_central_directory(zip_archive)?;
+    Ok(ArchiveSummary {
+        entry_count: entries.len() as u64,
+        compressed_size: entries.iter().map(|entry| entry.compressed_size).sum(),
+        uncompressed_size: entries.iter().map(|entry| entry.size).sum(),
+        has_encrypted_entries: entries.iter().any(CentralDirectoryEntry::is_encrypted),
+        has_unsupported_entries: !entries.iter().all(CentralDirectoryEntry::is_supported),
+        crx_header: crx_header(zip_archive)?,
+    })
+}
+
+/// The CRX header before the zip file, if there's one. There can only be
+/// one if the zip file doesn't start at the start of the file.
+fn crx_header<R: Read + Seek + Clone>(zip_archive: &ZipArchive<R>) -> Result<Option<CrxHeader>> {
+    if zip_archive.offset() == 0 {
+        return Ok(None);
+    }
+    let mut reader = zip_archive.clone().into_inner();
+    reader.seek(SeekFrom::Start(0))?;
+    read_crx_header(&mut BufReader::new(reader))
+}
+
+/// The indices of the files in the zip, sorted by what the central directory
+/// says about them, along with the central directory entries. If the central
+/// directory can't be read, there are no entries.
+fn extraction_order<R: Read + Seek + Clone, K: Ord>(
+    zip_archive: &ZipArchive<R>,
+    progress_reporter: &dyn UnzipProgressReporter,
+    key: impl Fn(&CentralDirectoryEntry) -> K,
+) -> (Vec<usize>, Vec<CentralDirectoryEntry>) {
+    match read_central_directory(zip_archive) {
+        Ok(entries) => {
+            let mut order: Vec<usize> = (0..entries.len()).collect();
+            order.sort_by_key(|i| key(&entries[*i]));
+            (order, entries)
+        }
+        Err(e) => {
+            let message = format!(
+                "Unable to read central directory, so extracting files in index order: {e}"
+            );
+            log::warn!("{message}");
+            progress_reporter.warning(WarningKind::CentralDirectoryUnreadable, &message);
+            ((0..zip_archive.len()).collect(), Vec::new())
+        }
+    }
+}
+
+/// Leave out of `order` the files not in [`UnzipOptions::entry_indices`], if
+/// it's given, reporting them as skipped.
+fn retain_entry_indices<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+    order: &mut Vec<usize>,
+    options: &UnzipOptions,
+) {
+    let Some(indices) = &options.entry_indices else {
+        return;
+    };
+    order.retain(|i| {
+        let selected = indices.contains(i);
+        if let (false, Some(name)) = (selected, archive.name_for_index(*i)) {
+            options.progress_reporter.extraction_skipped(name);
+        }
+        selected
+    });
+    log::info!("Will unzip {} files chosen by index", order.len());
+}
+
+/// Move the files selected by the priority filter, if there is one, to the
+/// front of the extraction order. Returns how many of them there are.
+fn prioritize<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+    order: &mut Vec<usize>,
+    options: &UnzipOptions,
+) -> usize {
+    let Some(priority_filter) = &options.priority_filter else {
+        return 0;
+    };
+    let (mut prioritized, rest): (Vec<usize>, Vec<usize>) = order.iter().partition(|i| {
+        archive
+            .name_for_index(**i)
+            .is_some_and(|name| priority_filter.should_unzip(name))
+    });
+    let priority_count = prioritized.len();
+    log::info!("Will unzip {priority_count} priority files first");
+    prioritized.extend(rest);
+    *order = prioritized;
+    priority_count
+}
+
+/// Whether the filename filter and entry indices select so little of the
+/// zip file that it's worth reading it in smaller pieces.
+fn is_selective<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+    entries: &[CentralDirectoryEntry],
+    options: &UnzipOptions,
+) -> bool {
+    /// Extracting less than this fraction of the data is selective.
+    const SELECTIVE_FRACTION: u64 = 10;
+    let filename_filter = options.filters.name_filter();
+    if filename_filter.is_none() && options.entry_indices.is_none() {
+        return false;
+    }
+    let total: u64 = entries.iter().map(|entry| entry.compressed_size).sum();
+    let selected: u64 = entries
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            options
+                .entry_indices
+                .as_ref()
+                .map_or(true, |indices| indices.contains(i))
+                && filename_filter.map_or(true, |filename_filter| {
+                    archive
+                        .name_for_index(*i)
+                        .is_some_and(|name| filename_filter.should_unzip(name))
+                })
+        })
+        .map(|(_, entry)| entry.compressed_size)
+        .sum();
+    selected.saturating_mul(SELECTIVE_FRACTION) < total
+}
+
+/// Extract the files with the given indices, in roughly the given order,
+/// except that the first `priority_count` are all extracted before any of
+/// the rest.
+fn unzip_serial_or_parallel<'a, T: Read + Seek + 'a>(
+    mut order: Vec<usize>,
+    priority_count: usize,
+    options: &UnzipOptions,
+    state: &UnzipState,
+    get_ziparchive_clone: impl Fn() -> ZipArchive<T> + Sync,
+    // Call when a file is going to be skipped
+    file_skip_callback: impl Fn() + Sync + Send + Clone,
+) -> Vec<anyhow::Error> {
+    if priority_count == 0 {
+        return unzip_files(
+            order,
+            options,
+            state,
+            &get_ziparchive_clone,
+            file_skip_callback,
+        );
+    }
+    let rest = order.split_off(priority_count);
+    let mut errors = unzip_files(
+        order,
+        options,
+        state,
+        &get_ziparchive_clone,
+        file_skip_callback.clone(),
+    );
+    if errors.is_empty() {
+        options.progress_reporter.priority_files_extracted();
+    }
+    errors.extend(unzip_files(
+        rest,
+        options,
+        state,
+        &get_ziparchive_clone,
+        file_skip_callback,
+    ));
+    errors
+}
+
+fn unzip_files<'a, T: Read + Seek + 'a>(
+    order: Vec<usize>,
+    options: &UnzipOptions,
+    state: &UnzipState,
+    get_ziparchive_clone: &(impl Fn() -> ZipArchive<T> + Sync),
+    file_skip_callback: impl Fn() + Sync + Send + Clone,
+) -> Vec<anyhow::Error> {
+    match (options.filters.name_filter(), options.single_threaded) {
+        (None, true) => order
+            .into_iter()
+            .map(|i| extract_file_by_index(&get_ziparchive_clone, i, options, state))
+            .filter_map(Result::err)
+            .collect(),
+        (None, false) => {
+            // We use par_bridge here rather than into_par_iter because it turns
             // out to better preserve ordering of the IDs in the input range,
             // i.e. we're more likely to ask our initial threads to act upon
             // file IDs 0, 1, 2, 3, 4, 5 rather than 0, 1000, 2000, 3000 etc.
             // On a device which is CPU-bound or IO-bound (rather than network
             // bound) that's beneficial because we can start to decompress
             // and write data to disk as soon as it arrives from the network.
-            (0..len)
+            order
+                .into_iter()
                 .par_bridge()
-                .map(|i| {
-                    extract_file_by_index(
-                        &get_ziparchive_clone,
-                        i,
-                        &options.output_directory,
-                        &options.password,
-                        progress_reporter,
-                        directory_creator,
-                    )
-                })
+                .map(|i| extract_file_by_index(&get_ziparchive_clone, i, options, state))
                 .filter_map(Result::err)
                 .collect()
         }
@@ -324,41 +1779,38 @@ fn unzip_serial_or_parallel<'a, T: Read + Seek + 'a>(
             // As we can't predict their order in the file, this may involve
             // arbitrary rewinds, so let's do it single-threaded.
             if !single_threaded {
-                log::warn!("Unzipping specific files - assuming --single-threaded since we currently cannot unzip specific files in a multi-threaded mode. If you need that, consider launching multiple copies of ripunzip in parallel.");
+                let message = "Unzipping specific files - assuming --single-threaded since we currently cannot unzip specific files in a multi-threaded mode. If you need that, consider launching multiple copies of ripunzip in parallel.";
+                log::warn!("{message}");
+                options
+                    .progress_reporter
+                    .warning(WarningKind::SingleThreaded, message);
             }
-            let mut filenames: Vec<_> = get_ziparchive_clone()
-                .file_names()
-                .filter(|name| filename_filter.as_ref().should_unzip(name))
-                .map(|s| s.to_string())
+            let ziparchive = get_ziparchive_clone();
+            let indices: Vec<usize> = order
+                .into_iter()
+                .filter(|i| match ziparchive.name_for_index(*i) {
+                    Some(name) if filename_filter.should_unzip(name) => true,
+                    Some(name) => {
+                        options.progress_reporter.extraction_skipped(name);
+                        false
+                    }
+                    None => false,
+                })
                 .collect();
-            // The filenames returned by the file_names() method above are in
-            // HashMap iteration order (i.e. random). To avoid creating lots
-            // of HTTPS streams for files which are nearby each other in the
-            // zip, we'd ideally extract them in order of file position.
-            // We have no way of knowing file position (without iterating the
-            // whole file) so instead let's sort them and hope that files were
-            // zipped in alphabetical order, or close to it. If we're wrong,
-            // we'll just end up rewinding, that is, creating extra redundant
-            // HTTP(S) streams.
-            filenames.sort();
-            log::info!("Will unzip {} matching filenames", filenames.len());
+            // To avoid creating lots of HTTP(S) streams for files which are
+            // nearby each other in the zip, we want to extract them in order
+            // of file position. Where that's known, it's the order we're
+            // given; otherwise we're given the order of the central
+            // directory, which is usually close. If we're wrong, we'll just
+            // end up rewinding, that is, creating extra redundant HTTP(S)
+            // streams.
+            log::info!("Will unzip {} matching filenames", indices.len());
             file_skip_callback();
 
-            // let progress_reporter: &dyn UnzipProgressReporter = options.progress_reporter.as_ref();
-            filenames
+            indices
                 .into_iter()
-                .map(|name| {
-                    let myzip: &mut zip::ZipArchive<T> = &mut get_ziparchive_clone();
-                    let file: ZipFile = match &options.password {
-                        None => myzip.by_name(&name)?,
-                        Some(string) => myzip.by_name_decrypt(&name, string.as_bytes())?,
-                    };
-                    let r = extract_file(
-                        file,
-                        &options.output_directory,
-                        progress_reporter,
-                        directory_creator,
-                    );
+                .map(|i| {
+                    let r = extract_file_by_index(&get_ziparchive_clone, i, options, state);
                     file_skip_callback();
                     r
                 })
@@ -371,24 +1823,40 @@ fn unzip_serial_or_parallel<'a, T: Read + Seek + 'a>(
 fn extract_file_by_index<'a, T: Read + Seek + 'a>(
     get_ziparchive_clone: impl Fn() -> ZipArchive<T> + Sync,
     i: usize,
-    output_directory: &Option<PathBuf>,
-    password: &Option<String>,
-    progress_reporter: &dyn UnzipProgressReporter,
-    directory_creator: &DirectoryCreator,
+    options: &UnzipOptions,
+    state: &UnzipState,
 ) -> Result<(), anyhow::Error> {
+    let queued = Instant::now();
+    let _permit = state.thread_tuner.as_ref().map(ThreadTuner::start_file);
+    let timer = EntryTimer::start(queued);
     let myzip: &mut zip::ZipArchive<T> = &mut get_ziparchive_clone();
-    let file: ZipFile = match password {
-        None => myzip.by_index(i)?,
-        Some(string) => myzip.by_index_decrypt(i, string.as_bytes())?,
+    let file = match &options.password {
+        None => myzip.by_index(i),
+        Some(string) => myzip.by_index_decrypt(i, string.as_bytes()),
     };
-    extract_file(file, output_directory, progress_reporter, directory_creator)
+    let file: ZipFile = match file {
+        Ok(file) => file,
+        Err(e) => {
+            let name = get_ziparchive_clone()
+                .name_for_index(i)
+                .unwrap_or("<unknown>")
+                .to_string();
+            return file_failed(&name, None, e.into(), options);
+        }
+    };
+    let unix_mode = file.unix_mode();
+    extract_file(file, unix_mode, timer, options, state)
 }
 
+/// Extract a file. Its Unix permissions are given separately, since
+/// they're only recorded in the central directory, and the file may have
+/// been read from its local header alone.
 fn extract_file(
     file: ZipFile,
-    output_directory: &Option<PathBuf>,
-    progress_reporter: &dyn UnzipProgressReporter,
-    directory_creator: &DirectoryCreator,
+    unix_mode: Option<u32>,
+    timer: EntryTimer,
+    options: &UnzipOptions,
+    state: &UnzipState,
 ) -> Result<(), anyhow::Error> {
     let name = file
         .enclosed_name()
@@ -396,117 +1864,936 @@ fn extract_file(
         .map(Path::to_string_lossy)
         .unwrap_or_else(|| Cow::Borrowed("<unprintable>"))
         .to_string();
-    extract_file_inner(file, output_directory, progress_reporter, directory_creator)
-        .with_context(|| format!("Failed to extract {name}"))
+    let header_offset = file.header_start();
+    let result = extract_file_inner(file, unix_mode, options, state);
+    let spent = timer.spent();
+    *state.timings.lock().unwrap() += spent;
+    match result {
+        Ok(()) => {
+            timer.log(&name, &spent);
+            Ok(())
+        }
+        Err(e) => file_failed(&name, Some(header_offset), e, options),
+    }
+}
+
+/// Report that a file couldn't be extracted, and return the error, unless
+/// it's corrupt and we're skipping such files.
+fn file_failed(
+    name: &str,
+    header_offset: Option<u64>,
+    error: anyhow::Error,
+    options: &UnzipOptions,
+) -> Result<()> {
+    let progress_reporter = options.progress_reporter.as_ref();
+    progress_reporter.extraction_failed(&ExtractionFailure {
+        name,
+        header_offset,
+        error: &error,
+    });
+    if options.skip_corrupt && is_corrupt(&error) {
+        let message = format!("Skipping {name} as it is corrupt: {error:#}");
+        log::warn!("{message}");
+        progress_reporter.warning(WarningKind::CorruptFileSkipped, &message);
+        return Ok(());
+    }
+    Err(error.context(format!("Failed to extract {name}")))
+}
+
+/// Whether an error means that the zip file is damaged, rather than that
+/// something went wrong with the output.
+fn is_corrupt(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<zip::result::ZipError>() {
+            matches!(e, zip::result::ZipError::InvalidArchive(_))
+        } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            // Corrupt DEFLATE streams are reported as invalid input.
+            matches!(
+                e.kind(),
+                ErrorKind::InvalidData | ErrorKind::InvalidInput | ErrorKind::UnexpectedEof
+            )
+        } else {
+            false
+        }
+    })
 }
 
 /// Extracts a file from a zip file.
 fn extract_file_inner(
     mut file: ZipFile,
-    output_directory: &Option<PathBuf>,
-    progress_reporter: &dyn UnzipProgressReporter,
-    directory_creator: &DirectoryCreator,
+    unix_mode: Option<u32>,
+    options: &UnzipOptions,
+    state: &UnzipState,
 ) -> Result<()> {
+    let progress_reporter: &dyn UnzipProgressReporter = options.progress_reporter.as_ref();
+    let metadata = EntryMetadata {
+        unix_mode,
+        ..EntryMetadata::from_zip_file(&file)
+    };
+    let entry_type = EntryType::of_zip_entry(file.name(), unix_mode);
+    if let Some(target) = apple_double_target(file.name())
+        .filter(|_| options.policy.restore_apple_double && options.output_sink.is_none())
+    {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        state.apple_double.defer(target, data);
+        state
+            .progress_batcher
+            .bytes_extracted(progress_reporter, file.compressed_size());
+        progress_reporter.extraction_skipped(file.name());
+        return Ok(());
+    }
+    if !options
+        .filters
+        .metadata_filter()
+        .selects(entry_type, &metadata)
+        || options.policy.skips(file.name())
+    {
+        state
+            .progress_batcher
+            .bytes_extracted(progress_reporter, file.compressed_size());
+        progress_reporter.extraction_skipped(file.name());
+        return Ok(());
+    }
     let name = file
         .enclosed_name()
         .ok_or_else(|| std::io::Error::new(ErrorKind::Unsupported, "path not safe to extract"))?;
-    let display_name = name.display().to_string();
-    let out_path = match output_directory {
-        Some(output_directory) => output_directory.join(name),
-        None => name,
+    let Some(name) = output_path(file.name(), name, options, state) else {
+        state
+            .progress_batcher
+            .bytes_extracted(progress_reporter, file.compressed_size());
+        return Ok(());
     };
+    let display_name = name.display().to_string();
     progress_reporter.extraction_starting(&display_name);
     log::debug!(
         "Start extract of file at {:x}, length {:x}, name {}",
-        file.data_start(),
+        file.header_start(),
+        file.compressed_size(),
+        display_name
+    );
+    let extracted = match &options.output_sink {
+        Some(output_sink) => {
+            extract_to_sink(
+                &mut file,
+                &name,
+                unix_mode,
+                options,
+                state,
+                output_sink.as_ref(),
+            )?;
+            true
+        }
+        None => extract_to_filesystem(&mut file, &name, unix_mode, options, state)?,
+    };
+    log::debug!(
+        "Finished extract of file at {:x}, length {:x}, name {}",
+        file.header_start(),
         file.compressed_size(),
         display_name
     );
+    if extracted {
+        progress_reporter.extraction_finished(&display_name);
+    } else {
+        progress_reporter.extraction_skipped(&display_name);
+    }
+    Ok(())
+}
+
+/// The path to which the file called `raw_name` in the archive, whose
+/// enclosed path is `name`, should be extracted, after sanitizing it and
+/// resolving case collisions. Returns `None` if it should be skipped.
+fn output_path(
+    raw_name: &str,
+    name: PathBuf,
+    options: &UnzipOptions,
+    state: &UnzipState,
+) -> Option<PathBuf> {
+    let progress_reporter: &dyn UnzipProgressReporter = options.progress_reporter.as_ref();
+    let name = if options.name_transforms.is_empty() {
+        name
+    } else {
+        let transformed = options
+            .name_transforms
+            .iter()
+            .fold(Cow::Borrowed(raw_name), |name, transform| {
+                Cow::Owned(transform.apply(&name).into_owned())
+            });
+        match sequential::enclosed_path(&transformed) {
+            Some(transformed) => transformed,
+            None if transformed.trim_matches('/').is_empty() => {
+                progress_reporter.extraction_skipped(raw_name);
+                return None;
+            }
+            None => {
+                let message = format!(
+                    "Skipping {} as {transformed} is outside the output directory",
+                    name.display()
+                );
+                log::info!("{message}");
+                progress_reporter.warning(WarningKind::FileSkipped, &message);
+                progress_reporter.extraction_skipped(raw_name);
+                return None;
+            }
+        }
+    };
+    let name = if !options.flatten {
+        name
+    } else if raw_name.ends_with('/') {
+        progress_reporter.extraction_skipped(&name.display().to_string());
+        return None;
+    } else {
+        let case_insensitive = options.case_collisions != CaseCollisionPolicy::Allow;
+        let flattened = state.flattened_names.claim(&name, case_insensitive)?;
+        if flattened != name {
+            progress_reporter.file_renamed(
+                &name.display().to_string(),
+                &flattened.display().to_string(),
+            );
+        }
+        flattened
+    };
+    let renamed = match options.policy.path_sanitization.sanitize(&name) {
+        Cow::Owned(sanitized) => Some(sanitized),
+        Cow::Borrowed(_) => None,
+    };
+    // Flattening has already dealt with collisions.
+    let case_collision = state
+        .case_collisions
+        .get(raw_name)
+        .filter(|_| !options.flatten);
+    let renamed = match case_collision {
+        Some(CollisionResolution::Skip) => {
+            let message = format!(
+                "Skipping {} as its name collides with another file",
+                name.display()
+            );
+            log::info!("{message}");
+            progress_reporter.warning(WarningKind::FileSkipped, &message);
+            progress_reporter.extraction_skipped(&name.display().to_string());
+            return None;
+        }
+        Some(CollisionResolution::Rename(new_name)) => Some(new_name.clone()),
+        None => renamed,
+    };
+    let renamed =
+        match existing_file_action(raw_name, renamed.as_ref().unwrap_or(&name), options, state) {
+            ExistingFileAction::Overwrite => renamed,
+            ExistingFileAction::Skip => {
+                log::info!("Skipping {} as it already exists", name.display());
+                progress_reporter.extraction_skipped(&name.display().to_string());
+                return None;
+            }
+            ExistingFileAction::Rename(new_name) => {
+                match sequential::enclosed_path(&new_name.to_string_lossy()) {
+                    Some(new_name) => Some(new_name),
+                    None => {
+                        let message = format!(
+                            "Skipping {} as {} is outside the output directory",
+                            name.display(),
+                            new_name.display()
+                        );
+                        log::info!("{message}");
+                        progress_reporter.warning(WarningKind::FileSkipped, &message);
+                        progress_reporter.extraction_skipped(&name.display().to_string());
+                        return None;
+                    }
+                }
+            }
+        };
+    let Some(new_name) = renamed else {
+        return Some(name);
+    };
+    log::info!("Renaming {} to {}", name.display(), new_name.display());
+    progress_reporter.file_renamed(&name.display().to_string(), &new_name.display().to_string());
+    progress_reporter.warning(
+        WarningKind::FileRenamed,
+        &format!("Renamed {} to {}", name.display(), new_name.display()),
+    );
+    Some(new_name)
+}
+
+/// What to do about a file which is to be extracted to `path`, if it
+/// already exists. Directories are extracted into whatever's there.
+fn existing_file_action(
+    raw_name: &str,
+    path: &Path,
+    options: &UnzipOptions,
+    state: &UnzipState,
+) -> ExistingFileAction {
+    match (&options.policy.overwrite, &state.output_dir) {
+        (OverwritePolicy::Ask(handler), Some(output_dir))
+            if !raw_name.ends_with('/') && output_dir.symlink_metadata(path).is_ok() =>
+        {
+            handler.handle_existing_file(path)
+        }
+        _ => ExistingFileAction::Overwrite,
+    }
+}
+
+/// Progress bar strategy. The overall progress across the entire zip file must be
+/// denoted in terms of *compressed* bytes, since at the outset we don't know the uncompressed
+/// size of each file. Yet, within a given file, we update progress based on the bytes
+/// of uncompressed data written, once per 1MB, because that's the information that we happen
+/// to have available. So, calculate how many compressed bytes relate to 1MB of uncompressed
+/// data, and the remainder.
+fn progress_updater_for<'a>(
+    file: &ZipFile,
+    name: &Path,
+    options: &'a UnzipOptions,
+    state: &'a UnzipState,
+) -> ProgressUpdater<impl Fn(u64, u64) + 'a> {
+    let progress_reporter: &dyn UnzipProgressReporter = options.progress_reporter.as_ref();
+    let display_name = name.display().to_string();
+    let compressed_size = file.compressed_size();
+    ProgressUpdater::new(
+        move |external_progress, bytes_written| {
+            progress_reporter.file_bytes_extracted(
+                &display_name,
+                external_progress,
+                compressed_size,
+            );
+            state.progress_batcher.bytes_extracted_and_written(
+                progress_reporter,
+                external_progress,
+                bytes_written,
+            );
+        },
+        compressed_size,
+        file.size(),
+        1024 * 1024,
+    )
+}
+
+fn extract_to_sink(
+    file: &mut ZipFile,
+    name: &Path,
+    unix_mode: Option<u32>,
+    options: &UnzipOptions,
+    state: &UnzipState,
+    output_sink: &dyn OutputSink,
+) -> Result<()> {
+    let mut metadata = EntryMetadata {
+        unix_mode,
+        ..EntryMetadata::from_zip_file(file)
+    };
+    if let ModificationTimes::Fixed(time) = options.policy.mtimes {
+        metadata.last_modified = Some(time);
+    }
+    if file.is_dir() {
+        return output_sink.create_directory(name, &metadata);
+    }
+    let mut progress_updater = progress_updater_for(file, name, options, state);
+    let file = QuotaReader::new(file, state.output_quota.as_ref());
+    let mut reader = progress_streams::ProgressReader::new(file, |bytes_read| {
+        progress_updater.progress(bytes_read as u64)
+    });
+    output_sink.write_file(name, &metadata, &mut reader)?;
+    progress_updater.finish();
+    Ok(())
+}
+
+/// Returns whether the file was extracted, rather than being skipped
+/// because it's unchanged.
+fn extract_to_filesystem(
+    file: &mut ZipFile,
+    name: &Path,
+    unix_mode: Option<u32>,
+    options: &UnzipOptions,
+    state: &UnzipState,
+) -> Result<bool> {
+    let output_dir = state
+        .output_dir
+        .as_ref()
+        .expect("output directory is opened unless there's a sink");
+    if options.delete_extraneous {
+        state
+            .extracted_paths
+            .lock()
+            .unwrap()
+            .insert(name.to_path_buf());
+    }
+    let mut extracted = true;
     if file.name().ends_with('/') {
-        directory_creator.create_dir_all(&out_path)?;
+        state.directory_creator.create_dir_all(output_dir, name)?;
+    } else if is_symlink(unix_mode) && options.policy.symlinks != SymlinkPolicy::File {
+        state
+            .progress_batcher
+            .bytes_extracted(options.progress_reporter.as_ref(), file.compressed_size());
+        if options.policy.symlinks == SymlinkPolicy::Skip {
+            let message = format!("Skipping {} as it is a symbolic link", name.display());
+            log::info!("{message}");
+            options
+                .progress_reporter
+                .warning(WarningKind::FileSkipped, &message);
+            return Ok(false);
+        }
+        let mut target = String::new();
+        file.read_to_string(&mut target)
+            .with_context(|| "Failed to read link")?;
+        if let Some(parent) = name.parent() {
+            state.directory_creator.create_dir_all(output_dir, parent)?;
+        }
+        // Permissions and times are those of what the link points to.
+        state.symlinks.defer(name, PathBuf::from(target));
+        return Ok(true);
+    } else if options.policy.overwrite.is_freshen() && is_unchanged(output_dir, name, file)? {
+        log::debug!("Skipping {} as it is unchanged", name.display());
+        state
+            .progress_batcher
+            .bytes_extracted(options.progress_reporter.as_ref(), file.compressed_size());
+        extracted = false;
     } else {
-        if let Some(parent) = out_path.parent() {
-            directory_creator.create_dir_all(parent)?;
-        }
-        let out_file = File::create(&out_path).with_context(|| "Failed to create file")?;
-        // Progress bar strategy. The overall progress across the entire zip file must be
-        // denoted in terms of *compressed* bytes, since at the outset we don't know the uncompressed
-        // size of each file. Yet, within a given file, we update progress based on the bytes
-        // of uncompressed data written, once per 1MB, because that's the information that we happen
-        // to have available. So, calculate how many compressed bytes relate to 1MB of uncompressed
-        // data, and the remainder.
+        if let Some(parent) = name.parent() {
+            state.directory_creator.create_dir_all(output_dir, parent)?;
+        }
+        #[cfg(windows)]
+        let attributes = state
+            .dos_attributes
+            .get(file.name())
+            .copied()
+            .unwrap_or_default();
         let uncompressed_size = file.size();
-        let compressed_size = file.compressed_size();
-        let mut progress_updater = ProgressUpdater::new(
-            |external_progress| {
-                progress_reporter.bytes_extracted(external_progress);
-            },
-            compressed_size,
-            uncompressed_size,
-            1024 * 1024,
-        );
-        let mut out_file = progress_streams::ProgressWriter::new(out_file, |bytes_written| {
-            progress_updater.progress(bytes_written as u64)
+        let crc32 = file.crc32();
+        let mut progress_updater = progress_updater_for(file, name, options, state);
+        let dedup_key = options.hardlink_duplicates.then(|| DedupKey {
+            crc32: file.crc32(),
+            size: uncompressed_size,
+            unix_mode,
         });
-        // Using a BufWriter here doesn't improve performance even on a VM with
-        // spinny disks.
-        std::io::copy(&mut file, &mut out_file).with_context(|| "Failed to write directory")?;
+        // Large files which aren't compressed can be copied by several
+        // threads at once, and any such files may be copied by the kernel.
+        let chunked_copy = state
+            .raw_reader
+            .as_deref()
+            .filter(|raw_reader| {
+                file.compression() == zip::CompressionMethod::Stored
+                    && !file.encrypted()
+                    && (uncompressed_size >= 2 * CHUNK_SIZE || raw_reader.can_copy_to_file())
+                    && dedup_key.is_none()
+            })
+            .map(|raw_reader| (raw_reader, file.data_start(), file.crc32()));
+        let mut contents: Box<dyn Read + '_> = Box::new(&mut *file);
+        if let Some(original) = dedup_key
+            .and_then(|key| state.deduplicator.original_for(&key))
+            .filter(|original| original != name)
+        {
+            let original_file = output_dir
+                .open(&original)
+                .with_context(|| "Failed to open identical file")?;
+            match compare_contents(original_file, contents)
+                .with_context(|| "Failed to compare with identical file")?
+            {
+                None => {
+                    log::debug!(
+                        "Hardlinking {} to identical file {}",
+                        name.display(),
+                        original.display()
+                    );
+                    link_duplicate(output_dir, &original, name)?;
+                    progress_updater.finish();
+                    if options.fsync {
+                        state.directory_needs_sync(name);
+                    }
+                    return Ok(true);
+                }
+                // A CRC collision, so write the file out after all.
+                Some(mismatched_contents) => contents = Box::new(mismatched_contents),
+            }
+        }
+        let mut open_options = cap_std::fs::OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        #[cfg(windows)]
+        if options.policy.permissions.restore_hidden_attribute
+            && attributes & central_directory::FILE_ATTRIBUTE_HIDDEN != 0
+        {
+            use cap_std::fs::OpenOptionsExt;
+            open_options.attributes(central_directory::FILE_ATTRIBUTE_HIDDEN);
+        }
+        let out_file = output_dir
+            .open_with(name, &open_options)
+            .with_context(|| "Failed to create file")?
+            .into_std();
+        if !options.sparse {
+            // Preallocating would fill in any holes.
+            preallocate(&out_file, uncompressed_size, state.output_quota.as_ref())
+                .with_context(|| "Failed to allocate space for file")?;
+        }
+        let chunked_copy_used = chunked_copy.is_some();
+        let result = match chunked_copy {
+            Some((raw_reader, data_start, crc32)) => {
+                let progress_updater = Mutex::new(&mut progress_updater);
+                copy_in_chunks(
+                    raw_reader,
+                    data_start,
+                    uncompressed_size,
+                    crc32,
+                    &out_file,
+                    state.output_quota.as_ref(),
+                    |bytes| progress_updater.lock().unwrap().progress(bytes),
+                )
+                .map(|()| uncompressed_size)
+            }
+            None => stream_to_file(
+                &out_file,
+                contents,
+                uncompressed_size,
+                options,
+                state,
+                &mut progress_updater,
+            ),
+        };
+        let bytes_written = result.map_err(|error| {
+            if !options.sparse && chunked_copy_used {
+                // Chunks are written at their offsets, so none of them is
+                // known to follow on from the start of the file.
+                discard_preallocation(&out_file, 0);
+            }
+            error
+        })?;
         progress_updater.finish();
-    }
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        if let Some(mode) = file.unix_mode() {
-            std::fs::set_permissions(&out_path, std::fs::Permissions::from_mode(mode))
+        if bytes_written < uncompressed_size {
+            // Don't leave preallocated space on the end of the file if the
+            // zip entry turned out shorter than advertised.
+            out_file.set_len(bytes_written)?;
+        }
+        if options.fsync {
+            out_file
+                .sync_all()
+                .with_context(|| "Failed to sync file to disk")?;
+        }
+        #[cfg(windows)]
+        if attributes & central_directory::FILE_ATTRIBUTE_READONLY != 0 {
+            let mut permissions = out_file.metadata()?.permissions();
+            permissions.set_readonly(true);
+            out_file
+                .set_permissions(permissions)
                 .with_context(|| "Failed to set permissions")?;
         }
+        if let Some(dedup_key) = dedup_key {
+            state.deduplicator.record(dedup_key, name);
+        }
+        if options.verify_after {
+            state.written_files.record(name, crc32);
+        }
     }
-    log::debug!(
-        "Finished extract of file at {:x}, length {:x}, name {}",
-        file.data_start(),
-        file.compressed_size(),
-        display_name
+    if options.fsync {
+        // Directory syncs are batched up until the end, since many files
+        // typically share a directory.
+        state.directory_needs_sync(name);
+    }
+    #[cfg(unix)]
+    restore_permissions(output_dir, name, unix_mode, state)?;
+    state.directory_times.restore(
+        output_dir,
+        name,
+        file.is_dir(),
+        &EntryMetadata::from_zip_file(file),
+        options.policy.mtimes,
+    )?;
+    Ok(extracted)
+}
+
+/// Give an extracted file the permissions recorded in the archive, less
+/// any umask being applied.
+#[cfg(unix)]
+fn restore_permissions(
+    output_dir: &Dir,
+    name: &Path,
+    unix_mode: Option<u32>,
+    state: &UnzipState,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = unix_mode {
+        let permissions = cap_std::fs::Permissions::from_std(std::fs::Permissions::from_mode(
+            mode & !state.umask,
+        ));
+        output_dir
+            .set_permissions(name, permissions)
+            .with_context(|| "Failed to set permissions")?;
+    }
+    Ok(())
+}
+
+/// Write a file's contents out in the usual way, returning how many bytes
+/// were written. If that fails, the file is cut back to what was written,
+/// dropping any space preallocated beyond it.
+fn stream_to_file<F: Fn(u64, u64)>(
+    out_file: &File,
+    contents: impl Read,
+    uncompressed_size: u64,
+    options: &UnzipOptions,
+    state: &UnzipState,
+    progress_updater: &mut ProgressUpdater<F>,
+) -> Result<u64> {
+    let mut written_in_order = 0;
+    let result = write_to_file(
+        out_file,
+        contents,
+        uncompressed_size,
+        options,
+        state,
+        progress_updater,
+        &mut written_in_order,
+    );
+    if result.is_err() && !options.sparse {
+        discard_preallocation(out_file, written_in_order);
+    }
+    result
+}
+
+/// Does the work of [`stream_to_file`], setting `written_in_order` to how
+/// many bytes from the start of the file are known to have been written,
+/// even if writing fails.
+fn write_to_file<F: Fn(u64, u64)>(
+    out_file: &File,
+    contents: impl Read,
+    uncompressed_size: u64,
+    options: &UnzipOptions,
+    state: &UnzipState,
+    progress_updater: &mut ProgressUpdater<F>,
+    written_in_order: &mut u64,
+) -> Result<u64> {
+    let double_buffered = uncompressed_size >= DOUBLE_BUFFER_THRESHOLD;
+    let mut out_file_writer = out_file;
+    let mut sparse_writer = options.sparse.then(|| SparseWriter::new(out_file));
+    let sink: &mut (dyn Write + Send) = match &mut sparse_writer {
+        Some(sparse_writer) => sparse_writer,
+        None => &mut out_file_writer,
+    };
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    let mut uring_writer = if options.sparse {
+        // Sparse output relies on seeking, so is written synchronously.
+        None
+    } else {
+        uring_writer::UringFileWriter::new(out_file)?
+    };
+    // io_uring writes are asynchronous already.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    let double_buffered = double_buffered && uring_writer.is_none();
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    let sink: &mut (dyn Write + Send) = match &mut uring_writer {
+        Some(uring_writer) => uring_writer,
+        None => sink,
+    };
+    let mut reader = progress_streams::ProgressReader::new(
+        QuotaReader::new(contents, state.output_quota.as_ref()),
+        |bytes_read| progress_updater.progress(bytes_read as u64),
     );
-    progress_reporter.extraction_finished(&display_name);
+    let mut sink = CountingWriter {
+        inner: sink,
+        written: 0,
+    };
+    // Using a BufWriter here doesn't improve performance even on a VM with
+    // spinny disks.
+    let result = if double_buffered {
+        buffer_pool::copy_double_buffered(&mut reader, &mut sink)
+    } else {
+        buffer_pool::copy(&mut reader, &mut TimedWriter(&mut sink))
+    };
+    *written_in_order = sink.written;
+    // io_uring writes at offsets, and may not have finished.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    if uring_writer.is_some() {
+        *written_in_order = 0;
+    }
+    let bytes_written = result.with_context(|| "Failed to write file")?;
+    if let Some(sparse_writer) = sparse_writer {
+        sparse_writer.finish()?;
+    }
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    if let Some(uring_writer) = uring_writer {
+        uring_writer.finish()?;
+    }
+    Ok(bytes_written)
+}
+
+/// A writer which counts the bytes written through it.
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.written += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether the file already exists in the output directory with the same
+/// contents as the zip entry, judging by its size and CRC.
+fn is_unchanged(output_dir: &Dir, name: &Path, file: &ZipFile) -> Result<bool> {
+    let existing = match output_dir.open(name) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| "Failed to open existing file"),
+    };
+    let metadata = existing.metadata()?;
+    if !metadata.is_file() || metadata.len() != file.size() {
+        return Ok(false);
+    }
+    let crc32 = crc32_of(existing).with_context(|| "Failed to read existing file")?;
+    Ok(crc32 == file.crc32())
+}
+
+fn crc32_of(mut reader: impl Read) -> std::io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = PooledBuffer::take();
+    loop {
+        let bytes_read = reader.read(&mut buf)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// The most space reserved for any one file. Lengths come from the archive,
+/// so can't be trusted, and reserving far more than is written could fill
+/// the disk, or stop extraction which would have fitted.
+const MAX_PREALLOCATION: u64 = 1024 * 1024 * 1024;
+
+/// Reserve disk space for a file of the given length before we write to it.
+/// This reduces fragmentation, and means we run out of disk space up front
+/// rather than partway through writing a large file. Filesystems which don't
+/// support allocation just have the file length set. No more is reserved
+/// than [`MAX_PREALLOCATION`], or than `output_quota` still allows.
+fn preallocate(file: &File, len: u64, output_quota: Option<&OutputQuota>) -> std::io::Result<()> {
+    let len = len
+        .min(MAX_PREALLOCATION)
+        .min(output_quota.map_or(u64::MAX, OutputQuota::remaining));
+    if len == 0 {
+        return Ok(());
+    }
+    match fs2::FileExt::allocate(file, len) {
+        Err(e) if e.kind() == ErrorKind::Unsupported => file.set_len(len),
+        result => result,
+    }
+}
+
+/// Give back the space reserved for a file which failed to extract, so
+/// that it isn't left at its full length as if it had been. Only the first
+/// `written` bytes, which are known to have been written, are kept.
+fn discard_preallocation(file: &File, written: u64) {
+    if let Err(e) = file.set_len(written) {
+        log::debug!("Failed to truncate partially extracted file: {e}");
+    }
+}
+
+/// State shared between all the threads working on a single unzip.
+#[derive(Default)]
+struct UnzipState {
+    directory_creator: DirectoryCreator,
+    /// Directories whose entries have changed and which must be synced to
+    /// disk before we're finished, if we've been asked to do so.
+    directories_to_sync: Mutex<HashSet<PathBuf>>,
+    /// Files which must be renamed or skipped because their names collide
+    /// with others.
+    case_collisions: HashMap<String, CollisionResolution>,
+    /// The directory into which files are extracted, unless they're going
+    /// to an [`OutputSink`].
+    output_dir: Option<Dir>,
+    /// The limit on the total number of bytes extracted, if any.
+    output_quota: Option<OutputQuota>,
+    /// Permission bits to clear on extracted files.
+    #[cfg(unix)]
+    umask: u32,
+    /// MS-DOS attributes to restore, by the names of files in the zip.
+    #[cfg(windows)]
+    dos_attributes: HashMap<String, u32>,
+    /// Everything which has been extracted, if we'll need to know that in
+    /// order to remove anything else.
+    extracted_paths: Mutex<HashSet<PathBuf>>,
+    /// Files which later identical files may be hardlinked to.
+    deduplicator: Deduplicator,
+    /// Modification times to give directories once everything has been
+    /// extracted.
+    directory_times: DirectoryTimes,
+    /// Used to copy large uncompressed files in chunks, if that's possible.
+    raw_reader: Option<Box<dyn RawReader>>,
+    progress_batcher: ProgressBatcher,
+    /// Limits how many files are extracted at once, if that's to be tuned
+    /// rather than being a thread per CPU.
+    thread_tuner: Option<ThreadTuner>,
+    /// The files written out, if they're to be verified.
+    written_files: WrittenFiles,
+    /// Links to create once everything else has been extracted.
+    symlinks: DeferredSymlinks,
+    /// AppleDouble files to restore once everything has been extracted.
+    apple_double: DeferredAppleDouble,
+    /// The names given to files so far, if flattening.
+    flattened_names: FlattenedNames,
+    /// Where the time went while extracting files.
+    timings: Mutex<Timings>,
+}
+
+impl UnzipState {
+    /// Note that the directory containing this path has changed.
+    fn directory_needs_sync(&self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            self.directories_to_sync
+                .lock()
+                .unwrap()
+                .insert(parent.to_path_buf());
+        }
+    }
+
+    fn sync_directories(&self) -> Result<()> {
+        let directories = std::mem::take(&mut *self.directories_to_sync.lock().unwrap());
+        log::debug!("Syncing {} directories", directories.len());
+        #[cfg(unix)]
+        if let Some(output_dir) = &self.output_dir {
+            for directory in directories {
+                let directory = if directory.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    &directory
+                };
+                // open_dir may use O_PATH, which gives a handle that can't be synced.
+                output_dir
+                    .open(directory)
+                    .and_then(|directory| directory.sync_all())
+                    .with_context(|| format!("Failed to sync directory {}", directory.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flush a directory's entries to disk. This is only possible on Unix;
+/// elsewhere, this does nothing.
+fn sync_directory(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(path)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Failed to sync directory {}", path.display()))?;
+    #[cfg(not(unix))]
+    let _ = path;
     Ok(())
 }
 
-/// An engine used to ensure we don't conflict in creating directories
-/// between threads
+/// Sandbox each of the threads in `pool`, so that they can only reach files
+/// beneath the output directory, or the temporary directory for output
+/// sinks.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+fn sandbox_pool(pool: &rayon::ThreadPool, options: &UnzipOptions) -> Result<()> {
+    let directory = match (&options.output_sink, &options.output_directory) {
+        // Output sinks may need to spool data to temporary files.
+        (Some(_), _) => std::env::temp_dir(),
+        (None, Some(output_directory)) => output_directory.clone(),
+        (None, None) => PathBuf::from("."),
+    };
+    let sandbox = sandbox::Sandbox::new(&[&directory])?;
+    pool.broadcast(|_| sandbox.apply())
+        .into_iter()
+        .collect::<Result<()>>()
+}
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+fn sandbox_pool(_pool: &rayon::ThreadPool, _options: &UnzipOptions) -> Result<()> {
+    bail!("Extraction can only be sandboxed on Linux, when built with the sandbox feature")
+}
+
+/// Read the process umask, to pass as [`UnzipOptions::umask`]. On Linux
+/// it's read from `/proc`, but elsewhere it can only be read by setting it,
+/// so it's briefly zero, and anything created by other threads at that
+/// moment may get more permissions than intended. So this is best called
+/// before any other threads are started.
+#[cfg(unix)]
+pub fn process_umask() -> Option<u32> {
+    use rustix::{fs::Mode, process::umask};
+    #[cfg(target_os = "linux")]
+    if let Some(mask) = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let mask = status
+                .lines()
+                .find_map(|line| line.strip_prefix("Umask:"))?;
+            u32::from_str_radix(mask.trim(), 8).ok()
+        })
+    {
+        return Some(mask);
+    }
+    let mask = umask(Mode::empty());
+    umask(mask);
+    Some(mask.bits() as u32)
+}
+
+/// There's no umask to read on this platform.
+#[cfg(not(unix))]
+pub fn process_umask() -> Option<u32> {
+    None
+}
+
+/// Creates directories, remembering which ones exist so that each is only
+/// looked for once, however many files are extracted into it.
 #[derive(Default)]
-struct DirectoryCreator(Mutex<()>);
+struct DirectoryCreator {
+    /// Directories which have been created, or found to exist already.
+    created: RwLock<HashSet<PathBuf>>,
+    /// Held while creating directories, so that threads don't conflict.
+    creating: Mutex<()>,
+}
 
 impl DirectoryCreator {
-    fn create_dir_all(&self, path: &Path) -> Result<()> {
-        // Fast path - avoid locking if the directory exists
-        if path.exists() {
+    fn create_dir_all(&self, dir: &Dir, path: &Path) -> Result<()> {
+        // Fast path - avoid the exclusive lock if we know it exists
+        if self.created.read().unwrap().contains(path) {
             return Ok(());
         }
-        let _exclusivity = self.0.lock().unwrap();
-        if path.exists() {
+        let _exclusivity = self.creating.lock().unwrap();
+        if self.created.read().unwrap().contains(path) {
             return Ok(());
         }
-        std::fs::create_dir_all(path).with_context(|| "Failed to create directory")
+        dir.create_dir_all(path)
+            .with_context(|| "Failed to create directory")?;
+        // Its ancestors exist now too.
+        let mut created = self.created.write().unwrap();
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() || !created.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::FilenameFilter;
-    use crate::{NullProgressReporter, UnzipEngine, UnzipOptions};
+    use super::{DirectoryCreator, FilenameFilter, STREAMING_THRESHOLD};
+    use crate::{
+        ArchiveSummary, CaseCollisionPolicy, ConcatSink, CrxHeader, ExistingFileAction,
+        ExistingFileHandler, ExtractionPolicy, FilenameSanitization, FilterSet, HttpOptions,
+        MemoryBudget, MetadataFilter, ModificationTimes, NullProgressReporter, OverwritePolicy,
+        PermissionsPolicy, ReadaheadWatermarks, SymlinkPolicy, TarSink, Timings, UnzipEngine,
+        UnzipOptions, UnzipProgressReporter, WarningKind, ZipCompression, ZipSink,
+    };
     use httptest::Server;
     use ripunzip_test_utils::*;
     use std::{
-        collections::HashSet,
+        collections::{HashMap, HashSet},
         env::{current_dir, set_current_dir},
         fs::{read_to_string, File},
         io::{Cursor, Seek, Write},
-        path::Path,
+        ops::Range,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::Duration,
     };
     use tempfile::tempdir;
     use test_log::test;
     use zip::{unstable::write::FileOptionsExt, write::ExtendedFileOptions};
-    use zip::{write::FileOptions, ZipWriter};
+    use zip::{
+        write::{FileOptions, SimpleFileOptions},
+        ZipWriter,
+    };
 
     struct UnzipSomeFilter;
     impl FilenameFilter for UnzipSomeFilter {
@@ -518,10 +2805,10 @@ mod tests {
 
     fn run_with_and_without_a_filename_filter<F>(fun: F)
     where
-        F: Fn(bool, Option<Box<dyn FilenameFilter + Sync>>),
+        F: Fn(bool, FilterSet),
     {
-        fun(true, None);
-        fun(false, Some(Box::new(UnzipSomeFilter)));
+        fun(true, FilterSet::default());
+        fun(false, FilterSet::new().filter(UnzipSomeFilter));
     }
 
     fn create_zip_file(path: &Path, include_a_txt: bool) {
@@ -564,6 +2851,33 @@ mod tests {
         zip.finish().unwrap();
     }
 
+    /// Options to extract into `output_directory`, for tests to override.
+    fn options_for<'a, 'b>(output_directory: &Path) -> UnzipOptions<'a, 'b> {
+        UnzipOptions {
+            output_directory: Some(output_directory.to_path_buf()),
+            ..test_options()
+        }
+    }
+
+    /// Options for tests to override. Names are neither sanitized nor
+    /// stopped from differing only in case on any platform, so tests behave
+    /// the same everywhere.
+    fn test_options<'a, 'b>() -> UnzipOptions<'a, 'b> {
+        UnzipOptions {
+            policy: test_policy(),
+            case_collisions: CaseCollisionPolicy::Allow,
+            ..Default::default()
+        }
+    }
+
+    /// The extraction policy of [`test_options`], for tests to override.
+    fn test_policy<'a>() -> ExtractionPolicy<'a> {
+        ExtractionPolicy {
+            path_sanitization: FilenameSanitization::None,
+            ..Default::default()
+        }
+    }
+
     fn check_files_exist(path: &Path, include_a_txt: bool) {
         let a = path.join("test/a.txt");
         let b = path.join("b.txt");
@@ -577,10 +2891,29 @@ mod tests {
         assert_eq!(read_to_string(c).unwrap(), "Contents of C\n");
     }
 
+    #[test]
+    fn test_extraction_order() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, len) in [("small", 1), ("large", 1000), ("medium", 100)] {
+            zip.start_file::<_, ExtendedFileOptions>(name, Default::default())
+                .unwrap();
+            zip.write_all(&vec![b'x'; len]).unwrap();
+        }
+        let archive = zip::ZipArchive::new(zip.finish().unwrap()).unwrap();
+        let (by_size, _) = super::extraction_order(&archive, &NullProgressReporter, |entry| {
+            std::cmp::Reverse(entry.compressed_size)
+        });
+        assert_eq!(by_size, [1, 2, 0]);
+        let (by_offset, entries) =
+            super::extraction_order(&archive, &NullProgressReporter, |entry| entry.header_offset);
+        assert_eq!(by_offset, [0, 1, 2]);
+        assert_eq!(entries.len(), 3);
+    }
+
     #[test]
     #[ignore] // because the chdir changes global state
     fn test_extract_no_path() {
-        run_with_and_without_a_filename_filter(|create_a, filename_filter| {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
             let td = tempdir().unwrap();
             let zf = td.path().join("z.zip");
             create_zip_file(&zf, create_a);
@@ -588,11 +2921,8 @@ mod tests {
             let old_dir = current_dir().unwrap();
             set_current_dir(td.path()).unwrap();
             let options = UnzipOptions {
-                output_directory: None,
-                password: None,
-                single_threaded: false,
-                filename_filter,
-                progress_reporter: Box::new(NullProgressReporter),
+                filters,
+                ..test_options()
             };
             UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
             set_current_dir(old_dir).unwrap();
@@ -602,139 +2932,2170 @@ mod tests {
 
     #[test]
     fn test_extract_with_path() {
-        run_with_and_without_a_filename_filter(|create_a, filename_filter| {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
             let td = tempdir().unwrap();
             let zf = td.path().join("z.zip");
             create_zip_file(&zf, create_a);
             let zf = File::open(zf).unwrap();
             let outdir = td.path().join("outdir");
             let options = UnzipOptions {
-                output_directory: Some(outdir.clone()),
-                password: None,
-                single_threaded: false,
-                filename_filter,
-                progress_reporter: Box::new(NullProgressReporter),
+                filters,
+                ..options_for(&outdir)
             };
             UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
             check_files_exist(&outdir, create_a);
         });
     }
 
+    fn staging_leftovers(path: &Path) -> Vec<String> {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with(".ripunzip-staging-"))
+            .collect()
+    }
+
     #[test]
-    fn test_extract_encrypted_with_path() {
-        run_with_and_without_a_filename_filter(|create_a, filename_filter| {
-            let td = tempdir().unwrap();
-            let zf = td.path().join("z.zip");
-            create_encrypted_zip_file(&zf, create_a);
+    fn test_preallocate() {
+        let td = tempdir().unwrap();
+        let path = td.path().join("f");
+        let file = File::create(&path).unwrap();
+        super::preallocate(&file, 0, None).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        super::preallocate(&file, 100000, None).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 100000);
+        // No more is reserved than could be written.
+        let file = File::create(td.path().join("g")).unwrap();
+        let quota = super::OutputQuota::new(1000);
+        super::preallocate(&file, 100000, Some(&quota)).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_extract_sparse() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut contents = vec![0u8; 1024 * 1024];
+        contents[1000] = 1;
+        {
+            let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+            zip.start_file::<_, ()>("disk.img", FileOptions::default())
+                .unwrap();
+            zip.write_all(&contents).unwrap();
+            zip.finish().unwrap();
+        }
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            sparse: true,
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_file(File::open(zf).unwrap())
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+        assert_eq!(std::fs::read(outdir.join("disk.img")).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_extract_to_tar() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, create_a);
             let zf = File::open(zf).unwrap();
+            let tar_sink = TarSink::new(Vec::new());
+            let options = UnzipOptions {
+                filters,
+                output_sink: Some(Box::new(&tar_sink)),
+                ..test_options()
+            };
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            let tar = tar_sink.into_inner().unwrap();
             let outdir = td.path().join("outdir");
+            tar::Archive::new(Cursor::new(tar)).unpack(&outdir).unwrap();
+            check_files_exist(&outdir, create_a);
+        });
+    }
+
+    #[test]
+    fn test_extract_to_zip() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, create_a);
+            let zf = File::open(zf).unwrap();
+            let zip_sink = ZipSink::new(Cursor::new(Vec::new())).compression(ZipCompression::Zstd);
             let options = UnzipOptions {
-                output_directory: Some(outdir.clone()),
-                password: Some("1Password".to_string()),
-                single_threaded: false,
-                filename_filter,
-                progress_reporter: Box::new(NullProgressReporter),
+                filters,
+                output_sink: Some(Box::new(&zip_sink)),
+                ..test_options()
             };
             UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            let repacked = zip_sink.into_inner().unwrap().into_inner();
+            let mut archive = zip::ZipArchive::new(Cursor::new(repacked)).unwrap();
+            for i in 0..archive.len() {
+                let file = archive.by_index(i).unwrap();
+                if file.is_file() {
+                    assert_eq!(file.compression(), zip::CompressionMethod::Zstd);
+                }
+            }
+            let outdir = td.path().join("outdir");
+            archive.extract(&outdir).unwrap();
             check_files_exist(&outdir, create_a);
         });
     }
 
     #[test]
-    fn test_list() {
+    fn test_sandbox() {
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        if !super::sandbox::is_supported() {
+            log::warn!("Skipping test_sandbox, as this kernel doesn't support Landlock");
+            return;
+        }
         let td = tempdir().unwrap();
         let zf = td.path().join("z.zip");
         create_zip_file(&zf, true);
-        let zf = File::open(zf).unwrap();
-        let filenames: HashSet<_> = UnzipEngine::for_file(zf).unwrap().list().unwrap().collect();
-        assert_eq!(
-            filenames,
-            ["test/", "test/a.txt", "b.txt", "test/c.txt"]
-                .into_iter()
-                .map(|s| s.to_string())
-                .collect()
-        )
+        for entries in [false, true] {
+            let outdir = td.path().join(format!("outdir{entries}"));
+            let options = UnzipOptions {
+                sandbox: true,
+                ..options_for(&outdir)
+            };
+            let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+            let result = if entries {
+                let count = engine.list_details().unwrap().len();
+                UnzipEngine::for_file(File::open(&zf).unwrap())
+                    .unwrap()
+                    .unzip_entries(0..count, options)
+            } else {
+                engine.unzip(options)
+            };
+            if cfg!(all(feature = "sandbox", target_os = "linux")) {
+                result.unwrap();
+                check_files_exist(&outdir, true);
+            } else {
+                assert!(result.is_err());
+            }
+        }
     }
 
     #[test]
-    fn test_extract_from_server() {
-        run_with_and_without_a_filename_filter(|create_a, filename_filter| {
+    fn test_extract_to_concat_sink() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
             let td = tempdir().unwrap();
-            let mut zip_data = Cursor::new(Vec::new());
-            create_zip(&mut zip_data, create_a, None);
-            let body = zip_data.into_inner();
-            println!("Whole zip:");
-            hexdump::hexdump(&body);
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, create_a);
+            let zf = File::open(zf).unwrap();
+            let concat_sink = ConcatSink::new(Vec::new());
+            let options = UnzipOptions {
+                single_threaded: true,
+                filters,
+                output_sink: Some(Box::new(&concat_sink)),
+                ..test_options()
+            };
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            let expected = if create_a {
+                "Contents of A\nContents of B\nContents of C\n"
+            } else {
+                "Contents of B\nContents of C\n"
+            };
+            assert_eq!(
+                String::from_utf8(concat_sink.into_inner()).unwrap(),
+                expected
+            );
+        });
+    }
 
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_extract_to_s3() {
+        use crate::{S3Credentials, S3Sink};
+        use httptest::{matchers::request::method_path, responders::status_code, Expectation};
+        // All the files are 14 bytes, so the smaller part size results in
+        // multipart uploads.
+        for part_size in [100, 10] {
             let server = Server::run();
-            set_up_server(&server, body, ServerType::Ranges);
-
-            let outdir = td.path().join("outdir");
+            for file in ["test/a.txt", "b.txt", "test/c.txt"] {
+                let path = format!("/bucket/out/{file}");
+                if part_size == 100 {
+                    server.expect(
+                        Expectation::matching(method_path("PUT", &path))
+                            .respond_with(status_code(200)),
+                    );
+                } else {
+                    // Initiating and completing the upload.
+                    server.expect(
+                        Expectation::matching(method_path("POST", &path))
+                            .times(2)
+                            .respond_with(status_code(200).body(
+                                "<InitiateMultipartUploadResult><UploadId>id</UploadId></InitiateMultipartUploadResult>",
+                            )),
+                    );
+                    server.expect(
+                        Expectation::matching(method_path("PUT", &path))
+                            .times(2)
+                            .respond_with(status_code(200).insert_header("ETag", "\"etag\"")),
+                    );
+                }
+            }
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, true);
+            let credentials = S3Credentials {
+                access_key_id: "id".into(),
+                secret_access_key: "secret".into(),
+                session_token: None,
+            };
+            let sink = S3Sink::new("bucket", "out/", "us-east-1", credentials)
+                .with_endpoint(&server.url_str(""))
+                .with_part_size(part_size);
             let options = UnzipOptions {
-                output_directory: Some(outdir.clone()),
-                password: None,
-                single_threaded: false,
-                filename_filter,
-                progress_reporter: Box::new(NullProgressReporter),
+                output_sink: Some(Box::new(sink)),
+                ..test_options()
             };
-            UnzipEngine::for_uri(&server.url("/foo").to_string(), None, || {})
+            UnzipEngine::for_file(File::open(zf).unwrap())
                 .unwrap()
                 .unzip(options)
                 .unwrap();
-            check_files_exist(&outdir, create_a);
-        });
+        }
     }
 
-    fn unzip_sample_zip(zip_params: ZipParams, server_type: ServerType) {
+    #[test]
+    fn test_extract_with_sanitization() {
+        struct RenameRecorder(
+            std::sync::Mutex<Vec<(String, String)>>,
+            std::sync::Mutex<Vec<WarningKind>>,
+        );
+        impl UnzipProgressReporter for &RenameRecorder {
+            fn file_renamed(&self, original_name: &str, new_name: &str) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((original_name.to_string(), new_name.to_string()));
+            }
+            fn warning(&self, kind: WarningKind, _message: &str) {
+                self.1.lock().unwrap().push(kind);
+            }
+        }
         let td = tempdir().unwrap();
-        let zip_data = ripunzip_test_utils::get_sample_zip(&zip_params);
+        let zf = td.path().join("z.zip");
+        {
+            let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+            for name in ["a?b.txt", "aux.c", "fine.txt"] {
+                zip.start_file::<_, ()>(name, FileOptions::default())
+                    .unwrap();
+                zip.write_all(b"Contents\n").unwrap();
+            }
+            zip.finish().unwrap();
+        }
+        let outdir = td.path().join("outdir");
+        let recorder = RenameRecorder(Default::default(), Default::default());
+        let options = UnzipOptions {
+            single_threaded: true,
+            progress_reporter: Box::new(&recorder),
+            policy: ExtractionPolicy {
+                path_sanitization: FilenameSanitization::Replace('-'),
+                ..Default::default()
+            },
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_file(File::open(zf).unwrap())
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+        for name in ["a-b.txt", "-ux.c", "fine.txt"] {
+            assert!(outdir.join(name).exists());
+        }
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                ("a?b.txt".to_string(), "a-b.txt".to_string()),
+                ("aux.c".to_string(), "-ux.c".to_string())
+            ]
+        );
+        assert_eq!(
+            *recorder.1.lock().unwrap(),
+            [WarningKind::FileRenamed, WarningKind::FileRenamed]
+        );
+    }
 
-        let server = Server::run();
-        set_up_server(&server, zip_data, server_type);
+    #[test]
+    fn test_extract_with_case_collisions() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        {
+            let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+            for name in ["README", "readme"] {
+                zip.start_file::<_, ()>(name, FileOptions::default())
+                    .unwrap();
+                zip.write_all(name.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+        for (policy, expected) in [
+            (CaseCollisionPolicy::Rename, Some("readme")),
+            (CaseCollisionPolicy::Skip, None),
+        ] {
+            let outdir = td.path().join(format!("{policy:?}"));
+            let options = UnzipOptions {
+                case_collisions: policy,
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_file(File::open(&zf).unwrap())
+                .unwrap()
+                .unzip(options)
+                .unwrap();
+            assert_eq!(read_to_string(outdir.join("README")).unwrap(), "README");
+            assert_eq!(
+                read_to_string(outdir.join("readme (1)")).ok().as_deref(),
+                expected
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_with_umask() {
+        use std::os::unix::fs::PermissionsExt;
+        for apply_umask in [false, true] {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            let options = FileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored)
+                .unix_permissions(0o777);
+            create_zip(File::create(&zf).unwrap(), true, Some(options));
+            let zf = File::open(zf).unwrap();
+            let outdir = td.path().join("outdir");
+            let options = UnzipOptions {
+                policy: ExtractionPolicy {
+                    permissions: PermissionsPolicy {
+                        apply_umask,
+                        ..Default::default()
+                    },
+                    ..test_policy()
+                },
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            let expected = if apply_umask {
+                0o777 & !super::process_umask().unwrap()
+            } else {
+                0o777
+            };
+            let mode = std::fs::metadata(outdir.join("b.txt"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, expected);
+        }
+    }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_through_symlink_refused() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let outside = td.path().join("outside");
         let outdir = td.path().join("outdir");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&outdir).unwrap();
+        std::os::unix::fs::symlink(&outside, outdir.join("test")).unwrap();
         let options = UnzipOptions {
-            output_directory: Some(outdir),
-            password: None,
-            single_threaded: false,
-            filename_filter: None,
-            progress_reporter: Box::new(NullProgressReporter),
+            ..options_for(&outdir)
         };
-        UnzipEngine::for_uri(&server.url("/foo").to_string(), None, || {})
+        assert!(UnzipEngine::for_file(File::open(zf).unwrap())
             .unwrap()
             .unzip(options)
+            .is_err());
+        assert!(!outside.join("a.txt").exists());
+        assert!(!outside.join("c.txt").exists());
+    }
+
+    #[test]
+    fn test_extract_with_max_output_size() {
+        // The three files in the zip are 14 bytes each.
+        for (max_output_size, should_succeed) in [(42, true), (41, false)] {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, true);
+            let zf = File::open(zf).unwrap();
+            let outdir = td.path().join("outdir");
+            let options = UnzipOptions {
+                max_output_size: Some(max_output_size),
+                ..options_for(&outdir)
+            };
+            let result = UnzipEngine::for_file(zf).unwrap().unzip(options);
+            assert_eq!(result.is_ok(), should_succeed);
+        }
+    }
+
+    #[test]
+    fn test_failed_extract_is_truncated() {
+        /// A reader which fails once `data` has been read.
+        struct FailingReader<'a>(&'a [u8]);
+        impl std::io::Read for FailingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(std::io::Error::other("failed"));
+                }
+                std::io::Read::read(&mut self.0, buf)
+            }
+        }
+        let td = tempdir().unwrap();
+        let contents: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        // Small files are written as they're read, and bigger ones on
+        // another thread a buffer at a time, so fail after whole buffers.
+        let buffer = crate::unzip::buffer_pool::BUFFER_SIZE;
+        for (len, fail_after) in [(1000, 300), (contents.len(), 3 * buffer)] {
+            assert_eq!(
+                len as u64 >= super::DOUBLE_BUFFER_THRESHOLD,
+                fail_after % buffer == 0
+            );
+            let path = td.path().join(format!("{len}"));
+            let file = File::create(&path).unwrap();
+            super::preallocate(&file, len as u64, None).unwrap();
+            let result = super::stream_to_file(
+                &file,
+                FailingReader(&contents[..fail_after]),
+                len as u64,
+                &UnzipOptions::default(),
+                &super::UnzipState::default(),
+                &mut super::ProgressUpdater::new(|_, _| {}, 0, len as u64, 1),
+            );
+            assert!(result.is_err());
+            drop(file);
+            let written = std::fs::read(&path).unwrap();
+            // Writes through io_uring aren't known to have been made in
+            // order, so nothing is kept.
+            if !(cfg!(all(feature = "io_uring", target_os = "linux")) && written.is_empty()) {
+                assert_eq!(written, &contents[..fail_after]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let mut archive = zip::ZipArchive::new(File::open(zf).unwrap()).unwrap();
+        let file = archive.by_name("b.txt").unwrap();
+        let dir =
+            cap_std::fs::Dir::open_ambient_dir(td.path(), cap_std::ambient_authority()).unwrap();
+        let name = Path::new("b.txt");
+        assert!(!super::is_unchanged(&dir, name, &file).unwrap());
+        std::fs::write(td.path().join(name), "Contents of B\n").unwrap();
+        assert!(super::is_unchanged(&dir, name, &file).unwrap());
+        std::fs::write(td.path().join(name), "Contents of X\n").unwrap();
+        assert!(!super::is_unchanged(&dir, name, &file).unwrap());
+        std::fs::write(td.path().join(name), "Contents of B\n\n").unwrap();
+        assert!(!super::is_unchanged(&dir, name, &file).unwrap());
+    }
+
+    #[test]
+    fn test_total_entries_expected() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        #[derive(Default)]
+        struct EntryCounter {
+            expected: AtomicU64,
+            finished: AtomicU64,
+            skipped: AtomicU64,
+        }
+        impl UnzipProgressReporter for &EntryCounter {
+            fn total_entries_expected(&self, expected: u64) {
+                self.expected.store(expected, Ordering::SeqCst);
+            }
+            fn extraction_finished(&self, _display_name: &str) {
+                self.finished.fetch_add(1, Ordering::SeqCst);
+            }
+            fn extraction_skipped(&self, _display_name: &str) {
+                self.skipped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, create_a);
+            let counter = EntryCounter::default();
+            let options = UnzipOptions {
+                filters,
+                progress_reporter: Box::new(&counter),
+                ..options_for(&td.path().join("outdir"))
+            };
+            let zf = File::open(&zf).unwrap();
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            let expected = if create_a { 4 } else { 3 };
+            assert_eq!(counter.expected.load(Ordering::SeqCst), expected);
+            assert_eq!(
+                counter.finished.load(Ordering::SeqCst) + counter.skipped.load(Ordering::SeqCst),
+                expected
+            );
+        });
+    }
+
+    #[test]
+    fn test_timings() {
+        #[derive(Default)]
+        struct TimingsRecorder(Mutex<Option<Timings>>);
+        impl UnzipProgressReporter for &TimingsRecorder {
+            fn timings(&self, timings: &Timings) {
+                *self.0.lock().unwrap() = Some(*timings);
+            }
+        }
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let recorder = TimingsRecorder::default();
+        let options = UnzipOptions {
+            progress_reporter: Box::new(&recorder),
+            ..options_for(&td.path().join("outdir"))
+        };
+        let zf = File::open(&zf).unwrap();
+        UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+        let timings = recorder.0.lock().unwrap().unwrap();
+        assert!(timings.opening > Duration::ZERO);
+        // Nothing was fetched over the network.
+        assert_eq!(timings.network_wait, Duration::ZERO);
+        assert!(timings.decompression + timings.writing > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_summary() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let summary = UnzipEngine::for_file(File::open(&zf).unwrap())
+            .unwrap()
+            .summary()
+            .unwrap();
+        assert_eq!(
+            summary,
+            ArchiveSummary {
+                entry_count: 4,
+                compressed_size: 42,
+                uncompressed_size: 42,
+                has_encrypted_entries: false,
+                has_unsupported_entries: false,
+                crx_header: None,
+            }
+        );
+        create_encrypted_zip_file(&zf, false);
+        let summary = UnzipEngine::for_file(File::open(&zf).unwrap())
+            .unwrap()
+            .summary()
+            .unwrap();
+        assert_eq!(summary.entry_count, 3);
+        assert!(summary.has_encrypted_entries);
+        assert!(!summary.has_unsupported_entries);
+        // Claim that the first file in the central directory is imploded.
+        let mut zip_data = std::fs::read(&zf).unwrap();
+        let central_directory = zip_data
+            .windows(4)
+            .position(|window| window == b"PK\x01\x02")
+            .unwrap();
+        zip_data[central_directory + 10] = 6;
+        std::fs::write(&zf, zip_data).unwrap();
+        let summary = UnzipEngine::for_file(File::open(&zf).unwrap())
+            .unwrap()
+            .summary()
             .unwrap();
+        assert!(summary.has_unsupported_entries);
     }
 
     #[test]
-    fn test_extract_biggish_zip_from_ranges_server() {
-        unzip_sample_zip(
-            ZipParams::new(FileSizes::Variable, 15, zip::CompressionMethod::Deflated),
-            ServerType::Ranges,
+    fn test_open_entry() {
+        use std::io::Read;
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+        let mut contents = String::new();
+        engine
+            .open_entry("test/c.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Contents of C\n");
+        assert!(engine.open_entry("d.txt").is_err());
+
+        let server = Server::run();
+        set_up_server(&server, std::fs::read(&zf).unwrap(), ServerType::Ranges);
+        let engine = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
         )
+        .unwrap();
+        let mut contents = String::new();
+        engine
+            .open_entry("b.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Contents of B\n");
     }
 
     #[test]
-    fn test_small_zip_from_ranges_server() {
-        unzip_sample_zip(
-            ZipParams::new(FileSizes::Variable, 3, zip::CompressionMethod::Deflated),
-            ServerType::Ranges,
+    fn test_open_entry_prefix() {
+        use std::io::Read;
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+        let mut contents = String::new();
+        engine
+            .open_entry_prefix("test/c.txt", 8)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Contents");
+        let mut contents = String::new();
+        engine
+            .open_entry_prefix("test/c.txt", 100)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Contents of C\n");
+
+        let server = Server::run();
+        set_up_server(&server, std::fs::read(&zf).unwrap(), ServerType::Ranges);
+        let engine = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
         )
+        .unwrap();
+        let mut contents = String::new();
+        engine
+            .open_entry_prefix("b.txt", 11)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Contents of");
+        assert!(engine.open_entry_prefix("d.txt", 1).is_err());
     }
 
     #[test]
-    fn test_small_zip_from_no_range_server() {
-        unzip_sample_zip(
-            ZipParams::new(FileSizes::Variable, 3, zip::CompressionMethod::Deflated),
-            ServerType::ContentLengthButNoRanges,
-        )
+    fn test_extract_with_freshen() {
+        struct SkipRecorder(std::sync::Mutex<Vec<String>>);
+        impl UnzipProgressReporter for &SkipRecorder {
+            fn extraction_skipped(&self, display_name: &str) {
+                self.0.lock().unwrap().push(display_name.to_string());
+            }
+        }
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let outdir = td.path().join("outdir");
+        for expected_skipped in [&[][..], &["test/a.txt", "test/c.txt"]] {
+            let recorder = SkipRecorder(Default::default());
+            let options = UnzipOptions {
+                progress_reporter: Box::new(&recorder),
+                policy: ExtractionPolicy {
+                    overwrite: OverwritePolicy::Freshen,
+                    ..test_policy()
+                },
+                ..options_for(&outdir)
+            };
+            let zf = File::open(&zf).unwrap();
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            check_files_exist(&outdir, true);
+            let mut skipped = recorder.0.into_inner().unwrap();
+            skipped.sort();
+            assert_eq!(skipped, expected_skipped);
+            // Changed files are extracted again.
+            std::fs::write(outdir.join("b.txt"), "Contents of X\n").unwrap();
+        }
     }
 
     #[test]
-    fn test_small_zip_from_no_content_length_server() {
-        unzip_sample_zip(
-            ZipParams::new(FileSizes::Variable, 3, zip::CompressionMethod::Deflated),
+    fn test_extract_with_delete_extraneous() {
+        for staging in [false, true] {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, false);
+            let zf = File::open(zf).unwrap();
+            let outdir = td.path().join("outdir");
+            std::fs::create_dir_all(outdir.join("test")).unwrap();
+            std::fs::write(outdir.join("test/a.txt"), "Old A").unwrap();
+            std::fs::write(outdir.join("old.txt"), "Old").unwrap();
+            let options = UnzipOptions {
+                staging,
+                delete_extraneous: true,
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            check_files_exist(&outdir, false);
+            assert!(!outdir.join("old.txt").exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_with_hardlink_duplicates() {
+        use std::os::unix::fs::MetadataExt;
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let options = FileOptions::<ExtendedFileOptions>::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .unix_permissions(0o644);
+        for (name, contents) in [
+            ("a.txt", "Same contents\n"),
+            ("b.txt", "Other content\n"),
+            ("dir/c.txt", "Same contents\n"),
+        ] {
+            zip.start_file(name, options.clone()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            single_threaded: true,
+            hardlink_duplicates: true,
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_file(File::open(zf).unwrap())
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+        let inode = |name: &str| std::fs::metadata(outdir.join(name)).unwrap().ino();
+        assert_eq!(inode("a.txt"), inode("dir/c.txt"));
+        assert_ne!(inode("a.txt"), inode("b.txt"));
+        assert_eq!(
+            read_to_string(outdir.join("dir/c.txt")).unwrap(),
+            "Same contents\n"
+        );
+    }
+
+    #[test]
+    fn test_extract_with_priority() {
+        struct EventRecorder(std::sync::Mutex<Vec<String>>);
+        impl UnzipProgressReporter for &EventRecorder {
+            fn extraction_finished(&self, display_name: &str) {
+                self.0.lock().unwrap().push(display_name.to_string());
+            }
+            fn priority_files_extracted(&self) {
+                self.0.lock().unwrap().push("<priority>".to_string());
+            }
+        }
+        struct JsonFilter;
+        impl FilenameFilter for JsonFilter {
+            fn should_unzip(&self, filename: &str) -> bool {
+                filename.ends_with(".json")
+            }
+        }
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        {
+            let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+            // Bigger files are usually extracted first.
+            for (name, len) in [("manifest.json", 10), ("big.bin", 1000), ("small.txt", 1)] {
+                zip.start_file::<_, ()>(name, FileOptions::default())
+                    .unwrap();
+                zip.write_all(&vec![b'x'; len]).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+        let recorder = EventRecorder(Default::default());
+        let options = UnzipOptions {
+            single_threaded: true,
+            progress_reporter: Box::new(&recorder),
+            priority_filter: Some(Box::new(JsonFilter)),
+            ..options_for(&td.path().join("outdir"))
+        };
+        UnzipEngine::for_file(File::open(zf).unwrap())
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["manifest.json", "<priority>", "big.bin", "small.txt"]
+        );
+    }
+
+    #[test]
+    fn test_file_progress() {
+        struct FileProgressRecorder(std::sync::Mutex<HashMap<String, (u64, u64)>>);
+        impl UnzipProgressReporter for &FileProgressRecorder {
+            fn file_bytes_extracted(&self, display_name: &str, count: u64, total: u64) {
+                let mut files = self.0.lock().unwrap();
+                let (extracted, recorded_total) =
+                    files.entry(display_name.to_string()).or_default();
+                *extracted += count;
+                *recorded_total = total;
+            }
+        }
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let recorder = FileProgressRecorder(Default::default());
+        let options = UnzipOptions {
+            progress_reporter: Box::new(&recorder),
+            ..options_for(&td.path().join("outdir"))
+        };
+        UnzipEngine::for_file(File::open(zf).unwrap())
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+        let files = recorder.0.into_inner().unwrap();
+        assert!(files.contains_key("test/a.txt"));
+        for (name, (extracted, total)) in files {
+            assert_eq!(extracted, total, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_extract_skipping_corrupt_files() {
+        struct WarningRecorder(std::sync::Mutex<Vec<WarningKind>>);
+        impl UnzipProgressReporter for &WarningRecorder {
+            fn warning(&self, kind: WarningKind, _message: &str) {
+                self.0.lock().unwrap().push(kind);
+            }
+        }
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for name in ["good.txt", "bad.txt"] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        let mut data = zip.finish().unwrap().into_inner();
+        // Spoil the checksum recorded for bad.txt.
+        let crc = crc32fast::hash(b"bad.txt").to_le_bytes();
+        for i in 0..data.len() - crc.len() {
+            if data[i..i + crc.len()] == crc {
+                data[i] ^= 0xff;
+            }
+        }
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        std::fs::write(&zf, data).unwrap();
+        for skip_corrupt in [false, true] {
+            let outdir = td.path().join(format!("outdir{skip_corrupt}"));
+            let recorder = WarningRecorder(Default::default());
+            let options = UnzipOptions {
+                progress_reporter: Box::new(&recorder),
+                skip_corrupt,
+                ..options_for(&outdir)
+            };
+            let result = UnzipEngine::for_file(File::open(&zf).unwrap())
+                .unwrap()
+                .unzip(options);
+            assert_eq!(result.is_ok(), skip_corrupt);
+            assert_eq!(read_to_string(outdir.join("good.txt")).unwrap(), "good.txt");
+            if skip_corrupt {
+                assert_eq!(
+                    *recorder.0.lock().unwrap(),
+                    [WarningKind::CorruptFileSkipped]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_extract_with_fsync() {
+        for staging in [false, true] {
+            run_with_and_without_a_filename_filter(|create_a, filters| {
+                let td = tempdir().unwrap();
+                let zf = td.path().join("z.zip");
+                create_zip_file(&zf, create_a);
+                let zf = File::open(zf).unwrap();
+                let outdir = td.path().join("outdir");
+                let options = UnzipOptions {
+                    filters,
+                    staging,
+                    fsync: true,
+                    ..options_for(&outdir)
+                };
+                UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+                check_files_exist(&outdir, create_a);
+            });
+        }
+    }
+
+    #[test]
+    fn test_extract_with_staging() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_zip_file(&zf, create_a);
+            let zf = File::open(zf).unwrap();
+            let outdir = td.path().join("outdir");
+            let options = UnzipOptions {
+                filters,
+                staging: true,
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            check_files_exist(&outdir, create_a);
+            assert!(staging_leftovers(td.path()).is_empty());
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(&outdir).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o777 & !super::process_umask().unwrap());
+            }
+        });
+    }
+
+    #[test]
+    #[ignore] // because the chdir changes global state
+    fn test_extract_with_staging_no_path() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let zf = File::open(zf).unwrap();
+        let outdir = td.path().join("outdir");
+        std::fs::create_dir(&outdir).unwrap();
+        let old_dir = current_dir().unwrap();
+        set_current_dir(&outdir).unwrap();
+        let options = UnzipOptions {
+            staging: true,
+            ..test_options()
+        };
+        let result = UnzipEngine::for_file(zf).unwrap().unzip(options);
+        set_current_dir(old_dir).unwrap();
+        result.unwrap();
+        // The current directory is merged into rather than replaced.
+        check_files_exist(&outdir, true);
+        assert!(staging_leftovers(td.path()).is_empty());
+        assert!(staging_leftovers(&outdir).is_empty());
+    }
+
+    #[test]
+    fn test_extract_with_staging_into_existing_directory() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let zf = File::open(zf).unwrap();
+        let outdir = td.path().join("outdir");
+        std::fs::create_dir_all(outdir.join("test")).unwrap();
+        std::fs::write(outdir.join("test/a.txt"), "Old contents").unwrap();
+        std::fs::write(outdir.join("test/d.txt"), "Unrelated").unwrap();
+        std::fs::create_dir(outdir.join("other")).unwrap();
+        std::fs::write(outdir.join("other/e.txt"), "Also unrelated").unwrap();
+        let options = UnzipOptions {
+            staging: true,
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+        check_files_exist(&outdir, true);
+        assert_eq!(
+            read_to_string(outdir.join("test/d.txt")).unwrap(),
+            "Unrelated"
+        );
+        assert_eq!(
+            read_to_string(outdir.join("other/e.txt")).unwrap(),
+            "Also unrelated"
+        );
+        assert!(staging_leftovers(td.path()).is_empty());
+    }
+
+    #[test]
+    fn test_failed_extract_with_staging_leaves_nothing() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let options: FileOptions<ExtendedFileOptions> =
+            FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("b.txt", options.clone()).unwrap();
+        zip.write_all(b"Contents of B\n").unwrap();
+        zip.start_file("../escape.txt", options).unwrap();
+        zip.write_all(b"Naughty\n").unwrap();
+        zip.finish().unwrap();
+        let zf = File::open(zf).unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            staging: true,
+            ..options_for(&outdir)
+        };
+        assert!(UnzipEngine::for_file(zf).unwrap().unzip(options).is_err());
+        assert!(!outdir.exists());
+        assert!(staging_leftovers(td.path()).is_empty());
+    }
+
+    #[test]
+    fn test_extract_encrypted_with_path() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let zf = td.path().join("z.zip");
+            create_encrypted_zip_file(&zf, create_a);
+            let zf = File::open(zf).unwrap();
+            let outdir = td.path().join("outdir");
+            let options = UnzipOptions {
+                password: Some("1Password".to_string()),
+                filters,
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_file(zf).unwrap().unzip(options).unwrap();
+            check_files_exist(&outdir, create_a);
+        });
+    }
+
+    #[test]
+    fn test_list() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let zf = File::open(zf).unwrap();
+        let filenames: HashSet<_> = UnzipEngine::for_file(zf)
+            .unwrap()
+            .list()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            filenames,
+            ["test/", "test/a.txt", "b.txt", "test/c.txt"]
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect()
+        )
+    }
+
+    #[test]
+    fn test_extract_from_reader() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let mut zip_data = Cursor::new(Vec::new());
+            create_zip(&mut zip_data, create_a, None);
+            zip_data.set_position(0);
+            let outdir = td.path().join("outdir");
+            let options = UnzipOptions {
+                filters,
+                ..options_for(&outdir)
+            };
+            let engine = UnzipEngine::for_reader(zip_data).unwrap();
+            assert_eq!(engine.zip_length(), 0);
+            engine.unzip(options).unwrap();
+            check_files_exist(&outdir, create_a);
+        });
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data, true, None);
+        zip_data.set_position(0);
+        let filenames: Vec<_> = UnzipEngine::for_reader(zip_data)
+            .unwrap()
+            .names_in_archive_order()
+            .unwrap();
+        assert_eq!(filenames, ["test/", "test/a.txt", "b.txt", "test/c.txt"]);
+        let tar = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let tar = {
+            let mut tar = tar;
+            tar.write_all(&create_tar(false)).unwrap();
+            tar.finish().unwrap()
+        };
+        let filenames: Vec<_> = UnzipEngine::for_reader(Cursor::new(tar))
+            .unwrap()
+            .names_in_archive_order()
+            .unwrap();
+        assert_eq!(filenames[0], "test/");
+    }
+
+    fn create_tar(include_a_txt: bool) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, entry_type: tar::EntryType, contents: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o755);
+            header.set_size(contents.len() as u64);
+            tar.append_data(&mut header, path, contents).unwrap();
+        };
+        append("./", tar::EntryType::Directory, b"");
+        append("./test/", tar::EntryType::Directory, b"");
+        if include_a_txt {
+            append("./test/a.txt", tar::EntryType::Regular, b"Contents of A\n");
+        }
+        append("./b.txt", tar::EntryType::Regular, b"Contents of B\n");
+        append("./test/c.txt", tar::EntryType::Regular, b"Contents of C\n");
+        append("./test/d.txt", tar::EntryType::Regular, b"D\n");
+        append("./test/fifo", tar::EntryType::Fifo, b"");
+        tar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_extract_tar() {
+        type Compressor = fn(&[u8]) -> Vec<u8>;
+        let gzip: Compressor = |data| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let zstd: Compressor = |data| zstd::encode_all(data, 0).unwrap();
+        for compress in [gzip, zstd] {
+            run_with_and_without_a_filename_filter(|create_a, filters| {
+                let td = tempdir().unwrap();
+                let tf = td.path().join("t.tar");
+                std::fs::write(&tf, compress(&create_tar(create_a))).unwrap();
+                let outdir = td.path().join("outdir");
+                let options = UnzipOptions {
+                    filters,
+                    ..options_for(&outdir)
+                };
+                let engine = UnzipEngine::for_file(File::open(&tf).unwrap()).unwrap();
+                engine.unzip(options).unwrap();
+                check_files_exist(&outdir, create_a);
+                assert_eq!(outdir.join("test/d.txt").exists(), create_a);
+                assert!(!outdir.join("test/fifo").exists());
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = std::fs::metadata(outdir.join("b.txt"))
+                        .unwrap()
+                        .permissions()
+                        .mode();
+                    assert_eq!(mode & 0o777, 0o755);
+                }
+
+                let engine = UnzipEngine::for_file(File::open(&tf).unwrap()).unwrap();
+                let filenames: Vec<_> = engine.names_in_archive_order().unwrap();
+                assert_eq!(filenames[0], "test/");
+                assert_eq!(filenames.last().unwrap(), "test/fifo");
+            });
+        }
+    }
+
+    #[test]
+    fn test_extract_from_server() {
+        run_with_and_without_a_filename_filter(|create_a, filters| {
+            let td = tempdir().unwrap();
+            let mut zip_data = Cursor::new(Vec::new());
+            create_zip(&mut zip_data, create_a, None);
+            let body = zip_data.into_inner();
+            println!("Whole zip:");
+            hexdump::hexdump(&body);
+
+            let server = Server::run();
+            set_up_server(&server, body, ServerType::Ranges);
+
+            let outdir = td.path().join("outdir");
+            let options = UnzipOptions {
+                filters,
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_uri(
+                &server.url("/foo").to_string(),
+                None,
+                ReadaheadWatermarks::default(),
+                HttpOptions::default(),
+                || {},
+            )
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+            check_files_exist(&outdir, create_a);
+        });
+    }
+
+    fn unzip_sample_zip(zip_params: ZipParams, server_type: ServerType) {
+        let td = tempdir().unwrap();
+        let zip_data = ripunzip_test_utils::get_sample_zip(&zip_params);
+
+        let server = Server::run();
+        set_up_server(&server, zip_data, server_type);
+
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
+        )
+        .unwrap()
+        .unzip(options)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_extract_while_reading_central_directory() {
+        // Enough files that the central directory is worth streaming.
+        let names: Vec<String> = (0..300)
+            .map(|i| {
+                format!(
+                    "dir{}/file-{i:05}-with-a-long-name-to-pad-out-the-directory",
+                    i % 10
+                )
+            })
+            .collect();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        for name in &names {
+            zip.start_file(name.as_str(), options).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        let mut zip_data = zip.finish().unwrap().into_inner();
+        // Mark the first file as having a data descriptor, so it can only be
+        // extracted once the whole central directory has been read.
+        let central_directory_start = zip::ZipArchive::new(Cursor::new(&zip_data))
+            .unwrap()
+            .central_directory_start() as usize;
+        assert!((zip_data.len() - central_directory_start) as u64 >= STREAMING_THRESHOLD);
+        zip_data[central_directory_start + 8] |= 0x08;
+
+        let server = Server::run();
+        set_up_server(&server, zip_data, ServerType::Ranges);
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            tune_thread_count: true,
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
+        )
+        .unwrap()
+        .unzip(options)
+        .unwrap();
+        for name in names.iter().step_by(37) {
+            assert_eq!(read_to_string(outdir.join(name)).unwrap(), *name);
+        }
+    }
+
+    #[test]
+    fn test_list_while_reading_central_directory() {
+        let names: Vec<String> = (0..300)
+            .map(|i| format!("file-{i:05}-with-a-long-name-to-pad-out-the-directory"))
+            .collect();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for name in &names {
+            zip.start_file(name.as_str(), zip::write::SimpleFileOptions::default())
+                .unwrap();
+        }
+        let zip_data = zip.finish().unwrap().into_inner();
+        let server = Server::run();
+        set_up_server(&server, zip_data, ServerType::Ranges);
+        let engine = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
+        )
+        .unwrap();
+        assert!(engine.zipfile.entry_count().is_some());
+        let listed: Vec<String> = engine.list().unwrap().map(Result::unwrap).collect();
+        assert_eq!(listed, names);
+    }
+
+    #[test]
+    fn test_known_content_length() {
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            3,
+            zip::CompressionMethod::Deflated,
+        ));
+        let server = Server::run();
+        server.expect(
+            httptest::Expectation::matching(httptest::matchers::request::method_path(
+                "HEAD", "/foo",
+            ))
+            .times(0)
+            .respond_with(httptest::responders::status_code(200)),
+        );
+        server.expect(
+            httptest::Expectation::matching(httptest::matchers::request::method_path(
+                "GET", "/foo",
+            ))
+            .times(..)
+            .respond_with(RangeAwareResponse::new(
+                206,
+                RangeAwareResponseType::Body {
+                    body: hyper::body::Bytes::from(zip_data.clone()),
+                    expected_range: None,
+                },
+            )),
+        );
+        let engine = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions {
+                content_length: Some(zip_data.len() as u64),
+                ..Default::default()
+            },
+            || {},
+        )
+        .unwrap();
+        assert_eq!(engine.zip_length(), zip_data.len() as u64);
+        assert_eq!(engine.list().unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_extract_biggish_zip_from_ranges_server() {
+        unzip_sample_zip(
+            ZipParams::new(FileSizes::Variable, 15, zip::CompressionMethod::Deflated),
+            ServerType::Ranges,
+        )
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            15,
+            zip::CompressionMethod::Deflated,
+        ));
+        let server = Server::run();
+        set_up_server(&server, zip_data, ServerType::Ranges);
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            tune_thread_count: true,
+            memory_budget: Some(MemoryBudget::new(1024 * 1024)),
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
+        )
+        .unwrap()
+        .unzip(options)
+        .unwrap();
+        assert_eq!(std::fs::read_dir(&outdir).unwrap().count(), 15);
+    }
+
+    #[test]
+    fn test_network_progress() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        #[derive(Default)]
+        struct NetworkRecorder {
+            started: Mutex<Vec<Range<u64>>>,
+            completed: Mutex<Vec<Range<u64>>>,
+            downloaded: AtomicU64,
+        }
+        impl UnzipProgressReporter for &NetworkRecorder {
+            fn request_started(&self, range: &Range<u64>) {
+                self.started.lock().unwrap().push(range.clone());
+            }
+            fn bytes_downloaded(&self, count: u64) {
+                self.downloaded.fetch_add(count, Ordering::Relaxed);
+            }
+            fn request_completed(&self, range: &Range<u64>) {
+                self.completed.lock().unwrap().push(range.clone());
+            }
+        }
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            3,
+            zip::CompressionMethod::Deflated,
+        ));
+        let len = zip_data.len() as u64;
+        let server = Server::run();
+        set_up_server(&server, zip_data, ServerType::Ranges);
+        let td = tempdir().unwrap();
+        let recorder = NetworkRecorder::default();
+        let options = UnzipOptions {
+            progress_reporter: Box::new(&recorder),
+            ..options_for(&td.path().join("outdir"))
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions::default(),
+            || {},
+        )
+        .unwrap()
+        .unzip(options)
+        .unwrap();
+        let started = recorder.started.into_inner().unwrap();
+        let mut completed = recorder.completed.into_inner().unwrap();
+        assert!(!started.is_empty());
+        assert!(started.iter().all(|range| range.end == len));
+        // Every request is finished with by the time extraction is over.
+        completed.sort_by_key(|range| range.start);
+        let mut sorted_started = started.clone();
+        sorted_started.sort_by_key(|range| range.start);
+        assert_eq!(completed, sorted_started);
+        let downloaded = recorder.downloaded.into_inner();
+        assert!(downloaded > 0 && downloaded <= len * started.len() as u64);
+    }
+
+    #[test]
+    fn test_small_zip_from_ranges_server() {
+        unzip_sample_zip(
+            ZipParams::new(FileSizes::Variable, 3, zip::CompressionMethod::Deflated),
+            ServerType::Ranges,
+        )
+    }
+
+    #[test]
+    fn test_small_zip_from_no_range_server() {
+        unzip_sample_zip(
+            ZipParams::new(FileSizes::Variable, 3, zip::CompressionMethod::Deflated),
+            ServerType::ContentLengthButNoRanges,
+        )
+    }
+
+    #[test]
+    fn test_small_zip_from_no_content_length_server() {
+        unzip_sample_zip(
+            ZipParams::new(FileSizes::Variable, 3, zip::CompressionMethod::Deflated),
             ServerType::NoContentLength,
         )
     }
+
+    #[test]
+    fn test_keep_download() {
+        let td = tempdir().unwrap();
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            3,
+            zip::CompressionMethod::Deflated,
+        ));
+        let server = Server::run();
+        set_up_server(
+            &server,
+            zip_data.clone(),
+            ServerType::ContentLengthButNoRanges,
+        );
+        let kept = td.path().join("kept.zip");
+        let engine = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions {
+                keep_download: Some(kept.clone()),
+                ..Default::default()
+            },
+            || {},
+        )
+        .unwrap();
+        assert_eq!(engine.list().unwrap().count(), 3);
+        assert_eq!(std::fs::read(kept).unwrap(), zip_data);
+    }
+
+    #[test]
+    fn test_resume_download() {
+        use httptest::{
+            all_of,
+            matchers::{contains, request, request::method_path},
+            responders::status_code,
+            Expectation,
+        };
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            3,
+            zip::CompressionMethod::Deflated,
+        ));
+        let len = zip_data.len();
+        // Half the zip file's been downloaded, or all of it.
+        for downloaded in [len / 2, len] {
+            let td = tempdir().unwrap();
+            let server = Server::run();
+            server.expect(
+                Expectation::matching(method_path("HEAD", "/foo"))
+                    .times(..)
+                    .respond_with(status_code(200)),
+            );
+            // The HEAD response has no length, so a range is asked for first
+            // to find it.
+            server.expect(
+                Expectation::matching(all_of![
+                    method_path("GET", "/foo"),
+                    request::headers(contains(("range", "bytes=0-0"))),
+                ])
+                .respond_with(status_code(200)),
+            );
+            // Only the rest of the zip file is sent, so it only comes out
+            // right if the download's resumed.
+            let response = if downloaded < len {
+                status_code(206)
+                    .append_header(
+                        "Content-Range",
+                        format!("bytes {downloaded}-{}/{len}", len - 1),
+                    )
+                    .body(zip_data[downloaded..].to_vec())
+            } else {
+                status_code(416).append_header("Content-Range", format!("bytes */{len}"))
+            };
+            server.expect(
+                Expectation::matching(all_of![
+                    method_path("GET", "/foo"),
+                    request::headers(contains(("if-range", "\"v1\""))),
+                ])
+                .respond_with(response),
+            );
+            let kept = td.path().join("kept.zip");
+            std::fs::write(td.path().join("kept.zip.part"), &zip_data[..downloaded]).unwrap();
+            std::fs::write(td.path().join("kept.zip.part.validator"), "\"v1\"").unwrap();
+            let engine = UnzipEngine::for_uri(
+                &server.url("/foo").to_string(),
+                None,
+                ReadaheadWatermarks::default(),
+                HttpOptions {
+                    keep_download: Some(kept.clone()),
+                    ..Default::default()
+                },
+                || {},
+            )
+            .unwrap();
+            assert_eq!(engine.list().unwrap().count(), 3);
+            assert_eq!(std::fs::read(kept).unwrap(), zip_data);
+            assert!(!td.path().join("kept.zip.part").exists());
+            assert!(!td.path().join("kept.zip.part.validator").exists());
+        }
+    }
+
+    #[test]
+    fn test_unvalidated_download_is_restarted() {
+        use httptest::{matchers::request::method_path, responders::status_code, Expectation};
+        let td = tempdir().unwrap();
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            3,
+            zip::CompressionMethod::Deflated,
+        ));
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(method_path("HEAD", "/foo"))
+                .times(..)
+                .respond_with(status_code(200)),
+        );
+        server.expect(
+            Expectation::matching(method_path("GET", "/foo"))
+                .times(..)
+                .respond_with(status_code(200).body(zip_data.clone())),
+        );
+        // Without knowing what version of the zip file this is from, it
+        // can't be added to.
+        let kept = td.path().join("kept.zip");
+        std::fs::write(td.path().join("kept.zip.part"), "Stale").unwrap();
+        let engine = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            None,
+            ReadaheadWatermarks::default(),
+            HttpOptions {
+                keep_download: Some(kept.clone()),
+                ..Default::default()
+            },
+            || {},
+        )
+        .unwrap();
+        assert_eq!(engine.list().unwrap().count(), 3);
+        assert_eq!(std::fs::read(kept).unwrap(), zip_data);
+    }
+
+    #[test]
+    fn test_content_encoding() {
+        use httptest::{matchers::request::method_path, responders::status_code, Expectation};
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            3,
+            zip::CompressionMethod::Deflated,
+        ));
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzipped.write_all(&zip_data).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        let zstd_compressed = zstd::encode_all(zip_data.as_slice(), 0).unwrap();
+        for (encoding, body) in [("gzip", gzipped), ("zstd", zstd_compressed)] {
+            // Even though it claims to support ranges, it has to be
+            // downloaded whole, as the ranges would be of the encoded data.
+            let server = Server::run();
+            server.expect(
+                Expectation::matching(method_path("HEAD", "/foo"))
+                    .times(..)
+                    .respond_with(
+                        status_code(200)
+                            .append_header("Content-Length", body.len().to_string())
+                            .append_header("Accept-Ranges", "bytes")
+                            .append_header("Content-Encoding", encoding),
+                    ),
+            );
+            server.expect(
+                Expectation::matching(method_path("GET", "/foo"))
+                    .times(..)
+                    .respond_with(
+                        status_code(200)
+                            .append_header("Content-Encoding", encoding)
+                            .body(body),
+                    ),
+            );
+            let engine = UnzipEngine::for_uri(
+                &server.url("/foo").to_string(),
+                None,
+                ReadaheadWatermarks::default(),
+                HttpOptions::default(),
+                || {},
+            )
+            .unwrap();
+            assert_eq!(engine.zip_length(), zip_data.len() as u64);
+            assert_eq!(engine.list().unwrap().count(), 3);
+        }
+    }
+
+    #[test]
+    fn test_engine_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<UnzipEngine>();
+    }
+
+    #[test]
+    fn test_directory_creator() {
+        let td = tempdir().unwrap();
+        let dir =
+            cap_std::fs::Dir::open_ambient_dir(td.path(), cap_std::ambient_authority()).unwrap();
+        let creator = DirectoryCreator::default();
+        creator.create_dir_all(&dir, Path::new("a/b/c")).unwrap();
+        assert!(td.path().join("a/b/c").is_dir());
+        // Its ancestors are known to exist without looking.
+        std::fs::remove_dir_all(td.path().join("a")).unwrap();
+        creator.create_dir_all(&dir, Path::new("a/b")).unwrap();
+        assert!(!td.path().join("a").exists());
+        creator.create_dir_all(&dir, Path::new("a/d")).unwrap();
+        assert!(td.path().join("a/d").is_dir());
+    }
+
+    #[test]
+    fn test_modification_times() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let archived = zip::DateTime::from_date_and_time(2001, 2, 3, 4, 5, 6).unwrap();
+        let options = SimpleFileOptions::default().last_modified_time(archived);
+        zip.add_directory("d/", options).unwrap();
+        zip.start_file("d/e/f.txt", options).unwrap();
+        zip.write_all(b"f").unwrap();
+        zip.finish().unwrap();
+        let archived =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(981173106);
+        let fixed = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1234);
+        for (modification_times, expected) in [
+            (ModificationTimes::Fixed(fixed), fixed),
+            (ModificationTimes::Archive, archived),
+        ] {
+            let outdir = td.path().join(format!("{modification_times:?}"));
+            let options = UnzipOptions {
+                policy: ExtractionPolicy {
+                    mtimes: modification_times,
+                    ..test_policy()
+                },
+                ..options_for(&outdir)
+            };
+            let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+            engine.unzip(options).unwrap();
+            let modified = |name: &str| {
+                std::fs::metadata(outdir.join(name))
+                    .unwrap()
+                    .modified()
+                    .unwrap()
+            };
+            assert_eq!(modified("d"), expected);
+            assert_eq!(modified("d/e/f.txt"), expected);
+            // This directory has no time in the archive.
+            if modification_times == ModificationTimes::Fixed(fixed) {
+                assert_eq!(modified("d/e"), fixed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ntfs_times() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        // FILETIMEs count 100ns intervals since 1601.
+        let filetime = |unix_seconds: u64| (unix_seconds + 11_644_473_600) * 10_000_000 + 1234;
+        let mut field = vec![0u8; 4];
+        field.extend_from_slice(&1u16.to_le_bytes());
+        field.extend_from_slice(&24u16.to_le_bytes());
+        for seconds in [1_000_000_000, 1_100_000_000, 900_000_000] {
+            field.extend_from_slice(&filetime(seconds).to_le_bytes());
+        }
+        // The zip crate won't write an NTFS extra field, so write it with
+        // a made-up ID and patch that afterwards.
+        let mut options = zip::write::FullFileOptions::default();
+        options
+            .add_extra_data(0x7a7a, field.into_boxed_slice(), false)
+            .unwrap();
+        zip.start_file("f.txt", options).unwrap();
+        zip.write_all(b"f").unwrap();
+        zip.finish().unwrap();
+        let mut data = std::fs::read(&zf).unwrap();
+        for i in 0..data.len() - 4 {
+            if data[i..i + 4] == *b"zz\x20\x00" {
+                data[i..i + 2].copy_from_slice(b"\x0a\x00");
+            }
+        }
+        std::fs::write(&zf, data).unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            policy: ExtractionPolicy {
+                mtimes: ModificationTimes::Archive,
+                ..Default::default()
+            },
+            ..options_for(&outdir)
+        };
+        UnzipEngine::for_file(File::open(&zf).unwrap())
+            .unwrap()
+            .unzip(options)
+            .unwrap();
+        let metadata = std::fs::metadata(outdir.join("f.txt")).unwrap();
+        let time = |seconds| {
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(seconds, 123_400)
+        };
+        assert_eq!(metadata.modified().unwrap(), time(1_000_000_000));
+        assert_eq!(metadata.accessed().unwrap(), time(1_100_000_000));
+        #[cfg(windows)]
+        assert_eq!(metadata.created().unwrap(), time(900_000_000));
+    }
+
+    #[test]
+    fn test_skip_macos_junk() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        for name in ["a/b.txt", "a/.DS_Store", "a/._b.txt", "__MACOSX/a/._b.txt"] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"x").unwrap();
+        }
+        zip.finish().unwrap();
+        for skip_macos_junk in [true, false] {
+            let outdir = td.path().join(format!("{skip_macos_junk}"));
+            let options = UnzipOptions {
+                policy: ExtractionPolicy {
+                    skip_macos_junk,
+                    ..Default::default()
+                },
+                ..options_for(&outdir)
+            };
+            UnzipEngine::for_file(File::open(&zf).unwrap())
+                .unwrap()
+                .unzip(options)
+                .unwrap();
+            assert!(outdir.join("a/b.txt").exists());
+            assert_eq!(outdir.join("a/.DS_Store").exists(), !skip_macos_junk);
+            assert_eq!(outdir.join("a/._b.txt").exists(), !skip_macos_junk);
+            assert_eq!(outdir.join("__MACOSX").exists(), !skip_macos_junk);
+        }
+    }
+
+    #[test]
+    fn test_restore_apple_double() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        zip.start_file("a/b.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"x").unwrap();
+        // An AppleDouble file holding only Finder info.
+        let mut apple_double = vec![0, 5, 22, 7, 0, 2, 0, 0];
+        apple_double.extend_from_slice(&[0; 16]);
+        apple_double.extend_from_slice(&[0, 1, 0, 0, 0, 9, 0, 0, 0, 38, 0, 0, 0, 32]);
+        apple_double.extend_from_slice(b"TEXTttxt");
+        apple_double.extend_from_slice(&[0; 24]);
+        zip.start_file("__MACOSX/a/._b.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&apple_double).unwrap();
+        zip.finish().unwrap();
+        let outdir = td.path().join("out");
+        let options = UnzipOptions {
+            policy: ExtractionPolicy {
+                restore_apple_double: true,
+                ..Default::default()
+            },
+            ..options_for(&outdir)
+        };
+        let result = UnzipEngine::for_file(File::open(&zf).unwrap())
+            .unwrap()
+            .unzip(options);
+        if !cfg!(target_os = "macos") {
+            assert!(result.is_err());
+            return;
+        }
+        result.unwrap();
+        assert!(!outdir.join("__MACOSX").exists());
+        #[cfg(target_os = "macos")]
+        {
+            let mut finder_info = [0u8; 32];
+            let len = rustix::fs::getxattr(
+                &outdir.join("a/b.txt"),
+                "com.apple.FinderInfo",
+                &mut finder_info[..],
+            )
+            .unwrap();
+            assert_eq!(&finder_info[..len.min(8)], b"TEXTttxt");
+        }
+    }
+
+    #[test]
+    fn test_list_details() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let archived = zip::DateTime::from_date_and_time(2001, 2, 3, 4, 5, 6).unwrap();
+        let options = SimpleFileOptions::default().last_modified_time(archived);
+        zip.start_file("big.txt", options).unwrap();
+        zip.write_all(&[b'a'; 1000]).unwrap();
+        zip.start_file("small.txt", options).unwrap();
+        zip.write_all(b"b").unwrap();
+        zip.finish().unwrap();
+        let archived =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(981173106);
+
+        let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+        let entries = engine.list_details().unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["big.txt", "small.txt"]);
+        assert_eq!(entries[0].size, 1000);
+        assert!(entries[0].compressed_size.unwrap() < 1000);
+        assert_eq!(entries[0].offset, Some(0));
+        assert!(entries[1].offset.unwrap() > 0);
+        assert_eq!(entries[1].last_modified, Some(archived));
+
+        // Streamed zip files give the sizes too, but not where entries are.
+        let engine = UnzipEngine::for_reader(File::open(&zf).unwrap()).unwrap();
+        let entries = engine.list_details().unwrap();
+        assert_eq!(entries[0].size, 1000);
+        assert_eq!(entries[1].size, 1);
+        assert_eq!(entries[1].offset, None);
+        assert_eq!(entries[1].last_modified, Some(archived));
+    }
+
+    struct RecordingHandler(std::sync::Mutex<Vec<PathBuf>>);
+    impl ExistingFileHandler for &RecordingHandler {
+        fn handle_existing_file(&self, path: &Path) -> ExistingFileAction {
+            self.0.lock().unwrap().push(path.to_path_buf());
+            if path == Path::new("b.txt") {
+                ExistingFileAction::Skip
+            } else {
+                ExistingFileAction::Rename(PathBuf::from("test/renamed.txt"))
+            }
+        }
+    }
+
+    #[test]
+    fn test_extract_with_existing_files() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let outdir = td.path().join("outdir");
+        std::fs::create_dir_all(outdir.join("test")).unwrap();
+        std::fs::write(outdir.join("b.txt"), "Old B\n").unwrap();
+        std::fs::write(outdir.join("test/c.txt"), "Old C\n").unwrap();
+        let handler = RecordingHandler(std::sync::Mutex::new(Vec::new()));
+        let options = UnzipOptions {
+            policy: ExtractionPolicy {
+                overwrite: OverwritePolicy::Ask(Box::new(&handler)),
+                ..test_policy()
+            },
+            ..options_for(&outdir)
+        };
+        let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+        engine.unzip(options).unwrap();
+        let mut asked = handler.0.into_inner().unwrap();
+        asked.sort();
+        assert_eq!(asked, [PathBuf::from("b.txt"), PathBuf::from("test/c.txt")]);
+        assert_eq!(read_to_string(outdir.join("b.txt")).unwrap(), "Old B\n");
+        assert_eq!(
+            read_to_string(outdir.join("test/c.txt")).unwrap(),
+            "Old C\n"
+        );
+        assert_eq!(
+            read_to_string(outdir.join("test/renamed.txt")).unwrap(),
+            "Contents of C\n"
+        );
+        assert_eq!(
+            read_to_string(outdir.join("test/a.txt")).unwrap(),
+            "Contents of A\n"
+        );
+    }
+
+    fn unzip_with_symlinks(zf: &Path, outdir: &Path, symlinks: SymlinkPolicy) {
+        let options = UnzipOptions {
+            policy: ExtractionPolicy {
+                symlinks,
+                ..test_policy()
+            },
+            ..options_for(outdir)
+        };
+        let engine = UnzipEngine::for_file(File::open(zf).unwrap()).unwrap();
+        engine.unzip(options).unwrap();
+    }
+
+    #[test]
+    fn test_extract_symlinks() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let options = SimpleFileOptions::default();
+        zip.add_symlink("links/file", "../dir/a.txt", options)
+            .unwrap();
+        zip.add_symlink("links/dir", "../dir", options).unwrap();
+        zip.add_symlink("escape", "../outside", options).unwrap();
+        zip.start_file("dir/a.txt", options).unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        zip.finish().unwrap();
+
+        let outdir = td.path().join("file");
+        unzip_with_symlinks(&zf, &outdir, SymlinkPolicy::File);
+        assert_eq!(
+            read_to_string(outdir.join("links/file")).unwrap(),
+            "../dir/a.txt"
+        );
+
+        let outdir = td.path().join("copy");
+        unzip_with_symlinks(&zf, &outdir, SymlinkPolicy::Copy);
+        assert!(!outdir.join("links/file").is_symlink());
+        assert_eq!(
+            read_to_string(outdir.join("links/file")).unwrap(),
+            "Contents of A\n"
+        );
+        assert_eq!(
+            read_to_string(outdir.join("links/dir/a.txt")).unwrap(),
+            "Contents of A\n"
+        );
+        assert!(!outdir.join("escape").exists());
+
+        let outdir = td.path().join("skip");
+        unzip_with_symlinks(&zf, &outdir, SymlinkPolicy::Skip);
+        assert!(!outdir.join("links/file").exists());
+        assert!(outdir.join("dir/a.txt").exists());
+
+        #[cfg(unix)]
+        {
+            let outdir = td.path().join("create");
+            unzip_with_symlinks(&zf, &outdir, SymlinkPolicy::Create);
+            assert_eq!(
+                std::fs::read_link(outdir.join("links/file")).unwrap(),
+                PathBuf::from("../dir/a.txt")
+            );
+            assert_eq!(
+                read_to_string(outdir.join("links/dir/a.txt")).unwrap(),
+                "Contents of A\n"
+            );
+            assert!(std::fs::symlink_metadata(outdir.join("escape")).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_extract_symlinks_escaping_through_links() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let options = SimpleFileOptions::default();
+        zip.add_symlink("p/q", ".", options).unwrap();
+        zip.add_symlink("p/r", "q/../../escaped", options).unwrap();
+        zip.finish().unwrap();
+        let outdir = td.path().join("outdir");
+        unzip_with_symlinks(&zf, &outdir, SymlinkPolicy::Create);
+        assert_eq!(
+            std::fs::read_link(outdir.join("p/q")).unwrap(),
+            PathBuf::from(".")
+        );
+        assert!(std::fs::symlink_metadata(outdir.join("p/r")).is_err());
+    }
+
+    #[test]
+    fn test_metadata_filter() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        for (name, year) in [("old.txt", 2001), ("new.txt", 2021)] {
+            let time = zip::DateTime::from_date_and_time(year, 1, 1, 0, 0, 0).unwrap();
+            zip.start_file(name, SimpleFileOptions::default().last_modified_time(time))
+                .unwrap();
+            zip.write_all(b"contents").unwrap();
+        }
+        zip.finish().unwrap();
+        // 2010-01-01
+        let since = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1262304000);
+        for from_stream in [false, true] {
+            let outdir = td.path().join(format!("{from_stream}"));
+            let options = UnzipOptions {
+                filters: FilterSet::new().metadata(MetadataFilter {
+                    modified_after: Some(since),
+                    ..Default::default()
+                }),
+                ..options_for(&outdir)
+            };
+            let zipfile = File::open(&zf).unwrap();
+            let engine = if from_stream {
+                UnzipEngine::for_reader(zipfile).unwrap()
+            } else {
+                UnzipEngine::for_file(zipfile).unwrap()
+            };
+            engine.unzip(options).unwrap();
+            assert!(outdir.join("new.txt").exists());
+            assert!(!outdir.join("old.txt").exists());
+        }
+    }
+
+    #[test]
+    fn test_unzip_entries() {
+        let names = ["a.txt", "b.txt", "c.txt", "d.txt"];
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for name in names {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        let zip_data = zip.finish().unwrap().into_inner();
+        let server = Server::run();
+        set_up_server(&server, zip_data.clone(), ServerType::Ranges);
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        std::fs::write(&zf, &zip_data).unwrap();
+        for source in ["file", "stream", "uri"] {
+            let engine = match source {
+                "file" => UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap(),
+                "stream" => UnzipEngine::for_reader(File::open(&zf).unwrap()).unwrap(),
+                _ => UnzipEngine::for_uri(
+                    &server.url("/foo").to_string(),
+                    None,
+                    ReadaheadWatermarks::default(),
+                    HttpOptions::default(),
+                    || {},
+                )
+                .unwrap(),
+            };
+            let outdir = td.path().join(source);
+            let options = UnzipOptions {
+                filters: FilterSet::new().exclude("d.txt"),
+                ..options_for(&outdir)
+            };
+            engine.unzip_entries([1, 3], options).unwrap();
+            for name in names {
+                assert_eq!(
+                    outdir.join(name).exists(),
+                    name == "b.txt",
+                    "{source} {name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_flatten() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let options = SimpleFileOptions::default();
+        zip.add_directory("a/", options).unwrap();
+        for name in ["a/x.txt", "b/c/x.txt", "b/y.txt"] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            flatten: true,
+            ..options_for(&outdir)
+        };
+        let engine = UnzipEngine::for_file(File::open(&zf).unwrap()).unwrap();
+        engine.unzip(options).unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&outdir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["x-1.txt", "x.txt", "y.txt"]);
+        let mut contents = [
+            read_to_string(outdir.join("x.txt")).unwrap(),
+            read_to_string(outdir.join("x-1.txt")).unwrap(),
+        ];
+        contents.sort();
+        assert_eq!(contents, ["a/x.txt", "b/c/x.txt"]);
+    }
+
+    #[test]
+    fn test_crx() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf, true);
+        let header = CrxHeader::V3 {
+            header: b"signed header".to_vec(),
+        };
+        let mut crx_data = b"Cr24\x03\x00\x00\x00\x0d\x00\x00\x00signed header".to_vec();
+        crx_data.extend(std::fs::read(&zf).unwrap());
+        let crx = td.path().join("z.crx");
+        std::fs::write(&crx, &crx_data).unwrap();
+        let server = Server::run();
+        set_up_server(&server, crx_data, ServerType::Ranges);
+        let engine = |source| match source {
+            "file" => UnzipEngine::for_file(File::open(&crx).unwrap()).unwrap(),
+            "stream" => UnzipEngine::for_reader(File::open(&crx).unwrap()).unwrap(),
+            _ => UnzipEngine::for_uri(
+                &server.url("/foo").to_string(),
+                None,
+                ReadaheadWatermarks::default(),
+                HttpOptions::default(),
+                || {},
+            )
+            .unwrap(),
+        };
+        for source in ["file", "stream", "uri"] {
+            let summary = engine(source).summary().unwrap();
+            assert_eq!(summary.crx_header.as_ref(), Some(&header));
+            assert_eq!(summary.entry_count, 4);
+            let outdir = td.path().join(source);
+            let options = UnzipOptions {
+                // Only the selected files are fetched, which needs their
+                // offsets in the CRX file rather than the zip file.
+                filters: FilterSet::new().exclude("test/a.txt"),
+                ..options_for(&outdir)
+            };
+            engine(source).unzip(options).unwrap();
+            check_files_exist(&outdir, false);
+        }
+    }
+
+    #[test]
+    fn test_concatenated_archives() {
+        let zip_of = |name: &str| {
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+            zip.finish().unwrap().into_inner()
+        };
+        let (first, second) = (zip_of("first.txt"), zip_of("second.txt"));
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        std::fs::write(&zf, [first.as_slice(), &second].concat()).unwrap();
+        let zipfile = File::open(&zf).unwrap();
+        let archives = UnzipEngine::archives_in_file(&zipfile).unwrap();
+        let len = (first.len() + second.len()) as u64;
+        assert_eq!(archives, [0..first.len() as u64, first.len() as u64..len]);
+        let names = |engine: UnzipEngine| {
+            engine
+                .list()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        for (archive, name) in archives.into_iter().zip(["first.txt", "second.txt"]) {
+            let engine = UnzipEngine::for_file_archive(zipfile.try_clone().unwrap(), archive);
+            assert_eq!(names(engine.unwrap()), [name]);
+        }
+        let engine = UnzipEngine::for_file(zipfile).unwrap();
+        assert_eq!(engine.pending_warnings.len(), 1);
+        assert_eq!(names(engine), ["second.txt"]);
+
+        std::fs::write(&zf, &first).unwrap();
+        let zipfile = File::open(&zf).unwrap();
+        let archives = UnzipEngine::archives_in_file(&zipfile).unwrap();
+        assert_eq!(archives, vec![0..first.len() as u64]);
+        assert!(UnzipEngine::for_file(zipfile)
+            .unwrap()
+            .pending_warnings
+            .is_empty());
+    }
 }
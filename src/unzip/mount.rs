@@ -0,0 +1,359 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Exposing the contents of a zip file as a read-only filesystem, using
+//! FUSE, so that enormous zip files can be browsed without extracting them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    io::{Read, Seek},
+    path::{Component, Path},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, Request, FUSE_ROOT_ID,
+};
+use rustix::io::Errno;
+use zip::ZipArchive;
+
+use super::EntryMetadata;
+
+/// How long the kernel may remember what it's told. The zip file never
+/// changes, so this can be long.
+const TTL: Duration = Duration::from_secs(3600);
+
+/// A file or directory in the zip file. Directories needn't have entries
+/// of their own, if they're implied by the files within them.
+struct Node {
+    parent: u64,
+    kind: FileType,
+    /// The index of the node's entry in the zip file, if it has one.
+    index: Option<usize>,
+    metadata: Option<EntryMetadata>,
+    children: BTreeMap<OsString, u64>,
+}
+
+impl Node {
+    fn directory(parent: u64) -> Self {
+        Self {
+            parent,
+            kind: FileType::Directory,
+            index: None,
+            metadata: None,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+/// The files and directories in a zip file, by inode number, which is one
+/// more than their position in `nodes`.
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn new<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Self> {
+        let mut tree = Self {
+            nodes: vec![Node::directory(FUSE_ROOT_ID)],
+        };
+        for index in 0..archive.len() {
+            let file = archive.by_index_raw(index)?;
+            // Files which couldn't be extracted aren't shown either.
+            let Some(path) = file.enclosed_name() else {
+                continue;
+            };
+            let kind = if file.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            let metadata = EntryMetadata::from_zip_file(&file);
+            let mut ino = FUSE_ROOT_ID;
+            for component in path.components() {
+                let Component::Normal(name) = component else {
+                    continue;
+                };
+                ino = tree.child(ino, name);
+            }
+            if ino == FUSE_ROOT_ID {
+                continue;
+            }
+            let node = tree.node_mut(ino);
+            node.kind = kind;
+            node.index = Some(index);
+            node.metadata = Some(metadata);
+        }
+        Ok(tree)
+    }
+
+    /// The child of `parent` called `name`, which is created as a directory
+    /// if it doesn't exist yet.
+    fn child(&mut self, parent: u64, name: &OsStr) -> u64 {
+        if let Some(ino) = self.node_mut(parent).children.get(name) {
+            return *ino;
+        }
+        self.nodes.push(Node::directory(parent));
+        let ino = self.nodes.len() as u64;
+        self.node_mut(parent)
+            .children
+            .insert(name.to_os_string(), ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[ino as usize - 1]
+    }
+}
+
+/// A read-only filesystem containing the files in a zip file. Each file is
+/// decompressed into memory when it's opened.
+struct ZipFilesystem<R: Read + Seek> {
+    archive: ZipArchive<R>,
+    tree: Tree,
+    /// The contents of each open file, by handle.
+    open_files: HashMap<u64, Vec<u8>>,
+    next_handle: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl<R: Read + Seek> ZipFilesystem<R> {
+    fn new(mut archive: ZipArchive<R>) -> Result<Self> {
+        let tree = Tree::new(&mut archive)?;
+        Ok(Self {
+            archive,
+            tree,
+            open_files: HashMap::new(),
+            next_handle: 0,
+            uid: rustix::process::getuid().as_raw(),
+            gid: rustix::process::getgid().as_raw(),
+        })
+    }
+
+    fn attr(&self, ino: u64, node: &Node) -> FileAttr {
+        let size = match node.kind {
+            FileType::Directory => 0,
+            _ => node.metadata.as_ref().map_or(0, |metadata| metadata.size),
+        };
+        let mtime = node
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.last_modified)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let default_perm = match node.kind {
+            FileType::Directory => 0o755,
+            _ => 0o644,
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: node.kind,
+            perm: node
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.unix_mode)
+                .map_or(default_perm, |mode| (mode & 0o7777) as u16),
+            nlink: match node.kind {
+                FileType::Directory => 2,
+                _ => 1,
+            },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+
+    fn decompress(&mut self, index: usize) -> Result<Vec<u8>> {
+        let mut file = self.archive.by_index(index)?;
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> Filesystem for ZipFilesystem<R> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = self
+            .tree
+            .node(parent)
+            .and_then(|node| node.children.get(name))
+            .and_then(|ino| Some((*ino, self.tree.node(*ino)?)));
+        match child {
+            Some((ino, node)) => reply.entry(&TTL, &self.attr(ino, node), 0),
+            None => reply.error(Errno::NOENT.raw_os_error()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.tree.node(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None => reply.error(Errno::NOENT.raw_os_error()),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let index = match self.tree.node(ino) {
+            None => return reply.error(Errno::NOENT.raw_os_error()),
+            Some(node) if node.kind == FileType::Directory => {
+                return reply.error(Errno::ISDIR.raw_os_error())
+            }
+            Some(node) => node.index,
+        };
+        let Some(index) = index else {
+            return reply.error(Errno::NOENT.raw_os_error());
+        };
+        match self.decompress(index) {
+            Ok(data) => {
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.open_files.insert(handle, data);
+                reply.opened(handle, 0);
+            }
+            Err(e) => {
+                log::warn!("Failed to decompress file {ino}: {e:#}");
+                reply.error(Errno::IO.raw_os_error());
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(data) = self.open_files.get(&fh) else {
+            return reply.error(Errno::BADF.raw_os_error());
+        };
+        let start = (offset.max(0) as usize).min(data.len());
+        let end = start.saturating_add(size as usize).min(data.len());
+        reply.data(&data[start..end]);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.open_files.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.tree.node(ino) else {
+            return reply.error(Errno::NOENT.raw_os_error());
+        };
+        let entries = [
+            (ino, FileType::Directory, OsStr::new(".")),
+            (node.parent, FileType::Directory, OsStr::new("..")),
+        ]
+        .into_iter()
+        .chain(node.children.iter().filter_map(|(name, child)| {
+            let kind = self.tree.node(*child)?.kind;
+            Some((*child, kind, name.as_os_str()))
+        }));
+        for (position, (ino, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            // The offset given is that of the next entry.
+            if reply.add(ino, position as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount the contents of `archive` at `mountpoint`, and serve them until
+/// the filesystem is unmounted.
+pub(crate) fn mount<R: Read + Seek>(archive: ZipArchive<R>, mountpoint: &Path) -> Result<()> {
+    let filesystem = ZipFilesystem::new(archive)?;
+    fuser::mount2(
+        filesystem,
+        mountpoint,
+        &[
+            MountOption::RO,
+            MountOption::FSName("ripunzip".to_string()),
+            MountOption::Subtype("zip".to_string()),
+        ],
+    )
+    .with_context(|| format!("Failed to mount at {}", mountpoint.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        io::{Cursor, Write},
+    };
+
+    use fuser::{FileType, FUSE_ROOT_ID};
+    use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+    use super::Tree;
+
+    #[test]
+    fn test_tree() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("a/b/c.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"c").unwrap();
+        zip.add_directory("a/d", SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("../escape.txt", SimpleFileOptions::default())
+            .unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        let tree = Tree::new(&mut archive).unwrap();
+
+        let root = tree.node(FUSE_ROOT_ID).unwrap();
+        assert_eq!(root.children.keys().collect::<Vec<_>>(), ["a"]);
+        let a = tree.node(root.children[OsStr::new("a")]).unwrap();
+        assert_eq!(a.kind, FileType::Directory);
+        assert!(a.index.is_none());
+        assert_eq!(a.children.keys().collect::<Vec<_>>(), ["b", "d"]);
+        let b = tree.node(a.children[OsStr::new("b")]).unwrap();
+        let c = tree.node(b.children[OsStr::new("c.txt")]).unwrap();
+        assert_eq!(c.kind, FileType::RegularFile);
+        assert_eq!(c.index, Some(0));
+        assert_eq!(c.metadata.as_ref().unwrap().size, 1);
+        let d = tree.node(a.children[OsStr::new("d")]).unwrap();
+        assert_eq!(d.kind, FileType::Directory);
+        assert_eq!(d.index, Some(1));
+    }
+}
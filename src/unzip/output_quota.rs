@@ -0,0 +1,105 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use thiserror::Error;
+
+/// Returned when extraction would write more than the permitted number of
+/// bytes.
+#[derive(Error, Debug)]
+#[error("Extraction exceeded the maximum output size of {limit} bytes")]
+pub(crate) struct QuotaExceededError {
+    limit: u64,
+}
+
+/// A limit on the total number of bytes extracted, shared between all the
+/// threads doing the extraction.
+pub(crate) struct OutputQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl OutputQuota {
+    pub(crate) fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// How many more bytes of output are permitted.
+    pub(crate) fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used.load(Ordering::Relaxed))
+    }
+
+    /// Account for `bytes` more bytes of output, failing if that takes us
+    /// over the limit. Once the limit is exceeded, every later call fails
+    /// too, so all threads stop promptly.
+    pub(crate) fn charge(&self, bytes: u64) -> Result<(), QuotaExceededError> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed);
+        if used.saturating_add(bytes) > self.limit {
+            Err(QuotaExceededError { limit: self.limit })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A reader which charges everything read from it to an [`OutputQuota`],
+/// if there is one.
+pub(crate) struct QuotaReader<'a, R: Read> {
+    inner: R,
+    quota: Option<&'a OutputQuota>,
+}
+
+impl<'a, R: Read> QuotaReader<'a, R> {
+    pub(crate) fn new(inner: R, quota: Option<&'a OutputQuota>) -> Self {
+        Self { inner, quota }
+    }
+}
+
+impl<R: Read> Read for QuotaReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        if let Some(quota) = self.quota {
+            quota
+                .charge(bytes_read as u64)
+                .map_err(std::io::Error::other)?;
+        }
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::{OutputQuota, QuotaReader};
+
+    #[test]
+    fn test_quota_shared_between_readers() {
+        let quota = OutputQuota::new(10);
+        let mut buf = Vec::new();
+        QuotaReader::new(Cursor::new([0u8; 6]), Some(&quota))
+            .read_to_end(&mut buf)
+            .unwrap();
+        let result = QuotaReader::new(Cursor::new([0u8; 6]), Some(&quota)).read_to_end(&mut buf);
+        assert!(result.is_err());
+        // Everything after exceeding the quota fails, too.
+        assert!(QuotaReader::new(Cursor::new([0u8; 1]), Some(&quota))
+            .read_to_end(&mut buf)
+            .is_err());
+        QuotaReader::new(Cursor::new([0u8; 100]), None)
+            .read_to_end(&mut buf)
+            .unwrap();
+    }
+}
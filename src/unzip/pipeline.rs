@@ -0,0 +1,397 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! When unzipping from a URI, fetching each file's data and decompressing it
+//! are done by different threads, so that network latency and CPU work
+//! overlap rather than each thread waiting on both in turn. A few fetch
+//! threads read the raw data for each file into memory, in the order the
+//! files will be extracted, and the threads doing the extraction read from
+//! those buffers instead of the network.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
+
+use super::thread_tuner;
+
+/// How many threads fetch data from the network.
+pub(crate) const FETCH_THREADS: usize = 2;
+
+/// Limits on how much data fetched from the network may be waiting in
+/// memory to be extracted. Fetching pauses once the buffered data would go
+/// above the high watermark, and resumes once it falls to the low
+/// watermark, so that fetching happens in bursts rather than a byte at a
+/// time whenever a file has been extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadaheadWatermarks {
+    /// The most data which may be buffered, in bytes. Files bigger than
+    /// this are read straight from the network as they're extracted.
+    pub high: u64,
+    /// How little data must be buffered, in bytes, before fetching resumes.
+    pub low: u64,
+}
+
+impl Default for ReadaheadWatermarks {
+    fn default() -> Self {
+        Self {
+            high: 64 * 1024 * 1024,
+            low: 32 * 1024 * 1024,
+        }
+    }
+}
+
+enum SpanState {
+    /// Not yet fetched.
+    Pending,
+    /// Fetched, and waiting to be extracted.
+    Ready(Arc<Vec<u8>>),
+    /// To be read straight from the network, because it's too big to
+    /// buffer, fetching it failed, or it has already been extracted.
+    Direct,
+}
+
+/// A range of the zip file containing one file's local header and data.
+struct Span {
+    end: u64,
+    state: SpanState,
+}
+
+#[derive(Default)]
+struct PipelineState {
+    watermarks: ReadaheadWatermarks,
+    /// Spans by start offset.
+    spans: BTreeMap<u64, Span>,
+    /// The start offsets of the spans still to be fetched, in order.
+    to_fetch: VecDeque<u64>,
+    bytes_buffered: u64,
+    /// The most that's ever been buffered.
+    peak_bytes_buffered: u64,
+    /// Whether fetching is waiting for the buffered data to fall to the
+    /// low watermark.
+    paused: bool,
+    finished: bool,
+}
+
+enum NextFetch {
+    Fetch(u64, u64),
+    Wait,
+    Done,
+}
+
+impl PipelineState {
+    /// Claim the next span to fetch, if there's room to buffer it.
+    fn claim_next(&mut self) -> NextFetch {
+        let watermarks = self.watermarks;
+        let Some(&start) = self.to_fetch.front().filter(|_| !self.finished) else {
+            return NextFetch::Done;
+        };
+        if self.paused && self.bytes_buffered > watermarks.low {
+            return NextFetch::Wait;
+        }
+        self.paused = false;
+        let end = self.spans[&start].end;
+        if self.bytes_buffered + (end - start) > watermarks.high {
+            self.paused = true;
+            return NextFetch::Wait;
+        }
+        self.to_fetch.pop_front();
+        self.bytes_buffered += end - start;
+        self.peak_bytes_buffered = self.peak_bytes_buffered.max(self.bytes_buffered);
+        NextFetch::Fetch(start, end)
+    }
+}
+
+/// Data fetched ahead of extraction, shared between the fetch threads and
+/// [`PipelinedReader`]s.
+pub(crate) struct Pipeline {
+    state: Mutex<PipelineState>,
+    changed: Condvar,
+}
+
+impl Pipeline {
+    pub(crate) fn new(watermarks: ReadaheadWatermarks) -> Self {
+        Self {
+            state: Mutex::new(PipelineState {
+                watermarks,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Plan to fetch these ranges of the zip file, in this order. Ranges
+    /// too big to buffer are left to be read directly.
+    pub(crate) fn plan(&self, ranges: impl IntoIterator<Item = Range<u64>>) {
+        let mut state = self.state.lock().unwrap();
+        for range in ranges {
+            if range.is_empty() || state.spans.contains_key(&range.start) {
+                continue;
+            }
+            let span_state = if range.end - range.start > state.watermarks.high {
+                SpanState::Direct
+            } else {
+                state.to_fetch.push_back(range.start);
+                SpanState::Pending
+            };
+            state.spans.insert(
+                range.start,
+                Span {
+                    end: range.end,
+                    state: span_state,
+                },
+            );
+        }
+    }
+
+    /// Fetch planned spans using `reader` until there are none left, or
+    /// until [`Pipeline::finish`] is called. Run by each fetch thread.
+    pub(crate) fn fetch(&self, mut reader: impl Read + Seek) {
+        while let Some((start, end)) = self.next_to_fetch() {
+            let mut data = vec![0u8; (end - start) as usize];
+            let result = reader
+                .seek(SeekFrom::Start(start))
+                .and_then(|_| reader.read_exact(&mut data));
+            let mut state = self.state.lock().unwrap();
+            let span = state.spans.get_mut(&start).unwrap();
+            match result {
+                Ok(()) => span.state = SpanState::Ready(Arc::new(data)),
+                Err(e) => {
+                    // Extraction will read this directly, and report any
+                    // error properly.
+                    log::debug!("Failed to fetch {start}..{end} ahead of extraction: {e}");
+                    span.state = SpanState::Direct;
+                    state.bytes_buffered -= end - start;
+                }
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Wait for there to be room in the buffer for the next span, then
+    /// claim it.
+    fn next_to_fetch(&self) -> Option<(u64, u64)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.claim_next() {
+                NextFetch::Fetch(start, end) => return Some((start, end)),
+                NextFetch::Wait => state = self.changed.wait(state).unwrap(),
+                NextFetch::Done => return None,
+            }
+        }
+    }
+
+    /// Lower the watermarks to `limit`, if they're above it. This must be
+    /// done before anything is planned.
+    pub(crate) fn limit_watermarks(&self, limit: ReadaheadWatermarks) {
+        let mut state = self.state.lock().unwrap();
+        state.watermarks = ReadaheadWatermarks {
+            high: state.watermarks.high.min(limit.high),
+            low: state.watermarks.low.min(limit.low),
+        };
+    }
+
+    /// The most data that's been buffered at once.
+    pub(crate) fn peak_bytes_buffered(&self) -> u64 {
+        self.state.lock().unwrap().peak_bytes_buffered
+    }
+
+    /// Stop fetching, and have anything not yet fetched read directly.
+    pub(crate) fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        state.to_fetch.clear();
+        for span in state.spans.values_mut() {
+            if matches!(span.state, SpanState::Pending) {
+                span.state = SpanState::Direct;
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Read from a fetched span containing `pos`, waiting for it to be
+    /// fetched if need be. Returns the number of bytes read and the start
+    /// of the span, or `None` if the data must be read directly.
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Option<(usize, u64)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let (&start, span) = state.spans.range(..=pos).next_back()?;
+            if pos >= span.end {
+                return None;
+            }
+            match &span.state {
+                SpanState::Pending => {
+                    let started = Instant::now();
+                    state = self.changed.wait(state).unwrap();
+                    thread_tuner::record_network_wait(started.elapsed());
+                }
+                SpanState::Ready(data) => {
+                    let data = &data[(pos - start) as usize..];
+                    let bytes_read = buf.len().min(data.len());
+                    buf[..bytes_read].copy_from_slice(&data[..bytes_read]);
+                    return Some((bytes_read, start));
+                }
+                SpanState::Direct => return None,
+            }
+        }
+    }
+
+    /// Discard a fetched span once its file has been extracted.
+    fn release(&self, start: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(span) = state.spans.get_mut(&start) else {
+            return;
+        };
+        if matches!(span.state, SpanState::Ready(_)) {
+            span.state = SpanState::Direct;
+            let len = span.end - start;
+            state.bytes_buffered -= len;
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// A reader which reads from the data fetched by a [`Pipeline`] where it
+/// can, and otherwise from the underlying reader. The fetched data it used
+/// is discarded when it's dropped, which is once the file it was used to
+/// extract is done with.
+pub(crate) struct PipelinedReader<R: Read + Seek> {
+    inner: R,
+    pipeline: Arc<Pipeline>,
+    pos: u64,
+    used_spans: Vec<u64>,
+}
+
+impl<R: Read + Seek> PipelinedReader<R> {
+    pub(crate) fn new(inner: R, pipeline: Arc<Pipeline>) -> Self {
+        Self {
+            inner,
+            pipeline,
+            pos: 0,
+            used_spans: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read + Seek + Clone> Clone for PipelinedReader<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            pipeline: self.pipeline.clone(),
+            pos: self.pos,
+            used_spans: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for PipelinedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = match self.pipeline.read_at(self.pos, buf) {
+            Some((bytes_read, span_start)) => {
+                if !self.used_spans.contains(&span_start) {
+                    self.used_spans.push(span_start);
+                }
+                bytes_read
+            }
+            None => {
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+                self.inner.read(buf)?
+            }
+        };
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<R: Read + Seek> Seek for PipelinedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            // Only the underlying reader knows where the end is.
+            SeekFrom::End(_) => self.inner.seek(pos)?,
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeked before start")
+            })?,
+        };
+        Ok(self.pos)
+    }
+}
+
+impl<R: Read + Seek> Drop for PipelinedReader<R> {
+    fn drop(&mut self) {
+        for span_start in &self.used_spans {
+            self.pipeline.release(*span_start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read, Seek, SeekFrom},
+        sync::Arc,
+    };
+
+    use super::{NextFetch, Pipeline, PipelinedReader, ReadaheadWatermarks};
+
+    fn watermarks(high: u64, low: u64) -> ReadaheadWatermarks {
+        ReadaheadWatermarks { high, low }
+    }
+
+    #[test]
+    fn test_pipelined_reads() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let pipeline = Arc::new(Pipeline::new(watermarks(300, 300)));
+        let spans = [0..100, 100..300, 300..1000];
+        pipeline.plan(spans.clone());
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                let reader = Cursor::new(data.clone());
+                scope.spawn(|| pipeline.fetch(reader));
+            }
+            // The underlying reader is only used for the span too big to
+            // buffer.
+            let mut underlying = vec![0u8; 300];
+            underlying.extend_from_slice(&data[300..]);
+            for span in spans {
+                let mut reader = PipelinedReader::new(Cursor::new(&underlying), pipeline.clone());
+                reader.seek(SeekFrom::Start(span.start)).unwrap();
+                let mut contents = vec![0u8; (span.end - span.start) as usize];
+                reader.read_exact(&mut contents).unwrap();
+                assert_eq!(contents, &data[span.start as usize..span.end as usize]);
+            }
+            pipeline.finish();
+        });
+        assert_eq!(pipeline.state.lock().unwrap().bytes_buffered, 0);
+    }
+
+    #[test]
+    fn test_fetching_pauses_between_watermarks() {
+        let pipeline = Pipeline::new(watermarks(300, 100));
+        pipeline.plan((0..5).map(|i| i * 100..(i + 1) * 100));
+        let mut state = pipeline.state.lock().unwrap();
+        for i in 0..3 {
+            assert!(matches!(
+                state.claim_next(),
+                NextFetch::Fetch(start, _) if start == i * 100
+            ));
+        }
+        assert!(matches!(state.claim_next(), NextFetch::Wait));
+        // Still above the low watermark, so fetching stays paused even
+        // though there's room for another span.
+        state.bytes_buffered = 200;
+        assert!(matches!(state.claim_next(), NextFetch::Wait));
+        state.bytes_buffered = 100;
+        assert!(matches!(state.claim_next(), NextFetch::Fetch(300, 400)));
+        assert!(matches!(state.claim_next(), NextFetch::Fetch(400, 500)));
+        assert!(matches!(state.claim_next(), NextFetch::Done));
+    }
+}
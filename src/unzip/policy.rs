@@ -0,0 +1,90 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{ExistingFileHandler, FilenameSanitization, ModificationTimes, SymlinkPolicy};
+
+/// How extracted files are named, and what they're given from the archive
+/// besides their contents.
+#[derive(Default)]
+pub struct ExtractionPolicy<'a> {
+    /// What permissions and attributes to give extracted files.
+    pub permissions: PermissionsPolicy,
+    /// What modification times to give extracted files and directories.
+    /// Output sinks are given the fixed time, if there is one, in place of
+    /// each file's time in the archive.
+    pub mtimes: ModificationTimes,
+    /// What to do with symbolic links in zip files. They're only known to
+    /// be links once the central directory has been read, so those in
+    /// zip files read from a stream are always extracted as files.
+    pub symlinks: SymlinkPolicy,
+    /// What to do about files which already exist in the output directory.
+    pub overwrite: OverwritePolicy<'a>,
+    /// How to deal with filenames which aren't valid on Windows.
+    pub path_sanitization: FilenameSanitization,
+    /// Whether to leave out the clutter macOS adds to zip files: the
+    /// `__MACOSX` directory, `.DS_Store` files and AppleDouble `._` files.
+    pub skip_macos_junk: bool,
+    /// Whether to give the Finder info, resource forks and other extended
+    /// attributes in AppleDouble `._` files back to the files they belong
+    /// to, rather than extracting them as files. This is only supported on
+    /// macOS, and only when extracting to a directory.
+    pub restore_apple_double: bool,
+}
+
+impl ExtractionPolicy<'_> {
+    /// Whether the entry called `name` should be left out regardless of
+    /// any filters.
+    pub(crate) fn skips(&self, name: &str) -> bool {
+        (self.skip_macos_junk || self.restore_apple_double) && is_macos_junk(name)
+    }
+}
+
+/// Whether `name` is something macOS leaves in zip files which is of no
+/// use elsewhere.
+fn is_macos_junk(name: &str) -> bool {
+    let name = name.trim_end_matches('/');
+    let last = name.rsplit('/').next().unwrap_or_default();
+    name.split('/').next() == Some("__MACOSX") || last == ".DS_Store" || last.starts_with("._")
+}
+
+/// What permissions and attributes to give extracted files. The default
+/// uses the permissions from the archive exactly, and doesn't set the
+/// hidden attribute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PermissionsPolicy {
+    /// Whether to mask the permissions of extracted files with the process
+    /// umask, as other programs do when creating files, rather than using
+    /// the permissions from the zip file exactly.
+    pub apply_umask: bool,
+    /// Whether to set the hidden attribute on files which had it when they
+    /// were zipped on Windows. The read-only attribute is always restored.
+    /// Has no effect on other platforms.
+    pub restore_hidden_attribute: bool,
+}
+
+/// What to do about files which already exist in the output directory.
+/// None of these has any effect when staging, since files are extracted
+/// into an empty directory then.
+#[derive(Default)]
+pub enum OverwritePolicy<'a> {
+    /// Replace them.
+    #[default]
+    Always,
+    /// Leave them alone if they have the same size and CRC as in the zip
+    /// file, and replace them otherwise. This makes re-extracting a mostly
+    /// unchanged zip file much quicker.
+    Freshen,
+    /// Ask this what to do about each.
+    Ask(Box<dyn ExistingFileHandler + Sync + 'a>),
+}
+
+impl OverwritePolicy<'_> {
+    pub(crate) fn is_freshen(&self) -> bool {
+        matches!(self, Self::Freshen)
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use anyhow::{bail, Result};
+
+/// The I/O scheduling priority of a thread, as set by `ionice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// The best-effort class, at a level from 0, the highest, to 7, the
+    /// lowest.
+    BestEffort(u8),
+    /// Only do I/O when no other process wants to.
+    Idle,
+}
+
+/// How much to lower the priority of the threads which extract files, so
+/// that extracting a huge archive in the background doesn't starve
+/// everything else on the machine. This is only supported on Linux.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerPriority {
+    /// The niceness to run at, from 0 to 19. Threads which are already
+    /// nicer than this are left alone.
+    pub nice: Option<i32>,
+    /// The I/O priority to run at. Setting this needs the `io_priority`
+    /// feature.
+    pub io: Option<IoPriority>,
+}
+
+impl WorkerPriority {
+    /// Lower the priority of the calling thread.
+    pub(crate) fn apply(&self) -> Result<()> {
+        if let Some(nice) = self.nice {
+            set_nice(nice)?;
+        }
+        if let Some(io) = self.io {
+            set_io_priority(io)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> Result<()> {
+    use anyhow::Context;
+    use rustix::{process::getpriority_process, process::setpriority_process, thread::gettid};
+
+    if !(0..=19).contains(&nice) {
+        bail!("Niceness must be between 0 and 19, not {nice}");
+    }
+    // On Linux, each thread has a niceness of its own, which can be set by
+    // passing its thread ID as the process.
+    let tid = Some(gettid());
+    let current = getpriority_process(tid).context("Failed to read thread niceness")?;
+    if nice > current {
+        setpriority_process(tid, nice).context("Failed to set thread niceness")?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> Result<()> {
+    bail!("Thread niceness can only be set on Linux")
+}
+
+#[cfg(all(feature = "io_priority", target_os = "linux"))]
+fn set_io_priority(io: IoPriority) -> Result<()> {
+    // From linux/ioprio.h.
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    const IOPRIO_CLASS_BE: u32 = 2;
+    const IOPRIO_CLASS_IDLE: u32 = 3;
+
+    let ioprio = match io {
+        IoPriority::BestEffort(level @ 0..=7) => {
+            (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level as u32
+        }
+        IoPriority::BestEffort(level) => {
+            bail!("Best-effort I/O priority must be between 0 and 7, not {level}")
+        }
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+    };
+    // A thread ID of zero means the calling thread.
+    // SAFETY: ioprio_set takes no pointers.
+    #[allow(unsafe_code)]
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+    if result != 0 {
+        return Err(anyhow::Error::new(std::io::Error::last_os_error())
+            .context("Failed to set thread I/O priority"));
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "io_priority", target_os = "linux")))]
+fn set_io_priority(_io: IoPriority) -> Result<()> {
+    bail!("I/O priority can only be set on Linux, when built with the io_priority feature")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use rustix::{process::getpriority_process, thread::gettid};
+
+    use super::{IoPriority, WorkerPriority};
+
+    #[test]
+    fn test_worker_priority() {
+        std::thread::spawn(|| {
+            WorkerPriority {
+                nice: Some(5),
+                io: None,
+            }
+            .apply()
+            .unwrap();
+            assert!(getpriority_process(Some(gettid())).unwrap() >= 5);
+            assert!(WorkerPriority {
+                nice: Some(20),
+                io: None,
+            }
+            .apply()
+            .is_err());
+            let io_priority = WorkerPriority {
+                nice: None,
+                io: Some(IoPriority::Idle),
+            }
+            .apply();
+            assert_eq!(io_priority.is_ok(), cfg!(feature = "io_priority"));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -6,11 +6,23 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::cmp::min;
+use std::{
+    cmp::min,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::UnzipProgressReporter;
+
+/// Progress is passed on once a thread has this many bytes to report...
+const BATCH_BYTES: u64 = 4 * 1024 * 1024;
+/// ...or this long after it last passed any on.
+const BATCH_INTERVAL: Duration = Duration::from_millis(100);
 
 /// A struct which can issue periodic updates indicating progress towards
-/// an external total, based on updates towards an internal goal.
-pub struct ProgressUpdater<F: Fn(u64)> {
+/// an external total, based on updates towards an internal goal. Each update
+/// gives the progress towards both.
+pub struct ProgressUpdater<F: Fn(u64, u64)> {
     callback: F,
     internal_progress: u64,
     per_update_internal: u64,
@@ -20,7 +32,7 @@ pub struct ProgressUpdater<F: Fn(u64)> {
     internal_total: u64,
 }
 
-impl<F: Fn(u64)> ProgressUpdater<F> {
+impl<F: Fn(u64, u64)> ProgressUpdater<F> {
     /// Create a new progress updater, with a callback to be called periodically.
     pub fn new(
         callback: F,
@@ -29,11 +41,9 @@ impl<F: Fn(u64)> ProgressUpdater<F> {
         per_update_internal: u64,
     ) -> Self {
         let per_update_internal = min(internal_total, per_update_internal);
-        let total_updates_expected = if per_update_internal == 0 {
-            0
-        } else {
-            internal_total / per_update_internal
-        };
+        let total_updates_expected = internal_total
+            .checked_div(per_update_internal)
+            .unwrap_or_default();
         let (update_external_amount, remainder_external) = if total_updates_expected == 0 {
             (0, external_total)
         } else {
@@ -62,13 +72,12 @@ impl<F: Fn(u64)> ProgressUpdater<F> {
     }
 
     fn send_due_updates(&mut self) {
-        let updates_due = if self.per_update_internal == 0 {
-            0
-        } else {
-            self.internal_progress / self.per_update_internal
-        };
+        let updates_due = self
+            .internal_progress
+            .checked_div(self.per_update_internal)
+            .unwrap_or_default();
         while updates_due > self.external_updates_sent {
-            (self.callback)(self.update_external_amount);
+            (self.callback)(self.update_external_amount, self.per_update_internal);
             self.external_updates_sent += 1;
         }
     }
@@ -78,18 +87,127 @@ impl<F: Fn(u64)> ProgressUpdater<F> {
     pub fn finish(&mut self) {
         self.internal_progress = self.internal_total;
         self.send_due_updates();
-        if self.remainder_external > 0 {
-            (self.callback)(self.remainder_external);
+        let remainder_internal = self
+            .internal_total
+            .saturating_sub(self.external_updates_sent * self.per_update_internal);
+        if self.remainder_external > 0 || remainder_internal > 0 {
+            (self.callback)(self.remainder_external, remainder_internal);
+        }
+    }
+}
+
+struct ProgressBatch {
+    bytes: u64,
+    bytes_written: u64,
+    last_sent: Instant,
+}
+
+/// Gathers up the bytes extracted by each worker thread, and passes them on
+/// to the progress reporter in batches, so that lots of threads extracting
+/// small files aren't all contending to report progress.
+pub(crate) struct ProgressBatcher {
+    /// One batch per thread in the thread pool, plus one for any other
+    /// thread.
+    batches: Vec<Mutex<ProgressBatch>>,
+}
+
+impl Default for ProgressBatcher {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            batches: (0..=rayon::current_num_threads())
+                .map(|_| {
+                    Mutex::new(ProgressBatch {
+                        bytes: 0,
+                        bytes_written: 0,
+                        last_sent: now,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ProgressBatcher {
+    /// Note that some bytes have been extracted by the current thread.
+    pub(crate) fn bytes_extracted(&self, reporter: &dyn UnzipProgressReporter, count: u64) {
+        self.bytes_extracted_and_written(reporter, count, 0)
+    }
+
+    /// Note that some bytes have been extracted by the current thread, and
+    /// `written` uncompressed bytes written out.
+    pub(crate) fn bytes_extracted_and_written(
+        &self,
+        reporter: &dyn UnzipProgressReporter,
+        count: u64,
+        written: u64,
+    ) {
+        let slot = rayon::current_thread_index().unwrap_or(self.batches.len() - 1);
+        let mut batch = self.batches[slot % self.batches.len()].lock().unwrap();
+        batch.bytes += count;
+        batch.bytes_written += written;
+        if batch.bytes >= BATCH_BYTES || batch.last_sent.elapsed() >= BATCH_INTERVAL {
+            Self::send(&mut batch, reporter);
+            batch.last_sent = Instant::now();
+        }
+    }
+
+    /// Pass on everything not yet reported.
+    pub(crate) fn flush(&self, reporter: &dyn UnzipProgressReporter) {
+        for batch in &self.batches {
+            Self::send(&mut batch.lock().unwrap(), reporter);
+        }
+    }
+
+    fn send(batch: &mut ProgressBatch, reporter: &dyn UnzipProgressReporter) {
+        let bytes = std::mem::take(&mut batch.bytes);
+        if bytes > 0 {
+            reporter.bytes_extracted(bytes);
+        }
+        let bytes_written = std::mem::take(&mut batch.bytes_written);
+        if bytes_written > 0 {
+            reporter.bytes_written(bytes_written);
         }
     }
 }
 
+#[test]
+fn test_progress_batcher() {
+    struct Recorder(Mutex<Vec<u64>>);
+    impl UnzipProgressReporter for Recorder {
+        fn bytes_extracted(&self, count: u64) {
+            self.0.lock().unwrap().push(count);
+        }
+    }
+    let recorder = Recorder(Mutex::default());
+    let batcher = ProgressBatcher::default();
+    for _ in 0..4 {
+        batcher.bytes_extracted(&recorder, BATCH_BYTES / 4 + 1);
+    }
+    // Enough has been extracted that it must have been reported.
+    assert_eq!(
+        recorder.0.lock().unwrap().iter().sum::<u64>(),
+        BATCH_BYTES + 4
+    );
+    batcher.bytes_extracted(&recorder, 10);
+    batcher.flush(&recorder);
+    assert_eq!(
+        recorder.0.lock().unwrap().iter().sum::<u64>(),
+        BATCH_BYTES + 14
+    );
+    let reports = recorder.0.lock().unwrap().len();
+    batcher.flush(&recorder);
+    assert_eq!(recorder.0.lock().unwrap().len(), reports);
+}
+
 #[test]
 fn test_progress_updater() {
     let amount_received = std::rc::Rc::new(std::cell::RefCell::new(0u64));
+    let internal_received = std::rc::Rc::new(std::cell::RefCell::new(0u64));
     let mut progresser = ProgressUpdater::new(
-        |progress| {
+        |progress, internal| {
             *(amount_received.borrow_mut()) += progress;
+            *(internal_received.borrow_mut()) += internal;
         },
         100,
         1000,
@@ -102,15 +220,17 @@ fn test_progress_updater() {
     assert_eq!(*amount_received.borrow(), 10);
     progresser.progress(800);
     assert_eq!(*amount_received.borrow(), 90);
+    assert_eq!(*internal_received.borrow(), 900);
     progresser.finish();
     assert_eq!(*amount_received.borrow(), 100);
+    assert_eq!(*internal_received.borrow(), 1000);
 }
 
 #[test]
 fn test_progress_updater_zero_external() {
     let amount_received = std::rc::Rc::new(std::cell::RefCell::new(0u64));
     let mut progresser = ProgressUpdater::new(
-        |progress| {
+        |progress, _| {
             *(amount_received.borrow_mut()) += progress;
         },
         0,
@@ -128,7 +248,7 @@ fn test_progress_updater_zero_external() {
 fn test_progress_updater_small_internal() {
     let amount_received = std::rc::Rc::new(std::cell::RefCell::new(0u64));
     let mut progresser = ProgressUpdater::new(
-        |progress| {
+        |progress, _| {
             *(amount_received.borrow_mut()) += progress;
         },
         100,
@@ -145,7 +265,7 @@ fn test_progress_updater_small_internal() {
 fn test_progress_updater_zero_internal() {
     let amount_received = std::rc::Rc::new(std::cell::RefCell::new(0u64));
     let mut progresser = ProgressUpdater::new(
-        |progress| {
+        |progress, _| {
             *(amount_received.borrow_mut()) += progress;
         },
         100,
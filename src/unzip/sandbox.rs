@@ -0,0 +1,351 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Restricts the threads which decompress an archive, so that a malicious
+//! archive which exploits a bug in decompression can't reach far. Landlock
+//! limits which files they can open, and a seccomp filter stops them
+//! running programs and the like. Both only apply to the threads which
+//! restrict themselves, and to threads they start, so whoever is unzipping
+//! can carry on as before once extraction is over.
+
+use std::{
+    fs::File,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+// Filesystem access rights, from linux/landlock.h.
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Every right which version 1 of the Landlock ABI knows about.
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+/// Directories which can still be read, since opening HTTP(S) connections
+/// needs to read name resolution settings and certificates.
+const READABLE_SYSTEM_DIRECTORIES: &[&str] = &["/etc"];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// Classic BPF instructions, from linux/filter.h: BPF_LD | BPF_W | BPF_ABS,
+// BPF_JMP | BPF_JEQ | BPF_K, BPF_JMP | BPF_JGE | BPF_K and BPF_RET | BPF_K.
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+#[cfg(target_arch = "x86_64")]
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+/// Where the system call number and architecture are in `seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// x32 system calls use the x86_64 architecture, with this bit set in their
+/// numbers, so they would otherwise slip past the denylist.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls which unzipping never needs, but which would help an
+/// attacker who had taken over a thread.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+];
+
+/// The restrictions to apply to each thread which decompresses an archive.
+pub(crate) struct Sandbox {
+    ruleset: OwnedFd,
+    filter: Vec<libc::sock_filter>,
+}
+
+impl Sandbox {
+    /// Prepare to restrict threads so that they can only create, change
+    /// and read files beneath `directories`, besides a few system
+    /// directories which they can read. This fails if the kernel doesn't
+    /// support Landlock.
+    pub(crate) fn new(directories: &[&Path]) -> Result<Self> {
+        let handled = handled_access_fs(landlock_abi()?);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: the attribute is a valid landlock_ruleset_attr of the size
+        // given, and the kernel only reads it.
+        #[allow(unsafe_code)]
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to create Landlock ruleset");
+        }
+        // SAFETY: the kernel just gave us this file descriptor, and nothing
+        // else owns it.
+        #[allow(unsafe_code)]
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        for directory in directories {
+            add_rule(&ruleset, directory, handled & !ACCESS_FS_EXECUTE)
+                .with_context(|| format!("Failed to allow access to {}", directory.display()))?;
+        }
+        for directory in READABLE_SYSTEM_DIRECTORIES.iter().map(Path::new) {
+            if directory.exists() {
+                add_rule(
+                    &ruleset,
+                    directory,
+                    ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+                )
+                .with_context(|| format!("Failed to allow access to {}", directory.display()))?;
+            }
+        }
+        Ok(Self {
+            ruleset,
+            filter: seccomp_filter()?,
+        })
+    }
+
+    /// Restrict the calling thread, and any threads it starts from now on.
+    /// This can't be undone.
+    pub(crate) fn apply(&self) -> Result<()> {
+        // Neither Landlock nor seccomp can be used by unprivileged threads
+        // which could gain privileges by running setuid programs.
+        // SAFETY: this prctl takes no pointers.
+        #[allow(unsafe_code)]
+        let result = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to stop the thread gaining privileges");
+        }
+        // SAFETY: the ruleset is an open Landlock ruleset.
+        #[allow(unsafe_code)]
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_restrict_self,
+                self.ruleset.as_raw_fd(),
+                0u32,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to restrict the thread with Landlock");
+        }
+        let program = libc::sock_fprog {
+            len: self.filter.len() as u16,
+            filter: self.filter.as_ptr() as *mut libc::sock_filter,
+        };
+        // SAFETY: the program points to the filter, which outlives this
+        // call, and the kernel copies it rather than changing it.
+        #[allow(unsafe_code)]
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                0u32,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to install the seccomp filter");
+        }
+        Ok(())
+    }
+}
+
+/// The version of the Landlock ABI which the kernel supports.
+fn landlock_abi() -> Result<i64> {
+    // SAFETY: asking for the version passes no attribute.
+    #[allow(unsafe_code)]
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        bail!("This kernel doesn't support Landlock, so extraction can't be sandboxed");
+    }
+    Ok(abi)
+}
+
+/// Whether the kernel supports Landlock, without which nothing can be
+/// sandboxed.
+#[cfg(test)]
+pub(crate) fn is_supported() -> bool {
+    landlock_abi().is_ok()
+}
+
+/// All the filesystem access rights which version `abi` of the Landlock
+/// ABI can restrict.
+fn handled_access_fs(abi: i64) -> u64 {
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        handled |= ACCESS_FS_IOCTL_DEV;
+    }
+    handled
+}
+
+/// Allow `allowed_access` to everything beneath `path`.
+fn add_rule(ruleset: &OwnedFd, path: &Path, allowed_access: u64) -> Result<()> {
+    let parent = File::open(path)?;
+    let attr = PathBeneathAttr {
+        allowed_access,
+        parent_fd: parent.as_raw_fd(),
+    };
+    // SAFETY: the attribute is a valid landlock_path_beneath_attr, and the
+    // kernel only reads it.
+    #[allow(unsafe_code)]
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0u32,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// A seccomp filter which makes each of [`DENIED_SYSCALLS`] fail with
+/// `EPERM`, as does any system call made using another architecture's
+/// calling convention, including x32's.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_filter() -> Result<Vec<libc::sock_filter>> {
+    let statement = |code, k| libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump_if_equal = |k, jt, jf| libc::sock_filter {
+        code: BPF_JMP_JEQ_K,
+        jt,
+        jf,
+        k,
+    };
+    let deny = statement(BPF_RET_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump_if_equal(AUDIT_ARCH, 1, 0),
+        deny,
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        libc::sock_filter {
+            code: BPF_JMP_JGE_K,
+            jt: 0,
+            jf: 1,
+            k: X32_SYSCALL_BIT,
+        },
+        deny,
+    ]);
+    for syscall in DENIED_SYSCALLS {
+        filter.push(jump_if_equal(*syscall as u32, 0, 1));
+        filter.push(deny);
+    }
+    filter.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+    Ok(filter)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp_filter() -> Result<Vec<libc::sock_filter>> {
+    bail!("Extraction can only be sandboxed on x86_64 and aarch64")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, process::Command};
+
+    use tempfile::tempdir;
+
+    use super::{is_supported, Sandbox};
+
+    #[test]
+    fn test_sandbox() {
+        if !is_supported() {
+            log::warn!("Skipping test_sandbox, as this kernel doesn't support Landlock");
+            return;
+        }
+        let inside = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        let sandbox = Sandbox::new(&[inside.path()]).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                sandbox.apply().unwrap();
+                File::create(inside.path().join("a")).unwrap();
+                std::fs::create_dir(inside.path().join("b")).unwrap();
+                assert_eq!(
+                    File::create(outside.path().join("a")).unwrap_err().kind(),
+                    std::io::ErrorKind::PermissionDenied
+                );
+                assert!(
+                    File::open("/etc/hosts").is_ok()
+                        || !std::path::Path::new("/etc/hosts").exists()
+                );
+                assert!(Command::new("true").status().is_err());
+            });
+        });
+        // Only the thread which was sandboxed is restricted.
+        File::create(outside.path().join("a")).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf},
+};
+
+/// How to deal with filenames which aren't valid on Windows, such as
+/// those containing `:` or named `CON`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilenameSanitization {
+    /// Leave filenames as they are.
+    None,
+    /// Replace each invalid character with the given character.
+    Replace(char),
+    /// Percent-encode invalid characters, so that `a:b` becomes `a%3Ab`.
+    PercentEncode,
+}
+
+impl Default for FilenameSanitization {
+    /// Names are sanitized only when running on Windows.
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::Replace('_')
+        } else {
+            Self::None
+        }
+    }
+}
+
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_invalid_char(c: char) -> bool {
+    matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*' | '\\') || c.is_ascii_control()
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    // Reserved names are reserved with any extension, too.
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+impl FilenameSanitization {
+    fn fix_char(&self, c: char, out: &mut String) {
+        match self {
+            Self::None => out.push(c),
+            Self::Replace(replacement) => out.push(*replacement),
+            Self::PercentEncode => {
+                let mut buf = [0u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("%{b:02X}"));
+                }
+            }
+        }
+    }
+
+    fn sanitize_component(&self, name: &str) -> String {
+        let mut out = String::with_capacity(name.len());
+        // Windows silently strips trailing dots and spaces.
+        let trailing_start = name.trim_end_matches(['.', ' ']).len();
+        for (i, c) in name.char_indices() {
+            let reserved_prefix = i == 0 && is_reserved_name(name);
+            if is_invalid_char(c) || i >= trailing_start || reserved_prefix {
+                self.fix_char(c, &mut out);
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    /// Sanitize each component of a relative path. Returns the original
+    /// path if nothing needed to change.
+    pub(crate) fn sanitize<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if *self == Self::None {
+            return Cow::Borrowed(path);
+        }
+        let mut changed = false;
+        let sanitized: PathBuf = path
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => {
+                    let name = name.to_string_lossy();
+                    let sanitized = self.sanitize_component(&name);
+                    changed |= sanitized != name;
+                    PathBuf::from(sanitized)
+                }
+                other => PathBuf::from(other.as_os_str()),
+            })
+            .collect();
+        if changed {
+            Cow::Owned(sanitized)
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::FilenameSanitization;
+
+    fn sanitize(mode: FilenameSanitization, path: &str) -> String {
+        mode.sanitize(Path::new(path))
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    #[test]
+    fn test_replace() {
+        let mode = FilenameSanitization::Replace('_');
+        assert_eq!(sanitize(mode, "a/b.txt"), "a/b.txt");
+        assert_eq!(sanitize(mode, "a:b/c?d*.txt"), "a_b/c_d_.txt");
+        assert_eq!(sanitize(mode, "dir. /file.."), "dir__/file__");
+        assert_eq!(sanitize(mode, "x/CON"), "x/_ON");
+        assert_eq!(sanitize(mode, "x/com1.txt"), "x/_om1.txt");
+        assert_eq!(sanitize(mode, "x/CONSOLE.txt"), "x/CONSOLE.txt");
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        let mode = FilenameSanitization::PercentEncode;
+        assert_eq!(sanitize(mode, "a:b|c"), "a%3Ab%7Cc");
+        assert_eq!(sanitize(mode, "aux.h"), "%61ux.h");
+        assert_eq!(sanitize(mode, "end."), "end%2E");
+    }
+
+    #[test]
+    fn test_none() {
+        let mode = FilenameSanitization::None;
+        assert_eq!(sanitize(mode, "a:b/CON"), "a:b/CON");
+    }
+}
@@ -11,14 +11,20 @@ use std::{
     collections::BTreeMap,
     io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
     ops::Range,
-    sync::{Arc, Condvar, Mutex},
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc, Condvar, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
 };
 
 use ranges::Ranges;
 use reqwest::blocking::Response;
 use thiserror::Error;
 
-use super::http_range_reader::{self, RangeFetcher};
+use super::{
+    http_range_reader::{self, RangeFetcher},
+    signature::SignatureVerifier,
+    thread_tuner, UnzipProgressReporter,
+};
 
 /// This is how much we read from the underlying HTTP stream in a given thread,
 /// before signalling other threads that they may wish to continue with their
@@ -33,6 +39,17 @@ use super::http_range_reader::{self, RangeFetcher};
 /// CPU usage.
 const DEFAULT_MAX_BLOCK: usize = 1024 * 1024;
 
+/// Servers which take at least this long to answer are far away, or slow.
+const HIGH_LATENCY: Duration = Duration::from_millis(100);
+
+/// Fewer, bigger reads are better for servers with high latency, since
+/// the stream stalls for longer whenever we stop reading from it.
+const HIGH_LATENCY_MAX_BLOCK: usize = 4 * 1024 * 1024;
+
+/// When we're only extracting a small part of the zip file, most of what
+/// we read in big blocks would be skipped over and thrown away.
+const SELECTIVE_MAX_BLOCK: usize = 256 * 1024;
+
 /// If we're going to skip over this much data in the underlying stream,
 /// discard the stream and start further ahead. This is a large number
 /// because it's expensive to create new HTTPS streams, and we also can't
@@ -40,15 +57,82 @@ const DEFAULT_MAX_BLOCK: usize = 1024 * 1024;
 /// an expensive rewind.
 const DEFAULT_SKIP_AHEAD_THRESHOLD: u64 = 2 * 1024 * 1024; // 2MB
 
-/// A hint to the [`SeekableHttpReaderEngine`] about the expected access pattern.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub(crate) enum AccessPattern {
+/// Needed ranges closer together than this are fetched as one, since a
+/// new request costs more than downloading a little data we don't need.
+const NEEDED_RANGE_MERGE_GAP: u64 = 64 * 1024;
+
+/// Options for reading zip files over HTTP(S).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpOptions {
+    /// How many bytes from the end of the zip file to fetch in a single
+    /// request when opening it. The end of the central directory record,
+    /// and often the whole central directory, are found there, so this
+    /// saves several round trips. Zero disables this.
+    pub tail_fetch_size: u64,
+    /// How much to read from the HTTP(S) stream at a time, before letting
+    /// other threads get on with extracting what's been read. If this is
+    /// `None`, it's chosen according to how quickly the server responds,
+    /// and how much of the zip file is being extracted.
+    pub chunk_size: Option<usize>,
+    /// Where to keep the zip file if the server doesn't support ranges, so
+    /// it has to be downloaded whole, rather than in an anonymous temporary
+    /// file. It's downloaded to the same path with `.part` appended, and
+    /// only renamed once it's all been downloaded. If such a file is left
+    /// over from an interrupted download, the download is resumed.
+    pub keep_download: Option<PathBuf>,
+    /// Check the zip file against this signature before reading any of it.
+    /// It's then downloaded whole, even if the server supports ranges.
+    pub signature: Option<SignatureVerifier>,
+    /// The size of the zip file, if it's already known, such as from a
+    /// package registry's API. The server then isn't asked for it before
+    /// the zip file is read, saving a round trip, but is assumed to
+    /// support ranges. Its ETag isn't known either, unless the zip file
+    /// has to be downloaded whole.
+    pub content_length: Option<u64>,
+    /// How the zip file will be read when files are extracted from it, if
+    /// this is known better than can be worked out from which files are
+    /// being extracted. `None` lets that be worked out.
+    pub access_pattern: Option<AccessPattern>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            tail_fetch_size: 128 * 1024,
+            chunk_size: None,
+            keep_download: None,
+            signature: None,
+            content_length: None,
+            access_pattern: None,
+        }
+    }
+}
+
+/// How much to read at a time from a server which took `latency` to
+/// respond to its first request.
+fn max_block_for_latency(latency: Duration) -> usize {
+    if latency >= HIGH_LATENCY {
+        HIGH_LATENCY_MAX_BLOCK
+    } else {
+        DEFAULT_MAX_BLOCK
+    }
+}
+
+/// A hint about how a zip file read over HTTP(S) will be read, so that
+/// the right parts of it can be fetched ahead of being needed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AccessPattern {
     /// We expect accesses all over the file.
     RandomAccess,
     /// We expect accesses starting from the beginning and moving to the end,
     /// though there might be some jumping around if multiple threads are
     /// reading from roughly the same area of the file.
     SequentialIsh,
+    /// We expect only these ranges of byte offsets to be read, roughly
+    /// in ascending order. Nothing outside them is fetched from the
+    /// server, other than for reads outside them all, and ranges close
+    /// to each other are fetched together.
+    PlannedRanges(Vec<Range<u64>>),
 }
 
 impl Default for AccessPattern {
@@ -136,6 +220,9 @@ struct State {
     /// Some problem was encountered creating a reader.
     /// All threads should abandon hope.
     read_failed_somewhere: bool,
+    /// The only parts of the file which will be read, sorted, if we know
+    /// them. Data outside them is never requested from the server.
+    needed_ranges: Vec<Range<u64>>,
 }
 
 impl State {
@@ -155,6 +242,7 @@ impl State {
         };
         Self {
             readahead_limit,
+            needed_ranges: needed_ranges(&access_pattern),
             access_pattern,
             skip_ahead_threshold,
             max_block,
@@ -173,19 +261,38 @@ impl State {
         let extra_size = block.len();
         self.cache.insert(pos, CacheCell::new(block));
         self.current_size += extra_size;
+        self.stats.peak_cache_size = self.stats.peak_cache_size.max(self.current_size);
         if let Some(readahead_limit) = self.readahead_limit {
-            // Shrink
+            // Shrink, but never discard the block just inserted, since it's
+            // about to be read even if there are blocks after it.
             while self.current_size > readahead_limit {
                 self.stats.cache_shrinks += 1;
-                let first_block = self.cache.iter().next().map(|(pos, _)| pos).cloned();
-                if let Some(pos) = first_block {
-                    let block = self.cache.remove(&pos).unwrap();
-                    self.current_size -= block.len();
+                let first_block = self
+                    .cache
+                    .keys()
+                    .find(|block_pos| **block_pos != pos)
+                    .cloned();
+                match first_block {
+                    Some(pos) => {
+                        let block = self.cache.remove(&pos).unwrap();
+                        self.current_size -= block.len();
+                    }
+                    None => break,
                 }
             }
         }
     }
 
+    /// The needed range containing `pos`, if only some ranges are needed
+    /// and that's one of them.
+    fn needed_range(&self, pos: u64) -> Option<Range<u64>> {
+        let index = self.needed_ranges.partition_point(|range| range.end <= pos);
+        self.needed_ranges
+            .get(index)
+            .filter(|range| range.start <= pos)
+            .cloned()
+    }
+
     /// Read from the readahead cache, if we can.
     /// If '`discard_read_data` is true, we assume that all data
     /// will be consumed exactly once, so we discard the data that has been read.
@@ -193,7 +300,11 @@ impl State {
     /// so then we will split the block and merely retain the bits that are
     /// not yet read by the readers.
     fn read_from_cache(&mut self, pos: u64, buf: &mut [u8]) -> Option<usize> {
-        let discard_read_data = matches!(self.access_pattern, AccessPattern::SequentialIsh);
+        // Each planned range is only read once too.
+        let discard_read_data = matches!(
+            self.access_pattern,
+            AccessPattern::SequentialIsh | AccessPattern::PlannedRanges(_)
+        );
         let mut block_to_discard = None;
         let mut return_value = None;
         for (possible_block_start, block) in self
@@ -239,11 +350,134 @@ impl std::fmt::Debug for State {
     }
 }
 
+/// The end of the file, fetched up front.
+struct Tail {
+    start: u64,
+    data: Vec<u8>,
+}
+
 /// Items related to reading from the underlying HTTP streams. This is
 /// in a separate struct because it's protected by a mutex.
 struct ReadingMaterials {
     range_fetcher: RangeFetcher,
-    reader: Option<(BufReader<Response>, u64)>, // second item in tuple is current reader pos
+    // Second item in tuple is current reader pos, third is where the
+    // response ends
+    reader: Option<(BufReader<ObservedResponse>, u64, u64)>,
+}
+
+/// Something which happened on the network while reading the resource, to
+/// be passed on to an [`UnzipProgressReporter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NetworkEvent {
+    RequestStarted(Range<u64>),
+    BytesDownloaded(u64),
+    RequestCompleted(Range<u64>),
+}
+
+impl NetworkEvent {
+    pub(crate) fn report(self, progress_reporter: &dyn UnzipProgressReporter) {
+        match self {
+            Self::RequestStarted(range) => progress_reporter.request_started(&range),
+            Self::BytesDownloaded(count) => progress_reporter.bytes_downloaded(count),
+            Self::RequestCompleted(range) => progress_reporter.request_completed(&range),
+        }
+    }
+}
+
+/// Where to send [`NetworkEvent`]s, if anywhere.
+type NetworkEvents = Arc<Mutex<Option<Sender<NetworkEvent>>>>;
+
+/// Send `event`, if anyone is listening, and return whether they were.
+fn send_network_event(network_events: &NetworkEvents, event: NetworkEvent) -> bool {
+    let network_events = network_events
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    network_events
+        .as_ref()
+        .is_some_and(|sender| sender.send(event).is_ok())
+}
+
+/// A response to a request for `range` of the resource, which reports how
+/// much of it has been downloaded, and when it's no longer needed.
+struct ObservedResponse {
+    response: Response,
+    range: Range<u64>,
+    network_events: NetworkEvents,
+    /// Whether the request was reported as started, so its completion
+    /// should be too.
+    reported: bool,
+}
+
+impl ObservedResponse {
+    /// Request `range` of the resource.
+    fn fetch(
+        range_fetcher: &RangeFetcher,
+        range: Range<u64>,
+        network_events: &NetworkEvents,
+    ) -> Result<Self, http_range_reader::Error> {
+        let reported =
+            send_network_event(network_events, NetworkEvent::RequestStarted(range.clone()));
+        match range_fetcher.fetch_range_until(range.start, range.end) {
+            Ok(response) => Ok(Self {
+                response,
+                range,
+                network_events: network_events.clone(),
+                reported,
+            }),
+            Err(e) => {
+                if reported {
+                    send_network_event(network_events, NetworkEvent::RequestCompleted(range));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Read for ObservedResponse {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.response.read(buf)?;
+        if bytes_read > 0 {
+            send_network_event(
+                &self.network_events,
+                NetworkEvent::BytesDownloaded(bytes_read as u64),
+            );
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl Drop for ObservedResponse {
+    fn drop(&mut self) {
+        if self.reported {
+            send_network_event(
+                &self.network_events,
+                NetworkEvent::RequestCompleted(self.range.clone()),
+            );
+        }
+    }
+}
+
+/// Sends [`NetworkEvent`]s until dropped, as returned by
+/// [`SeekableHttpReaderEngine::send_network_events`].
+pub(crate) struct NetworkEventsGuard<'a>(&'a SeekableHttpReaderEngine);
+
+impl Drop for NetworkEventsGuard<'_> {
+    fn drop(&mut self) {
+        // Finish with the current stream first, so that its request is
+        // reported as completed. Nobody can be reading from it, unless
+        // something panicked.
+        if let Ok(mut state) = self.0.state.lock() {
+            if let Some(reading_materials) = state.reader.as_mut() {
+                reading_materials.reader = None;
+            }
+        }
+        *self
+            .0
+            .network_events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
 }
 
 /// A type which can produce objects that can be [`Read`] and [`Seek`] even
@@ -255,6 +489,8 @@ struct ReadingMaterials {
 pub(crate) struct SeekableHttpReaderEngine {
     /// Total stream length
     len: u64,
+    /// The ETag the server gave for the resource, if any.
+    etag: Option<String>,
     /// Overall state of this object, mostly related to the readahead cache
     /// of blocks we already read, but also with the all-important boolean
     /// stating whether any thread is already reading on the underlying stream.
@@ -263,6 +499,14 @@ pub(crate) struct SeekableHttpReaderEngine {
     /// readahead cache and all other threads should consider if their read
     /// request can be serviced.
     read_completed: Condvar,
+    /// The end of the file, if it's been fetched. This is kept for as long
+    /// as the engine lives, since it's typically read more than once.
+    tail: OnceLock<Tail>,
+    /// Whether the block size was chosen by us rather than the user, so
+    /// we may change our minds about it.
+    max_block_is_automatic: bool,
+    /// Where to report requests and the data they fetch, if anywhere.
+    network_events: NetworkEvents,
 }
 
 /// Some results about the success (or otherwise) of this reader.
@@ -278,23 +522,31 @@ pub(crate) struct SeekableHttpReaderStatistics {
     /// Number of times we had to discard data from the cache because it
     /// was too big.
     pub(crate) cache_shrinks: usize,
+    /// The most data that's been held in the cache at once.
+    pub(crate) peak_cache_size: usize,
 }
 
 impl SeekableHttpReaderEngine {
     /// Create a new seekable HTTP reader engine for this URI. This constructor
     /// will query the server to discover whether it supports HTTP ranges;
     /// if not, an error will be returned.
+    /// `chunk_size` is how much to read from the stream at a time; if it's
+    /// `None`, that's chosen automatically. If `content_length` is given,
+    /// the server isn't queried, and is assumed to support ranges.
     pub(crate) fn new(
         uri: String,
         readahead_limit: Option<usize>,
         access_pattern: AccessPattern,
+        chunk_size: Option<usize>,
+        content_length: Option<u64>,
     ) -> Result<Arc<Self>, Error> {
         Self::with_configuration(
             uri,
             readahead_limit,
             access_pattern,
             DEFAULT_SKIP_AHEAD_THRESHOLD,
-            DEFAULT_MAX_BLOCK,
+            chunk_size,
+            content_length,
         )
     }
 
@@ -304,15 +556,29 @@ impl SeekableHttpReaderEngine {
         readahead_limit: Option<usize>,
         access_pattern: AccessPattern,
         skip_ahead_threshold: u64,
-        max_block: usize,
+        max_block: Option<usize>,
+        content_length: Option<u64>,
     ) -> Result<Arc<Self>, Error> {
-        let range_fetcher = RangeFetcher::new(uri).map_err(Error::RangeFetcherError)?;
+        let range_fetcher =
+            RangeFetcher::new(uri, content_length).map_err(Error::RangeFetcherError)?;
         if !range_fetcher.accepts_ranges() {
             return Err(Error::AcceptRangesNotSupported);
         }
         let len = range_fetcher.len();
+        let etag = range_fetcher.etag().map(str::to_string);
+        let max_block_is_automatic = max_block.is_none();
+        let max_block = max_block.unwrap_or_else(|| {
+            let max_block = max_block_for_latency(range_fetcher.latency());
+            log::debug!(
+                "Server took {:?} to respond; reading 0x{:x} bytes at a time",
+                range_fetcher.latency(),
+                max_block
+            );
+            max_block
+        });
         Ok(Arc::new(Self {
             len,
+            etag,
             state: Mutex::new(State::new(
                 readahead_limit,
                 access_pattern,
@@ -324,9 +590,80 @@ impl SeekableHttpReaderEngine {
                 }),
             )),
             read_completed: Condvar::new(),
+            tail: OnceLock::new(),
+            max_block_is_automatic,
+            network_events: NetworkEvents::default(),
         }))
     }
 
+    /// Hint that only a small part of the file will be read, so reading
+    /// big blocks would mostly fetch data which is then skipped. Does
+    /// nothing if a chunk size was specified.
+    pub(crate) fn expect_selective_reads(&self) {
+        if !self.max_block_is_automatic {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.max_block > SELECTIVE_MAX_BLOCK {
+            log::debug!(
+                "Extracting selectively; reading 0x{:x} bytes at a time",
+                SELECTIVE_MAX_BLOCK
+            );
+            state.max_block = SELECTIVE_MAX_BLOCK;
+        }
+    }
+
+    /// Keep no more than `limit` bytes which have been fetched but not yet
+    /// read, if that's less than the current limit. At least a block is
+    /// always kept.
+    pub(crate) fn limit_readahead(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        let limit = limit.max(state.max_block);
+        state.readahead_limit = Some(state.readahead_limit.map_or(limit, |old| old.min(limit)));
+    }
+
+    /// Fetch the last `len` bytes of the file in a single request. This is
+    /// only an optimization, so failure is merely logged.
+    pub(crate) fn prefetch_tail(&self, len: u64) {
+        let len = len.min(self.len);
+        if len == 0 {
+            return;
+        }
+        let start = self.len - len;
+        let mut state = self.state.lock().unwrap();
+        // Nobody else can be reading yet, so the reading materials are
+        // still there.
+        let Some(reading_stuff) = state.reader.as_ref() else {
+            return;
+        };
+        let mut data = Vec::with_capacity(len as usize);
+        let result = ObservedResponse::fetch(
+            &reading_stuff.range_fetcher,
+            start..self.len,
+            &self.network_events,
+        )
+        .map_err(std::io::Error::other)
+        .and_then(|response| response.take(len).read_to_end(&mut data));
+        match result {
+            Ok(_) if data.len() as u64 == len => {
+                state.stats.num_http_streams += 1;
+                log::debug!("Fetched the last {len} bytes up front");
+                let _ = self.tail.set(Tail { start, data });
+            }
+            Ok(_) => log::warn!("Unable to fetch the end of the file: it was truncated"),
+            Err(e) => log::warn!("Unable to fetch the end of the file: {e}"),
+        }
+    }
+
+    /// Read from the tail fetched up front, if it contains `pos`.
+    fn read_from_tail(&self, pos: u64, buf: &mut [u8]) -> Option<usize> {
+        let tail = self.tail.get().filter(|tail| pos >= tail.start)?;
+        let data = &tail.data[(pos - tail.start) as usize..];
+        let bytes_read = buf.len().min(data.len());
+        buf[..bytes_read].copy_from_slice(&data[..bytes_read]);
+        Some(bytes_read)
+    }
+
     /// Create an object which can be used to read from this HTTP location
     /// in a seekable fashion.
     pub(crate) fn create_reader(self: Arc<Self>) -> SeekableHttpReader {
@@ -373,6 +710,11 @@ impl SeekableHttpReaderEngine {
             ));
         }
 
+        if let Some(bytes_read_from_tail) = self.read_from_tail(pos, buf) {
+            log::debug!("Tail success");
+            return Ok(bytes_read_from_tail);
+        }
+
         // Claim CACHE mutex
         let mut state = self.state.lock().unwrap();
         // Is there block in cache?
@@ -382,6 +724,7 @@ impl SeekableHttpReaderEngine {
             return Ok(bytes_read_from_cache);
         }
         // - If no, check if read in progress
+        let started = Instant::now();
         let mut reading_stuff = state.reader.take();
         //   Is there read in progress?
         while reading_stuff.is_none() {
@@ -396,6 +739,7 @@ impl SeekableHttpReaderEngine {
             //     check cache again
             if let Some(bytes_read_from_cache) = state.read_from_cache(pos, buf) {
                 log::debug!("Deferred cache success");
+                thread_tuner::record_network_wait(started.elapsed());
                 return Ok(bytes_read_from_cache);
             }
             reading_stuff = state.reader.take();
@@ -412,6 +756,7 @@ impl SeekableHttpReaderEngine {
         state.expect_skip_ahead = false;
         let skip_ahead_threshold = state.skip_ahead_threshold;
         let max_block = state.max_block;
+        let needed_range = state.needed_range(pos);
         //     release STATE mutex
         drop(state);
         //     perform read
@@ -422,6 +767,7 @@ impl SeekableHttpReaderEngine {
             expect_skip_ahead,
             skip_ahead_threshold,
             max_block,
+            needed_range,
         );
         if read_result.is_err() {
             let mut state = self.state.lock().unwrap();
@@ -431,13 +777,16 @@ impl SeekableHttpReaderEngine {
         // reading_materials has been repopulated, so wake up all other
         // threads to check.
         self.read_completed.notify_all();
+        thread_tuner::record_network_wait(started.elapsed());
         read_result
     }
 
-    #[allow(clippy::comparison_chain)]
+    #[allow(clippy::comparison_chain, clippy::too_many_arguments)]
     // Read from the underlying HTTP stream
     // This is a separate function because if it errors at any point
     // we need to take cleanup action in the caller.
+    // If `needed_range` is given, it's the only part of the file around
+    // `pos` which will be read, so nothing outside it is fetched.
     fn perform_read_using_reader(
         &self,
         buf: &mut [u8],
@@ -446,11 +795,29 @@ impl SeekableHttpReaderEngine {
         expect_skip_ahead: bool,
         skip_ahead_threshold: u64,
         max_block: usize,
+        needed_range: Option<Range<u64>>,
     ) -> std::io::Result<usize> {
         // First check if we need to rewind, OR if we need to fast forward
         // and are expecting to skip over some significant data.
-        if let Some((_, readerpos)) = reading_stuff.reader.as_ref() {
-            if pos < *readerpos {
+        if let Some((_, readerpos, readerend)) = reading_stuff.reader.as_ref() {
+            if pos >= *readerend {
+                log::debug!(
+                    "New reader will be required at 0x{:x} - old reader ended at 0x{:x}",
+                    pos,
+                    *readerend
+                );
+                reading_stuff.reader = None;
+            } else if needed_range
+                .as_ref()
+                .is_some_and(|range| range.start > *readerpos)
+            {
+                log::debug!(
+                    "Skipping unneeded data: New reader will be required at 0x{:x} - old reader pos was 0x{:x}",
+                    pos,
+                    *readerpos
+                );
+                reading_stuff.reader = None;
+            } else if pos < *readerpos {
                 log::debug!(
                     "Rewinding: New reader will be required at 0x{:x} - old reader pos was 0x{:x}",
                     pos,
@@ -475,19 +842,23 @@ impl SeekableHttpReaderEngine {
         let mut reader_created = false;
         if reading_stuff.reader.is_none() {
             log::debug!("create_reader");
+            let end = needed_range.map_or(self.len, |range| range.end);
             reading_stuff.reader = Some((
                 BufReader::new(
-                    reading_stuff
-                        .range_fetcher
-                        .fetch_range(pos)
-                        .map_err(|e| std::io::Error::new(ErrorKind::Unsupported, e.to_string()))?,
+                    ObservedResponse::fetch(
+                        &reading_stuff.range_fetcher,
+                        pos..end,
+                        &self.network_events,
+                    )
+                    .map_err(|e| std::io::Error::new(ErrorKind::Unsupported, e.to_string()))?,
                 ),
                 pos,
+                end,
             ));
             reader_created = true;
         };
 
-        let (reader, reader_pos) = reading_stuff.reader.as_mut().unwrap();
+        let (reader, reader_pos, reader_end) = reading_stuff.reader.as_mut().unwrap();
         if pos > *reader_pos {
             log::debug!(
                 "Read: reading ahead from 0x{:x} to 0x{:x} without skipping",
@@ -498,7 +869,7 @@ impl SeekableHttpReaderEngine {
         while pos >= *reader_pos {
             // Fast forward beyond the desired position, recording any reads in the cache
             // for later.
-            let to_read = min(max_block, self.len as usize - *reader_pos as usize);
+            let to_read = min(max_block, (*reader_end - *reader_pos) as usize);
             let mut new_block = vec![0u8; to_read];
             reader.read_exact(&mut new_block)?;
             //     claim STATE mutex
@@ -532,13 +903,17 @@ impl SeekableHttpReaderEngine {
         self.len
     }
 
+    /// The ETag the server gave for the resource, if any.
+    pub(crate) fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
     /// Update the expected access pattern. You must not call this when
     /// any threads might be reading from any [`SeekableHttpReader`] created
     /// by this engine; that may panic.
     pub(crate) fn set_expected_access_pattern(&self, access_pattern: AccessPattern) {
         let mut state = self.state.lock().unwrap();
-        let old_access_pattern = state.access_pattern;
-        if old_access_pattern == access_pattern {
+        if state.access_pattern == access_pattern {
             return;
         }
         log::debug!(
@@ -553,13 +928,18 @@ impl SeekableHttpReaderEngine {
                 let reading_materials = state.reader.as_mut().expect(
                     "Must not call set_expected_access_pattern while a read is in progress",
                 );
-                let new_reader = reading_materials.range_fetcher.fetch_range(0);
+                let new_reader = ObservedResponse::fetch(
+                    &reading_materials.range_fetcher,
+                    0..self.len,
+                    &self.network_events,
+                );
                 if let Ok(new_reader) = new_reader {
-                    reading_materials.reader = Some((BufReader::new(new_reader), 0));
+                    reading_materials.reader = Some((BufReader::new(new_reader), 0, self.len));
                 }
             }
             state.stats.num_http_streams += 1;
         }
+        state.needed_ranges = needed_ranges(&access_pattern);
         state.access_pattern = access_pattern;
     }
 
@@ -569,6 +949,16 @@ impl SeekableHttpReaderEngine {
         state.expect_skip_ahead = true;
     }
 
+    /// Send every request made from now on, and the data it fetches, to
+    /// `sender`, until the returned guard is dropped.
+    pub(crate) fn send_network_events(
+        &self,
+        sender: Sender<NetworkEvent>,
+    ) -> NetworkEventsGuard<'_> {
+        *self.network_events.lock().unwrap() = Some(sender);
+        NetworkEventsGuard(self)
+    }
+
     /// Return some statistics about the success (or otherwise) of this stream.
     pub(crate) fn get_stats(&self) -> SeekableHttpReaderStatistics {
         self.state.lock().unwrap().stats.clone()
@@ -581,6 +971,32 @@ impl Drop for SeekableHttpReaderEngine {
     }
 }
 
+/// The only ranges of the file which need fetching if it's read in this
+/// way, sorted, with those close to each other merged so they're fetched
+/// together. Empty if they aren't known.
+fn needed_ranges(access_pattern: &AccessPattern) -> Vec<Range<u64>> {
+    let AccessPattern::PlannedRanges(ranges) = access_pattern else {
+        return Vec::new();
+    };
+    let mut ranges: Vec<Range<u64>> = ranges
+        .iter()
+        .filter(|range| !range.is_empty())
+        .cloned()
+        .collect();
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut needed_ranges: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match needed_ranges.last_mut() {
+            Some(last) if range.start <= last.end + NEEDED_RANGE_MERGE_GAP => {
+                last.end = last.end.max(range.end)
+            }
+            _ => needed_ranges.push(range),
+        }
+    }
+    log::debug!("Fetching only {} ranges of the file", needed_ranges.len());
+    needed_ranges
+}
+
 /// A [`Read`] which is also [`Seek`] to read from arbitrary places on an
 /// HTTP stream. Cheap to clone. Create using [`SeekableHttpReader::create_reader`].
 #[derive(Clone)]
@@ -636,14 +1052,20 @@ impl Read for SeekableHttpReader {
 #[cfg(test)]
 mod tests {
     use ripunzip_test_utils::{ExpectedRange, RangeAwareResponse, RangeAwareResponseType};
-    use std::io::{Read, Seek, SeekFrom};
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        time::Duration,
+    };
     use test_log::test;
 
     use httptest::{matchers::*, Expectation, Server};
 
     use crate::unzip::seekable_http_reader::DEFAULT_MAX_BLOCK;
 
-    use super::{AccessPattern, CacheCell, SeekableHttpReaderEngine};
+    use super::{
+        max_block_for_latency, AccessPattern, CacheCell, SeekableHttpReaderEngine,
+        HIGH_LATENCY_MAX_BLOCK, NEEDED_RANGE_MERGE_GAP, SELECTIVE_MAX_BLOCK,
+    };
 
     #[test]
     fn test_cachecell() {
@@ -666,6 +1088,131 @@ mod tests {
         assert!(cell.entirely_consumed());
     }
 
+    #[test]
+    fn test_prefetch_tail() {
+        let server = Server::run();
+        server.expect(get_head_expectation());
+        let seekable_http_reader_engine = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::RandomAccess,
+            None,
+            None,
+        )
+        .unwrap();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/foo"))
+                .times(1)
+                .respond_with(RangeAwareResponse::new(
+                    206,
+                    RangeAwareResponseType::Body {
+                        body: TEST_BODY.into(),
+                        expected_range: Some(ExpectedRange {
+                            expected_start: 8,
+                            expected_end: 12,
+                        }),
+                    },
+                )),
+        );
+        seekable_http_reader_engine.prefetch_tail(4);
+        // No more requests are needed to read the end, however often.
+        let mut seekable_http_reader = seekable_http_reader_engine.create_reader();
+        let mut throwaway = [0u8; 4];
+        for _ in 0..2 {
+            seekable_http_reader.seek(SeekFrom::Start(8)).unwrap();
+            seekable_http_reader.read_exact(&mut throwaway).unwrap();
+            assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "89AB");
+        }
+    }
+
+    #[test]
+    fn test_max_block_heuristics() {
+        assert_eq!(
+            max_block_for_latency(Duration::from_millis(5)),
+            DEFAULT_MAX_BLOCK
+        );
+        assert_eq!(
+            max_block_for_latency(Duration::from_millis(300)),
+            HIGH_LATENCY_MAX_BLOCK
+        );
+
+        let server = Server::run();
+        server.expect(get_head_expectation());
+        let automatic = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::RandomAccess,
+            None,
+            None,
+        )
+        .unwrap();
+        automatic.expect_selective_reads();
+        assert_eq!(
+            automatic.state.lock().unwrap().max_block,
+            SELECTIVE_MAX_BLOCK
+        );
+
+        let server = Server::run();
+        server.expect(get_head_expectation());
+        let explicit = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::RandomAccess,
+            Some(DEFAULT_MAX_BLOCK),
+            None,
+        )
+        .unwrap();
+        explicit.expect_selective_reads();
+        assert_eq!(explicit.state.lock().unwrap().max_block, DEFAULT_MAX_BLOCK);
+    }
+
+    #[test]
+    fn test_planned_ranges() {
+        let mut server = Server::run();
+        server.expect(get_head_expectation());
+        let seekable_http_reader_engine = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::SequentialIsh,
+            Some(DEFAULT_MAX_BLOCK),
+            None,
+        )
+        .unwrap();
+        let far_away = 10 + NEEDED_RANGE_MERGE_GAP + 1;
+        seekable_http_reader_engine.set_expected_access_pattern(AccessPattern::PlannedRanges(
+            vec![far_away..far_away + 1, 8..10, 1..2, 2..4],
+        ));
+        assert_eq!(
+            seekable_http_reader_engine
+                .state
+                .lock()
+                .unwrap()
+                .needed_ranges,
+            [1..10, far_away..far_away + 1]
+        );
+        server.verify_and_clear();
+
+        // Pretend the gap is too big to fetch.
+        seekable_http_reader_engine
+            .state
+            .lock()
+            .unwrap()
+            .needed_ranges = vec![1..4, 8..10];
+        let mut seekable_http_reader = seekable_http_reader_engine.create_reader();
+        let mut throwaway = [0u8; 3];
+        server.expect(get_range_expectation(1, 4));
+        seekable_http_reader.seek(SeekFrom::Start(1)).unwrap();
+        seekable_http_reader.read_exact(&mut throwaway).unwrap();
+        assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "123");
+        server.verify_and_clear();
+        server.expect(get_range_expectation(8, 10));
+        seekable_http_reader.seek(SeekFrom::Start(8)).unwrap();
+        seekable_http_reader
+            .read_exact(&mut throwaway[..2])
+            .unwrap();
+        assert_eq!(std::str::from_utf8(&throwaway[..2]).unwrap(), "89");
+    }
+
     #[test]
     fn test_unlimited_readahead() {
         do_test(None, AccessPattern::SequentialIsh)
@@ -718,9 +1265,10 @@ mod tests {
         let seekable_http_reader_engine = SeekableHttpReaderEngine::with_configuration(
             server.url("/foo").to_string(),
             readahead_limit,
-            access_pattern,
+            access_pattern.clone(),
             4,
-            DEFAULT_MAX_BLOCK,
+            Some(DEFAULT_MAX_BLOCK),
+            None,
         )
         .unwrap();
 
@@ -798,7 +1346,8 @@ mod tests {
                 readahead_limit,
                 access_pattern,
                 4,
-                4,
+                Some(4),
+                None,
             )
             .unwrap();
 
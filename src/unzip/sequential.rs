@@ -0,0 +1,405 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Extracting archives which can only be read from start to end, such as
+//! tar files and zip files piped to us. One thread reads the archive,
+//! handing each small file to the thread pool to be written out, and
+//! writing out big files itself.
+
+use std::{
+    io::{Cursor, ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+
+use super::{
+    file_failed, output_path, output_quota::QuotaReader, preallocate,
+    progress_updater::ProgressUpdater, stream_to_file, thread_tuner::ThreadTuner,
+    verification::CrcReader, EntryMetadata, EntryType, ModificationTimes, UnzipOptions,
+    UnzipProgressReporter, UnzipState, WarningKind,
+};
+
+/// Files no bigger than this are read into memory, to be written out by
+/// another thread. Bigger ones are written out by the thread reading the
+/// archive.
+#[cfg(not(test))]
+const MAX_BUFFERED_ENTRY: u64 = 1024 * 1024;
+/// Tests shouldn't need big files to exercise both.
+#[cfg(test)]
+const MAX_BUFFERED_ENTRY: u64 = 8;
+
+/// How many files may be waiting in memory to be written out.
+const ENTRY_QUEUE_LEN: usize = 64;
+
+/// Opens an archive, from the start.
+pub(crate) type Opener = Box<dyn Fn() -> Result<Box<dyn Read + Send>> + Send>;
+
+/// An [`Opener`] for a stream which can only be read once.
+pub(crate) fn read_once(reader: Box<dyn Read + Send>) -> Opener {
+    let reader = Mutex::new(Some(reader));
+    Box::new(move || {
+        reader
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow!("The archive can only be read once, as it's a stream"))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    File,
+    Directory,
+    /// Symbolic links, which aren't extracted.
+    Symlink,
+    /// Hard links, devices and so on, which aren't extracted either. The
+    /// description, such as "a device", is used to explain why.
+    Other(&'static str),
+}
+
+/// What's needed to extract an entry in an archive, apart from its data.
+pub(crate) struct StreamedEntry {
+    /// The name in the archive. Directories' names end with `/`, as in zip
+    /// files.
+    pub(crate) name: String,
+    pub(crate) kind: EntryKind,
+    /// The size is zero if it's not known until the data has been read.
+    pub(crate) metadata: EntryMetadata,
+}
+
+impl StreamedEntry {
+    pub(crate) fn entry_type(&self) -> EntryType {
+        match self.kind {
+            EntryKind::File => EntryType::File,
+            EntryKind::Directory => EntryType::Directory,
+            EntryKind::Symlink => EntryType::Symlink,
+            EntryKind::Other(_) => EntryType::Other,
+        }
+    }
+}
+
+/// The relative path at which to extract the file called `name`, or `None`
+/// if it would escape the output directory.
+pub(crate) fn enclosed_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(component) => path.push(component),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Passes the entries read from an archive on to be extracted.
+pub(crate) struct EntryDispatcher<'a, 'b, 'c> {
+    /// Where to send small files to be written out, unless everything is
+    /// written out by the thread reading the archive.
+    queue: Option<SyncSender<(StreamedEntry, Vec<u8>)>>,
+    options: &'a UnzipOptions<'b, 'c>,
+    state: &'a UnzipState,
+    errors: Vec<anyhow::Error>,
+    /// How many entries have been dispatched so far, which is the index
+    /// of the next.
+    dispatched: usize,
+}
+
+impl<'a, 'b, 'c> EntryDispatcher<'a, 'b, 'c> {
+    pub(crate) fn options(&self) -> &'a UnzipOptions<'b, 'c> {
+        self.options
+    }
+
+    pub(crate) fn state(&self) -> &'a UnzipState {
+        self.state
+    }
+
+    /// Extract `entry`, if the filters and entry indices select it, reading
+    /// its data from `contents`, which may be left partly unread. Returns
+    /// false if extraction has been abandoned, so nothing more should be
+    /// read.
+    pub(crate) fn dispatch(&mut self, mut entry: StreamedEntry, contents: &mut dyn Read) -> bool {
+        let index = self.dispatched;
+        self.dispatched += 1;
+        if let Some(indices) = &self.options.entry_indices {
+            if !indices.contains(&index) {
+                self.options
+                    .progress_reporter
+                    .extraction_skipped(&entry.name);
+                return true;
+            }
+        }
+        if let Some(filename_filter) = self.options.filters.name_filter() {
+            if !filename_filter.should_unzip(&entry.name) {
+                self.options
+                    .progress_reporter
+                    .extraction_skipped(&entry.name);
+                return true;
+            }
+        }
+        if !self
+            .options
+            .filters
+            .metadata_filter()
+            .selects(entry.entry_type(), &entry.metadata)
+            || self.options.policy.skips(&entry.name)
+        {
+            self.options
+                .progress_reporter
+                .extraction_skipped(&entry.name);
+            return true;
+        }
+        let result = match &self.queue {
+            Some(queue) if entry.metadata.size <= MAX_BUFFERED_ENTRY => {
+                // Files of unknown size are buffered until they turn out
+                // to be too big.
+                let mut data = Vec::with_capacity(entry.metadata.size as usize);
+                match contents.take(MAX_BUFFERED_ENTRY + 1).read_to_end(&mut data) {
+                    Ok(len) if len as u64 <= MAX_BUFFERED_ENTRY => {
+                        entry.metadata.size = len as u64;
+                        return queue.send((entry, data)).is_ok();
+                    }
+                    Ok(_) => extract_entry(
+                        &entry,
+                        &mut Cursor::new(data).chain(contents),
+                        self.options,
+                        self.state,
+                    ),
+                    Err(e) => file_failed(&entry.name, None, e.into(), self.options),
+                }
+            }
+            _ => extract_entry(&entry, contents, self.options, self.state),
+        };
+        if let Err(e) = result {
+            self.errors.push(e);
+        }
+        true
+    }
+
+    /// Note that an entry couldn't be extracted after all.
+    pub(crate) fn fail_entry(&mut self, name: &str, error: anyhow::Error) {
+        if let Err(e) = file_failed(name, None, error, self.options) {
+            self.errors.push(e);
+        }
+    }
+
+    /// Note an error which isn't specific to an entry.
+    pub(crate) fn fail(&mut self, error: anyhow::Error) {
+        self.errors.push(error);
+    }
+}
+
+/// Extract the entries which `read_entries` reads from an archive and
+/// gives to the [`EntryDispatcher`]. Returns any errors.
+pub(crate) fn extract_sequentially(
+    options: &UnzipOptions,
+    state: &UnzipState,
+    read_entries: impl FnOnce(&mut EntryDispatcher) + Send,
+) -> Vec<anyhow::Error> {
+    if options.policy.overwrite.is_freshen() || options.hardlink_duplicates {
+        return vec![anyhow!(
+            "Files can only be freshened or hardlinked to identical files when extracted from zip files which can be read in any order"
+        )];
+    }
+    if options.single_threaded {
+        let mut dispatcher = EntryDispatcher {
+            queue: None,
+            options,
+            state,
+            errors: Vec::new(),
+            dispatched: 0,
+        };
+        read_entries(&mut dispatcher);
+        return dispatcher.errors;
+    }
+    let (sender, receiver) = sync_channel(ENTRY_QUEUE_LEN);
+    std::thread::scope(|scope| {
+        let reading = scope.spawn(move || {
+            let mut dispatcher = EntryDispatcher {
+                queue: Some(sender),
+                options,
+                state,
+                errors: Vec::new(),
+                dispatched: 0,
+            };
+            read_entries(&mut dispatcher);
+            dispatcher.errors
+        });
+        let mut errors: Vec<anyhow::Error> = receiver
+            .into_iter()
+            .par_bridge()
+            .map(|(entry, data): (StreamedEntry, Vec<u8>)| {
+                let _permit = state.thread_tuner.as_ref().map(ThreadTuner::start_file);
+                extract_entry(&entry, &mut data.as_slice(), options, state)
+            })
+            .filter_map(Result::err)
+            .collect();
+        errors.extend(reading.join().unwrap());
+        errors
+    })
+}
+
+fn extract_entry(
+    entry: &StreamedEntry,
+    contents: &mut dyn Read,
+    options: &UnzipOptions,
+    state: &UnzipState,
+) -> Result<()> {
+    extract_entry_inner(entry, contents, options, state)
+        .or_else(|e| file_failed(&entry.name, None, e, options))
+}
+
+fn extract_entry_inner(
+    entry: &StreamedEntry,
+    contents: &mut dyn Read,
+    options: &UnzipOptions,
+    state: &UnzipState,
+) -> Result<()> {
+    let progress_reporter: &dyn UnzipProgressReporter = options.progress_reporter.as_ref();
+    let description = match entry.kind {
+        EntryKind::Symlink => Some("a symbolic link"),
+        EntryKind::Other(description) => Some(description),
+        EntryKind::File | EntryKind::Directory => None,
+    };
+    if let Some(description) = description {
+        let message = format!("Skipping {} as it is {description}", entry.name);
+        log::info!("{message}");
+        progress_reporter.warning(WarningKind::FileSkipped, &message);
+        progress_reporter.extraction_skipped(&entry.name);
+        return Ok(());
+    }
+    let name = enclosed_path(&entry.name)
+        .ok_or_else(|| std::io::Error::new(ErrorKind::Unsupported, "path not safe to extract"))?;
+    let Some(name) = output_path(&entry.name, name, options, state) else {
+        return Ok(());
+    };
+    let display_name = name.display().to_string();
+    progress_reporter.extraction_starting(&display_name);
+    let size = entry.metadata.size;
+    let mut progress_updater = ProgressUpdater::new(
+        |progress, bytes_written| {
+            progress_reporter.file_bytes_extracted(&display_name, progress, size);
+            state
+                .progress_batcher
+                .bytes_extracted_and_written(progress_reporter, 0, bytes_written);
+        },
+        size,
+        size,
+        1024 * 1024,
+    );
+    let is_dir = entry.kind == EntryKind::Directory;
+    if let Some(output_sink) = &options.output_sink {
+        let mut metadata = entry.metadata.clone();
+        if let ModificationTimes::Fixed(time) = options.policy.mtimes {
+            metadata.last_modified = Some(time);
+        }
+        if is_dir {
+            output_sink.create_directory(&name, &metadata)?;
+        } else {
+            let contents = QuotaReader::new(contents, state.output_quota.as_ref());
+            let mut reader = progress_streams::ProgressReader::new(contents, |bytes_read| {
+                progress_updater.progress(bytes_read as u64)
+            });
+            output_sink.write_file(&name, &metadata, &mut reader)?;
+        }
+        progress_updater.finish();
+        progress_reporter.extraction_finished(&display_name);
+        return Ok(());
+    }
+    let output_dir = state
+        .output_dir
+        .as_ref()
+        .expect("output directory is opened unless there's a sink");
+    if options.delete_extraneous {
+        state.extracted_paths.lock().unwrap().insert(name.clone());
+    }
+    if is_dir {
+        state.directory_creator.create_dir_all(output_dir, &name)?;
+    } else {
+        if let Some(parent) = name.parent() {
+            state.directory_creator.create_dir_all(output_dir, parent)?;
+        }
+        let mut open_options = cap_std::fs::OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        let out_file = output_dir
+            .open_with(&name, &open_options)
+            .with_context(|| "Failed to create file")?
+            .into_std();
+        if !options.sparse {
+            preallocate(&out_file, size, state.output_quota.as_ref())
+                .with_context(|| "Failed to allocate space for file")?;
+        }
+        // Tar files don't record CRCs, so files are checked against what
+        // was read from the archive.
+        let mut crc_reader = None;
+        let contents: &mut dyn Read = if options.verify_after {
+            crc_reader.insert(CrcReader::new(contents))
+        } else {
+            contents
+        };
+        let bytes_written = stream_to_file(
+            &out_file,
+            contents,
+            size,
+            options,
+            state,
+            &mut progress_updater,
+        )?;
+        if size == 0 {
+            // The progress updater can't report progress towards an unknown
+            // size.
+            state
+                .progress_batcher
+                .bytes_extracted_and_written(progress_reporter, 0, bytes_written);
+        }
+        if options.fsync {
+            out_file
+                .sync_all()
+                .with_context(|| "Failed to sync file to disk")?;
+        }
+        if let Some(crc_reader) = crc_reader {
+            state.written_files.record(&name, crc_reader.crc32());
+        }
+    }
+    progress_updater.finish();
+    if options.fsync {
+        state.directory_needs_sync(&name);
+    }
+    #[cfg(unix)]
+    super::restore_permissions(output_dir, &name, entry.metadata.unix_mode, state)?;
+    state.directory_times.restore(
+        output_dir,
+        &name,
+        is_dir,
+        &entry.metadata,
+        options.policy.mtimes,
+    )?;
+    progress_reporter.extraction_finished(&display_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::enclosed_path;
+
+    #[test]
+    fn test_enclosed_path() {
+        assert_eq!(enclosed_path("a/./b"), Some(PathBuf::from("a/b")));
+        assert_eq!(enclosed_path("a/../b"), None);
+        assert_eq!(enclosed_path("/etc/passwd"), None);
+        assert_eq!(enclosed_path("./"), None);
+    }
+}
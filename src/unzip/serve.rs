@@ -0,0 +1,234 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serving the files in a zip file over HTTP, decompressing each as it's
+//! requested, so that web bundles and static sites can be previewed
+//! without extracting them.
+
+use std::{
+    io::{Read, Seek},
+    ops::Range,
+};
+
+use anyhow::{anyhow, Result};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use zip::ZipArchive;
+
+/// Serve the files in `archive` at `address`, such as `127.0.0.1:8080`,
+/// until the process is killed.
+pub(crate) fn serve<R: Read + Seek + Clone + Send>(
+    archive: ZipArchive<R>,
+    address: &str,
+) -> Result<()> {
+    let server =
+        Server::http(address).map_err(|e| anyhow!("Failed to listen on {address}: {e}"))?;
+    log::info!("Serving on http://{}/", server.server_addr());
+    serve_on(&archive, &server, rayon::current_num_threads());
+    Ok(())
+}
+
+/// Answer requests to `server` on `threads` threads, until it's unblocked
+/// once for each of them.
+fn serve_on<R: Read + Seek + Clone + Send>(
+    archive: &ZipArchive<R>,
+    server: &Server,
+    threads: usize,
+) {
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let mut archive = archive.clone();
+            scope.spawn(move || {
+                while let Ok(request) = server.recv() {
+                    let url = request.url().to_string();
+                    if let Err(e) = respond(&mut archive, request) {
+                        log::warn!("Failed to serve {url}: {e:#}");
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn respond<R: Read + Seek>(archive: &mut ZipArchive<R>, request: Request) -> Result<()> {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        request.respond(Response::empty(405))?;
+        return Ok(());
+    }
+    let index = match target(archive, request.url()) {
+        Target::Entry(index) => index,
+        Target::Directory(path) => {
+            // Links relative to the index page need it to be in the
+            // directory.
+            let location = format!("/{path}/");
+            request.respond(Response::empty(301).with_header(header("Location", &location)))?;
+            return Ok(());
+        }
+        Target::NotFound => {
+            request.respond(Response::from_string("Not found").with_status_code(404))?;
+            return Ok(());
+        }
+    };
+    let mut file = archive.by_index(index)?;
+    let len = file.size();
+    let content_type = mime_guess::from_path(file.name()).first_or_octet_stream();
+    let range = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Range"))
+        .map(|header| requested_range(header.value.as_str(), len));
+    let mut headers = vec![
+        header("Content-Type", content_type.essence_str()),
+        header("Accept-Ranges", "bytes"),
+    ];
+    let (status, range) = match range {
+        None => (200, 0..len),
+        Some(Some(range)) => {
+            headers.push(header(
+                "Content-Range",
+                &format!("bytes {}-{}/{len}", range.start, range.end - 1),
+            ));
+            (206, range)
+        }
+        Some(None) => {
+            let content_range = format!("bytes */{len}");
+            request.respond(
+                Response::empty(416).with_header(header("Content-Range", &content_range)),
+            )?;
+            return Ok(());
+        }
+    };
+    // The data before the range still has to be decompressed.
+    std::io::copy(&mut (&mut file).take(range.start), &mut std::io::sink())?;
+    let body = file.take(range.end - range.start);
+    request.respond(Response::new(
+        StatusCode(status),
+        headers,
+        body,
+        Some((range.end - range.start) as usize),
+        None,
+    ))?;
+    Ok(())
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// What a URL refers to.
+enum Target {
+    Entry(usize),
+    /// A directory with an `index.html`, whose URL lacks a trailing slash.
+    Directory(String),
+    NotFound,
+}
+
+/// Find what a URL refers to. Directories are served by their
+/// `index.html`.
+fn target<R: Read + Seek>(archive: &ZipArchive<R>, url: &str) -> Target {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let Ok(path) = percent_encoding::percent_decode_str(path).decode_utf8() else {
+        return Target::NotFound;
+    };
+    let path = path.trim_start_matches('/');
+    if path.is_empty() || path.ends_with('/') {
+        return archive
+            .index_for_name(&format!("{path}index.html"))
+            .map_or(Target::NotFound, Target::Entry);
+    }
+    if let Some(index) = archive.index_for_name(path) {
+        return Target::Entry(index);
+    }
+    match archive.index_for_name(&format!("{path}/index.html")) {
+        Some(_) => Target::Directory(path.to_string()),
+        None => Target::NotFound,
+    }
+}
+
+/// The bytes requested by a `Range` header for a file of length `len`, or
+/// `None` if they can't be satisfied. Only single ranges are supported;
+/// others are treated as requests for the whole file.
+fn requested_range(header: &str, len: u64) -> Option<Range<u64>> {
+    let Some((start, end)) = header
+        .strip_prefix("bytes=")
+        .filter(|ranges| !ranges.contains(','))
+        .and_then(|range| range.split_once('-'))
+    else {
+        return Some(0..len);
+    };
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => len.saturating_sub(suffix.parse().ok()?)..len,
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => start.parse().ok()?..end.parse::<u64>().ok()?.saturating_add(1).min(len),
+    };
+    (range.start < range.end).then_some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use tiny_http::Server;
+    use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+    use super::{requested_range, serve_on};
+
+    #[test]
+    fn test_requested_range() {
+        assert_eq!(requested_range("bytes=0-3", 10), Some(0..4));
+        assert_eq!(requested_range("bytes=5-", 10), Some(5..10));
+        assert_eq!(requested_range("bytes=-3", 10), Some(7..10));
+        assert_eq!(requested_range("bytes=5-100", 10), Some(5..10));
+        assert_eq!(requested_range("bytes=0-1,4-5", 10), Some(0..10));
+        assert_eq!(requested_range("bytes=10-", 10), None);
+        assert_eq!(requested_range("bytes=x-", 10), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.add_directory("site", SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("site/index.html", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"<p>Hello</p>").unwrap();
+        zip.start_file("site/a b.css", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"0123456789").unwrap();
+        let archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", server.server_addr());
+        std::thread::scope(|scope| {
+            scope.spawn(|| serve_on(&archive, &server, 1));
+            let client = reqwest::blocking::Client::new();
+
+            let response = client.get(format!("{base}/site/")).send().unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["Content-Type"], "text/html");
+            assert_eq!(response.text().unwrap(), "<p>Hello</p>");
+
+            let response = client.get(format!("{base}/site")).send().unwrap();
+            assert_eq!(response.text().unwrap(), "<p>Hello</p>");
+
+            let response = client
+                .get(format!("{base}/site/a%20b.css"))
+                .header("Range", "bytes=2-4")
+                .send()
+                .unwrap();
+            assert_eq!(response.status(), 206);
+            assert_eq!(response.headers()["Content-Type"], "text/css");
+            assert_eq!(response.headers()["Content-Range"], "bytes 2-4/10");
+            assert_eq!(response.text().unwrap(), "234");
+
+            let response = client.get(format!("{base}/missing")).send().unwrap();
+            assert_eq!(response.status(), 404);
+
+            server.unblock();
+        });
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{io::Read, path::Path};
+
+use anyhow::{Context, Result};
+use minisign_verify::{PublicKey, Signature};
+
+/// Checks zip files against detached signatures made by minisign, or
+/// anything else which writes Ed25519 signatures in its format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureVerifier {
+    public_key: PublicKey,
+    /// The signature, as written in a `.minisig` file.
+    signature: String,
+}
+
+impl SignatureVerifier {
+    /// A verifier for the signature in the file at `signature`, which must
+    /// have been made with the secret key whose public key is in the file
+    /// at `public_key`, as written by `minisign -G`.
+    pub fn load(signature: &Path, public_key: &Path) -> Result<Self> {
+        let public_key = std::fs::read_to_string(public_key)
+            .with_context(|| format!("Failed to read public key {}", public_key.display()))?;
+        let signature = std::fs::read_to_string(signature)
+            .with_context(|| format!("Failed to read signature {}", signature.display()))?;
+        Self::new(&signature, &public_key)
+    }
+
+    /// A verifier for `signature`, the contents of a `.minisig` file, made
+    /// with the secret key whose public key is `public_key`. That may be
+    /// the contents of a `.pub` file, or just the line with the key on it.
+    pub fn new(signature: &str, public_key: &str) -> Result<Self> {
+        let public_key = match public_key.trim().lines().count() {
+            1 => PublicKey::from_base64(public_key.trim()),
+            _ => PublicKey::decode(public_key),
+        }
+        .context("Invalid public key")?;
+        Signature::decode(signature).context("Invalid signature")?;
+        Ok(Self {
+            public_key,
+            signature: signature.to_string(),
+        })
+    }
+
+    /// Check that everything read from `reader` is what was signed.
+    pub fn verify(&self, mut reader: impl Read) -> Result<()> {
+        let signature = Signature::decode(&self.signature)?;
+        let verified = match self.public_key.verify_stream(&signature) {
+            Ok(mut verifier) => {
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    let read = reader.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    verifier.update(&buffer[..read]);
+                }
+                verifier.finalize()
+            }
+            // Older versions of minisign signed the data itself, rather
+            // than its hash, so it all has to be read first.
+            Err(minisign_verify::Error::UnsupportedLegacyMode) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                self.public_key.verify(&data, &signature, true)
+            }
+            Err(error) => Err(error),
+        };
+        verified.context("The zip file doesn't match its signature")?;
+        log::info!("Verified signature: {}", signature.trusted_comment());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignatureVerifier;
+
+    const PUBLIC_KEY: &str = "untrusted comment: minisign public key E7620F1842B4E81F
+RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3
+";
+
+    #[test]
+    fn test_verify() {
+        let signatures = [
+            "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==
+",
+            // Made by an older version of minisign.
+            "untrusted comment: signature from minisign secret key
+RWQf6LRCGA9i59SLOFxz6NxvASXDJeRtuZykwQepbDEGt87ig1BNpWaVWuNrm73YiIiJbq71Wi+dP9eKL8OC351vwIasSSbXxwA=
+trusted comment: timestamp:1555779966\tfile:test
+QtKMXWyYcwdpZAlPF7tE2ENJkRd1ujvKjlj1m9RtHTBnZPa5WKU5uWRs5GoP5M/VqE81QFuMKI5k/SfNQUaOAA==
+",
+        ];
+        for signature in signatures {
+            let verifier = SignatureVerifier::new(signature, PUBLIC_KEY).unwrap();
+            verifier.verify(&b"test"[..]).unwrap();
+            assert!(verifier.verify(&b"Test"[..]).is_err());
+            let bare_key = PUBLIC_KEY.lines().nth(1).unwrap();
+            let verifier = SignatureVerifier::new(signature, bare_key).unwrap();
+            verifier.verify(&b"test"[..]).unwrap();
+        }
+        assert!(SignatureVerifier::new("nonsense", PUBLIC_KEY).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::Read,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use zip::read::ZipFile;
+
+use super::central_directory::ntfs_times;
+
+/// Information about an entry in the zip file which is being output.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EntryMetadata {
+    /// The uncompressed size of the entry.
+    pub size: u64,
+    /// Unix permissions, if the zip file recorded them.
+    pub unix_mode: Option<u32>,
+    /// Last modification time, if the zip file recorded a valid one.
+    /// Zip files don't record a time zone, so this assumes UTC, unless
+    /// the time came from an NTFS extra field.
+    pub last_modified: Option<SystemTime>,
+    /// Last access time, if the zip file recorded one in an NTFS extra
+    /// field.
+    pub accessed: Option<SystemTime>,
+    /// Creation time, if the zip file recorded one in an NTFS extra field.
+    pub created: Option<SystemTime>,
+    /// The zip compression method the entry was stored with, such as 8
+    /// for deflate, if it came from a zip file.
+    pub compression_method: Option<u16>,
+}
+
+impl EntryMetadata {
+    pub(crate) fn from_zip_file(file: &ZipFile) -> Self {
+        let ntfs = file.extra_data().and_then(ntfs_times).unwrap_or_default();
+        Self {
+            size: file.size(),
+            unix_mode: file.unix_mode(),
+            last_modified: ntfs
+                .modified
+                .or_else(|| file.last_modified().and_then(zip_time_to_system_time)),
+            accessed: ntfs.accessed,
+            created: ntfs.created,
+            #[allow(deprecated)]
+            compression_method: Some(file.compression().to_u16()),
+        }
+    }
+}
+
+/// Somewhere to put the files extracted from a zip file, instead of
+/// writing them into a directory. Entries may be output from several
+/// threads at once. Paths are relative, and have already been checked
+/// not to escape the root.
+pub trait OutputSink: Sync {
+    /// Output a directory.
+    fn create_directory(&self, path: &Path, metadata: &EntryMetadata) -> Result<()>;
+    /// Output a file, whose data can be read from `contents`.
+    fn write_file(
+        &self,
+        path: &Path,
+        metadata: &EntryMetadata,
+        contents: &mut dyn Read,
+    ) -> Result<()>;
+    /// Called once all entries have been output successfully.
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: OutputSink + ?Sized> OutputSink for &T {
+    fn create_directory(&self, path: &Path, metadata: &EntryMetadata) -> Result<()> {
+        (**self).create_directory(path, metadata)
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        metadata: &EntryMetadata,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        (**self).write_file(path, metadata, contents)
+    }
+
+    fn finish(&self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Convert an MS-DOS timestamp from a zip file, treating it as UTC.
+pub(crate) fn zip_time_to_system_time(time: zip::DateTime) -> Option<SystemTime> {
+    if !time.is_valid() {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil algorithm.
+    let (year, month, day) = (time.year() as i64, time.month() as i64, time.day() as i64);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let seconds =
+        days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    // MS-DOS times start in 1980, so this is never negative.
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+/// Convert a time to an MS-DOS timestamp for a zip file, in UTC. Times
+/// which MS-DOS timestamps can't represent give `None`.
+pub(crate) fn system_time_to_zip_time(time: SystemTime) -> Option<zip::DateTime> {
+    let seconds = time.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs() as i64;
+    let time = UtcTime::from_unix_seconds(seconds);
+    zip::DateTime::from_date_and_time(
+        time.year.try_into().ok()?,
+        time.month,
+        time.day,
+        time.hour,
+        time.minute,
+        time.second,
+    )
+    .ok()
+}
+
+/// A date and time of day in UTC.
+pub(crate) struct UtcTime {
+    pub(crate) year: i64,
+    pub(crate) month: u8,
+    pub(crate) day: u8,
+    pub(crate) hour: u8,
+    pub(crate) minute: u8,
+    pub(crate) second: u8,
+}
+
+impl UtcTime {
+    /// The time `seconds` after the Unix epoch.
+    pub(crate) fn from_unix_seconds(seconds: i64) -> Self {
+        // Howard Hinnant's civil_from_days algorithm.
+        let days = seconds.div_euclid(86400) + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let second_of_day = seconds.rem_euclid(86400);
+        Self {
+            year: year_of_era + era * 400 + i64::from(month <= 2),
+            month: month as u8,
+            day: day as u8,
+            hour: (second_of_day / 3600) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            second: (second_of_day % 60) as u8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{system_time_to_zip_time, zip_time_to_system_time};
+
+    #[test]
+    fn test_zip_time_to_system_time() {
+        let time = zip::DateTime::from_date_and_time(2024, 3, 1, 12, 30, 4).unwrap();
+        assert_eq!(
+            zip_time_to_system_time(time),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1709296204))
+        );
+        let time = zip::DateTime::from_date_and_time(1980, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            zip_time_to_system_time(time),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(315532800))
+        );
+    }
+
+    #[test]
+    fn test_system_time_to_zip_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1709296204);
+        assert_eq!(
+            system_time_to_zip_time(time),
+            Some(zip::DateTime::from_date_and_time(2024, 3, 1, 12, 30, 4).unwrap())
+        );
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(951782404);
+        assert_eq!(
+            system_time_to_zip_time(time).and_then(zip_time_to_system_time),
+            Some(time)
+        );
+        assert_eq!(system_time_to_zip_time(SystemTime::UNIX_EPOCH), None);
+    }
+}
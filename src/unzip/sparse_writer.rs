@@ -0,0 +1,110 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{Seek, SeekFrom, Write};
+
+/// Size of the blocks which we check for zeros. Filesystems can only leave
+/// holes in whole blocks, and 4K is the most common block size.
+const BLOCK_SIZE: u64 = 4096;
+
+/// A writer which seeks over whole blocks of zeros instead of writing them,
+/// so that on filesystems which support it the output file is sparse.
+/// [`SparseWriter::finish`] must be called once all data has been written.
+pub(crate) struct SparseWriter<W: Write + Seek> {
+    inner: W,
+    pos: u64,
+    /// Whether we've skipped over zeros at the end of what's been written
+    /// so far.
+    pending_hole: bool,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pos: 0,
+            pending_hole: false,
+        }
+    }
+
+    /// Finish writing. If the data ended in zeros, we need to write the
+    /// final byte so that the file is the right length.
+    pub(crate) fn finish(mut self) -> std::io::Result<W> {
+        if self.pending_hole {
+            self.inner.seek(SeekFrom::Current(-1))?;
+            self.inner.write_all(&[0])?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            // Split into chunks which line up with block boundaries in the
+            // output file.
+            let to_boundary = BLOCK_SIZE - (self.pos % BLOCK_SIZE);
+            let chunk_len = std::cmp::min(to_boundary, remaining.len() as u64) as usize;
+            let (chunk, rest) = remaining.split_at(chunk_len);
+            if chunk_len as u64 == BLOCK_SIZE && chunk.iter().all(|b| *b == 0) {
+                self.inner.seek(SeekFrom::Current(chunk_len as i64))?;
+                self.pending_hole = true;
+            } else {
+                self.inner.write_all(chunk)?;
+                self.pending_hole = false;
+            }
+            self.pos += chunk_len as u64;
+            remaining = rest;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::{SparseWriter, BLOCK_SIZE};
+
+    fn write_sparse(data: &[u8], write_size: usize) -> Vec<u8> {
+        let mut writer = SparseWriter::new(Cursor::new(Vec::new()));
+        for chunk in data.chunks(write_size) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_sparse_writer() {
+        let block = BLOCK_SIZE as usize;
+        let mut data = vec![1u8; 100];
+        data.extend(vec![0u8; block * 3]);
+        data.extend(vec![2u8; 10]);
+        data.extend(vec![0u8; block * 2 + 7]);
+        for write_size in [1, 100, block, block * 10] {
+            assert_eq!(write_sparse(&data, write_size), data);
+        }
+    }
+
+    #[test]
+    fn test_sparse_writer_skips_zero_blocks() {
+        // Start with a non-zero cursor so we can spot which bytes were
+        // written rather than skipped.
+        let block = BLOCK_SIZE as usize;
+        let mut writer = SparseWriter::new(Cursor::new(vec![9u8; block * 3]));
+        writer.write_all(&vec![0u8; block * 3]).unwrap();
+        let output = writer.finish().unwrap().into_inner();
+        assert!(output[..block * 3 - 1].iter().all(|b| *b == 9));
+        assert_eq!(output[block * 3 - 1], 0);
+    }
+}
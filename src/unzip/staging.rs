@@ -0,0 +1,212 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tempfile::TempDir;
+
+use super::long_path::verbatim_path;
+
+/// A temporary directory alongside the real output directory. Files are
+/// extracted here first, and then swapped into place in one go only once
+/// everything has been extracted successfully. If this is dropped without
+/// calling [`StagingDirectory::commit`], the staged files are deleted.
+pub(crate) struct StagingDirectory {
+    dir: TempDir,
+    /// The absolute path of the output directory.
+    target: PathBuf,
+    /// Whether the output directory is the current directory, which must be
+    /// left while it's swapped.
+    target_is_current_dir: bool,
+}
+
+impl StagingDirectory {
+    /// Create a staging directory for the given output directory, or for
+    /// the current working directory if there's none. The directory is
+    /// given the permissions the process umask allows.
+    pub(crate) fn new(output_directory: Option<&Path>, umask: Option<u32>) -> Result<Self> {
+        let current_dir = std::env::current_dir()?;
+        let target = match output_directory {
+            Some(output_directory) => std::fs::canonicalize(output_directory)
+                .unwrap_or_else(|_| current_dir.join(output_directory)),
+            None => current_dir.clone(),
+        };
+        let target_is_current_dir = is_same_dir(&target, &current_dir);
+        // The staging directory must be on the same filesystem as the target
+        // so that the two can be swapped, so put it alongside.
+        let parent = target
+            .parent()
+            .with_context(|| "Output directory has no parent to stage in")?;
+        std::fs::create_dir_all(parent)
+            .with_context(|| "Failed to create parent of output directory")?;
+        let dir = tempfile::Builder::new()
+            .prefix(".ripunzip-staging-")
+            .tempdir_in(parent)
+            .with_context(|| "Failed to create staging directory")?;
+        // Temporary directories are only accessible by their owner, but this
+        // one may become the output directory, which should be created with
+        // the usual permissions.
+        #[cfg(unix)]
+        if let Some(umask) = umask {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = 0o777 & !umask;
+            std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(mode))
+                .with_context(|| "Failed to set permissions of staging directory")?;
+        }
+        #[cfg(not(unix))]
+        let _ = umask;
+        log::debug!("Staging extraction in {}", dir.path().display());
+        Ok(Self {
+            dir,
+            target,
+            target_is_current_dir,
+        })
+    }
+
+    /// The directory into which files should be extracted.
+    pub(crate) fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Move the staged files into the real output directory. If it already
+    /// exists, whatever the staged files don't replace is hard linked into
+    /// the staging directory, which is then swapped with the output
+    /// directory, so the old and new trees are each only ever seen whole.
+    /// Returns the directories whose entries were changed.
+    pub(crate) fn commit(self) -> Result<Vec<PathBuf>> {
+        let parent = self.target.parent().unwrap().to_path_buf();
+        let mut changed_directories = vec![parent.clone()];
+        if !self.target.exists() {
+            std::fs::rename(self.dir.path(), &self.target)
+                .with_context(|| "Failed to move staging directory into place")?;
+            return Ok(changed_directories);
+        }
+        link_into(
+            &verbatim_path(&self.target),
+            &verbatim_path(self.dir.path()),
+            Path::new(""),
+            &mut |relative_path| changed_directories.push(self.target.join(relative_path)),
+        )
+        .with_context(|| "Failed to add existing files to staging directory")?;
+        std::fs::set_permissions(
+            self.dir.path(),
+            std::fs::metadata(&self.target)?.permissions(),
+        )
+        .with_context(|| "Failed to set permissions of staging directory")?;
+        // The current directory can't be moved on every platform, and we
+        // mustn't be left inside the old tree once it's deleted.
+        if self.target_is_current_dir {
+            std::env::set_current_dir(&parent)?;
+        }
+        let swapped = swap(self.dir.path(), &self.target);
+        if self.target_is_current_dir {
+            std::env::set_current_dir(&self.target)?;
+        }
+        swapped.with_context(|| "Failed to swap staging directory into place")?;
+        // Dropping self.dir deletes the old tree, which is now where the
+        // staged files were.
+        Ok(changed_directories)
+    }
+}
+
+/// Atomically exchange the directories at `a` and `b`.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn swap(a: &Path, b: &Path) -> std::io::Result<()> {
+    use rustix::fs::{renameat_with, RenameFlags, CWD};
+    match renameat_with(CWD, a, CWD, b, RenameFlags::EXCHANGE) {
+        Ok(()) => Ok(()),
+        // Not every filesystem supports this.
+        Err(e) if e == rustix::io::Errno::INVAL || e == rustix::io::Errno::NOSYS => {
+            swap_by_renaming(a, b)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn swap(a: &Path, b: &Path) -> std::io::Result<()> {
+    swap_by_renaming(a, b)
+}
+
+/// Exchange the directories at `a` and `b` without being able to do so
+/// atomically. `b` is moved aside and `a` moved into its place, so `b` is
+/// briefly missing, but never partly replaced.
+fn swap_by_renaming(a: &Path, b: &Path) -> std::io::Result<()> {
+    let mut aside = a.as_os_str().to_owned();
+    aside.push(".old");
+    let aside = PathBuf::from(aside);
+    std::fs::rename(b, &aside)?;
+    if let Err(e) = std::fs::rename(a, b) {
+        let _ = std::fs::rename(&aside, b);
+        return Err(e);
+    }
+    std::fs::rename(&aside, a)
+}
+
+fn is_same_dir(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Hard link everything within `from` into `to`, except where `to` already
+/// has something of the same name, recursing into directories. Where a
+/// file can't be hard linked, it's copied. Directories which gain entries
+/// are reported to `changed` by their path relative to `from`.
+fn link_into(
+    from: &Path,
+    to: &Path,
+    relative_path: &Path,
+    changed: &mut impl FnMut(&Path),
+) -> Result<()> {
+    let mut linked_any = false;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let destination = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if std::fs::symlink_metadata(&destination).is_err() {
+                std::fs::create_dir(&destination)?;
+                std::fs::set_permissions(&destination, entry.metadata()?.permissions())?;
+                linked_any = true;
+            } else if !std::fs::symlink_metadata(&destination)?.is_dir() {
+                continue;
+            }
+            link_into(
+                &entry.path(),
+                &destination,
+                &relative_path.join(entry.file_name()),
+                changed,
+            )?;
+        } else if std::fs::symlink_metadata(&destination).is_err() {
+            link_entry(&entry.path(), &destination, file_type)
+                .with_context(|| format!("Failed to link {}", entry.path().display()))?;
+            linked_any = true;
+        }
+    }
+    if linked_any {
+        changed(relative_path);
+    }
+    Ok(())
+}
+
+/// Hard link a file, or copy it if that's not possible.
+fn link_entry(from: &Path, to: &Path, file_type: std::fs::FileType) -> std::io::Result<()> {
+    if std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    if file_type.is_symlink() {
+        return std::os::unix::fs::symlink(std::fs::read_link(from)?, to);
+    }
+    #[cfg(not(unix))]
+    let _ = file_type;
+    std::fs::copy(from, to).map(|_| ())
+}
@@ -0,0 +1,179 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Remote zip files with enormous central directories take a long time to
+//! fetch before the zip crate will let us extract anything. Instead, files
+//! can be extracted as soon as their central directory entries have been
+//! read, by reading their local headers directly.
+
+use std::{
+    io::{Read, Seek, SeekFrom},
+    sync::{mpsc::sync_channel, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+
+use super::{
+    central_directory::{
+        central_directory_entries, CentralDirectoryEntry, CentralDirectoryLocation,
+        FLAG_DATA_DESCRIPTOR, FLAG_ENCRYPTED,
+    },
+    extract_file,
+    thread_tuner::{EntryTimer, ThreadTuner},
+    UnzipOptions, UnzipState,
+};
+
+/// Central directories at least this big are worth extracting from while
+/// they're still being read.
+#[cfg(not(test))]
+pub(crate) const STREAMING_THRESHOLD: u64 = 1024 * 1024;
+/// Tests shouldn't need enormous zip files to exercise this.
+#[cfg(test)]
+pub(crate) const STREAMING_THRESHOLD: u64 = 16 * 1024;
+
+/// How many entries may be read ahead of those being extracted.
+const ENTRY_QUEUE_LEN: usize = 1024;
+
+/// Extract files as their central directory entries are read from
+/// `directory_reader`, reading their local headers and data from
+/// `data_reader`. Returns any errors, along with the indices of the files
+/// which can only be extracted once the zip crate has read the whole
+/// central directory: those which are encrypted, or whose sizes aren't
+/// recorded in their local headers.
+pub(crate) fn unzip_while_reading_central_directory<R, D>(
+    directory_reader: D,
+    data_reader: R,
+    location: &CentralDirectoryLocation,
+    options: &UnzipOptions,
+    state: &UnzipState,
+) -> (Vec<anyhow::Error>, Vec<usize>)
+where
+    R: Read + Seek + Clone + Sync,
+    D: Read + Seek + Send,
+{
+    let deferred = Mutex::new(Vec::new());
+    let extract = |(index, entry): (usize, CentralDirectoryEntry)| -> Result<()> {
+        if entry.flags & (FLAG_ENCRYPTED | FLAG_DATA_DESCRIPTOR) != 0 {
+            deferred.lock().unwrap().push(index);
+            return Ok(());
+        }
+        let queued = Instant::now();
+        let _permit = state.thread_tuner.as_ref().map(ThreadTuner::start_file);
+        let timer = EntryTimer::start(queued);
+        let mut reader = data_reader.clone();
+        reader.seek(SeekFrom::Start(entry.header_offset))?;
+        let file = zip::read::read_zipfile_from_stream(&mut reader)?
+            .ok_or_else(|| anyhow!("No local header at {:x}", entry.header_offset))?;
+        extract_file(file, entry.unix_mode(), timer, options, state)
+    };
+    let (sender, receiver) = sync_channel(ENTRY_QUEUE_LEN);
+    let errors = std::thread::scope(|scope| {
+        let reading = scope.spawn(move || -> Result<()> {
+            for (index, entry) in central_directory_entries(directory_reader, location)?.enumerate()
+            {
+                if sender.send((index, entry?)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        let mut errors: Vec<anyhow::Error> = if options.single_threaded {
+            receiver
+                .into_iter()
+                .map(extract)
+                .filter_map(Result::err)
+                .collect()
+        } else {
+            receiver
+                .into_iter()
+                .par_bridge()
+                .map(extract)
+                .filter_map(Result::err)
+                .collect()
+        };
+        if let Err(e) = reading.join().unwrap() {
+            errors.push(e.context("Failed to read central directory"));
+        }
+        errors
+    });
+    let mut deferred = deferred.into_inner().unwrap();
+    // They're extracted in the order they're given, which should be the
+    // order in the zip file.
+    deferred.sort_unstable();
+    (errors, deferred)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use tempfile::tempdir;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::unzip_while_reading_central_directory;
+    use crate::unzip::{central_directory::locate_central_directory, UnzipState};
+    use crate::{CaseCollisionPolicy, ExtractionPolicy, FilenameSanitization, UnzipOptions};
+
+    #[test]
+    fn test_unzip_while_reading_central_directory() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().unix_permissions(0o755);
+        zip.start_file("a.sh", options).unwrap();
+        zip.write_all(b"a").unwrap();
+        zip.add_directory("dir", SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("dir/b.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"b").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let location = locate_central_directory(Cursor::new(&data))
+            .unwrap()
+            .unwrap();
+        assert_eq!(location.entry_count, 3);
+
+        let td = tempdir().unwrap();
+        let options = UnzipOptions {
+            output_directory: Some(td.path().to_path_buf()),
+            policy: ExtractionPolicy {
+                path_sanitization: FilenameSanitization::None,
+                ..Default::default()
+            },
+            case_collisions: CaseCollisionPolicy::Allow,
+            ..Default::default()
+        };
+        let state = UnzipState {
+            output_dir: Some(
+                cap_std::fs::Dir::open_ambient_dir(td.path(), cap_std::ambient_authority())
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let (errors, deferred) = unzip_while_reading_central_directory(
+            Cursor::new(&data),
+            Cursor::new(&data),
+            &location,
+            &options,
+            &state,
+        );
+        assert!(errors.is_empty());
+        assert!(deferred.is_empty());
+        assert_eq!(std::fs::read(td.path().join("a.sh")).unwrap(), b"a");
+        assert_eq!(std::fs::read(td.path().join("dir/b.txt")).unwrap(), b"b");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(td.path().join("a.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+    }
+}
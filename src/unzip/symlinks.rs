@@ -0,0 +1,320 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Symbolic links in zip files. Unless they're extracted as plain files,
+//! they're only created once everything else has been extracted, so that
+//! nothing is ever written through them.
+
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use cap_fs_ext::DirExt;
+use cap_std::fs::Dir;
+
+use super::{ExtractionFailure, UnzipProgressReporter, WarningKind};
+
+/// What to do with symbolic links in zip files. Creating symbolic links on
+/// Windows needs Developer Mode or administrator privileges, so the other
+/// policies may be more useful there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Extract each as a plain file containing the path it points to.
+    #[default]
+    File,
+    /// Create symbolic links.
+    Create,
+    /// On Windows, create directory junctions for links to directories,
+    /// which need no privileges, and symbolic links for other links.
+    /// Elsewhere, the same as [`SymlinkPolicy::Create`].
+    Junction,
+    /// Copy whatever each link points to.
+    Copy,
+    /// Don't extract links.
+    Skip,
+}
+
+/// The most links followed while resolving where a link points, like
+/// Linux's limit on links in a path.
+const MAX_LINKS_FOLLOWED: usize = 40;
+
+/// The Unix file type bits for a symbolic link.
+const S_IFLNK: u32 = 0o120000;
+const S_IFMT: u32 = 0o170000;
+
+/// Whether an entry with these Unix permissions is a symbolic link.
+pub(crate) fn is_symlink(unix_mode: Option<u32>) -> bool {
+    unix_mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+}
+
+/// Links to be created once everything else has been extracted, with the
+/// paths they point to.
+#[derive(Default)]
+pub(crate) struct DeferredSymlinks(Mutex<Vec<(PathBuf, PathBuf)>>);
+
+impl DeferredSymlinks {
+    pub(crate) fn defer(&self, link: &Path, target: PathBuf) {
+        self.0.lock().unwrap().push((link.to_path_buf(), target));
+    }
+
+    /// Create the links in `output_dir`, which is at `output_directory`,
+    /// according to `policy`. Links which would point outside it are
+    /// skipped. Those which can't be created are reported to
+    /// `progress_reporter` as failures.
+    pub(crate) fn create(
+        self,
+        output_dir: &Dir,
+        output_directory: &Path,
+        policy: SymlinkPolicy,
+        progress_reporter: &dyn UnzipProgressReporter,
+    ) -> Vec<anyhow::Error> {
+        let mut links = self.0.into_inner().unwrap();
+        // Links to links are only copied properly if those they point to
+        // come first, which they usually do if they're nearer the root.
+        links.sort_by_key(|(link, _)| link.components().count());
+        // Whether a link points outside can depend on the other links, in
+        // whatever order they're created, so links are left out until all
+        // those left point inside when resolved through each other.
+        let mut enclosed: HashMap<_, _> = links.iter().cloned().collect();
+        loop {
+            let escaping: Vec<_> = enclosed
+                .iter()
+                .filter(|(link, target)| {
+                    resolve_target(output_dir, &enclosed, link, target).is_none()
+                })
+                .map(|(link, _)| link.clone())
+                .collect();
+            if escaping.is_empty() {
+                break;
+            }
+            for link in escaping {
+                enclosed.remove(&link);
+            }
+        }
+        let mut errors = Vec::new();
+        for (link, target) in links {
+            let resolved = enclosed
+                .contains_key(&link)
+                .then(|| resolve_target(output_dir, &enclosed, &link, &target))
+                .flatten();
+            let Some(resolved) = resolved else {
+                let message = format!(
+                    "Skipping {} as it points outside the output directory",
+                    link.display()
+                );
+                log::info!("{message}");
+                progress_reporter.warning(WarningKind::FileSkipped, &message);
+                continue;
+            };
+            if let Err(error) = create_link(
+                output_dir,
+                output_directory,
+                policy,
+                &link,
+                &target,
+                &resolved,
+            ) {
+                let name = link.display().to_string();
+                let error = error.context(format!("Failed to create link {name}"));
+                progress_reporter.extraction_failed(&ExtractionFailure {
+                    name: &name,
+                    header_offset: None,
+                    error: &error,
+                });
+                errors.push(error);
+            }
+        }
+        errors
+    }
+}
+
+/// Where `target`, which the link at `link` points to, is relative to
+/// `output_dir`, or `None` if it's outside it. Other links are followed along
+/// the way, both those already in `output_dir` and those in `links` which
+/// are yet to be created, as the target could otherwise escape through them
+/// despite looking enclosed.
+fn resolve_target(
+    output_dir: &Dir,
+    links: &HashMap<PathBuf, PathBuf>,
+    link: &Path,
+    target: &Path,
+) -> Option<PathBuf> {
+    // The components still to be resolved, last first.
+    let mut pending = Vec::new();
+    push_components(&mut pending, target);
+    push_components(&mut pending, link.parent().unwrap_or(Path::new("")));
+    let mut resolved = PathBuf::new();
+    let mut links_followed = 0;
+    while let Some(component) = pending.pop() {
+        match component.components().next() {
+            Some(Component::Normal(component)) => {
+                resolved.push(component);
+                // Links can't replace directories, so those stay as they are.
+                let link_target = match output_dir.symlink_metadata(&resolved) {
+                    Ok(metadata) if metadata.is_dir() => None,
+                    metadata => match links.get(&resolved) {
+                        Some(link_target) => Some(link_target.clone()),
+                        None if metadata.is_ok_and(|metadata| metadata.is_symlink()) => {
+                            Some(output_dir.read_link_contents(&resolved).ok()?)
+                        }
+                        None => None,
+                    },
+                };
+                if let Some(link_target) = link_target {
+                    links_followed += 1;
+                    if links_followed > MAX_LINKS_FOLLOWED {
+                        return None;
+                    }
+                    resolved.pop();
+                    push_components(&mut pending, &link_target);
+                }
+            }
+            Some(Component::CurDir) => {}
+            Some(Component::ParentDir) => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Add the components of `path` to `pending`, last first.
+fn push_components(pending: &mut Vec<PathBuf>, path: &Path) {
+    pending.extend(
+        path.components()
+            .rev()
+            .map(|component| PathBuf::from(component.as_os_str())),
+    );
+}
+
+/// Create the link at `link` to `target`, which is `resolved` relative to
+/// the output directory. Junctions need that directory's path,
+/// `output_directory`.
+#[cfg_attr(not(windows), allow(unused_variables))]
+fn create_link(
+    output_dir: &Dir,
+    output_directory: &Path,
+    policy: SymlinkPolicy,
+    link: &Path,
+    target: &Path,
+    resolved: &Path,
+) -> Result<()> {
+    // Like other files, links replace whatever's already there, unless
+    // it's a directory.
+    if output_dir
+        .symlink_metadata(link)
+        .is_ok_and(|metadata| !metadata.is_dir())
+    {
+        output_dir.remove_file(link)?;
+    }
+    let is_dir = output_dir
+        .metadata(resolved)
+        .is_ok_and(|metadata| metadata.is_dir());
+    match policy {
+        SymlinkPolicy::Copy if is_dir => {
+            if link.starts_with(resolved) {
+                anyhow::bail!("it points to a directory containing it, so can't be copied");
+            }
+            copy_dir(output_dir, resolved, link).with_context(|| "Failed to copy directory")
+        }
+        SymlinkPolicy::Copy => output_dir
+            .copy(resolved, output_dir, link)
+            .map(|_| ())
+            .with_context(|| "Failed to copy file"),
+        #[cfg(windows)]
+        SymlinkPolicy::Junction if is_dir => {
+            let output_directory = std::env::current_dir()?.join(output_directory);
+            create_junction(
+                &output_directory.join(resolved),
+                &output_directory.join(link),
+            )
+        }
+        _ if is_dir => Ok(output_dir.symlink_dir(target, link)?),
+        _ => Ok(output_dir.symlink_file(target, link)?),
+    }
+}
+
+fn copy_dir(dir: &Dir, from: &Path, to: &Path) -> std::io::Result<()> {
+    dir.create_dir_all(to)?;
+    for entry in dir.read_dir(from)? {
+        let name = entry?.file_name();
+        let (from, to) = (from.join(&name), to.join(&name));
+        if dir.metadata(&from)?.is_dir() {
+            copy_dir(dir, &from, &to)?;
+        } else {
+            dir.copy(&from, dir, &to)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn create_junction(target: &Path, link: &Path) -> Result<()> {
+    let status = std::process::Command::new("cmd")
+        .args(["/C", "mklink", "/J"])
+        .arg(link)
+        .arg(target)
+        .stdout(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("mklink failed: {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    use cap_std::{ambient_authority, fs::Dir};
+    use tempfile::tempdir;
+
+    use super::resolve_target;
+
+    #[test]
+    fn test_resolve_target() {
+        let td = tempdir().unwrap();
+        let dir = Dir::open_ambient_dir(td.path(), ambient_authority()).unwrap();
+        let links = HashMap::from([(PathBuf::from("p/q"), PathBuf::from("."))]);
+        let resolve = |link: &str, target: &str| {
+            resolve_target(&dir, &links, Path::new(link), Path::new(target))
+        };
+        assert_eq!(resolve("a/b", "c"), Some(PathBuf::from("a/c")));
+        assert_eq!(resolve("a/b", "../c/./d"), Some(PathBuf::from("c/d")));
+        assert_eq!(resolve("a/b", "../../c"), None);
+        assert_eq!(resolve("a", "/etc/passwd"), None);
+        // Links yet to be created are followed too.
+        assert_eq!(resolve("p/r", "q/../s"), Some(PathBuf::from("s")));
+        assert_eq!(resolve("p/r", "q/../../escaped"), None);
+
+        #[cfg(unix)]
+        {
+            use cap_fs_ext::DirExt;
+            dir.create_dir_all("a/b").unwrap();
+            dir.symlink_dir(".", "a/here").unwrap();
+            dir.symlink_dir("b", "a/down").unwrap();
+            dir.symlink_dir("loop", "a/loop").unwrap();
+            // Going up from a link goes up from where it points.
+            assert_eq!(resolve("a/x", "down/../c"), Some(PathBuf::from("a/c")));
+            assert_eq!(resolve("a/x", "here/../c"), Some(PathBuf::from("c")));
+            assert_eq!(resolve("a/x", "here/../../c"), None);
+            // So does going up from a link's own directory.
+            assert_eq!(resolve("a/down/x", "../c"), Some(PathBuf::from("a/c")));
+            assert_eq!(resolve("a/x", "loop/c"), None);
+        }
+    }
+}
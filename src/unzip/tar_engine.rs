@@ -0,0 +1,255 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Extracting gzip- or zstd-compressed tar files, which are often what's
+//! shipped where a zip file was expected. A tar file has no index, so it's
+//! decompressed from start to end.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+
+use super::{
+    sequential::{
+        extract_sequentially, read_once, EntryDispatcher, EntryKind, Opener, StreamedEntry,
+    },
+    EntryMetadata, ListedEntry, UnzipEngineImpl, UnzipOptions, UnzipState,
+};
+
+/// How a tar file is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Recognize a compressed tar file from its first few bytes. Returns
+    /// `None` for anything else, such as a zip file.
+    pub(crate) fn sniff(mut reader: impl Read) -> std::io::Result<Option<Self>> {
+        let mut magic = Vec::new();
+        (&mut reader).take(4).read_to_end(&mut magic)?;
+        Ok(Self::from_magic(&magic))
+    }
+
+    pub(crate) fn from_magic(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn decoder<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            // Some tools compress big files as several gzip members.
+            Self::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        })
+    }
+}
+
+/// Engine which knows how to extract a compressed tar file, either a local
+/// one, one fetched over HTTP(S), or one read from a stream.
+pub(crate) struct UnzipTarEngine {
+    compression: Compression,
+    open: Opener,
+}
+
+impl UnzipTarEngine {
+    pub(crate) fn for_file(compression: Compression, file: File) -> Self {
+        Self {
+            compression,
+            open: Box::new(move || {
+                let mut file = file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(file))
+            }),
+        }
+    }
+
+    pub(crate) fn for_uri(compression: Compression, uri: String) -> Self {
+        Self {
+            compression,
+            open: Box::new(move || Ok(Box::new(reqwest::blocking::get(&uri)?.error_for_status()?))),
+        }
+    }
+
+    pub(crate) fn for_stream(compression: Compression, reader: Box<dyn Read + Send>) -> Self {
+        Self {
+            compression,
+            open: read_once(reader),
+        }
+    }
+}
+
+impl UnzipEngineImpl for UnzipTarEngine {
+    fn unzip(&mut self, options: &UnzipOptions, state: &UnzipState) -> Vec<anyhow::Error> {
+        let reader = match (self.open)() {
+            Ok(reader) => reader,
+            Err(e) => return vec![e],
+        };
+        let compression = self.compression;
+        extract_sequentially(options, state, move |dispatcher| {
+            read_entries(compression, reader, dispatcher)
+        })
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut archive = tar::Archive::new(self.compression.decoder((self.open)()?)?);
+        let mut names = Vec::new();
+        for entry in archive.entries()? {
+            if let Some(entry) = streamed_entry(&entry?)? {
+                names.push(entry.name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn list_details(&self) -> Result<Vec<ListedEntry>> {
+        let mut archive = tar::Archive::new(self.compression.decoder((self.open)()?)?);
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            if let Some(entry) = streamed_entry(&entry?)? {
+                entries.push(ListedEntry {
+                    entry_type: entry.entry_type(),
+                    name: entry.name,
+                    size: entry.metadata.size,
+                    // Tar files are compressed as a whole.
+                    compressed_size: None,
+                    offset: None,
+                    last_modified: entry.metadata.last_modified,
+                    crc32: None,
+                    compression_method: None,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    #[cfg(windows)]
+    fn dos_attributes(&self) -> Result<std::collections::HashMap<String, u32>> {
+        Ok(Default::default())
+    }
+
+    #[cfg(all(feature = "fuse", unix))]
+    fn mount(&self, _mountpoint: &std::path::Path) -> Result<()> {
+        Err(anyhow::anyhow!("Only zip files can be mounted"))
+    }
+
+    #[cfg(feature = "serve")]
+    fn serve(&self, _address: &str) -> Result<()> {
+        Err(anyhow::anyhow!("Only zip files can be served"))
+    }
+}
+
+/// Describe an entry in a tar file, or return `None` for the entry for the
+/// root directory itself.
+fn streamed_entry<R: Read>(entry: &tar::Entry<R>) -> Result<Option<StreamedEntry>> {
+    let header = entry.header();
+    let kind = match header.entry_type() {
+        tar::EntryType::Regular | tar::EntryType::Continuous => EntryKind::File,
+        tar::EntryType::Directory => EntryKind::Directory,
+        tar::EntryType::Symlink => EntryKind::Symlink,
+        tar::EntryType::Link => EntryKind::Other("a hard link"),
+        tar::EntryType::Char | tar::EntryType::Block => EntryKind::Other("a device"),
+        tar::EntryType::Fifo => EntryKind::Other("a FIFO"),
+        _ => EntryKind::Other("a special file"),
+    };
+    // Names often start with ./ in tar files.
+    let mut name = String::from_utf8_lossy(&entry.path_bytes())
+        .trim_start_matches("./")
+        .to_string();
+    if name.is_empty() || name == "." {
+        return Ok(None);
+    }
+    if kind == EntryKind::Directory && !name.ends_with('/') {
+        name.push('/');
+    }
+    let metadata = EntryMetadata {
+        size: entry.size(),
+        unix_mode: header.mode().ok().map(|mode| mode & 0o7777),
+        last_modified: header
+            .mtime()
+            .ok()
+            .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)),
+        accessed: None,
+        created: None,
+        // Tar files are compressed as a whole.
+        compression_method: None,
+    };
+    Ok(Some(StreamedEntry {
+        name,
+        kind,
+        metadata,
+    }))
+}
+
+/// Decompress the tar file from `reader`, giving its entries to
+/// `dispatcher`.
+fn read_entries(compression: Compression, reader: impl Read, dispatcher: &mut EntryDispatcher) {
+    let progress_reporter = dispatcher.options().progress_reporter.as_ref();
+    let state = dispatcher.state();
+    // Progress through the whole tar file is in terms of compressed bytes,
+    // since that's the length we know up front.
+    let reader = progress_streams::ProgressReader::new(reader, |bytes_read| {
+        state
+            .progress_batcher
+            .bytes_extracted(progress_reporter, bytes_read as u64)
+    });
+    let mut archive = match compression.decoder(reader) {
+        Ok(decoder) => tar::Archive::new(decoder),
+        Err(e) => return dispatcher.fail(e.context("Failed to start decompressing tar file")),
+    };
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            return dispatcher.fail(anyhow::Error::from(e).context("Failed to read tar file"))
+        }
+    };
+    for entry in entries {
+        // There's no way to find the next entry after a corrupt one.
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                return dispatcher.fail(anyhow::Error::from(e).context("Failed to read tar file"))
+            }
+        };
+        match streamed_entry(&entry) {
+            Ok(Some(streamed_entry)) => {
+                if !dispatcher.dispatch(streamed_entry, &mut entry) {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => return dispatcher.fail(e.context("Failed to read tar header")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::Compression;
+
+    #[test]
+    fn test_sniff() {
+        let sniff = |data: &[u8]| Compression::sniff(Cursor::new(data)).unwrap();
+        assert_eq!(sniff(&[0x1f, 0x8b, 8, 0]), Some(Compression::Gzip));
+        assert_eq!(sniff(&[0x28, 0xb5, 0x2f, 0xfd]), Some(Compression::Zstd));
+        assert_eq!(sniff(b"PK\x03\x04"), None);
+        assert_eq!(sniff(b""), None);
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+
+use super::sink::{EntryMetadata, OutputSink};
+
+/// An [`OutputSink`] which writes the extracted files into a tar archive.
+/// Entries can only be appended to the archive one at a time, so this
+/// gains little from unzipping on multiple threads.
+pub struct TarSink<W: Write + Send> {
+    builder: Mutex<tar::Builder<W>>,
+}
+
+impl<W: Write + Send> TarSink<W> {
+    /// Create a sink which writes a tar archive to the given `Write`.
+    pub fn new(w: W) -> Self {
+        Self {
+            builder: Mutex::new(tar::Builder::new(w)),
+        }
+    }
+
+    /// Finish the tar archive, if that hasn't happened already, and return
+    /// the underlying `Write`.
+    pub fn into_inner(self) -> Result<W> {
+        Ok(self.builder.into_inner().unwrap().into_inner()?)
+    }
+
+    fn append(
+        &self,
+        path: &Path,
+        entry_type: tar::EntryType,
+        metadata: &EntryMetadata,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(metadata.size);
+        header.set_mode(metadata.unix_mode.map_or_else(
+            || {
+                if entry_type.is_dir() {
+                    0o755
+                } else {
+                    0o644
+                }
+            },
+            |mode| mode & 0o7777,
+        ));
+        if let Some(mtime) = metadata
+            .last_modified
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        {
+            header.set_mtime(mtime.as_secs());
+        }
+        self.builder
+            .lock()
+            .unwrap()
+            .append_data(&mut header, path, contents)
+            .with_context(|| "Failed to write to tar archive")
+    }
+}
+
+impl<W: Write + Send> OutputSink for TarSink<W> {
+    fn create_directory(&self, path: &Path, metadata: &EntryMetadata) -> Result<()> {
+        let metadata = EntryMetadata {
+            size: 0,
+            ..metadata.clone()
+        };
+        self.append(
+            path,
+            tar::EntryType::Directory,
+            &metadata,
+            &mut std::io::empty(),
+        )
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        metadata: &EntryMetadata,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        self.append(path, tar::EntryType::Regular, metadata, contents)
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.builder
+            .lock()
+            .unwrap()
+            .finish()
+            .with_context(|| "Failed to finish tar archive")
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Extracting a file on every CPU at once is right when decompression is
+//! the bottleneck. But when threads spend most of their time waiting for
+//! the network or the disk, more of them just contend with each other, and
+//! buffer more data in memory. So we watch where the time goes, and limit
+//! how many files are extracted at once accordingly.
+
+use std::{
+    cell::Cell,
+    io::Write,
+    ops::AddAssign,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often to reconsider how many files to extract at once.
+const ADJUSTMENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// If threads spend more than this fraction of their time waiting for one
+/// thing, that's the bottleneck.
+const BOTTLENECK_FRACTION: f64 = 0.5;
+
+thread_local! {
+    static NETWORK_WAIT: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+    static WRITE_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+    /// Whether this thread is already extracting a file; rayon may run
+    /// another extraction on this thread while it waits for chunks of the
+    /// first to be copied, and that mustn't wait for a permit.
+    static HOLDING_PERMIT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Note that this thread spent `duration` waiting for data from the
+/// network.
+pub(crate) fn record_network_wait(duration: Duration) {
+    NETWORK_WAIT.with(|total| total.set(total.get() + duration));
+}
+
+/// Note that this thread spent `duration` writing extracted data.
+pub(crate) fn record_write_time(duration: Duration) {
+    WRITE_TIME.with(|total| total.set(total.get() + duration));
+}
+
+/// A writer which records how long writes to it take.
+pub(crate) struct TimedWriter<W: Write>(pub(crate) W);
+
+impl<W: Write> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let started = Instant::now();
+        let result = self.0.write(buf);
+        record_write_time(started.elapsed());
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let started = Instant::now();
+        let result = self.0.flush();
+        record_write_time(started.elapsed());
+        result
+    }
+}
+
+/// Where the time went while unzipping an archive. Apart from opening it,
+/// these are totals across all the threads extracting files, so may add up
+/// to more than the time it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Opening the archive, which for most zip files includes reading the
+    /// central directory.
+    pub opening: Duration,
+    /// Waiting for data from the network.
+    pub network_wait: Duration,
+    /// Decompressing, or rather, whatever extracting files took other than
+    /// waiting for the network and writing.
+    pub decompression: Duration,
+    /// Writing extracted data out.
+    pub writing: Duration,
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        self.opening += other.opening;
+        self.network_wait += other.network_wait;
+        self.decompression += other.decompression;
+        self.writing += other.writing;
+    }
+}
+
+/// Measures where the time went while extracting one file, so that it can
+/// be logged and added up.
+pub(crate) struct EntryTimer {
+    queue_wait: Duration,
+    started: Instant,
+    network_wait_before: Duration,
+    write_time_before: Duration,
+}
+
+impl EntryTimer {
+    /// Start timing a file which was ready to be extracted at `queued`.
+    pub(crate) fn start(queued: Instant) -> Self {
+        let started = Instant::now();
+        Self {
+            queue_wait: started.duration_since(queued),
+            started,
+            network_wait_before: NETWORK_WAIT.with(Cell::get),
+            write_time_before: WRITE_TIME.with(Cell::get),
+        }
+    }
+
+    /// Where the time went since the file started being extracted.
+    pub(crate) fn spent(&self) -> Timings {
+        let network_wait = NETWORK_WAIT.with(Cell::get) - self.network_wait_before;
+        let writing = WRITE_TIME.with(Cell::get) - self.write_time_before;
+        // Whatever's left was spent decompressing, more or less.
+        let decompression = self
+            .started
+            .elapsed()
+            .saturating_sub(network_wait + writing);
+        Timings {
+            opening: Duration::ZERO,
+            network_wait,
+            decompression,
+            writing,
+        }
+    }
+
+    pub(crate) fn log(&self, name: &str, spent: &Timings) {
+        log::info!(
+            "Extracted {}: queued for {:?}, fetching for {:?}, decompressing for {:?}, writing for {:?}",
+            name,
+            self.queue_wait,
+            spent.network_wait,
+            spent.decompression,
+            spent.writing
+        );
+    }
+}
+
+/// What's limiting how quickly files are extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bottleneck {
+    Network,
+    Cpu,
+    Disk,
+}
+
+impl Bottleneck {
+    fn classify(busy: Duration, network_wait: Duration, write_time: Duration) -> Self {
+        let fraction = |duration: Duration| duration.as_secs_f64() / busy.as_secs_f64();
+        if fraction(network_wait) > BOTTLENECK_FRACTION {
+            Self::Network
+        } else if fraction(write_time) > BOTTLENECK_FRACTION {
+            Self::Disk
+        } else {
+            Self::Cpu
+        }
+    }
+}
+
+/// Time spent by all the threads extracting files since the last
+/// adjustment.
+struct Interval {
+    started: Instant,
+    busy: Duration,
+    network_wait: Duration,
+    write_time: Duration,
+}
+
+impl Interval {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            busy: Duration::ZERO,
+            network_wait: Duration::ZERO,
+            write_time: Duration::ZERO,
+        }
+    }
+}
+
+struct TunerState {
+    limit: usize,
+    active: usize,
+    interval: Interval,
+}
+
+/// Limits how many files are extracted at once, raising the limit when
+/// we're CPU-bound and lowering it when we're waiting for the network or
+/// the disk.
+pub(crate) struct ThreadTuner {
+    max_threads: usize,
+    min_threads: usize,
+    state: Mutex<TunerState>,
+    permit_released: Condvar,
+}
+
+impl ThreadTuner {
+    pub(crate) fn new(max_threads: usize) -> Self {
+        let max_threads = max_threads.max(1);
+        Self {
+            max_threads,
+            // Keep some parallelism even when waiting for the network, so
+            // that one file is decompressed while the next is fetched.
+            min_threads: max_threads.min(2),
+            state: Mutex::new(TunerState {
+                limit: max_threads,
+                active: 0,
+                interval: Interval::new(Instant::now()),
+            }),
+            permit_released: Condvar::new(),
+        }
+    }
+
+    /// Wait until another file may be extracted. The returned permit
+    /// measures where the time went until it's dropped.
+    pub(crate) fn start_file(&self) -> FilePermit<'_> {
+        let nested = HOLDING_PERMIT.with(|holding| holding.replace(true));
+        if !nested {
+            let mut state = self.state.lock().unwrap();
+            while state.active >= state.limit {
+                state = self.permit_released.wait(state).unwrap();
+            }
+            state.active += 1;
+        }
+        FilePermit {
+            tuner: self,
+            nested,
+            started: Instant::now(),
+            network_wait_before: NETWORK_WAIT.with(Cell::get),
+            write_time_before: WRITE_TIME.with(Cell::get),
+        }
+    }
+
+    fn finish_file(
+        &self,
+        now: Instant,
+        busy: Duration,
+        network_wait: Duration,
+        write_time: Duration,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        self.record(&mut state, now, busy, network_wait, write_time);
+        // The limit may have gone up, so wake everyone to check.
+        self.permit_released.notify_all();
+    }
+
+    fn record(
+        &self,
+        state: &mut TunerState,
+        now: Instant,
+        busy: Duration,
+        network_wait: Duration,
+        write_time: Duration,
+    ) {
+        let interval = &mut state.interval;
+        interval.busy += busy;
+        interval.network_wait += network_wait;
+        interval.write_time += write_time;
+        if now.duration_since(interval.started) < ADJUSTMENT_INTERVAL || interval.busy.is_zero() {
+            return;
+        }
+        let bottleneck =
+            Bottleneck::classify(interval.busy, interval.network_wait, interval.write_time);
+        let limit = match bottleneck {
+            Bottleneck::Cpu => (state.limit + 1).min(self.max_threads),
+            Bottleneck::Network | Bottleneck::Disk => (state.limit - 1).max(self.min_threads),
+        };
+        if limit != state.limit {
+            log::debug!(
+                "{:?}-bound; extracting {} files at once rather than {}",
+                bottleneck,
+                limit,
+                state.limit
+            );
+            state.limit = limit;
+        }
+        state.interval = Interval::new(now);
+    }
+}
+
+/// Permission to extract a file, from a [`ThreadTuner`].
+pub(crate) struct FilePermit<'a> {
+    tuner: &'a ThreadTuner,
+    nested: bool,
+    started: Instant,
+    network_wait_before: Duration,
+    write_time_before: Duration,
+}
+
+impl Drop for FilePermit<'_> {
+    fn drop(&mut self) {
+        // Time spent on a nested file is counted by the outer one.
+        if self.nested {
+            return;
+        }
+        HOLDING_PERMIT.with(|holding| holding.set(false));
+        let now = Instant::now();
+        self.tuner.finish_file(
+            now,
+            now.duration_since(self.started),
+            NETWORK_WAIT.with(Cell::get) - self.network_wait_before,
+            WRITE_TIME.with(Cell::get) - self.write_time_before,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Bottleneck, ThreadTuner, ADJUSTMENT_INTERVAL};
+
+    #[test]
+    fn test_classify() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            Bottleneck::classify(ms(100), ms(80), ms(10)),
+            Bottleneck::Network
+        );
+        assert_eq!(
+            Bottleneck::classify(ms(100), ms(10), ms(80)),
+            Bottleneck::Disk
+        );
+        assert_eq!(
+            Bottleneck::classify(ms(100), ms(20), ms(20)),
+            Bottleneck::Cpu
+        );
+    }
+
+    #[test]
+    fn test_limit_follows_bottleneck() {
+        let tuner = ThreadTuner::new(4);
+        let mut now = Instant::now();
+        let mut adjust = |network_wait: u64, write_time: u64| {
+            now += ADJUSTMENT_INTERVAL;
+            let mut state = tuner.state.lock().unwrap();
+            tuner.record(
+                &mut state,
+                now,
+                Duration::from_millis(100),
+                Duration::from_millis(network_wait),
+                Duration::from_millis(write_time),
+            );
+        };
+        for _ in 0..5 {
+            adjust(90, 0);
+        }
+        assert_eq!(tuner.state.lock().unwrap().limit, 2);
+        adjust(0, 0);
+        assert_eq!(tuner.state.lock().unwrap().limit, 3);
+        adjust(0, 90);
+        assert_eq!(tuner.state.lock().unwrap().limit, 2);
+        for _ in 0..5 {
+            adjust(0, 0);
+        }
+        assert_eq!(tuner.state.lock().unwrap().limit, 4);
+    }
+
+    #[test]
+    fn test_nested_permits() {
+        let tuner = ThreadTuner::new(1);
+        let outer = tuner.start_file();
+        // This would wait forever if it needed a permit of its own.
+        let inner = tuner.start_file();
+        drop(inner);
+        drop(outer);
+        assert_eq!(tuner.state.lock().unwrap().active, 0);
+    }
+}
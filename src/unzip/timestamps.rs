@@ -0,0 +1,165 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use cap_fs_ext::{DirExt, SystemTimeSpec};
+use cap_std::fs::Dir;
+
+use super::EntryMetadata;
+
+/// What modification times to give extracted files and directories.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ModificationTimes {
+    /// Leave them with the time they were extracted.
+    #[default]
+    Extraction,
+    /// The times recorded in the archive, where there are any.
+    Archive,
+    /// The same time for everything, so that extracting the same archive
+    /// always produces an identical tree.
+    Fixed(SystemTime),
+}
+
+impl ModificationTimes {
+    /// The time to give something whose time in the archive is `archived`.
+    fn time_for(self, archived: Option<SystemTime>) -> Option<SystemTime> {
+        match self {
+            Self::Extraction => None,
+            Self::Archive => archived,
+            Self::Fixed(time) => Some(time),
+        }
+    }
+}
+
+/// The times to give an extracted file or directory.
+#[derive(Debug, Clone, Copy)]
+struct Times {
+    modified: SystemTime,
+    accessed: Option<SystemTime>,
+    /// Creation times can only be set on Windows.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    created: Option<SystemTime>,
+}
+
+/// Sets the modification times of extracted files as they're extracted,
+/// and remembers those of directories until the end, since extracting
+/// files into a directory changes its time. Where the archive records
+/// access and creation times too, in an NTFS extra field, those are
+/// restored along with its modification times.
+#[derive(Default)]
+pub(crate) struct DirectoryTimes(Mutex<HashMap<PathBuf, Times>>);
+
+impl DirectoryTimes {
+    /// Give the file or directory which has been extracted at `name` the
+    /// times it should have.
+    pub(crate) fn restore(
+        &self,
+        output_dir: &Dir,
+        name: &Path,
+        is_dir: bool,
+        archived: &EntryMetadata,
+        modification_times: ModificationTimes,
+    ) -> Result<()> {
+        if let ModificationTimes::Fixed(time) = modification_times {
+            // Directories which aren't in the archive are created too.
+            let mut directory_times = self.0.lock().unwrap();
+            for parent in name.ancestors().skip(1) {
+                if parent.as_os_str().is_empty()
+                    || directory_times
+                        .insert(parent.to_path_buf(), Times::modified(time))
+                        .is_some()
+                {
+                    break;
+                }
+            }
+        }
+        let times = match modification_times.time_for(archived.last_modified) {
+            Some(modified) if modification_times == ModificationTimes::Archive => Times {
+                modified,
+                accessed: archived.accessed,
+                created: archived.created,
+            },
+            Some(modified) => Times::modified(modified),
+            None => return Ok(()),
+        };
+        if is_dir {
+            self.0.lock().unwrap().insert(name.to_path_buf(), times);
+        } else {
+            set_times(output_dir, name, &times)?;
+        }
+        Ok(())
+    }
+
+    /// Once everything has been extracted, give the directories their
+    /// times.
+    pub(crate) fn apply(self, output_dir: &Dir) -> Result<()> {
+        for (name, times) in self.0.into_inner().unwrap() {
+            set_times(output_dir, &name, &times)?;
+        }
+        Ok(())
+    }
+}
+
+impl Times {
+    fn modified(modified: SystemTime) -> Self {
+        Self {
+            modified,
+            accessed: None,
+            created: None,
+        }
+    }
+}
+
+fn set_times(output_dir: &Dir, name: &Path, times: &Times) -> Result<()> {
+    #[cfg(windows)]
+    if times.created.is_some() {
+        return set_windows_times(output_dir, name, times);
+    }
+    let spec = |time| SystemTimeSpec::Absolute(cap_std::time::SystemTime::from_std(time));
+    output_dir
+        .set_times(name, times.accessed.map(spec), Some(spec(times.modified)))
+        .with_context(|| format!("Failed to set modification time of {}", name.display()))
+}
+
+/// Set all three of the times Windows records, with `SetFileTime`.
+#[cfg(windows)]
+fn set_windows_times(output_dir: &Dir, name: &Path, times: &Times) -> Result<()> {
+    use cap_std::fs::{OpenOptions, OpenOptionsExt};
+    use std::os::windows::fs::FileTimesExt;
+
+    // Setting times only needs the right to write attributes, which even
+    // read-only files grant, and directories can only be opened with
+    // backup semantics.
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    let file = output_dir
+        .open_with(
+            name,
+            OpenOptions::new()
+                .access_mode(FILE_WRITE_ATTRIBUTES)
+                .custom_flags(FILE_FLAG_BACKUP_SEMANTICS),
+        )
+        .with_context(|| format!("Failed to open {} to set its times", name.display()))?
+        .into_std();
+    let mut file_times = std::fs::FileTimes::new().set_modified(times.modified);
+    if let Some(accessed) = times.accessed {
+        file_times = file_times.set_accessed(accessed);
+    }
+    if let Some(created) = times.created {
+        file_times = file_times.set_created(created);
+    }
+    file.set_times(file_times)
+        .with_context(|| format!("Failed to set times of {}", name.display()))
+}
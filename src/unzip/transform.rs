@@ -0,0 +1,138 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{borrow::Cow, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use regex::{Regex, RegexBuilder};
+
+/// A sed-style substitution which rewrites entries' names before they're
+/// extracted, like GNU tar's `--transform`.
+#[derive(Debug, Clone)]
+pub struct NameTransform {
+    regex: Regex,
+    /// The replacement, in the form the regex crate expects.
+    replacement: String,
+    /// Whether to replace every match, rather than just the first.
+    global: bool,
+}
+
+impl NameTransform {
+    /// Parse an expression such as `s/^old-prefix/new-prefix/`. Any
+    /// character may be used in place of `/`. The replacement may refer to
+    /// the whole match as `&` and to groups as `\1` to `\9`. The `g` flag
+    /// replaces every match, and the `i` flag ignores case.
+    pub fn parse(expression: &str) -> Result<Self> {
+        let mut chars = expression.chars();
+        if chars.next() != Some('s') {
+            bail!("expected an expression of the form s/REGEX/REPLACEMENT/FLAGS");
+        }
+        let delimiter = chars
+            .next()
+            .ok_or_else(|| anyhow!("expected a delimiter after s"))?;
+        let mut parts = vec![String::new()];
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(c) if c == delimiter => parts.last_mut().unwrap().push(c),
+                    Some(c) => {
+                        parts.last_mut().unwrap().push('\\');
+                        parts.last_mut().unwrap().push(c);
+                    }
+                    None => bail!("expression ends with a backslash"),
+                },
+                c if c == delimiter && parts.len() < 3 => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+        let [pattern, replacement, flags] = <[String; 3]>::try_from(parts)
+            .map_err(|_| anyhow!("expected an expression of the form s/REGEX/REPLACEMENT/FLAGS"))?;
+        let mut builder = RegexBuilder::new(&pattern);
+        let mut global = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => {
+                    builder.case_insensitive(true);
+                }
+                _ => bail!("unknown flag {flag}"),
+            }
+        }
+        Ok(Self {
+            regex: builder.build()?,
+            replacement: convert_replacement(&replacement),
+            global,
+        })
+    }
+
+    /// The name `name` is rewritten to.
+    pub(crate) fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.global {
+            self.regex.replace_all(name, self.replacement.as_str())
+        } else {
+            self.regex.replace(name, self.replacement.as_str())
+        }
+    }
+}
+
+impl FromStr for NameTransform {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        Self::parse(expression)
+    }
+}
+
+/// Convert a sed replacement to the regex crate's syntax.
+fn convert_replacement(replacement: &str) -> String {
+    let mut converted = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => {
+                    converted.push_str("${");
+                    converted.push(digit);
+                    converted.push('}');
+                }
+                Some(c) => converted.push(c),
+                None => {}
+            },
+            c => converted.push(c),
+        }
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NameTransform;
+
+    fn transform(expression: &str, name: &str) -> String {
+        NameTransform::parse(expression)
+            .unwrap()
+            .apply(name)
+            .into_owned()
+    }
+
+    #[test]
+    fn test_transform() {
+        assert_eq!(transform("s/^old/new/", "old/a/old"), "new/a/old");
+        assert_eq!(transform("s/old/new/g", "old/a/old"), "new/a/new");
+        assert_eq!(transform("s,^a/,,", "a/b"), "b");
+        assert_eq!(transform("s/A/b/i", "a"), "b");
+        assert_eq!(transform(r"s/\(x\)\//[&]/", "(x)/y"), "[(x)/]y");
+        assert_eq!(transform(r"s/(.*)\.txt/\1.md/", "a.txt"), "a.md");
+        assert_eq!(transform(r"s/a/\&$1/", "a"), "&$1");
+        assert!(NameTransform::parse("s/a/b").is_err());
+        assert!(NameTransform::parse("s/a/b/q").is_err());
+        assert!(NameTransform::parse("y/a/b/").is_err());
+    }
+}
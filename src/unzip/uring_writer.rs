@@ -0,0 +1,207 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Writing of output files using io_uring. A single background thread
+//! runs an io_uring event loop, and decompression threads hand it buffers
+//! to write, so that they can carry on decompressing instead of waiting
+//! for the disk.
+
+use std::{
+    fs::File,
+    io::{ErrorKind, Write},
+    sync::{mpsc as std_mpsc, OnceLock},
+};
+
+use tokio::sync::mpsc;
+use tokio_uring::buf::IoBuf;
+
+/// How much decompressed data to gather up before submitting a write.
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// How many buffers may be waiting to be written for each file before
+/// the decompression thread has to wait.
+const MAX_BUFFERS_IN_FLIGHT: usize = 4;
+
+/// A request to the io_uring thread to start writing a new file.
+struct FileRequest {
+    file: File,
+    buffers: mpsc::Receiver<Vec<u8>>,
+    result: std_mpsc::Sender<std::io::Result<()>>,
+}
+
+static URING_THREAD: OnceLock<Option<mpsc::UnboundedSender<FileRequest>>> = OnceLock::new();
+
+/// Returns a channel to the io_uring thread, starting it if need be.
+/// Returns `None` if io_uring is unavailable, e.g. on older kernels.
+fn uring_thread() -> Option<&'static mpsc::UnboundedSender<FileRequest>> {
+    URING_THREAD
+        .get_or_init(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let (started_sender, started_receiver) = std_mpsc::channel();
+            std::thread::Builder::new()
+                .name("ripunzip-uring".into())
+                .spawn(move || {
+                    let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                        Ok(runtime) => runtime,
+                        Err(e) => {
+                            log::debug!("io_uring unavailable: {e}");
+                            let _ = started_sender.send(false);
+                            return;
+                        }
+                    };
+                    let _ = started_sender.send(true);
+                    runtime.block_on(run_uring_thread(receiver));
+                })
+                .ok()?;
+            started_receiver.recv().unwrap_or(false).then_some(sender)
+        })
+        .as_ref()
+}
+
+async fn run_uring_thread(mut requests: mpsc::UnboundedReceiver<FileRequest>) {
+    while let Some(request) = requests.recv().await {
+        tokio_uring::spawn(async move {
+            let result = write_file(request.file, request.buffers).await;
+            let _ = request.result.send(result);
+        });
+    }
+}
+
+async fn write_file(file: File, mut buffers: mpsc::Receiver<Vec<u8>>) -> std::io::Result<()> {
+    let file = tokio_uring::fs::File::from_std(file);
+    let mut pos = 0u64;
+    let mut result = Ok(());
+    while let Some(mut buf) = buffers.recv().await {
+        let mut written = 0;
+        while written < buf.len() {
+            let (write_result, slice) = file.write_at(buf.slice(written..), pos).await;
+            buf = slice.into_inner();
+            match write_result {
+                Ok(0) => result = Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    written += n;
+                    pos += n as u64;
+                    continue;
+                }
+                Err(e) => result = Err(e),
+            }
+            break;
+        }
+        if result.is_err() {
+            // Dropping the receiver tells the writer to give up.
+            break;
+        }
+    }
+    let close_result = file.close().await;
+    result.and(close_result)
+}
+
+/// A writer which sends data to the io_uring thread to be written to a file.
+/// [`UringFileWriter::finish`] must be called to find out whether the writes
+/// were successful.
+pub(crate) struct UringFileWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<Vec<u8>>,
+    result: std_mpsc::Receiver<std::io::Result<()>>,
+}
+
+impl UringFileWriter {
+    /// Create a writer for the given file, or return `None` if io_uring
+    /// can't be used.
+    pub(crate) fn new(file: &File) -> std::io::Result<Option<Self>> {
+        let uring_thread = match uring_thread() {
+            Some(uring_thread) => uring_thread,
+            None => return Ok(None),
+        };
+        let (sender, buffers) = mpsc::channel(MAX_BUFFERS_IN_FLIGHT);
+        let (result_sender, result) = std_mpsc::channel();
+        let request = FileRequest {
+            file: file.try_clone()?,
+            buffers,
+            result: result_sender,
+        };
+        if uring_thread.send(request).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            sender,
+            result,
+        }))
+    }
+
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        let buffer = std::mem::replace(&mut self.buffer, Vec::with_capacity(BUFFER_SIZE));
+        if self.sender.blocking_send(buffer).is_err() {
+            // The io_uring side has stopped receiving, which means a write
+            // failed.
+            return Err(self.wait_for_result());
+        }
+        Ok(())
+    }
+
+    fn wait_for_result(&self) -> std::io::Error {
+        match self.result.recv() {
+            Ok(Err(e)) => e,
+            _ => std::io::Error::other("io_uring thread went away"),
+        }
+    }
+
+    /// Wait for all outstanding writes to complete.
+    pub(crate) fn finish(mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer()?;
+        }
+        let UringFileWriter { sender, result, .. } = self;
+        drop(sender);
+        result
+            .recv()
+            .unwrap_or_else(|_| Err(std::io::Error::other("io_uring thread went away")))
+    }
+}
+
+impl Write for UringFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(buf.len(), BUFFER_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == BUFFER_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::{UringFileWriter, BUFFER_SIZE};
+
+    #[test]
+    fn test_uring_writer() {
+        let td = tempdir().unwrap();
+        let path = td.path().join("f");
+        let file = std::fs::File::create(&path).unwrap();
+        let data: Vec<u8> = (0..BUFFER_SIZE * 3 + 17).map(|i| i as u8).collect();
+        match UringFileWriter::new(&file).unwrap() {
+            Some(mut writer) => {
+                writer.write_all(&data).unwrap();
+                writer.finish().unwrap();
+            }
+            // io_uring isn't available in this environment.
+            None => return,
+        }
+        assert_eq!(std::fs::read(path).unwrap(), data);
+    }
+}
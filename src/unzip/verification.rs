@@ -0,0 +1,126 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reading back extracted files once they've all been written, to check
+//! that what's on disk is what was in the archive.
+
+use std::{
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use cap_std::fs::Dir;
+use rayon::prelude::*;
+
+use super::{crc32_of, ExtractionFailure, UnzipProgressReporter};
+
+/// The CRC-32 of each file which has been written out.
+#[derive(Default)]
+pub(crate) struct WrittenFiles(Mutex<Vec<(PathBuf, u32)>>);
+
+impl WrittenFiles {
+    pub(crate) fn record(&self, path: &Path, crc32: u32) {
+        self.0.lock().unwrap().push((path.to_path_buf(), crc32));
+    }
+
+    /// Read back each file from `dir`, reporting those which are missing
+    /// or different to `progress_reporter`.
+    pub(crate) fn verify(
+        self,
+        dir: &Dir,
+        progress_reporter: &dyn UnzipProgressReporter,
+    ) -> Vec<anyhow::Error> {
+        self.0
+            .into_inner()
+            .unwrap()
+            .par_iter()
+            .filter_map(|(path, expected)| {
+                let error = match dir.open(path).and_then(crc32_of) {
+                    Ok(actual) if actual == *expected => return None,
+                    Ok(actual) => anyhow::Error::from(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("CRC-32 is {actual:08x} rather than {expected:08x}"),
+                    )),
+                    Err(e) => e.into(),
+                };
+                let name = path.display().to_string();
+                let error = error.context(format!("{name} was not written out correctly"));
+                progress_reporter.extraction_failed(&ExtractionFailure {
+                    name: &name,
+                    header_offset: None,
+                    error: &error,
+                });
+                Some(error)
+            })
+            .collect()
+    }
+}
+
+/// Works out the CRC-32 of the data read through it.
+pub(crate) struct CrcReader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub(crate) fn crc32(self) -> u32 {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use cap_std::{ambient_authority, fs::Dir};
+    use tempfile::tempdir;
+
+    use super::WrittenFiles;
+    use crate::NullProgressReporter;
+
+    #[test]
+    fn test_verify() {
+        let td = tempdir().unwrap();
+        std::fs::write(td.path().join("a.txt"), b"a").unwrap();
+        std::fs::write(td.path().join("b.txt"), b"short").unwrap();
+        let written_files = WrittenFiles::default();
+        written_files.record(Path::new("a.txt"), crc32fast::hash(b"a"));
+        written_files.record(Path::new("b.txt"), crc32fast::hash(b"shortened"));
+        written_files.record(Path::new("missing.txt"), 0);
+        let dir = Dir::open_ambient_dir(td.path(), ambient_authority()).unwrap();
+        let mut errors: Vec<String> = written_files
+            .verify(&dir, &NullProgressReporter)
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "b.txt was not written out correctly",
+                "missing.txt was not written out correctly"
+            ]
+        );
+    }
+}
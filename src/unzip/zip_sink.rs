@@ -0,0 +1,171 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::{Read, Seek, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use tempfile::SpooledTempFile;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::sink::{system_time_to_zip_time, EntryMetadata, OutputSink};
+
+/// Files which compress to more than this are compressed into a temporary
+/// file rather than in memory, before being copied into the zip file.
+const SPOOL_LIMIT: usize = 16 * 1024 * 1024;
+
+/// How a [`ZipSink`] compresses the files it writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZipCompression {
+    /// Store files without compressing them.
+    Stored,
+    /// Compress files with deflate, which any unzip tool can read.
+    Deflate,
+    /// Compress files with bzip2.
+    Bzip2,
+    /// Compress files with Zstandard, which is quicker to decompress and
+    /// usually smaller, but which fewer unzip tools can read.
+    Zstd,
+}
+
+impl ZipCompression {
+    fn method(self) -> CompressionMethod {
+        match self {
+            Self::Stored => CompressionMethod::Stored,
+            Self::Deflate => CompressionMethod::Deflated,
+            Self::Bzip2 => CompressionMethod::Bzip2,
+            Self::Zstd => CompressionMethod::Zstd,
+        }
+    }
+
+    /// The way an entry compressed with the zip compression method `method`
+    /// can be compressed again. Methods which can't be written are replaced
+    /// with deflate.
+    fn of_zip_method(method: u16) -> Self {
+        match method {
+            0 => Self::Stored,
+            12 => Self::Bzip2,
+            93 => Self::Zstd,
+            _ => Self::Deflate,
+        }
+    }
+}
+
+/// An [`OutputSink`] which writes the extracted files into a new zip file,
+/// compressed the same way as they were in the original unless told
+/// otherwise. Each file is compressed on the thread which extracted it, and
+/// only copying it into the zip file happens one file at a time.
+pub struct ZipSink<W: Write + Seek + Send> {
+    writer: Mutex<Option<ZipWriter<W>>>,
+    finished: Mutex<Option<W>>,
+    compression: Option<ZipCompression>,
+}
+
+impl<W: Write + Seek + Send> ZipSink<W> {
+    /// Create a sink which writes a zip file to the given `Write`.
+    pub fn new(w: W) -> Self {
+        Self {
+            writer: Mutex::new(Some(ZipWriter::new(w))),
+            finished: Mutex::new(None),
+            compression: None,
+        }
+    }
+
+    /// Compress every file with `compression`, rather than the way it was
+    /// compressed before.
+    pub fn compression(mut self, compression: ZipCompression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Finish the zip file, if that hasn't happened already, and return the
+    /// underlying `Write`.
+    pub fn into_inner(self) -> Result<W> {
+        self.finish()?;
+        Ok(self.finished.into_inner().unwrap().unwrap())
+    }
+
+    fn options(&self, metadata: &EntryMetadata) -> SimpleFileOptions {
+        let compression = self.compression.unwrap_or_else(|| {
+            metadata
+                .compression_method
+                .map_or(ZipCompression::Deflate, ZipCompression::of_zip_method)
+        });
+        let mut options = SimpleFileOptions::default()
+            .compression_method(compression.method())
+            .large_file(metadata.size >= u32::MAX as u64);
+        if let Some(mode) = metadata.unix_mode {
+            options = options.unix_permissions(mode);
+        }
+        if let Some(time) = metadata.last_modified.and_then(system_time_to_zip_time) {
+            options = options.last_modified_time(time);
+        }
+        options
+    }
+
+    fn with_writer(&self, f: impl FnOnce(&mut ZipWriter<W>) -> Result<()>) -> Result<()> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => f(writer),
+            None => bail!("The zip file has already been finished"),
+        }
+    }
+}
+
+/// The name of the entry for `path` in a zip file, which always uses `/`
+/// to separate directories.
+fn entry_name(path: &Path) -> String {
+    itertools::join(
+        path.components()
+            .map(|component| component.as_os_str().to_string_lossy()),
+        "/",
+    )
+}
+
+impl<W: Write + Seek + Send> OutputSink for ZipSink<W> {
+    fn create_directory(&self, path: &Path, metadata: &EntryMetadata) -> Result<()> {
+        let options = self.options(metadata);
+        self.with_writer(|writer| {
+            writer
+                .add_directory(entry_name(path), options)
+                .with_context(|| "Failed to write to zip file")
+        })
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        metadata: &EntryMetadata,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        // Compress the file into a zip file of its own, so that this can
+        // happen on many threads at once, then copy it across.
+        let mut single = ZipWriter::new(SpooledTempFile::new(SPOOL_LIMIT));
+        single.start_file(entry_name(path), self.options(metadata))?;
+        std::io::copy(contents, &mut single)?;
+        let mut single = ZipArchive::new(single.finish()?)?;
+        let file = single.by_index_raw(0)?;
+        self.with_writer(|writer| {
+            writer
+                .raw_copy_file(file)
+                .with_context(|| "Failed to write to zip file")
+        })
+    }
+
+    fn finish(&self) -> Result<()> {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let w = writer
+                .finish()
+                .with_context(|| "Failed to finish zip file")?;
+            *self.finished.lock().unwrap() = Some(w);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright 2024 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Extracting a zip file as it's read from a stream which can't seek, such
+//! as a pipe, by reading each file's local header in turn rather than the
+//! central directory at the end.
+
+use std::io::{BufReader, Cursor, Read};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{
+    central_directory::{
+        ntfs_times, u16_at, u32_at, u64_at, zip64_extra_field, CENTRAL_DIRECTORY_HEADER_SIGNATURE,
+        END_OF_CENTRAL_DIRECTORY_SIGNATURE, FLAG_DATA_DESCRIPTOR, FLAG_ENCRYPTED,
+        ZIP64_PLACEHOLDER,
+    },
+    sequential::{
+        extract_sequentially, read_once, EntryDispatcher, EntryKind, Opener, StreamedEntry,
+    },
+    sink::zip_time_to_system_time,
+    ArchiveSummary, CrxHeader, EntryMetadata, ListedEntry, UnzipEngineImpl, UnzipOptions,
+    UnzipState,
+};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const LOCAL_FILE_HEADER_LEN: usize = 30;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// Engine which knows how to extract a zip file from a stream, which can
+/// only be read once.
+pub(crate) struct UnzipStreamEngine {
+    open: Opener,
+    /// The header which came before the zip file, if it's a Chrome
+    /// extension. It's been read already.
+    crx_header: Option<CrxHeader>,
+}
+
+impl UnzipStreamEngine {
+    pub(crate) fn new(reader: Box<dyn Read + Send>, crx_header: Option<CrxHeader>) -> Self {
+        Self {
+            open: read_once(reader),
+            crx_header,
+        }
+    }
+}
+
+impl UnzipEngineImpl for UnzipStreamEngine {
+    fn unzip(&mut self, options: &UnzipOptions, state: &UnzipState) -> Vec<anyhow::Error> {
+        let reader = match (self.open)() {
+            Ok(reader) => reader,
+            Err(e) => return vec![e],
+        };
+        extract_sequentially(options, state, move |dispatcher| {
+            let progress_reporter = dispatcher.options().progress_reporter.as_ref();
+            let state = dispatcher.state();
+            let reader = progress_streams::ProgressReader::new(reader, |bytes_read| {
+                state
+                    .progress_batcher
+                    .bytes_extracted(progress_reporter, bytes_read as u64)
+            });
+            if let Err(e) = read_entries(reader, dispatcher) {
+                dispatcher.fail(e);
+            }
+        })
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        read_entries((self.open)()?, &mut names)?;
+        Ok(names)
+    }
+
+    fn list_details(&self) -> Result<Vec<ListedEntry>> {
+        let mut entries = Vec::new();
+        read_entries((self.open)()?, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn summary(&self) -> Result<ArchiveSummary> {
+        Ok(ArchiveSummary {
+            crx_header: self.crx_header.clone(),
+            ..ArchiveSummary::of_listed(&self.list_details()?)
+        })
+    }
+
+    #[cfg(windows)]
+    fn dos_attributes(&self) -> Result<std::collections::HashMap<String, u32>> {
+        // They're only recorded in the central directory.
+        Ok(Default::default())
+    }
+
+    #[cfg(all(feature = "fuse", unix))]
+    fn mount(&self, _mountpoint: &std::path::Path) -> Result<()> {
+        Err(anyhow!("Zip files can't be mounted from a stream"))
+    }
+
+    #[cfg(feature = "serve")]
+    fn serve(&self, _address: &str) -> Result<()> {
+        Err(anyhow!("Zip files can't be served from a stream"))
+    }
+}
+
+/// What to do with each entry read from the stream.
+trait Visitor {
+    /// Handle an entry, whose data can be read from `contents`, though it
+    /// needn't all be. Returns false if nothing more should be read.
+    fn visit(&mut self, entry: StreamedEntry, contents: &mut dyn Read) -> bool;
+
+    /// An entry's data turned out not to match the CRC after it.
+    fn corrupt(&mut self, name: &str, error: anyhow::Error);
+}
+
+impl Visitor for EntryDispatcher<'_, '_, '_> {
+    fn visit(&mut self, entry: StreamedEntry, contents: &mut dyn Read) -> bool {
+        self.dispatch(entry, contents)
+    }
+
+    fn corrupt(&mut self, name: &str, error: anyhow::Error) {
+        self.fail_entry(name, error);
+    }
+}
+
+/// Lists the names of the entries.
+impl Visitor for Vec<String> {
+    fn visit(&mut self, entry: StreamedEntry, _contents: &mut dyn Read) -> bool {
+        self.push(entry.name);
+        true
+    }
+
+    fn corrupt(&mut self, _name: &str, _error: anyhow::Error) {}
+}
+
+/// Lists the entries, decompressing each to find its size, since that's
+/// not always recorded before the data.
+impl Visitor for Vec<ListedEntry> {
+    fn visit(&mut self, entry: StreamedEntry, contents: &mut dyn Read) -> bool {
+        let size = std::io::copy(contents, &mut std::io::sink()).unwrap_or(entry.metadata.size);
+        self.push(ListedEntry {
+            entry_type: entry.entry_type(),
+            name: entry.name,
+            size,
+            compressed_size: None,
+            offset: None,
+            last_modified: entry.metadata.last_modified,
+            // This may only be known after the data.
+            crc32: None,
+            compression_method: None,
+        });
+        true
+    }
+
+    fn corrupt(&mut self, _name: &str, _error: anyhow::Error) {}
+}
+
+/// What a local header says about the entry which follows it.
+struct LocalHeader {
+    /// The header itself, so that the zip crate can read it again.
+    bytes: Vec<u8>,
+    flags: u16,
+    compression_method: u16,
+    compressed_size: u64,
+    /// Whether the sizes in any data descriptor are 64 bits.
+    zip64: bool,
+    name: String,
+    metadata: EntryMetadata,
+}
+
+/// Read the next local header, or return `None` once the central directory
+/// is reached.
+fn read_local_header(reader: &mut impl Read) -> Result<Option<LocalHeader>> {
+    let mut bytes = Vec::with_capacity(LOCAL_FILE_HEADER_LEN);
+    reader
+        .take(LOCAL_FILE_HEADER_LEN as u64)
+        .read_to_end(&mut bytes)?;
+    if bytes.is_empty() {
+        bail!("The stream ended before the central directory, so may be truncated");
+    }
+    if bytes.len() >= 4
+        && [
+            CENTRAL_DIRECTORY_HEADER_SIGNATURE,
+            END_OF_CENTRAL_DIRECTORY_SIGNATURE,
+        ]
+        .contains(&u32_at(&bytes, 0))
+    {
+        return Ok(None);
+    }
+    if bytes.len() < LOCAL_FILE_HEADER_LEN {
+        bail!("The stream ended in the middle of a local header");
+    }
+    if u32_at(&bytes, 0) != LOCAL_FILE_HEADER_SIGNATURE {
+        bail!("Invalid local header; the stream may not be a zip file");
+    }
+    let name_len = u16_at(&bytes, 26) as usize;
+    let extra_fields_len = u16_at(&bytes, 28) as usize;
+    bytes.resize(LOCAL_FILE_HEADER_LEN + name_len + extra_fields_len, 0);
+    reader.read_exact(&mut bytes[LOCAL_FILE_HEADER_LEN..])?;
+    let name = String::from_utf8_lossy(&bytes[LOCAL_FILE_HEADER_LEN..][..name_len]).to_string();
+    let extra_fields = &bytes[LOCAL_FILE_HEADER_LEN + name_len..];
+    let mut uncompressed_size = u32_at(&bytes, 22) as u64;
+    let mut compressed_size = u32_at(&bytes, 18) as u64;
+    let zip64_field = zip64_extra_field(extra_fields);
+    if uncompressed_size == ZIP64_PLACEHOLDER as u64 || compressed_size == ZIP64_PLACEHOLDER as u64
+    {
+        // Local headers' Zip64 extra fields have both sizes, if either is
+        // needed.
+        let sizes = zip64_field
+            .and_then(|field| Some((u64_at(field, 0)?, u64_at(field, 8)?)))
+            .ok_or_else(|| anyhow!("Zip64 extra field missing from local header"))?;
+        (uncompressed_size, compressed_size) = sizes;
+    }
+    let ntfs = ntfs_times(extra_fields).unwrap_or_default();
+    let last_modified = ntfs.modified.or_else(|| {
+        zip::DateTime::try_from_msdos(u16_at(&bytes, 12), u16_at(&bytes, 10))
+            .ok()
+            .and_then(zip_time_to_system_time)
+    });
+    Ok(Some(LocalHeader {
+        flags: u16_at(&bytes, 6),
+        compression_method: u16_at(&bytes, 8),
+        compressed_size,
+        zip64: zip64_field.is_some(),
+        name,
+        metadata: EntryMetadata {
+            // Permissions are only recorded in the central directory.
+            unix_mode: None,
+            // This is the size if there's no data descriptor, which is when
+            // it's needed.
+            size: uncompressed_size,
+            last_modified,
+            accessed: ntfs.accessed,
+            created: ntfs.created,
+            compression_method: Some(u16_at(&bytes, 8)),
+        },
+        bytes,
+    }))
+}
+
+/// Read the entries in the zip file from `reader`, giving them to
+/// `visitor`.
+fn read_entries(reader: impl Read, visitor: &mut impl Visitor) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    while let Some(header) = read_local_header(&mut reader)? {
+        let kind = if header.name.ends_with('/') {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        };
+        let has_data_descriptor = header.flags & FLAG_DATA_DESCRIPTOR != 0;
+        if header.flags & FLAG_ENCRYPTED != 0 {
+            if has_data_descriptor {
+                bail!(
+                    "{} is encrypted, so where it ends can't be found",
+                    header.name
+                );
+            }
+            let entry = StreamedEntry {
+                name: header.name,
+                kind: EntryKind::Other("an encrypted file"),
+                metadata: header.metadata,
+            };
+            if !visitor.visit(entry, &mut std::io::empty()) {
+                return Ok(());
+            }
+            std::io::copy(
+                &mut (&mut reader).take(header.compressed_size),
+                &mut std::io::sink(),
+            )?;
+        } else if !has_data_descriptor {
+            // The zip crate can decompress anything whose compressed size
+            // is known.
+            let mut header_and_data = Cursor::new(&header.bytes).chain(&mut reader);
+            let mut file = zip::read::read_zipfile_from_stream(&mut header_and_data)
+                .with_context(|| format!("Failed to read {}", header.name))?
+                .ok_or_else(|| anyhow!("Missing local header for {}", header.name))?;
+            let entry = StreamedEntry {
+                name: header.name,
+                kind,
+                metadata: header.metadata,
+            };
+            // Dropping the file skips whatever wasn't read.
+            if !visitor.visit(entry, &mut file) {
+                return Ok(());
+            }
+        } else if header.compression_method == METHOD_DEFLATED {
+            // DEFLATE streams mark their own ends, so the data descriptor
+            // can be found after them.
+            let mut contents = CrcReader::new(flate2::bufread::DeflateDecoder::new(&mut reader));
+            let entry = StreamedEntry {
+                name: header.name.clone(),
+                kind,
+                metadata: EntryMetadata {
+                    size: 0,
+                    ..header.metadata
+                },
+            };
+            let carry_on = visitor.visit(entry, &mut contents);
+            std::io::copy(&mut contents, &mut std::io::sink())
+                .with_context(|| format!("Failed to decompress {}", header.name))?;
+            let crc32 = contents.hasher.finalize();
+            let expected_crc32 = read_data_descriptor(&mut reader, header.zip64)?;
+            if crc32 != expected_crc32 {
+                visitor.corrupt(
+                    &header.name,
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid checksum").into(),
+                );
+            }
+            if !carry_on {
+                return Ok(());
+            }
+        } else {
+            let method = match header.compression_method {
+                METHOD_STORED => "stored".to_string(),
+                method => format!("compressed with method {method}"),
+            };
+            bail!(
+                "{} is {method} with its size after its data, so where it ends can't be found",
+                header.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Read the data descriptor following an entry's data, returning its CRC.
+fn read_data_descriptor(reader: &mut impl Read, zip64: bool) -> Result<u32> {
+    let mut field = [0u8; 4];
+    reader.read_exact(&mut field)?;
+    // The signature is optional.
+    if u32::from_le_bytes(field) == DATA_DESCRIPTOR_SIGNATURE {
+        reader.read_exact(&mut field)?;
+    }
+    let mut sizes = [0u8; 16];
+    let sizes_len = if zip64 { 16 } else { 8 };
+    reader
+        .read_exact(&mut sizes[..sizes_len])
+        .context("The stream ended in the middle of a data descriptor")?;
+    Ok(u32::from_le_bytes(field))
+}
+
+/// Calculates the CRC of everything read through it.
+struct CrcReader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> CrcReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::{read_entries, DATA_DESCRIPTOR_SIGNATURE, LOCAL_FILE_HEADER_SIGNATURE};
+
+    /// Write an entry as streaming zip writers do, with its sizes and CRC
+    /// in a data descriptor after its data.
+    fn write_streamed_entry(out: &mut Vec<u8>, name: &str, contents: &[u8], crc32: u32) {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(contents).unwrap();
+        let data = encoder.finish().unwrap();
+        out.extend(LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+        out.extend([20, 0, 8, 0, 8, 0, 0, 0, 0x21, 0]);
+        out.extend([0; 12]);
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend([0, 0]);
+        out.extend(name.as_bytes());
+        out.extend(data.iter());
+        out.extend(DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+        out.extend(crc32.to_le_bytes());
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend((contents.len() as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn test_read_entries() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("a.txt", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        zip.add_directory("dir", SimpleFileOptions::default())
+            .unwrap();
+        let mut data = zip.finish().unwrap().into_inner();
+        // Drop the central directory, apart from the signature which marks
+        // its start, and add entries with data descriptors before it.
+        let central_directory = data
+            .windows(4)
+            .position(|window| window == [0x50, 0x4b, 0x01, 0x02])
+            .unwrap();
+        data.truncate(central_directory);
+        let contents = b"Contents of B\n".repeat(10);
+        write_streamed_entry(
+            &mut data,
+            "dir/b.txt",
+            &contents,
+            crc32fast::hash(&contents),
+        );
+        write_streamed_entry(&mut data, "dir/c.txt", b"C", 0);
+        data.extend([0x50, 0x4b, 0x01, 0x02]);
+
+        struct Collector(Vec<(String, Vec<u8>)>, Vec<String>);
+        impl super::Visitor for Collector {
+            fn visit(
+                &mut self,
+                entry: super::StreamedEntry,
+                contents: &mut dyn std::io::Read,
+            ) -> bool {
+                let mut data = Vec::new();
+                contents.read_to_end(&mut data).unwrap();
+                self.0.push((entry.name, data));
+                true
+            }
+
+            fn corrupt(&mut self, name: &str, _error: anyhow::Error) {
+                self.1.push(name.to_string());
+            }
+        }
+        let mut collector = Collector(Vec::new(), Vec::new());
+        read_entries(Cursor::new(&data), &mut collector).unwrap();
+        assert_eq!(
+            collector.0,
+            [
+                ("a.txt".to_string(), b"Contents of A\n".to_vec()),
+                ("dir/".to_string(), Vec::new()),
+                ("dir/b.txt".to_string(), contents),
+                ("dir/c.txt".to_string(), b"C".to_vec()),
+            ]
+        );
+        assert_eq!(collector.1, ["dir/c.txt"]);
+
+        data.truncate(data.len() - 4);
+        assert!(read_entries(Cursor::new(data), &mut Vec::<String>::new()).is_err());
+    }
+}
@@ -73,38 +73,45 @@ impl httptest::responders::Responder for RangeAwareResponse {
                 body,
                 expected_range,
             } => {
-                let (body, content_length) =
-                    if let Some(range) = req.headers().get(http::header::RANGE) {
-                        let range_regex = Regex::new(r"bytes=(\d+)-(\d+)").unwrap();
-                        if let Some(captures) = range_regex.captures(range.to_str().unwrap()) {
-                            let from = captures
-                                .get(1)
-                                .and_then(|s| s.as_str().parse::<usize>().ok())
-                                .unwrap();
-                            let to = captures
-                                .get(2)
-                                .and_then(|s| s.as_str().parse::<usize>().ok())
-                                .unwrap();
+                let (body, content_length) = if let Some(range) =
+                    req.headers().get(http::header::RANGE)
+                {
+                    let range_regex = Regex::new(r"bytes=(\d+)-(\d+)").unwrap();
+                    if let Some(captures) = range_regex.captures(range.to_str().unwrap()) {
+                        let from = captures
+                            .get(1)
+                            .and_then(|s| s.as_str().parse::<usize>().ok())
+                            .unwrap();
+                        let to = captures
+                            .get(2)
+                            .and_then(|s| s.as_str().parse::<usize>().ok())
+                            .unwrap();
 
-                            if let Some(expected_range) = expected_range {
-                                assert_eq!(
-                                    expected_range.expected_start, from as u64,
-                                    "Unexpected start location"
-                                );
-                                assert_eq!(
-                                    expected_range.expected_end, to as u64,
-                                    "Unexpected end location"
-                                );
-                            }
-                            (body.slice(from..to), to - from)
-                        } else {
-                            assert!(expected_range.is_none());
-                            (body.clone(), body.len())
+                        if let Some(expected_range) = expected_range {
+                            assert_eq!(
+                                expected_range.expected_start, from as u64,
+                                "Unexpected start location"
+                            );
+                            assert_eq!(
+                                expected_range.expected_end, to as u64,
+                                "Unexpected end location"
+                            );
                         }
+                        let sent = body.slice(from..to);
+                        // Content-Range gives the last byte sent, not the one after it.
+                        let to = to.saturating_sub(1);
+                        builder = builder
+                            .header("Content-Range", format!("bytes {from}-{to}/{}", body.len()));
+                        let content_length = sent.len();
+                        (sent, content_length)
                     } else {
                         assert!(expected_range.is_none());
                         (body.clone(), body.len())
-                    };
+                    }
+                } else {
+                    assert!(expected_range.is_none());
+                    (body.clone(), body.len())
+                };
                 (Some(body), content_length)
             }
         };